@@ -0,0 +1,629 @@
+//! Cartridges in the iNES format
+//!
+//! An iNES file is a 16 byte header, an optional 512 byte trainer, the PRG ROM in 16KB units and
+//! the CHR ROM in 8KB units:
+//!
+//! ```text
+//! 0-3   "NES" followed by MS-DOS end of file
+//! 4     PRG ROM size in 16KB units
+//! 5     CHR ROM size in 8KB units, 0 means the board has CHR RAM
+//! 6     Flags 6: mirroring, battery, trainer, four-screen and the lower nibble of the mapper
+//! 7     Flags 7: the upper nibble of the mapper
+//! 8     PRG RAM size in 8KB units, 0 means 8KB
+//! 9     Flags 9: TV system
+//! 10-15 Unused padding
+//! ```
+//!
+//! NES 2.0 headers are marked by bits 2-3 of flags 7 being `10`, and use bytes 8-15 for larger
+//! mapper numbers and ROM sizes, submappers, exact RAM sizes, the timing and the console type.
+//! Old dumping tools left garbage like "DiskDude!" in bytes 7-15, which is ignored with a warning.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use utils::{crc32, md5};
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 0x0200;
+const PRG_ROM_UNIT: usize = 0x4000;
+const CHR_ROM_UNIT: usize = 0x2000;
+const PRG_RAM_UNIT: usize = 0x2000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    INes,
+    Nes2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    /// Every nametable is the first page of VRAM
+    SingleScreenLower,
+    /// Every nametable is the second page of VRAM
+    SingleScreenUpper,
+    FourScreen,
+}
+
+/// The TV system the cartridge was made for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    /// Works on both NTSC and PAL consoles
+    Dual,
+    Dendy,
+}
+
+/// The console the cartridge was made for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Console {
+    Nes,
+    VsSystem {
+        ppu: u8,
+        hardware: u8,
+    },
+    Playchoice10,
+    /// One of the extended console types of NES 2.0
+    Extended(u8),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CartridgeError {
+    /// The file doesn't start with the iNES magic
+    InvalidMagic,
+    /// The file is shorter than its header says
+    Truncated { expected: usize, actual: usize },
+    /// The header says there's no PRG ROM
+    NoPrgRom,
+    /// The ROM sizes in the header are too big to exist
+    RomTooBig,
+    /// The cartridge uses a mapper that isn't emulated
+    UnsupportedMapper(u16),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CartridgeError::InvalidMagic => write!(f, "Invalid magic header"),
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "ROM is truncated, expected {} bytes but got {}",
+                expected, actual
+            ),
+            CartridgeError::NoPrgRom => write!(f, "ROM has no PRG ROM"),
+            CartridgeError::RomTooBig => write!(f, "ROM sizes in the header are too big"),
+            CartridgeError::UnsupportedMapper(mapper) => {
+                write!(f, "Unsupported mapper {}", mapper)
+            }
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+pub struct Cartridge {
+    pub format: Format,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub prg_ram_size: usize,
+    /// The size of the battery-backed PRG RAM
+    pub prg_nvram_size: usize,
+    /// The size of the CHR RAM, for boards without CHR ROM
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    /// Whether the PRG RAM is battery-backed and should be saved
+    pub battery: bool,
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub region: Region,
+    pub console: Console,
+    /// The number of miscellaneous ROMs after the CHR ROM
+    pub misc_roms: u8,
+    /// The default expansion port device, as numbered by NES 2.0
+    pub expansion_device: u8,
+    /// Problems with the header that didn't stop it from being loaded
+    pub warnings: Vec<&'static str>,
+    /// The CRC32 of the whole file
+    pub hash: u32,
+    /// The MD5 of the PRG and CHR ROM, as FCEUX calculates it
+    pub md5: [u8; 16],
+    /// The file the cartridge was loaded from, if any
+    pub path: Option<PathBuf>,
+}
+
+impl Cartridge {
+    /// Load a cartridge from an iNES file
+    pub fn from_file(filename: &str) -> Result<Cartridge, Box<dyn Error>> {
+        let mut f = File::open(filename)?;
+        let mut buffer = Vec::new();
+        f.read_to_end(&mut buffer)?;
+
+        let mut cartridge = Cartridge::from_bytes(&buffer)?;
+        cartridge.path = Some(PathBuf::from(filename));
+        Ok(cartridge)
+    }
+
+    /// Parse a cartridge from the contents of an iNES file
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use corrosiones::cartridge::{Cartridge, Mirroring};
+    ///
+    /// let mut rom = vec![b'N', b'E', b'S', 0x1A, 0x01, 0x01, 0x01, 0x00];
+    /// rom.resize(16 + 0x4000 + 0x2000, 0x00);
+    ///
+    /// let cartridge = Cartridge::from_bytes(&rom).unwrap();
+    ///
+    /// assert_eq!(cartridge.prg_rom.len(), 0x4000);
+    /// assert_eq!(cartridge.chr_rom.len(), 0x2000);
+    /// assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+    /// ```
+    pub fn from_bytes(bytes: &[u8]) -> Result<Cartridge, CartridgeError> {
+        if bytes.len() < 4 || bytes[0..4] != [b'N', b'E', b'S', 0x1A] {
+            return Err(CartridgeError::InvalidMagic);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(CartridgeError::Truncated {
+                expected: HEADER_SIZE,
+                actual: bytes.len(),
+            });
+        }
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&bytes[..HEADER_SIZE]);
+        let mut warnings = Vec::new();
+
+        let format = if header[7] & 0b0000_1100 == 0b0000_1000 {
+            Format::Nes2
+        } else {
+            // Bytes 12-15 are always zero in a clean iNES 1.0 header
+            if header[7] & 0b0000_1100 != 0 || header[12..].iter().any(|&byte| byte != 0) {
+                warnings.push("Ignoring garbage in bytes 7-15 of the iNES header");
+                for byte in &mut header[7..] {
+                    *byte = 0;
+                }
+            }
+            Format::INes
+        };
+        let nes2 = format == Format::Nes2;
+        let flags6 = header[6];
+        let flags7 = header[7];
+
+        let (prg_rom_size, chr_rom_size) = if nes2 {
+            (
+                nes2_rom_size(header[4], header[9] & 0x0F, PRG_ROM_UNIT)
+                    .ok_or(CartridgeError::RomTooBig)?,
+                nes2_rom_size(header[5], header[9] >> 4, CHR_ROM_UNIT)
+                    .ok_or(CartridgeError::RomTooBig)?,
+            )
+        } else {
+            (
+                usize::from(header[4]) * PRG_ROM_UNIT,
+                usize::from(header[5]) * CHR_ROM_UNIT,
+            )
+        };
+        if prg_rom_size == 0 {
+            return Err(CartridgeError::NoPrgRom);
+        }
+        let has_trainer = flags6 & 0b0000_0100 > 0;
+        let trainer_size = if has_trainer { TRAINER_SIZE } else { 0 };
+
+        let prg_start = HEADER_SIZE + trainer_size;
+        let expected = prg_start
+            .checked_add(prg_rom_size)
+            .and_then(|size| size.checked_add(chr_rom_size))
+            .ok_or(CartridgeError::RomTooBig)?;
+        let chr_start = prg_start + prg_rom_size;
+        if bytes.len() < expected {
+            return Err(CartridgeError::Truncated {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+
+        let mirroring = if flags6 & 0b0000_1000 > 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 0b0000_0001 > 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let battery = flags6 & 0b0000_0010 > 0;
+        let console = match flags7 & 0b0000_0011 {
+            0 => Console::Nes,
+            1 => Console::VsSystem {
+                ppu: if nes2 { header[13] & 0x0F } else { 0 },
+                hardware: if nes2 { header[13] >> 4 } else { 0 },
+            },
+            2 => Console::Playchoice10,
+            _ if nes2 => Console::Extended(header[13] & 0x0F),
+            _ => Console::Nes,
+        };
+
+        let mut cartridge = Cartridge {
+            format,
+            prg_rom: bytes[prg_start..chr_start].to_vec(),
+            chr_rom: bytes[chr_start..expected].to_vec(),
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            mirroring,
+            battery,
+            trainer: if has_trainer {
+                Some(bytes[HEADER_SIZE..prg_start].to_vec())
+            } else {
+                None
+            },
+            mapper: u16::from(flags6 >> 4 | flags7 & 0b1111_0000),
+            submapper: 0,
+            region: Region::Ntsc,
+            console,
+            misc_roms: 0,
+            expansion_device: 0,
+            warnings,
+            hash: crc32(bytes),
+            md5: md5(&bytes[prg_start..expected]),
+            path: None,
+        };
+
+        if nes2 {
+            cartridge.mapper |= u16::from(header[8] & 0x0F) << 8;
+            cartridge.submapper = header[8] >> 4;
+            cartridge.prg_ram_size = ram_size(header[10] & 0x0F);
+            cartridge.prg_nvram_size = ram_size(header[10] >> 4);
+            cartridge.chr_ram_size = ram_size(header[11] & 0x0F);
+            cartridge.chr_nvram_size = ram_size(header[11] >> 4);
+            cartridge.region = match header[12] & 0b0000_0011 {
+                0 => Region::Ntsc,
+                1 => Region::Pal,
+                2 => Region::Dual,
+                _ => Region::Dendy,
+            };
+            cartridge.misc_roms = header[14] & 0b0000_0011;
+            cartridge.expansion_device = header[15] & 0b0011_1111;
+        } else {
+            let prg_ram_size = usize::from(header[8].max(1)) * PRG_RAM_UNIT;
+            if battery {
+                cartridge.prg_nvram_size = prg_ram_size;
+            } else {
+                cartridge.prg_ram_size = prg_ram_size;
+            }
+            if chr_rom_size == 0 {
+                cartridge.chr_ram_size = CHR_ROM_UNIT;
+            }
+            if header[9] & 0b0000_0001 > 0 {
+                cartridge.region = Region::Pal;
+            }
+        }
+
+        Ok(cartridge)
+    }
+
+    /// The number of 16KB PRG ROM banks
+    pub fn prg_banks(&self) -> usize {
+        self.prg_rom.len() / PRG_ROM_UNIT
+    }
+}
+
+/// The size of a ROM in a NES 2.0 header
+///
+/// When the upper nibble is $F, the lower byte holds an exponent and a multiplier instead, for
+/// sizes that aren't a multiple of the unit. Those can be too big to count.
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+    if msb == 0x0F {
+        let exponent = u32::from(lsb >> 2);
+        let multiplier = usize::from(lsb & 0b0000_0011) * 2 + 1;
+        2usize.checked_pow(exponent)?.checked_mul(multiplier)
+    } else {
+        Some((usize::from(msb) << 8 | usize::from(lsb)) * unit)
+    }
+}
+
+/// The size of a RAM in a NES 2.0 header, given as a shift count of 64 bytes
+fn ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(prg_banks: u8, chr_banks: u8, flags6: u8, flags7: u8) -> Vec<u8> {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, prg_banks, chr_banks, flags6, flags7];
+        rom.resize(HEADER_SIZE, 0x00);
+        rom
+    }
+
+    fn rom(prg_banks: u8, chr_banks: u8, flags6: u8, flags7: u8) -> Vec<u8> {
+        let mut rom = header(prg_banks, chr_banks, flags6, flags7);
+        if flags6 & 0b0000_0100 > 0 {
+            rom.extend(vec![0xEE; TRAINER_SIZE]);
+        }
+        rom.extend(vec![0xAA; usize::from(prg_banks) * PRG_ROM_UNIT]);
+        rom.extend(vec![0xCC; usize::from(chr_banks) * CHR_ROM_UNIT]);
+        rom
+    }
+
+    #[test]
+    fn parse_sizes() {
+        let cartridge = Cartridge::from_bytes(&rom(2, 1, 0x00, 0x00)).unwrap();
+
+        assert_eq!(cartridge.prg_rom, vec![0xAA; 0x8000]);
+        assert_eq!(cartridge.chr_rom, vec![0xCC; 0x2000]);
+        assert_eq!(cartridge.chr_ram_size, 0);
+        assert_eq!(cartridge.prg_ram_size, 0x2000);
+        assert_eq!(cartridge.prg_banks(), 2);
+    }
+
+    #[test]
+    fn no_chr_rom_means_chr_ram() {
+        let cartridge = Cartridge::from_bytes(&rom(1, 0, 0x00, 0x00)).unwrap();
+
+        assert!(cartridge.chr_rom.is_empty());
+        assert_eq!(cartridge.chr_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn parse_mirroring() {
+        let mirroring = |flags6| {
+            Cartridge::from_bytes(&rom(1, 1, flags6, 0x00))
+                .unwrap()
+                .mirroring
+        };
+
+        assert_eq!(mirroring(0b0000_0000), Mirroring::Horizontal);
+        assert_eq!(mirroring(0b0000_0001), Mirroring::Vertical);
+        assert_eq!(mirroring(0b0000_1001), Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn parse_flags() {
+        let mut bytes = rom(1, 1, 0b0000_0110, 0x00);
+        bytes[8] = 4;
+        bytes[9] = 0x01;
+
+        let cartridge = Cartridge::from_bytes(&bytes).unwrap();
+
+        assert!(cartridge.battery);
+        assert_eq!(cartridge.trainer, Some(vec![0xEE; TRAINER_SIZE]));
+        assert_eq!(cartridge.prg_rom, vec![0xAA; 0x4000]);
+        assert_eq!(cartridge.prg_ram_size, 0);
+        assert_eq!(cartridge.prg_nvram_size, 0x8000);
+        assert_eq!(cartridge.region, Region::Pal);
+        assert_eq!(cartridge.format, Format::INes);
+    }
+
+    #[test]
+    fn parse_mapper() {
+        let cartridge = Cartridge::from_bytes(&rom(1, 1, 0x10, 0x40)).unwrap();
+
+        assert_eq!(cartridge.mapper, 0x41);
+        assert_eq!(cartridge.submapper, 0);
+    }
+
+    fn nes2(prg_banks: u8, chr_banks: u8) -> Vec<u8> {
+        rom(prg_banks, chr_banks, 0x00, 0b0000_1000)
+    }
+
+    #[test]
+    fn detect_nes2() {
+        let cartridge = Cartridge::from_bytes(&nes2(1, 1)).unwrap();
+
+        assert_eq!(cartridge.format, Format::Nes2);
+        assert!(cartridge.warnings.is_empty());
+    }
+
+    #[test]
+    fn parse_nes2_mapper() {
+        let mut bytes = rom(1, 1, 0x40, 0x18);
+        bytes[8] = 0x21;
+
+        let cartridge = Cartridge::from_bytes(&bytes).unwrap();
+
+        assert_eq!(cartridge.mapper, 0x114);
+        assert_eq!(cartridge.submapper, 2);
+    }
+
+    #[test]
+    fn parse_nes2_ram_sizes() {
+        let mut bytes = nes2(1, 0);
+        bytes[6] = 0b0000_0010;
+        bytes[10] = 0x70;
+        bytes[11] = 0x07;
+
+        let cartridge = Cartridge::from_bytes(&bytes).unwrap();
+
+        assert_eq!(cartridge.prg_ram_size, 0);
+        assert_eq!(cartridge.prg_nvram_size, 0x2000);
+        assert_eq!(cartridge.chr_ram_size, 0x2000);
+        assert_eq!(cartridge.chr_nvram_size, 0);
+    }
+
+    #[test]
+    fn parse_nes2_rom_sizes() {
+        let mut bytes = header(0x00, 0x00, 0x00, 0b0000_1000);
+        bytes[9] = 0x01;
+        bytes.extend(vec![0xAA; 0x100 * PRG_ROM_UNIT]);
+
+        let cartridge = Cartridge::from_bytes(&bytes).unwrap();
+
+        assert_eq!(cartridge.prg_rom.len(), 0x100 * PRG_ROM_UNIT);
+        assert!(cartridge.chr_rom.is_empty());
+    }
+
+    #[test]
+    fn nes2_exponent_rom_sizes() {
+        // 2^14 * 3
+        assert_eq!(
+            nes2_rom_size(14 << 2 | 0x01, 0x0F, PRG_ROM_UNIT),
+            Some(0xC000)
+        );
+        assert_eq!(
+            nes2_rom_size(0x02, 0x01, CHR_ROM_UNIT),
+            Some(0x102 * 0x2000)
+        );
+    }
+
+    #[test]
+    fn huge_nes2_rom_sizes_are_rejected() {
+        let sizes = |prg, chr, msb| {
+            let mut bytes = header(prg, chr, 0x00, 0b0000_1000);
+            bytes[9] = msb;
+            Cartridge::from_bytes(&bytes).err()
+        };
+
+        // 2^63 * 7
+        assert_eq!(sizes(0xFF, 0x01, 0x0F), Some(CartridgeError::RomTooBig));
+        // 2^63 of both
+        assert_eq!(sizes(0xFC, 0xFC, 0xFF), Some(CartridgeError::RomTooBig));
+        // 2^62, which adds up but isn't in the file
+        assert_eq!(
+            sizes(0xF8, 0x01, 0x0F),
+            Some(CartridgeError::Truncated {
+                expected: 16 + (1 << 62) + 0x2000,
+                actual: 16,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_nes2_timing_and_console() {
+        let region = |timing| {
+            let mut bytes = nes2(1, 1);
+            bytes[12] = timing;
+            Cartridge::from_bytes(&bytes).unwrap().region
+        };
+
+        assert_eq!(region(0), Region::Ntsc);
+        assert_eq!(region(1), Region::Pal);
+        assert_eq!(region(2), Region::Dual);
+        assert_eq!(region(3), Region::Dendy);
+
+        let mut bytes = nes2(1, 1);
+        bytes[7] |= 0x01;
+        bytes[13] = 0x23;
+        bytes[14] = 0x01;
+        bytes[15] = 0x01;
+        let cartridge = Cartridge::from_bytes(&bytes).unwrap();
+
+        assert_eq!(
+            cartridge.console,
+            Console::VsSystem {
+                ppu: 0x03,
+                hardware: 0x02,
+            }
+        );
+        assert_eq!(cartridge.misc_roms, 1);
+        assert_eq!(cartridge.expansion_device, 1);
+    }
+
+    #[test]
+    fn diskdude_garbage_is_ignored() {
+        let mut bytes = rom(1, 1, 0x10, 0x00);
+        bytes[7..16].copy_from_slice(b"DiskDude!");
+
+        let cartridge = Cartridge::from_bytes(&bytes).unwrap();
+
+        assert_eq!(cartridge.format, Format::INes);
+        assert_eq!(cartridge.mapper, 1);
+        assert_eq!(cartridge.prg_ram_size, 0x2000);
+        assert_eq!(
+            cartridge.warnings,
+            vec!["Ignoring garbage in bytes 7-15 of the iNES header"]
+        );
+    }
+
+    #[test]
+    fn md5_skips_the_header_and_trainer() {
+        let bytes = rom(1, 1, 0b0000_0100, 0x00);
+
+        let cartridge = Cartridge::from_bytes(&bytes).unwrap();
+
+        assert_eq!(cartridge.md5, md5(&bytes[HEADER_SIZE + TRAINER_SIZE..]));
+        assert_eq!(cartridge.hash, crc32(&bytes));
+    }
+
+    #[test]
+    fn md5_skips_data_after_chr_rom() {
+        let bytes = rom(1, 1, 0x00, 0x00);
+        let mut padded = bytes.clone();
+        // A PlayChoice INST-ROM
+        padded.extend_from_slice(&[0xAB; 0x2000]);
+
+        let cartridge = Cartridge::from_bytes(&padded).unwrap();
+
+        assert_eq!(cartridge.md5, md5(&bytes[HEADER_SIZE..]));
+    }
+
+    #[test]
+    fn rejects_invalid_magic() {
+        let mut bytes = rom(1, 1, 0x00, 0x00);
+        bytes[3] = 0x00;
+
+        assert_eq!(
+            Cartridge::from_bytes(&bytes).err(),
+            Some(CartridgeError::InvalidMagic)
+        );
+        assert_eq!(
+            Cartridge::from_bytes(&[]).err(),
+            Some(CartridgeError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = rom(2, 1, 0x00, 0x00);
+
+        assert_eq!(
+            Cartridge::from_bytes(&bytes[..0x4000]).err(),
+            Some(CartridgeError::Truncated {
+                expected: 16 + 0x8000 + 0x2000,
+                actual: 0x4000,
+            })
+        );
+        assert_eq!(
+            Cartridge::from_bytes(&bytes[..8]).err(),
+            Some(CartridgeError::Truncated {
+                expected: 16,
+                actual: 8,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_missing_prg_rom() {
+        assert_eq!(
+            Cartridge::from_bytes(&header(0, 1, 0x00, 0x00)).err(),
+            Some(CartridgeError::NoPrgRom)
+        );
+    }
+
+    #[test]
+    fn errors_describe_the_problem() {
+        let error = CartridgeError::Truncated {
+            expected: 32,
+            actual: 16,
+        };
+
+        assert_eq!(
+            error.to_string(),
+            "ROM is truncated, expected 32 bytes but got 16"
+        );
+        assert_eq!(
+            CartridgeError::UnsupportedMapper(4).to_string(),
+            "Unsupported mapper 4"
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! The standard NES controller
+//!
+//! Writing 1 to $4016 holds the controllers in strobe mode, where they keep reloading the state
+//! of their buttons. Writing 0 latches the buttons, which are then read one bit at a time from
+//! $4016 (port 1) and $4017 (port 2) in the order A, B, Select, Start, Up, Down, Left, Right.
+
+use cpu::device::Device;
+use state::{Chunk, ChunkReader};
+
+pub const A: u8 = 0b0000_0001;
+pub const B: u8 = 0b0000_0010;
+pub const SELECT: u8 = 0b0000_0100;
+pub const START: u8 = 0b0000_1000;
+pub const UP: u8 = 0b0001_0000;
+pub const DOWN: u8 = 0b0010_0000;
+pub const LEFT: u8 = 0b0100_0000;
+pub const RIGHT: u8 = 0b1000_0000;
+
+#[derive(Default)]
+pub struct Controller {
+    buttons: u8,
+    strobe: bool,
+    shift: u8,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller::default()
+    }
+
+    /// Set the buttons currently held down, one bit per button
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons;
+        }
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    /// Read the next button from the shift register
+    ///
+    /// After all eight buttons have been read, an official controller keeps returning 1.
+    pub fn read(&mut self) -> u8 {
+        let bit = self.peek();
+        if !self.strobe {
+            self.shift = self.shift >> 1 | 0x80;
+        }
+        bit
+    }
+
+    /// The button the next read returns, without shifting the register
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & 0x01
+        } else {
+            self.shift & 0x01
+        }
+    }
+
+    /// Handle a write to $4016, where bit 0 controls the strobe
+    pub fn write(&mut self, byte: u8) {
+        self.strobe = byte & 0x01 != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.buttons);
+        chunk.write_bool(self.strobe);
+        chunk.write_u8(self.shift);
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.buttons = chunk.read_u8()?;
+        self.strobe = chunk.read_bool()?;
+        self.shift = chunk.read_u8()?;
+        Ok(())
+    }
+}
+
+/// Both controller ports, mapped to $4016-$4017
+///
+/// A write to $4016 strobes both controllers, while $4017 only ever reads from port 2. Writes to
+/// $4017 belong to the APU frame counter and are ignored here.
+#[derive(Default)]
+pub struct Controllers {
+    pub ports: [Controller; 2],
+}
+
+impl Controllers {
+    pub fn new() -> Controllers {
+        Controllers::default()
+    }
+
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        for controller in &self.ports {
+            controller.save_state(chunk);
+        }
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        for controller in &mut self.ports {
+            controller.load_state(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for Controllers {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.ports[usize::from(addr & 0x01)].read()
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.ports[usize::from(addr & 0x01)].peek()
+    }
+
+    /// Only the lowest bits are driven by the controllers, the top three are open bus
+    fn open_bus_mask(&self, _addr: u16) -> u8 {
+        0xE0
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        if addr == 0x4016 {
+            for controller in &mut self.ports {
+                controller.write(byte);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_buttons_in_order() {
+        let mut controller = Controller::new();
+        controller.set_buttons(A | START | RIGHT);
+
+        controller.write(0x01);
+        controller.write(0x00);
+        let bits: Vec<u8> = (0..8).map(|_| controller.read()).collect();
+
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn returns_ones_after_eight_reads() {
+        let mut controller = Controller::new();
+
+        controller.write(0x01);
+        controller.write(0x00);
+        for _ in 0..8 {
+            assert_eq!(controller.read(), 0);
+        }
+
+        assert_eq!(controller.read(), 1);
+    }
+
+    #[test]
+    fn strobe_keeps_returning_a() {
+        let mut controller = Controller::new();
+        controller.set_buttons(A | B);
+
+        controller.write(0x01);
+
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+        controller.set_buttons(B);
+        assert_eq!(controller.read(), 0);
+    }
+
+    #[test]
+    fn buttons_are_latched_when_strobe_ends() {
+        let mut controller = Controller::new();
+        controller.set_buttons(A);
+
+        controller.write(0x01);
+        controller.write(0x00);
+        controller.set_buttons(B);
+
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 0);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut controller = Controller::new();
+        controller.set_buttons(A | UP);
+        controller.write(0x01);
+        controller.write(0x00);
+        controller.read();
+
+        let mut chunk = Chunk::new(b"TEST");
+        controller.save_state(&mut chunk);
+        let mut restored = Controller::new();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load controller");
+
+        assert_eq!(restored.buttons(), A | UP);
+        assert_eq!(restored.read(), 0);
+        assert_eq!(restored.read(), 0);
+        assert_eq!(restored.read(), 0);
+        assert_eq!(restored.read(), 1);
+    }
+
+    #[test]
+    fn peek_does_not_shift() {
+        let mut controller = Controller::new();
+        controller.set_buttons(A);
+        controller.write(0x01);
+        controller.write(0x00);
+
+        assert_eq!(controller.peek(), 1);
+        assert_eq!(controller.peek(), 1);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.peek(), 0);
+    }
+
+    #[test]
+    fn ports_are_strobed_together() {
+        let mut controllers = Controllers::new();
+        controllers.ports[0].set_buttons(A);
+        controllers.ports[1].set_buttons(B);
+
+        Device::write(&mut controllers, 0x4016, 0x01);
+        Device::write(&mut controllers, 0x4016, 0x00);
+
+        assert_eq!(Device::read(&mut controllers, 0x4016), 1);
+        assert_eq!(Device::read(&mut controllers, 0x4017), 0);
+        assert_eq!(Device::read(&mut controllers, 0x4017), 1);
+    }
+}
@@ -0,0 +1,45 @@
+//! Devices on the CPU bus
+//!
+//! Components like the PPU, the APU, the controllers and the cartridge register themselves with
+//! the memory for a range of addresses, and the memory hands every read and write in that range
+//! over to them. Reading a register can change the state of the device (reading $2002 clears the
+//! vblank flag), so reads get a mutable device, while `peek` lets debuggers look at the bus
+//! without disturbing anything.
+//!
+//! Devices with a clock of their own, like the PPU, only run between instructions. The memory
+//! tells them the CPU cycle of every access first, so a register read sees them in the state they
+//! are in on that cycle.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub trait Device {
+    /// Read a byte, with any side effects the read has on the device
+    fn read(&mut self, addr: u16) -> u8;
+
+    /// Read a byte without any side effects
+    fn peek(&self, addr: u16) -> u8;
+
+    fn write(&mut self, addr: u16, byte: u8);
+
+    /// Catch up with the CPU before an access on a cycle of it, for devices that run alongside it
+    fn sync(&mut self, _cycle: u64) {}
+
+    /// The bits of an address the device doesn't drive, which read back from the open bus
+    fn open_bus_mask(&self, _addr: u16) -> u8 {
+        0x00
+    }
+}
+
+/// A device registered for an inclusive range of addresses
+pub(crate) struct Mapping {
+    pub start: u16,
+    pub end: u16,
+    pub device: Rc<RefCell<dyn Device>>,
+}
+
+impl Mapping {
+    pub fn contains(&self, addr: u16) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+}
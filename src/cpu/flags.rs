@@ -1,3 +1,5 @@
+use state::{Chunk, ChunkReader};
+
 pub(crate) struct Flags {
     pub(crate) carry: bool,
     pub(crate) zero: bool,
@@ -86,6 +88,17 @@ impl Flags {
     pub fn set_decimal(&mut self, decimal: bool) {
         self.decimal = decimal;
     }
+
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.as_byte());
+        chunk.write_bool(self.break_command);
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.set_from_byte(chunk.read_u8()?);
+        self.break_command = chunk.read_bool()?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -118,4 +131,23 @@ mod test {
 
         assert_eq!(flags.as_byte(), 0b1110_1000);
     }
+
+    #[test]
+    fn flags_state_round_trip() {
+        let mut flags = Flags::new();
+        flags.set_carry(true);
+        flags.set_overflow(true);
+        flags.break_command = true;
+
+        let mut chunk = Chunk::new(b"TEST");
+        flags.save_state(&mut chunk);
+
+        let mut restored = Flags::new();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load flags");
+
+        assert_eq!(restored.as_byte(), flags.as_byte());
+        assert!(restored.break_command);
+    }
 }
@@ -1,12 +1,22 @@
 //! The Memory module
 //!
 //! Implements the memory as it was in the NES, with write guards for the ROM and mirroring
+//!
+//! Reads from addresses nothing drives return the last value that was on the data bus, the same
+//! as on the real hardware. Games and test ROMs rely on that open bus behaviour.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use cpu::device::{Device, Mapping};
+use state::{Chunk, SaveState};
 
-const RAM_SIZE: usize = 0x0800;
+pub(crate) const RAM_SIZE: usize = 0x0800;
 const IO_SIZE: usize = 0x0028;
 // const EXPANSION_ROM_SIZE: usize = 0x1980;
 const SRAM_SIZE: usize = 0x2000;
 const ROM_SIZE: usize = 0x8000;
+const PRG_BANK_SIZE: usize = 0x4000;
 
 pub struct Memory {
     ram: Vec<u8>,
@@ -14,6 +24,14 @@ pub struct Memory {
     expansion_rom: Vec<u8>,
     pub(crate) sram: Vec<u8>,
     pub(crate) rom: Vec<u8>,
+    pub(crate) prg_banks: u16,
+    devices: Vec<Mapping>,
+    /// The last value on the data bus
+    bus: u8,
+    /// The page of a sprite DMA started by a write to $4014, until the CPU runs it
+    dma_page: Option<u8>,
+    /// The CPU cycle of the next access, every read or write takes one
+    cycle: u64,
 }
 
 impl Default for Memory {
@@ -24,6 +42,11 @@ impl Default for Memory {
             expansion_rom: Vec::new(),
             sram: Vec::new(),
             rom: Vec::new(),
+            prg_banks: 2,
+            devices: Vec::new(),
+            bus: 0x00,
+            dma_page: None,
+            cycle: 0,
         }
     }
 }
@@ -94,9 +117,117 @@ impl Memory {
         Ok(())
     }
 
+    /// The PRG ROM bank mapped in at an address
+    ///
+    /// Banks are counted in 16KB units from the start of PRG ROM, so a cartridge with a single
+    /// bank has bank 0 mirrored at both $8000 and $C000. Returns `None` for addresses outside of
+    /// PRG ROM.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut memory = corrosiones::cpu::memory::Memory::new();
+    ///
+    /// memory.load_rom(vec![0xFF; 0x8000]).expect("Failed to load rom");
+    ///
+    /// assert_eq!(memory.prg_bank(0x0000), None);
+    /// assert_eq!(memory.prg_bank(0x8000), Some(0));
+    /// assert_eq!(memory.prg_bank(0xC000), Some(1));
+    /// ```
+    pub fn prg_bank(&self, addr: u16) -> Option<u16> {
+        if addr < 0x8000 || self.rom.is_empty() {
+            return None;
+        }
+        let bank = (usize::from(addr) - 0x8000) / PRG_BANK_SIZE;
+        Some(bank as u16 % self.prg_banks.max(1))
+    }
+
+    /// Add the writable parts of the memory to a save state
+    ///
+    /// The ROM isn't saved, it's expected to be loaded from the same file before the state is
+    /// restored.
+    pub fn save_state(&self, state: &mut SaveState) {
+        let mut ram = Chunk::new(b"RAM ");
+        ram.write_bytes(&self.ram);
+        state.add(ram);
+
+        // Cartridges keep their own PRG RAM, this is only there without one
+        if !self.sram.is_empty() {
+            let mut sram = Chunk::new(b"SRAM");
+            sram.write_bytes(&self.sram);
+            state.add(sram);
+        }
+
+        let mut io = Chunk::new(b"IO  ");
+        io.write_bytes(&self.io);
+        io.write_u8(self.bus);
+        state.add(io);
+    }
+
+    /// Restore the memory from a save state
+    ///
+    /// Sections missing from the state are left as they are.
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), &'static str> {
+        if let Some(mut chunk) = state.chunk(b"RAM ") {
+            self.load_ram(chunk.read_bytes()?.to_vec())?;
+        }
+        if let Some(mut chunk) = state.chunk(b"SRAM") {
+            self.load_sram(chunk.read_bytes()?.to_vec())?;
+        }
+        if let Some(mut chunk) = state.chunk(b"IO  ") {
+            let io = chunk.read_bytes()?;
+            if io.len() > IO_SIZE {
+                return Err("IO too big");
+            }
+            self.io = io.to_vec();
+            self.io.resize(IO_SIZE, 0x00);
+            if chunk.has_more() {
+                self.bus = chunk.read_u8()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Register a device for a range of addresses
+    ///
+    /// Every read and write between `start` and `end`, inclusive, goes to the device instead of
+    /// the memory. A device registered later takes precedence over earlier ones where their ranges
+    /// overlap. The internal RAM at $0000-$1FFF can't be taken over.
+    ///
+    /// The memory doesn't save the state of devices, that's up to whoever owns them.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The first address of the range
+    /// * `end` - The last address of the range
+    /// * `device` - The device to handle the range
+    pub fn register(&mut self, start: u16, end: u16, device: Rc<RefCell<dyn Device>>) {
+        self.devices.push(Mapping { start, end, device });
+    }
+
+    /// Remove the devices registered for exactly this range of addresses
+    pub fn unregister(&mut self, start: u16, end: u16) {
+        self.devices
+            .retain(|mapping| mapping.start != start || mapping.end != end);
+    }
+
+    /// Find the device handling an address, if any
+    fn device(&self, addr: u16) -> Option<&Rc<RefCell<dyn Device>>> {
+        if addr < 0x2000 {
+            return None;
+        }
+        self.devices
+            .iter()
+            .rev()
+            .find(|mapping| mapping.contains(addr))
+            .map(|mapping| &mapping.device)
+    }
+
     /// Read from the memory
     ///
-    /// Returns the correct byte from memory, after taking in account any mirroring
+    /// Returns the correct byte from memory, after taking in account any mirroring. Reads from
+    /// registered devices can change their state, use `peek` to look at the memory without that.
     ///
     /// # Arguments
     ///
@@ -112,15 +243,45 @@ impl Memory {
     /// assert_eq!(memory.read(0x0001), 0xAD);
     /// assert_eq!(memory.read(0x1001), 0xAD); // Mirrored RAM read
     /// ```
-    pub fn read(&self, addr: u16) -> u8 {
+    pub fn read(&mut self, addr: u16) -> u8 {
+        let byte = match self.device(addr) {
+            Some(device) => {
+                let mut device = device.borrow_mut();
+                device.sync(self.cycle);
+                let mask = device.open_bus_mask(addr);
+                device.read(addr) & !mask | self.bus & mask
+            }
+            None => self.read_internal(addr),
+        };
+        self.bus = byte;
+        self.cycle += 1;
+        byte
+    }
+
+    /// Read from the memory without any side effects
+    ///
+    /// Meant for debuggers and disassemblers, which shouldn't disturb the machine they look at.
+    pub fn peek(&self, addr: u16) -> u8 {
+        if let Some(device) = self.device(addr) {
+            let device = device.borrow();
+            let mask = device.open_bus_mask(addr);
+            return device.peek(addr) & !mask | self.bus & mask;
+        }
+        self.read_internal(addr)
+    }
+
+    fn read_internal(&self, addr: u16) -> u8 {
         let addr = usize::from(addr);
         let result = match addr {
             0x0000...0x1FFF => self.ram[addr % 0x0800],
             0x2000...0x3FFF => self.io[(addr - 0x2000) % 0x0008],
-            0x4000...0x401F => self.io[addr - 0x4000 + 0x0008],
-            0x4020...0x5FFF => self.expansion_rom[addr - 0x4020],
-            0x6000...0x7FFF => self.sram[addr - 0x6000],
-            0x8000...0xFFFF => self.rom[addr - 0x8000],
+            // Bit 5 of the APU status isn't driven
+            0x4015 => self.io[0x15 + 0x0008] & !0x20 | self.bus & 0x20,
+            // The rest of the APU registers are write only, and the test registers are disabled
+            0x4000...0x401F => self.bus,
+            0x4020...0x5FFF => self.unmapped(&self.expansion_rom, addr - 0x4020),
+            0x6000...0x7FFF => self.unmapped(&self.sram, addr - 0x6000),
+            0x8000...0xFFFF => self.unmapped(&self.rom, addr - 0x8000),
             _ => panic!("Reading from 0x{:04X?} is unsupported", addr),
         };
 
@@ -128,6 +289,21 @@ impl Memory {
         result
     }
 
+    /// Set the CPU cycle of the next access, at the start of an instruction
+    pub(crate) fn set_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+
+    /// Take the page of a pending sprite DMA
+    pub(crate) fn take_dma(&mut self) -> Option<u8> {
+        self.dma_page.take()
+    }
+
+    /// Read from a section that might not be there, in which case the read is open bus
+    fn unmapped(&self, section: &[u8], index: usize) -> u8 {
+        section.get(index).cloned().unwrap_or(self.bus)
+    }
+
     /// Write to the memory
     ///
     /// # Panics
@@ -146,11 +322,21 @@ impl Memory {
     /// assert_eq!(memory.read(0x0001), 0xAB);
     /// ```
     pub fn write(&mut self, addr: u16, byte: u8) {
+        self.bus = byte;
+        let cycle = self.cycle;
+        self.cycle += 1;
+        if let Some(device) = self.device(addr) {
+            let mut device = device.borrow_mut();
+            device.sync(cycle);
+            device.write(addr, byte);
+            return;
+        }
         let addr = usize::from(addr);
         // println!("Writing into 0x{:04X?}", addr);
         match addr {
             0x0000...0x1FFF => self.ram[addr % 0x0800] = byte,
             0x2000...0x3FFF => self.io[(addr - 0x2000) % 0x0008] = byte,
+            0x4014 => self.dma_page = Some(byte),
             0x4000...0x401F => self.io[addr - 0x4000 + 0x0008] = byte,
             0x6000...0x7FFF => self.sram[addr - 0x6000] = byte,
             _ => panic!("Unable to write to 0x{:04X?}", addr),
@@ -202,11 +388,156 @@ mod test {
     fn read_from_upper_io() {
         let mut memory = Memory::new();
         memory.io = vec![0x00; 0x28];
-        memory.io[0x08] = 0xDE;
-        memory.io[0x27] = 0xAD;
+        memory.io[0x1D] = 0xFF;
+
+        assert_eq!(memory.read(0x4015), 0xDF);
+    }
+
+    #[test]
+    fn write_only_io_is_open_bus() {
+        let mut memory = Memory::new();
+        memory.write(0x4000, 0xDE);
+        memory.write(0x401F, 0xAD);
+
+        assert_eq!(memory.read(0x4000), 0xAD);
+        assert_eq!(memory.read(0x4014), 0xAD);
+    }
+
+    #[test]
+    fn write_to_oam_dma_starts_a_dma() {
+        let mut memory = Memory::new();
+
+        memory.write(0x4014, 0x02);
+
+        assert_eq!(memory.take_dma(), Some(0x02));
+        assert_eq!(memory.take_dma(), None);
+    }
+
+    #[test]
+    fn unmapped_reads_are_open_bus() {
+        let mut memory = Memory::new();
+        memory.load_ram(vec![0x42]).expect("Failed to load ram");
 
-        assert_eq!(memory.read(0x4000), 0xDE);
-        assert_eq!(memory.read(0x401F), 0xAD);
+        memory.read(0x0000);
+
+        assert_eq!(memory.read(0x4020), 0x42);
+        assert_eq!(memory.read(0x5FFF), 0x42);
+        assert_eq!(memory.read(0x6000), 0x42);
+        assert_eq!(memory.read(0xFFFC), 0x42);
+    }
+
+    #[test]
+    fn undriven_device_bits_are_open_bus() {
+        let mut memory = Memory::new();
+        memory.load_ram(Vec::new()).expect("Failed to load ram");
+        memory.register(0x4016, 0x4016, Rc::new(RefCell::new(Partial)));
+        memory.write(0x0000, 0x40);
+
+        assert_eq!(memory.peek(0x4016), 0x41);
+        assert_eq!(memory.read(0x4016), 0x41);
+    }
+
+    /// A register counting how often it has been read
+    #[derive(Default)]
+    struct Counter {
+        reads: u8,
+        written: u8,
+    }
+
+    impl Device for Counter {
+        fn read(&mut self, _addr: u16) -> u8 {
+            self.reads += 1;
+            self.reads
+        }
+
+        fn peek(&self, _addr: u16) -> u8 {
+            self.reads
+        }
+
+        fn write(&mut self, _addr: u16, byte: u8) {
+            self.written = byte;
+        }
+    }
+
+    /// A register only driving its lowest bit
+    struct Partial;
+
+    impl Device for Partial {
+        fn read(&mut self, _addr: u16) -> u8 {
+            0xFF
+        }
+
+        fn peek(&self, _addr: u16) -> u8 {
+            0xFF
+        }
+
+        fn write(&mut self, _addr: u16, _byte: u8) {}
+
+        fn open_bus_mask(&self, _addr: u16) -> u8 {
+            0xFE
+        }
+    }
+
+    #[test]
+    fn reads_from_devices_have_side_effects() {
+        let mut memory = Memory::new();
+        let counter = Rc::new(RefCell::new(Counter::default()));
+        memory.register(0x2000, 0x3FFF, counter.clone());
+
+        assert_eq!(memory.read(0x2002), 1);
+        assert_eq!(memory.read(0x3FFF), 2);
+        assert_eq!(memory.peek(0x2002), 2);
+        assert_eq!(counter.borrow().reads, 2);
+    }
+
+    #[test]
+    fn writes_go_to_devices() {
+        let mut memory = Memory::new();
+        let counter = Rc::new(RefCell::new(Counter::default()));
+        memory.register(0x4016, 0x4016, counter.clone());
+
+        memory.write(0x4016, 0xAB);
+        memory.write(0x4015, 0xCD);
+
+        assert_eq!(counter.borrow().written, 0xAB);
+        assert_eq!(memory.read(0x4015), 0xCD);
+    }
+
+    #[test]
+    fn later_devices_take_precedence() {
+        let mut memory = Memory::new();
+        let first = Rc::new(RefCell::new(Counter::default()));
+        let second = Rc::new(RefCell::new(Counter::default()));
+        memory.register(0x4000, 0x401F, first.clone());
+        memory.register(0x4016, 0x4017, second.clone());
+
+        memory.read(0x4016);
+        memory.read(0x4000);
+
+        assert_eq!(first.borrow().reads, 1);
+        assert_eq!(second.borrow().reads, 1);
+    }
+
+    #[test]
+    fn unregister_removes_a_device() {
+        let mut memory = Memory::new();
+        let counter = Rc::new(RefCell::new(Counter::default()));
+        memory.register(0x4000, 0x4013, counter.clone());
+        memory.write(0x4000, 0x12);
+
+        memory.unregister(0x4000, 0x4013);
+        memory.write(0x4000, 0x34);
+
+        assert_eq!(counter.borrow().written, 0x12);
+    }
+
+    #[test]
+    fn ram_can_not_be_taken_over() {
+        let mut memory = Memory::new();
+        memory.load_ram(vec![0xAB]).expect("Failed to load ram");
+        memory.register(0x0000, 0xFFFF, Rc::new(RefCell::new(Counter::default())));
+
+        assert_eq!(memory.read(0x0000), 0xAB);
     }
 
     #[test]
@@ -227,6 +558,49 @@ mod test {
         assert_eq!(memory.read(0x6001), 0x02);
     }
 
+    #[test]
+    fn prg_bank_of_mirrored_rom() {
+        let mut memory = Memory::new();
+        memory.rom = vec![0x00; 0x8000];
+        memory.prg_banks = 1;
+
+        assert_eq!(memory.prg_bank(0x8000), Some(0));
+        assert_eq!(memory.prg_bank(0xC000), Some(0));
+        assert_eq!(memory.prg_bank(0x7FFF), None);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut memory = Memory::new();
+        memory
+            .load_ram(vec![0x01, 0x02])
+            .expect("Failed to load ram");
+        memory.load_sram(vec![0x03]).expect("Failed to load sram");
+        memory.write(0x4015, 0x0F);
+
+        let mut state = SaveState::new(0);
+        memory.save_state(&mut state);
+
+        let mut restored = Memory::new();
+        restored.load_state(&state).expect("Failed to load state");
+
+        assert_eq!(restored.ram, memory.ram);
+        assert_eq!(restored.sram, memory.sram);
+        assert_eq!(restored.read(0x4015), 0x0F);
+    }
+
+    #[test]
+    fn state_with_oversized_ram() {
+        let mut state = SaveState::new(0);
+        let mut chunk = Chunk::new(b"RAM ");
+        chunk.write_bytes(&[0x00; RAM_SIZE + 1]);
+        state.add(chunk);
+
+        let mut memory = Memory::new();
+
+        assert_eq!(memory.load_state(&state), Err("RAM too big"));
+    }
+
     #[test]
     fn read_from_rom() {
         let mut memory = Memory::new();
@@ -1,13 +1,17 @@
 pub mod addressing;
+pub mod device;
 pub mod flags;
 pub mod memory;
 pub mod opcodes;
 pub mod utils;
 
+use std::cell::{Ref, RefCell};
 use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
+use std::path::PathBuf;
+use std::rc::Rc;
 
+use cartridge::Cartridge;
+use controller::Controllers;
 use cpu::opcodes::bitwise::and::and;
 use cpu::opcodes::bitwise::or::{eor, ora};
 use cpu::opcodes::bitwise::rotate::{rol, ror};
@@ -32,31 +36,71 @@ use cpu::opcodes::storage::load::{lax, lda, ldx, ldy};
 use cpu::opcodes::storage::store::{sta, stx, sty};
 use cpu::opcodes::storage::transfer::{tax, tay, tsx, txa, txs, tya};
 use cpu::opcodes::system::nop;
+use debug::disassembler::disassemble;
+use debug::symbols::{SymbolTable, PRG_BANK_SIZE};
+use mapper::{self, Mapper};
+use ppu::Ppu;
+use state::{Chunk, SaveState};
 
 pub(crate) use cpu::addressing::Addressing;
 pub(crate) use cpu::flags::Flags;
 pub(crate) use cpu::memory::Memory;
 
+/// CPU cycles in an NTSC frame, 341 * 262 PPU dots at three dots per CPU cycle
+///
+/// Only roughly, the PPU drops a dot every other frame with rendering enabled and frames don't
+/// end on a CPU cycle. `CPU::frame` follows the frames of the PPU.
+pub const CYCLES_PER_FRAME: u64 = 29_781;
+
+const NMI_VECTOR: u16 = 0xFFFA;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
 pub struct CPU {
     pub memory: Memory,
+    pub symbols: SymbolTable,
+    controllers: Rc<RefCell<Controllers>>,
+    ppu: Rc<RefCell<Ppu>>,
+    mapper: Option<Rc<RefCell<dyn Mapper>>>,
     flags: Flags,
     pc: u16,
     sp: u8,
     a: u8,
     x: u8,
     y: u8,
+    cycles: u64,
+    rom_hash: u32,
+    rom_md5: [u8; 16],
+    save_directory: Option<PathBuf>,
+    sram_flush_interval: u64,
+    last_sram_flush: u64,
 }
 
 impl Default for CPU {
     fn default() -> CPU {
+        let mut memory = Memory::new();
+        let controllers = Rc::new(RefCell::new(Controllers::new()));
+        memory.register(0x4016, 0x4017, controllers.clone());
+        let ppu = Rc::new(RefCell::new(Ppu::new()));
+        memory.register(0x2000, 0x3FFF, ppu.clone());
+
         CPU {
-            memory: Memory::new(),
+            memory,
+            symbols: SymbolTable::new(),
+            controllers,
+            ppu,
+            mapper: None,
             flags: Flags::new(),
             pc: 0,
             sp: 0xFD,
             a: 0,
             x: 0,
             y: 0,
+            cycles: 0,
+            rom_hash: 0,
+            rom_md5: [0; 16],
+            save_directory: None,
+            sram_flush_interval: 0,
+            last_sram_flush: 0,
         }
     }
 }
@@ -186,7 +230,7 @@ impl CPU {
     }
 
     /// Read a byte from an address
-    pub fn raw_read_byte(&self, address: u16) -> u8 {
+    pub fn raw_read_byte(&mut self, address: u16) -> u8 {
         self.memory.read(address)
     }
 
@@ -198,7 +242,7 @@ impl CPU {
     /// Read a double from an address
     ///
     /// Reads two bytes and combines them in a 16-bit double in little endian
-    pub fn read_double(&self, address: u16) -> u16 {
+    pub fn read_double(&mut self, address: u16) -> u16 {
         let lsb = self.memory.read(address);
         let msb = self.memory.read(address + 1);
         (u16::from(msb) << 8) | u16::from(lsb)
@@ -212,6 +256,101 @@ impl CPU {
         }
     }
 
+    /// The number of cycles the CPU has run since power on
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The number of frames the PPU has started since power on
+    pub fn frame(&self) -> u64 {
+        self.ppu.borrow().frame()
+    }
+
+    /// Run until the start of the next frame
+    pub fn step_frame(&mut self) {
+        let frame = self.frame();
+        while self.frame() == frame {
+            self.step(false);
+        }
+    }
+
+    /// The CRC32 of the loaded ROM file
+    pub fn rom_hash(&self) -> u32 {
+        self.rom_hash
+    }
+
+    /// The MD5 of the PRG and CHR data of the loaded ROM, as FCEUX calculates it
+    pub fn rom_md5(&self) -> [u8; 16] {
+        self.rom_md5
+    }
+
+    /// Set the buttons held down on the controller in a port
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The controller port, 0 or 1
+    /// * `buttons` - The buttons held down, see the `controller` module for the bits
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+        self.controllers.borrow_mut().ports[port].set_buttons(buttons);
+    }
+
+    /// Keep the save files of battery-backed cartridges in a directory
+    ///
+    /// By default the save file is kept next to the ROM, with a `.sav` extension. Has to be set
+    /// before the ROM is loaded.
+    pub fn set_save_directory<P: Into<PathBuf>>(&mut self, directory: P) {
+        self.save_directory = Some(directory.into());
+    }
+
+    /// Save battery-backed SRAM every so many frames, if it has changed
+    ///
+    /// With an interval of 0, the default, SRAM is only saved when the CPU is dropped or
+    /// `flush_sram` is called.
+    pub fn set_sram_flush_interval(&mut self, frames: u64) {
+        self.sram_flush_interval = frames;
+    }
+
+    /// Save battery-backed SRAM right away
+    pub fn flush_sram(&mut self) -> Result<(), Box<dyn Error>> {
+        self.last_sram_flush = self.frame();
+        if let Some(ref mapper) = self.mapper {
+            mapper.borrow_mut().prg_ram().flush()?;
+        }
+        Ok(())
+    }
+
+    /// The 16KB PRG ROM bank mapped in at an address, as used by debug symbols
+    pub fn prg_bank(&self, addr: u16) -> Option<u16> {
+        match self.mapper {
+            Some(ref mapper) => mapper
+                .borrow()
+                .prg_rom_offset(addr)
+                .map(|offset| (offset / PRG_BANK_SIZE) as u16),
+            None => self.memory.prg_bank(addr),
+        }
+    }
+
+    /// The file battery-backed SRAM is saved to for a cartridge
+    ///
+    /// Cartridges that weren't loaded from a file are named after their hash, and only saved if
+    /// there's a save directory.
+    fn save_file(&self, cartridge: &Cartridge) -> Option<PathBuf> {
+        match (&self.save_directory, &cartridge.path) {
+            (Some(directory), Some(rom)) => Some(
+                directory
+                    .join(rom.file_name().unwrap_or_default())
+                    .with_extension("sav"),
+            ),
+            (Some(directory), None) => Some(directory.join(format!("{:08X}.sav", cartridge.hash))),
+            (None, Some(rom)) => Some(rom.with_extension("sav")),
+            (None, None) => None,
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
     pub fn set_pc(&mut self, address: u16) {
         self.pc = address;
     }
@@ -229,35 +368,155 @@ impl CPU {
     }
 
     pub fn load_file(&mut self, filename: String) -> Result<(), Box<Error>> {
-        let mut f = File::open(filename)?;
-        let mut buffer = Vec::new();
-        f.read_to_end(&mut buffer)?;
+        let cartridge = Cartridge::from_file(&filename)?;
+        for warning in &cartridge.warnings {
+            eprintln!("{}: {}", filename, warning);
+        }
+        self.load_cartridge(cartridge)
+    }
+
+    /// Insert a cartridge and power on
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use corrosiones::cartridge::Cartridge;
+    /// use corrosiones::cpu::CPU;
+    ///
+    /// let mut rom = vec![b'N', b'E', b'S', 0x1A, 0x01, 0x01, 0x00, 0x00];
+    /// rom.resize(16 + 0x4000 + 0x2000, 0x00);
+    ///
+    /// let mut cpu = CPU::new();
+    /// cpu.load_cartridge(Cartridge::from_bytes(&rom).unwrap()).unwrap();
+    /// ```
+    pub fn load_cartridge(&mut self, cartridge: Cartridge) -> Result<(), Box<dyn Error>> {
+        let save_file = if cartridge.battery {
+            self.save_file(&cartridge)
+        } else {
+            None
+        };
+        let trainer = cartridge.trainer.clone();
+        let (rom_hash, rom_md5) = (cartridge.hash, cartridge.md5);
+
+        // Save the PRG RAM of a previous cartridge first, it might be the same one reading it back
+        self.flush_sram()?;
+        let mapper = mapper::create(cartridge)?;
+        {
+            let mut mapper = mapper.borrow_mut();
+            let prg_ram = mapper.prg_ram();
+            if let Some(path) = save_file {
+                prg_ram.attach(path)?;
+            }
+            if let Some(ref trainer) = trainer {
+                prg_ram.load_trainer(trainer)?;
+            }
+        }
+
+        self.memory.unregister(0x4020, 0xFFFF);
+        self.memory.register(0x4020, 0xFFFF, mapper.clone());
+        self.ppu.borrow_mut().set_mapper(mapper.clone());
+        self.mapper = Some(mapper);
 
-        self.process_file(&buffer[..])?;
+        self.rom_hash = rom_hash;
+        self.rom_md5 = rom_md5;
         self.memory.load_ram(Vec::new())?;
-        self.memory.load_sram(Vec::new())?;
         self.reset_vector();
 
         Ok(())
     }
 
-    fn process_file(&mut self, buffer: &[u8]) -> Result<(), &'static str> {
-        if buffer[0..=3] != [b'N', b'E', b'S', 0x1A] {
-            return Err("Invalid magic header");
+    /// Save the state of the whole machine
+    ///
+    /// The state can be restored with `load_state` on a CPU that has the same ROM loaded. See the
+    /// `state` module for the format.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = SaveState::new(self.rom_hash);
+
+        let mut cpu = Chunk::new(b"CPU ");
+        cpu.write_u16(self.pc);
+        cpu.write_u8(self.sp);
+        cpu.write_u8(self.a);
+        cpu.write_u8(self.x);
+        cpu.write_u8(self.y);
+        self.flags.save_state(&mut cpu);
+        cpu.write_u64(self.cycles);
+        state.add(cpu);
+
+        self.memory.save_state(&mut state);
+
+        if let Some(ref mapper) = self.mapper {
+            let mut chunk = Chunk::new(b"MAPR");
+            mapper.borrow().save_state(&mut chunk);
+            state.add(chunk);
+        }
+
+        let mut controllers = Chunk::new(b"CTRL");
+        self.controllers.borrow().save_state(&mut controllers);
+        state.add(controllers);
+
+        let mut ppu = Chunk::new(b"PPU ");
+        self.ppu.borrow().save_state(&mut ppu);
+        state.add(ppu);
+
+        state.to_bytes()
+    }
+
+    /// Restore the state of the whole machine
+    ///
+    /// Fails without touching the machine if the state is invalid or was made with a different
+    /// ROM.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        let state = SaveState::from_bytes(bytes)?;
+        if state.rom_hash != self.rom_hash {
+            return Err("Save state was made with a different ROM");
+        }
+
+        // The chunks are restored one at a time, so a broken chunk late in the state leaves the
+        // earlier ones applied. Put the machine back the way it was if that happens.
+        let backup = SaveState::from_bytes(&self.save_state())?;
+        if let Err(error) = self.restore(&state) {
+            self.restore(&backup)?;
+            return Err(error);
         }
-        let rom_control_byte1 = buffer[6];
-        let rom_control_byte2 = buffer[7];
 
-        let mapper = (rom_control_byte1 & 0b1111_0000) >> 4 | (rom_control_byte2 & 0b1111_0000);
+        Ok(())
+    }
 
-        match mapper {
-            0 => nrom(self, buffer)?,
-            _ => return Err("Unsupported mapper"),
+    fn restore(&mut self, state: &SaveState) -> Result<(), &'static str> {
+        if let Some(mut chunk) = state.chunk(b"CPU ") {
+            self.pc = chunk.read_u16()?;
+            self.sp = chunk.read_u8()?;
+            self.a = chunk.read_u8()?;
+            self.x = chunk.read_u8()?;
+            self.y = chunk.read_u8()?;
+            self.flags.load_state(&mut chunk)?;
+            if chunk.has_more() {
+                self.cycles = chunk.read_u64()?;
+            }
+        }
+        self.memory.load_state(state)?;
+        if let (Some(mapper), Some(mut chunk)) = (&self.mapper, state.chunk(b"MAPR")) {
+            mapper.borrow_mut().load_state(&mut chunk)?;
+        }
+        if let Some(mut chunk) = state.chunk(b"CTRL") {
+            self.controllers.borrow_mut().load_state(&mut chunk)?;
+        }
+        if let Some(mut chunk) = state.chunk(b"PPU ") {
+            self.ppu.borrow_mut().load_state(&mut chunk)?;
         }
 
         Ok(())
     }
 
+    /// Press the reset button
+    ///
+    /// RAM and PRG RAM are left alone, so a trainer loaded at $7000 is still there after the reset.
+    pub fn reset(&mut self) {
+        self.sp = self.sp.wrapping_sub(3);
+        self.flags.set_interrupt_disable(true);
+        self.reset_vector();
+    }
+
     /// Jump to the reset vector
     fn reset_vector(&mut self) {
         let address = self.read_double(0xFFFC);
@@ -265,10 +524,25 @@ impl CPU {
     }
 
     pub fn step(&mut self, debug: bool) -> Option<u8> {
+        self.memory.set_cycle(self.cycles);
+        if self.ppu.borrow_mut().take_nmi() {
+            let cycles = self.interrupt(NMI_VECTOR);
+            self.run_cycles(u64::from(cycles));
+            return Some(cycles);
+        }
+        if self.irq_line() && !self.flags.interrupt_disable {
+            let cycles = self.interrupt(IRQ_VECTOR);
+            self.run_cycles(u64::from(cycles));
+            return Some(cycles);
+        }
         if debug {
+            if let Some(label) = self.symbols.label(self.pc, self.prg_bank(self.pc)) {
+                println!("{}:", label);
+            }
             println!(
-                "{:04X?} A:{:02X?} X:{:02X?} Y:{:02X?} P:{:02X?} SP:{:02X?}",
+                "{:04X?} {:<24} A:{:02X?} X:{:02X?} Y:{:02X?} P:{:02X?} SP:{:02X?}",
                 self.pc,
+                disassemble(self, self.pc).0,
                 self.a,
                 self.x,
                 self.y,
@@ -488,32 +762,92 @@ impl CPU {
             _ => panic!("Unknown opcode: 0x{:02X?}", byte),
         };
 
+        self.run_cycles(u64::from(cycles));
+        if let Some(page) = self.memory.take_dma() {
+            self.oam_dma(page);
+        }
+        if self.sram_flush_interval > 0
+            && self.frame() >= self.last_sram_flush + self.sram_flush_interval
+        {
+            if let Err(error) = self.flush_sram() {
+                eprintln!("Failed to save SRAM: {}", error);
+            }
+        }
+
         Some(cycles)
     }
-}
 
-pub fn nrom(cpu: &mut CPU, buffer: &[u8]) -> Result<(), &'static str> {
-    let trainer = buffer[6] & 0b0000_0100 > 0;
-    let bank_offset = if trainer { 16 + 512 } else { 16 };
-    match buffer[4] {
-        1 => {
-            let mut bank = Vec::from(&buffer[bank_offset..(bank_offset + 0x4000)]);
-            bank.extend(&buffer[bank_offset..(bank_offset + 0x4000)]);
-            cpu.memory.load_rom(bank)?;
+    /// Whether anything is holding the IRQ line low
+    pub fn irq_line(&self) -> bool {
+        match self.mapper {
+            Some(ref mapper) => mapper.borrow().irq(),
+            None => false,
         }
-        2 => cpu
-            .memory
-            .load_rom(Vec::from(&buffer[bank_offset..(bank_offset + 0x8000)]))?,
-        _ => {
-            return Err("NROM only supports 1 or 2 PRG ROM banks");
+    }
+
+    /// Push the program counter and flags and jump through an interrupt vector
+    ///
+    /// The break flag is pushed clear, which is how an interrupt handler tells a hardware
+    /// interrupt apart from BRK.
+    fn interrupt(&mut self, vector: u16) -> u8 {
+        let pc = self.pc;
+        self.push_stack((pc >> 8) as u8);
+        self.push_stack(pc as u8);
+        let flags = self.flags.as_byte();
+        self.push_stack(flags);
+        self.flags.set_interrupt_disable(true);
+        self.pc = self.read_double(vector);
+        7
+    }
+
+    /// Copy a page of memory to the PPU OAM
+    ///
+    /// The DMA writes every byte to $2004, stalling the CPU for 513 cycles, plus one more to line
+    /// up with a read cycle if the DMA starts on an odd cycle. The stall shows up in `cycles`, not
+    /// in the cycles returned from `step`.
+    fn oam_dma(&mut self, page: u8) {
+        let stall = 513 + (self.cycles & 0x01);
+        // The copy starts after the stall to line up with a read cycle
+        self.memory.set_cycle(self.cycles + stall - 512);
+        let start = u16::from(page) << 8;
+        for offset in 0x00..=0xFF {
+            let byte = self.memory.read(start + offset);
+            self.memory.write(0x2004, byte);
+        }
+        self.run_cycles(stall);
+    }
+
+    /// Count cycles the CPU spent, clocking the cartridge and the PPU along with it
+    fn run_cycles(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        if let Some(ref mapper) = self.mapper {
+            let mut mapper = mapper.borrow_mut();
+            for _ in 0..cycles {
+                mapper.cpu_clock();
+            }
+        }
+        self.ppu.borrow_mut().run_to_cycle(self.cycles);
+    }
+
+    /// Every pixel of the last frame the PPU drew, a row at a time, see `Ppu::frame_buffer`
+    pub fn frame_buffer(&self) -> Ref<'_, [u16]> {
+        Ref::map(self.ppu.borrow(), |ppu| ppu.frame_buffer())
+    }
+
+    /// The level of the audio output, from the cartridge as there's no APU yet
+    pub fn audio(&self) -> f32 {
+        match self.mapper {
+            Some(ref mapper) => mapper.borrow().audio(),
+            None => 0.0,
         }
     }
-    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use cpu::device::Device;
+    use mapper::test::cartridge;
 
     #[test]
     fn read_next_byte() {
@@ -593,6 +927,339 @@ mod test {
         assert_eq!(result, 0xFF);
     }
 
+    #[test]
+    fn state_round_trip() {
+        let mut cpu = CPU {
+            pc: 0x1234,
+            sp: 0xAB,
+            a: 0x01,
+            x: 0x02,
+            y: 0x03,
+            cycles: 0x0123_4567,
+            ..CPU::default()
+        };
+        cpu.memory
+            .load_ram(vec![0xDE, 0xAD])
+            .expect("Failed to load ram");
+        cpu.memory
+            .load_sram(Vec::new())
+            .expect("Failed to load sram");
+        cpu.flags.set_carry(true);
+
+        let state = cpu.save_state();
+        let mut restored = CPU::new();
+        restored.load_state(&state).expect("Failed to load state");
+
+        assert_eq!(restored.pc, 0x1234);
+        assert_eq!(restored.sp, 0xAB);
+        assert_eq!((restored.a, restored.x, restored.y), (0x01, 0x02, 0x03));
+        assert_eq!(restored.flags.as_byte(), cpu.flags.as_byte());
+        assert_eq!(restored.cycles, cpu.cycles);
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.raw_read_byte(0x0001), 0xAD);
+    }
+
+    #[test]
+    fn step_counts_cycles() {
+        let mut cpu = CPU::new();
+        // INX, JMP $0000
+        cpu.memory
+            .load_ram(vec![0xE8, 0x4C, 0x00, 0x00])
+            .expect("Failed to load ram");
+
+        cpu.step(false);
+        cpu.step(false);
+
+        assert_eq!(cpu.cycles(), 5);
+    }
+
+    /// Records the writes to OAMDATA
+    #[derive(Default)]
+    struct Oam {
+        data: Vec<u8>,
+    }
+
+    impl Device for Oam {
+        fn read(&mut self, _addr: u16) -> u8 {
+            0x00
+        }
+
+        fn peek(&self, _addr: u16) -> u8 {
+            0x00
+        }
+
+        fn write(&mut self, _addr: u16, byte: u8) {
+            self.data.push(byte);
+        }
+    }
+
+    #[test]
+    fn oam_dma_copies_a_page() {
+        let mut cpu = CPU::new();
+        let oam = Rc::new(RefCell::new(Oam::default()));
+        cpu.memory.register(0x2004, 0x2004, oam.clone());
+        // LDA #$01, STA $4014
+        let mut ram = vec![0xA9, 0x01, 0x8D, 0x14, 0x40];
+        ram.resize(0x0100, 0x00);
+        ram.extend((0x00..=0xFF).collect::<Vec<u8>>());
+        cpu.memory.load_ram(ram).expect("Failed to load ram");
+
+        cpu.step(false);
+        assert_eq!(cpu.step(false), Some(4));
+
+        assert_eq!(oam.borrow().data, (0x00..=0xFF).collect::<Vec<u8>>());
+        assert_eq!(cpu.cycles(), 6 + 513);
+    }
+
+    #[test]
+    fn oam_dma_on_an_odd_cycle_stalls_longer() {
+        let mut cpu = CPU::new();
+        // LDX $10, LDA #$00, STA $4014
+        cpu.memory
+            .load_ram(vec![0xA6, 0x10, 0xA9, 0x00, 0x8D, 0x14, 0x40])
+            .expect("Failed to load ram");
+
+        cpu.step(false);
+        cpu.step(false);
+        cpu.step(false);
+
+        assert_eq!(cpu.cycles(), 9 + 514);
+    }
+
+    #[test]
+    fn step_frame_runs_until_the_next_frame() {
+        let mut cpu = CPU::new();
+        cpu.memory
+            .load_ram(vec![0xE8, 0x4C, 0x00, 0x00])
+            .expect("Failed to load ram");
+
+        cpu.step_frame();
+
+        assert_eq!(cpu.frame(), 1);
+        assert!(cpu.cycles() >= CYCLES_PER_FRAME);
+        assert!(cpu.cycles() < CYCLES_PER_FRAME + 3);
+    }
+
+    #[test]
+    fn frames_follow_the_ppu() {
+        let mut cpu = CPU::new();
+        cpu.memory
+            .load_ram(vec![0xE8, 0x4C, 0x00, 0x00])
+            .expect("Failed to load ram");
+        // Rendering drops a dot every other frame
+        cpu.raw_write_byte(0x2001, 0x08);
+
+        for _ in 0..30 {
+            cpu.step_frame();
+        }
+
+        assert_eq!(cpu.frame(), 30);
+        assert!(cpu.cycles() < 30 * CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn ppu_registers_are_on_the_bus() {
+        let mut cpu = CPU::new();
+        cpu.memory.load_ram(Vec::new()).expect("Failed to load ram");
+
+        cpu.memory.write(0x2006, 0x3F);
+        cpu.memory.write(0x2006, 0x01);
+        cpu.memory.write(0x2007, 0x2A);
+        cpu.memory.write(0x3FFE, 0x3F);
+        cpu.memory.write(0x3FFE, 0x01);
+
+        assert_eq!(cpu.memory.read(0x2007), 0x2A);
+    }
+
+    #[test]
+    fn ppu_draws_into_the_frame_buffer() {
+        let mut cpu = CPU::new();
+        cpu.load_cartridge(cartridge(0, 0x4000, 0))
+            .expect("Failed to load cartridge");
+        // Set the backdrop to $21, turn on the background and loop
+        cpu.memory
+            .load_ram(vec![
+                0xA9, 0x3F, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, 0xA9, 0x21, 0x8D, 0x07,
+                0x20, 0xA9, 0x08, 0x8D, 0x01, 0x20, 0x4C, 0x14, 0x00,
+            ])
+            .expect("Failed to load ram");
+        cpu.pc = 0x0000;
+
+        cpu.step_frame();
+        cpu.step_frame();
+
+        assert_eq!(cpu.frame_buffer().len(), 256 * 240);
+        assert!(cpu.frame_buffer().iter().all(|&pixel| pixel == 0x21));
+    }
+
+    #[test]
+    fn ppu_raises_an_nmi_at_vblank() {
+        let mut cpu = CPU::new();
+        cpu.load_cartridge(cartridge(0, 0x4000, 0))
+            .expect("Failed to load cartridge");
+        // Turn on the NMI and loop, the NMI vector points at a loop at $0101
+        let mut ram = vec![0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x00];
+        ram.resize(0x0101, 0x00);
+        ram.extend(&[0x4C, 0x01, 0x01]);
+        cpu.memory.load_ram(ram).expect("Failed to load ram");
+        cpu.pc = 0x0000;
+
+        while cpu.cycles() < 241 * 341 / 3 - 10 {
+            cpu.step(false);
+        }
+        assert_eq!(cpu.pc, 0x0005);
+
+        cpu.step_frame();
+        assert_eq!(cpu.pc, 0x0101);
+        assert_eq!(cpu.sp, 0xFA);
+    }
+
+    #[test]
+    fn cartridges_are_mapped_through_their_mapper() {
+        let mut cpu = CPU::new();
+        cpu.load_cartridge(cartridge(1, 0x20000, 0x2000))
+            .expect("Failed to load cartridge");
+
+        // The last bank is fixed at $C000 when MMC1 powers on
+        assert_eq!(cpu.pc, 0x0F0F);
+        assert_eq!(cpu.prg_bank(0xC000), Some(7));
+
+        // Switch to the third bank at $8000 through the shift register
+        for bit in 0..5 {
+            cpu.raw_write_byte(0xE000, 0x02 >> bit);
+        }
+        assert_eq!(cpu.raw_read_byte(0x8000), 0x04);
+        assert_eq!(cpu.prg_bank(0x8000), Some(2));
+
+        let state = cpu.save_state();
+        let mut restored = CPU::new();
+        restored
+            .load_cartridge(cartridge(1, 0x20000, 0x2000))
+            .expect("Failed to load cartridge");
+        restored.load_state(&state).expect("Failed to load state");
+
+        assert_eq!(restored.raw_read_byte(0x8000), 0x04);
+    }
+
+    /// A CPU with an MMC3 holding the IRQ line low
+    fn cpu_with_pending_irq() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_cartridge(cartridge(4, 0x20000, 0x2000))
+            .expect("Failed to load cartridge");
+        cpu.raw_write_byte(0xC000, 0x00);
+        cpu.raw_write_byte(0xE001, 0x00);
+        {
+            let mut mapper = cpu.mapper.as_ref().unwrap().borrow_mut();
+            for _ in 0..16 {
+                mapper.ppu_clock(0x0000);
+            }
+            mapper.ppu_clock(0x1000);
+        }
+        cpu
+    }
+
+    #[test]
+    fn mapper_irq_interrupts_the_cpu() {
+        let mut cpu = cpu_with_pending_irq();
+        cpu.pc = 0x1234;
+        cpu.flags.set_interrupt_disable(false);
+        let sp = cpu.sp;
+
+        assert_eq!(cpu.step(false), Some(7));
+
+        assert_eq!(cpu.pc, 0x0F0F);
+        assert_eq!(cpu.sp, sp.wrapping_sub(3));
+        assert_eq!(cpu.raw_read_byte(0x0100 + u16::from(sp)), 0x12);
+        assert_eq!(cpu.raw_read_byte(0x00FF + u16::from(sp)), 0x34);
+        assert_eq!(cpu.raw_read_byte(0x00FE + u16::from(sp)) & 0x10, 0x00);
+        assert!(cpu.flags.interrupt_disable);
+    }
+
+    #[test]
+    fn irq_is_masked_by_interrupt_disable() {
+        let mut cpu = cpu_with_pending_irq();
+        // NOP
+        cpu.memory.load_ram(vec![0xEA]).expect("Failed to load ram");
+        cpu.pc = 0x0000;
+        cpu.flags.set_interrupt_disable(true);
+
+        assert!(cpu.irq_line());
+        assert_eq!(cpu.step(false), Some(2));
+        assert_eq!(cpu.pc, 0x0001);
+    }
+
+    #[test]
+    fn steps_clock_the_mapper() {
+        let mut cpu = CPU::new();
+        cpu.load_cartridge(cartridge(24, 0x20000, 0x2000))
+            .expect("Failed to load cartridge");
+        cpu.memory
+            .load_ram(vec![0xEA; 0x0800])
+            .expect("Failed to load ram");
+        // VRC6 IRQ two cycles away, and a pulse channel at full volume
+        cpu.raw_write_byte(0xF000, 0xFE);
+        cpu.raw_write_byte(0xF001, 0x06);
+        cpu.raw_write_byte(0x9000, 0x8F);
+        cpu.raw_write_byte(0x9002, 0x80);
+
+        cpu.step(false);
+
+        assert!(cpu.irq_line());
+        assert!(cpu.audio() > 0.0);
+    }
+
+    #[test]
+    fn load_state_rejects_other_roms() {
+        let cpu = CPU {
+            rom_hash: 0x1111_1111,
+            ..CPU::default()
+        };
+        let state = cpu.save_state();
+        let mut other = CPU {
+            rom_hash: 0x2222_2222,
+            pc: 0x0042,
+            ..CPU::default()
+        };
+
+        assert_eq!(
+            other.load_state(&state),
+            Err("Save state was made with a different ROM")
+        );
+        assert_eq!(other.pc, 0x0042);
+    }
+
+    #[test]
+    fn load_state_leaves_the_machine_alone_if_a_chunk_is_broken() {
+        let mut cpu = CPU::new();
+        cpu.memory
+            .load_ram(vec![0x00; 0x0800])
+            .expect("Failed to load ram");
+        cpu.pc = 0x4321;
+        cpu.raw_write_byte(0x0010, 0xCD);
+        let mut state = SaveState::new(cpu.rom_hash);
+        let mut registers = Chunk::new(b"CPU ");
+        registers.write_u16(0x1234);
+        for _ in 0..4 {
+            registers.write_u8(0x00);
+        }
+        cpu.flags.save_state(&mut registers);
+        state.add(registers);
+        let mut ram = Chunk::new(b"RAM ");
+        ram.write_bytes(&[0xAB; 0x0800]);
+        state.add(ram);
+        // A PPU chunk that stops after the first register
+        let mut ppu = Chunk::new(b"PPU ");
+        ppu.write_u8(0x00);
+        state.add(ppu);
+
+        assert_eq!(
+            cpu.load_state(&state.to_bytes()),
+            Err("Save state chunk is truncated")
+        );
+        assert_eq!(cpu.pc, 0x4321);
+        assert_eq!(cpu.raw_read_byte(0x0010), 0xCD);
+    }
+
     #[test]
     fn offset_pc_by_max_negative() {
         let mut cpu = CPU {
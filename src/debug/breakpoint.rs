@@ -0,0 +1,175 @@
+//! Breakpoints on the program counter
+//!
+//! Breakpoints can be given as an address (`$C000`, `0xC000`), a bank qualified address
+//! (`$03:C000`) or a label from the symbol table, optionally with an offset (`NMI_Handler+3`).
+
+use cpu::CPU;
+use debug::symbols::{parse_address, parse_number, SymbolTable};
+
+#[derive(Debug, PartialEq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub bank: Option<u16>,
+}
+
+impl Breakpoint {
+    /// Parse a breakpoint, resolving labels with the symbol table
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use corrosiones::debug::breakpoint::Breakpoint;
+    /// use corrosiones::debug::symbols::SymbolTable;
+    ///
+    /// let mut symbols = SymbolTable::new();
+    /// symbols.insert("NMI_Handler", 0xC000, Some(1));
+    ///
+    /// let breakpoint = Breakpoint::parse("NMI_Handler+2", &symbols).unwrap();
+    ///
+    /// assert_eq!(breakpoint.address, 0xC002);
+    /// assert_eq!(breakpoint.bank, Some(1));
+    /// ```
+    pub fn parse(input: &str, symbols: &SymbolTable) -> Result<Breakpoint, &'static str> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Err("Empty breakpoint");
+        }
+
+        if let Some((address, bank)) = parse_address(input) {
+            return Ok(Breakpoint { address, bank });
+        }
+
+        let (name, offset) = match input.find('+') {
+            Some(index) => {
+                let offset = parse_number(&input[index + 1..]).ok_or("Invalid offset")?;
+                (input[..index].trim(), offset)
+            }
+            None => (input, 0),
+        };
+        let (address, bank) = symbols.address(name).ok_or("Unknown label")?;
+        if u32::from(address) + offset > 0xFFFF {
+            return Err("Breakpoint outside of the address space");
+        }
+
+        Ok(Breakpoint {
+            address: address + offset as u16,
+            bank,
+        })
+    }
+
+    /// Check if the CPU is about to execute the instruction at the breakpoint
+    ///
+    /// Breakpoints with a bank only trigger when that bank is mapped in.
+    pub fn hit(&self, cpu: &CPU) -> bool {
+        if cpu.pc() != self.address {
+            return false;
+        }
+        match self.bank {
+            Some(bank) => cpu.prg_bank(self.address) == Some(bank),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_addresses() {
+        let symbols = SymbolTable::new();
+
+        assert_eq!(
+            Breakpoint::parse("$C000", &symbols),
+            Ok(Breakpoint {
+                address: 0xC000,
+                bank: None
+            })
+        );
+        assert_eq!(
+            Breakpoint::parse("0x8000", &symbols),
+            Ok(Breakpoint {
+                address: 0x8000,
+                bank: None
+            })
+        );
+        assert_eq!(
+            Breakpoint::parse("$02:A000", &symbols),
+            Ok(Breakpoint {
+                address: 0xA000,
+                bank: Some(2)
+            })
+        );
+    }
+
+    #[test]
+    fn parse_labels() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("player_x", 0x0040, None);
+
+        assert_eq!(
+            Breakpoint::parse("player_x", &symbols),
+            Ok(Breakpoint {
+                address: 0x0040,
+                bank: None
+            })
+        );
+        assert_eq!(
+            Breakpoint::parse("player_x + $10", &symbols),
+            Ok(Breakpoint {
+                address: 0x0050,
+                bank: None
+            })
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("Last", 0xFFFF, None);
+
+        assert_eq!(Breakpoint::parse("", &symbols), Err("Empty breakpoint"));
+        assert_eq!(Breakpoint::parse("missing", &symbols), Err("Unknown label"));
+        assert_eq!(Breakpoint::parse("Last+x", &symbols), Err("Invalid offset"));
+        assert_eq!(
+            Breakpoint::parse("Last+1", &symbols),
+            Err("Breakpoint outside of the address space")
+        );
+    }
+
+    #[test]
+    fn hit_checks_the_program_counter() {
+        let mut cpu = CPU::new();
+        cpu.set_pc(0x0010);
+
+        let breakpoint = Breakpoint {
+            address: 0x0010,
+            bank: None,
+        };
+
+        assert!(breakpoint.hit(&cpu));
+        cpu.set_pc(0x0011);
+        assert!(!breakpoint.hit(&cpu));
+    }
+
+    #[test]
+    fn hit_checks_the_bank() {
+        let mut cpu = CPU::new();
+        cpu.memory
+            .load_rom(vec![0xEA; 0x8000])
+            .expect("Failed to load rom");
+        cpu.set_pc(0xC000);
+
+        let in_bank = Breakpoint {
+            address: 0xC000,
+            bank: Some(1),
+        };
+        let other_bank = Breakpoint {
+            address: 0xC000,
+            bank: Some(0),
+        };
+
+        assert!(in_bank.hit(&cpu));
+        assert!(!other_bank.hit(&cpu));
+    }
+}
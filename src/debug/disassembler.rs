@@ -0,0 +1,342 @@
+//! Disassembler for traces and listings
+//!
+//! Operands that point at memory are shown with their label from the CPU's symbol table when
+//! there is one.
+
+use cpu::addressing::Addressing;
+use cpu::CPU;
+
+/// Decode an opcode into its mnemonic and addressing mode
+///
+/// Implied instructions have no addressing mode. Returns `None` for opcodes the CPU doesn't
+/// know about.
+fn decode(opcode: u8) -> Option<(&'static str, Option<Addressing>)> {
+    let (mnemonic, addressing) = match opcode {
+        0x00 => ("BRK", None),
+        0x01 => ("ORA", Some(Addressing::IndirectX)),
+        0x04 | 0x44 | 0x64 => ("NOP", Some(Addressing::ZeroPage)),
+        0x05 => ("ORA", Some(Addressing::ZeroPage)),
+        0x06 => ("ASL", Some(Addressing::ZeroPage)),
+        0x08 => ("PHP", None),
+        0x09 => ("ORA", Some(Addressing::Immediate)),
+        0x0A => ("ASL", Some(Addressing::Accumulator)),
+        0x0C => ("NOP", Some(Addressing::Absolute)),
+        0x0D => ("ORA", Some(Addressing::Absolute)),
+        0x0E => ("ASL", Some(Addressing::Absolute)),
+
+        0x10 => ("BPL", Some(Addressing::Relative)),
+        0x11 => ("ORA", Some(Addressing::IndirectY)),
+        0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => ("NOP", Some(Addressing::ZeroPageX)),
+        0x15 => ("ORA", Some(Addressing::ZeroPageX)),
+        0x16 => ("ASL", Some(Addressing::ZeroPageX)),
+        0x18 => ("CLC", None),
+        0x19 => ("ORA", Some(Addressing::AbsoluteY)),
+        0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xEA | 0xFA => ("NOP", None),
+        0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => ("NOP", Some(Addressing::AbsoluteX)),
+        0x1D => ("ORA", Some(Addressing::AbsoluteX)),
+        0x1E => ("ASL", Some(Addressing::AbsoluteX)),
+
+        0x20 => ("JSR", Some(Addressing::Absolute)),
+        0x21 => ("AND", Some(Addressing::IndirectX)),
+        0x24 => ("BIT", Some(Addressing::ZeroPage)),
+        0x25 => ("AND", Some(Addressing::ZeroPage)),
+        0x26 => ("ROL", Some(Addressing::ZeroPage)),
+        0x28 => ("PLP", None),
+        0x29 => ("AND", Some(Addressing::Immediate)),
+        0x2A => ("ROL", Some(Addressing::Accumulator)),
+        0x2C => ("BIT", Some(Addressing::Absolute)),
+        0x2D => ("AND", Some(Addressing::Absolute)),
+        0x2E => ("ROL", Some(Addressing::Absolute)),
+
+        0x30 => ("BMI", Some(Addressing::Relative)),
+        0x31 => ("AND", Some(Addressing::IndirectY)),
+        0x35 => ("AND", Some(Addressing::ZeroPageX)),
+        0x36 => ("ROL", Some(Addressing::ZeroPageX)),
+        0x38 => ("SEC", None),
+        0x39 => ("AND", Some(Addressing::AbsoluteY)),
+        0x3D => ("AND", Some(Addressing::AbsoluteX)),
+        0x3E => ("ROL", Some(Addressing::AbsoluteX)),
+
+        0x40 => ("RTI", None),
+        0x41 => ("EOR", Some(Addressing::IndirectX)),
+        0x45 => ("EOR", Some(Addressing::ZeroPage)),
+        0x46 => ("LSR", Some(Addressing::ZeroPage)),
+        0x48 => ("PHA", None),
+        0x49 => ("EOR", Some(Addressing::Immediate)),
+        0x4A => ("LSR", Some(Addressing::Accumulator)),
+        0x4C => ("JMP", Some(Addressing::Absolute)),
+        0x4D => ("EOR", Some(Addressing::Absolute)),
+        0x4E => ("LSR", Some(Addressing::Absolute)),
+
+        0x50 => ("BVC", Some(Addressing::Relative)),
+        0x51 => ("EOR", Some(Addressing::IndirectY)),
+        0x55 => ("EOR", Some(Addressing::ZeroPageX)),
+        0x56 => ("LSR", Some(Addressing::ZeroPageX)),
+        0x58 => ("CLI", None),
+        0x59 => ("EOR", Some(Addressing::AbsoluteY)),
+        0x5D => ("EOR", Some(Addressing::AbsoluteX)),
+        0x5E => ("LSR", Some(Addressing::AbsoluteX)),
+
+        0x60 => ("RTS", None),
+        0x61 => ("ADC", Some(Addressing::IndirectX)),
+        0x65 => ("ADC", Some(Addressing::ZeroPage)),
+        0x66 => ("ROR", Some(Addressing::ZeroPage)),
+        0x68 => ("PLA", None),
+        0x69 => ("ADC", Some(Addressing::Immediate)),
+        0x6A => ("ROR", Some(Addressing::Accumulator)),
+        0x6C => ("JMP", Some(Addressing::Indirect)),
+        0x6D => ("ADC", Some(Addressing::Absolute)),
+        0x6E => ("ROR", Some(Addressing::Absolute)),
+
+        0x70 => ("BVS", Some(Addressing::Relative)),
+        0x71 => ("ADC", Some(Addressing::IndirectY)),
+        0x75 => ("ADC", Some(Addressing::ZeroPageX)),
+        0x76 => ("ROR", Some(Addressing::ZeroPageX)),
+        0x78 => ("SEI", None),
+        0x79 => ("ADC", Some(Addressing::AbsoluteY)),
+        0x7D => ("ADC", Some(Addressing::AbsoluteX)),
+        0x7E => ("ROR", Some(Addressing::AbsoluteX)),
+
+        0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => ("NOP", Some(Addressing::Immediate)),
+        0x81 => ("STA", Some(Addressing::IndirectX)),
+        0x84 => ("STY", Some(Addressing::ZeroPage)),
+        0x85 => ("STA", Some(Addressing::ZeroPage)),
+        0x86 => ("STX", Some(Addressing::ZeroPage)),
+        0x88 => ("DEY", None),
+        0x8A => ("TXA", None),
+        0x8C => ("STY", Some(Addressing::Absolute)),
+        0x8D => ("STA", Some(Addressing::Absolute)),
+        0x8E => ("STX", Some(Addressing::Absolute)),
+
+        0x90 => ("BCC", Some(Addressing::Relative)),
+        0x91 => ("STA", Some(Addressing::IndirectY)),
+        0x94 => ("STY", Some(Addressing::ZeroPageX)),
+        0x95 => ("STA", Some(Addressing::ZeroPageX)),
+        0x96 => ("STX", Some(Addressing::ZeroPageY)),
+        0x98 => ("TYA", None),
+        0x99 => ("STA", Some(Addressing::AbsoluteY)),
+        0x9A => ("TXS", None),
+        0x9D => ("STA", Some(Addressing::AbsoluteX)),
+
+        0xA0 => ("LDY", Some(Addressing::Immediate)),
+        0xA1 => ("LDA", Some(Addressing::IndirectX)),
+        0xA2 => ("LDX", Some(Addressing::Immediate)),
+        0xA3 => ("LAX", Some(Addressing::IndirectX)),
+        0xA4 => ("LDY", Some(Addressing::ZeroPage)),
+        0xA5 => ("LDA", Some(Addressing::ZeroPage)),
+        0xA6 => ("LDX", Some(Addressing::ZeroPage)),
+        0xA7 => ("LAX", Some(Addressing::ZeroPage)),
+        0xA8 => ("TAY", None),
+        0xA9 => ("LDA", Some(Addressing::Immediate)),
+        0xAA => ("TAX", None),
+        0xAC => ("LDY", Some(Addressing::Absolute)),
+        0xAD => ("LDA", Some(Addressing::Absolute)),
+        0xAE => ("LDX", Some(Addressing::Absolute)),
+        0xAF => ("LAX", Some(Addressing::Absolute)),
+
+        0xB0 => ("BCS", Some(Addressing::Relative)),
+        0xB1 => ("LDA", Some(Addressing::IndirectY)),
+        0xB3 => ("LAX", Some(Addressing::IndirectY)),
+        0xB4 => ("LDY", Some(Addressing::ZeroPageX)),
+        0xB5 => ("LDA", Some(Addressing::ZeroPageX)),
+        0xB6 => ("LDX", Some(Addressing::ZeroPageY)),
+        0xB7 => ("LAX", Some(Addressing::ZeroPageY)),
+        0xB8 => ("CLV", None),
+        0xB9 => ("LDA", Some(Addressing::AbsoluteY)),
+        0xBA => ("TSX", None),
+        0xBC => ("LDY", Some(Addressing::AbsoluteX)),
+        0xBD => ("LDA", Some(Addressing::AbsoluteX)),
+        0xBE => ("LDX", Some(Addressing::AbsoluteY)),
+        0xBF => ("LAX", Some(Addressing::AbsoluteY)),
+
+        0xC0 => ("CPY", Some(Addressing::Immediate)),
+        0xC1 => ("CMP", Some(Addressing::IndirectX)),
+        0xC3 => ("DCP", Some(Addressing::IndirectX)),
+        0xC4 => ("CPY", Some(Addressing::ZeroPage)),
+        0xC5 => ("CMP", Some(Addressing::ZeroPage)),
+        0xC6 => ("DEC", Some(Addressing::ZeroPage)),
+        0xC7 => ("DCP", Some(Addressing::ZeroPage)),
+        0xC8 => ("INY", None),
+        0xC9 => ("CMP", Some(Addressing::Immediate)),
+        0xCA => ("DEX", None),
+        0xCC => ("CPY", Some(Addressing::Absolute)),
+        0xCD => ("CMP", Some(Addressing::Absolute)),
+        0xCE => ("DEC", Some(Addressing::Absolute)),
+        0xCF => ("DCP", Some(Addressing::Absolute)),
+
+        0xD0 => ("BNE", Some(Addressing::Relative)),
+        0xD1 => ("CMP", Some(Addressing::IndirectY)),
+        0xD3 => ("DCP", Some(Addressing::IndirectY)),
+        0xD5 => ("CMP", Some(Addressing::ZeroPageX)),
+        0xD6 => ("DEC", Some(Addressing::ZeroPageX)),
+        0xD7 => ("DCP", Some(Addressing::ZeroPageX)),
+        0xD8 => ("CLD", None),
+        0xD9 => ("CMP", Some(Addressing::AbsoluteY)),
+        0xDB => ("DCP", Some(Addressing::AbsoluteY)),
+        0xDD => ("CMP", Some(Addressing::AbsoluteX)),
+        0xDE => ("DEC", Some(Addressing::AbsoluteX)),
+        0xDF => ("DCP", Some(Addressing::AbsoluteX)),
+
+        0xE0 => ("CPX", Some(Addressing::Immediate)),
+        0xE1 => ("SBC", Some(Addressing::IndirectX)),
+        0xE4 => ("CPX", Some(Addressing::ZeroPage)),
+        0xE5 => ("SBC", Some(Addressing::ZeroPage)),
+        0xE6 => ("INC", Some(Addressing::ZeroPage)),
+        0xE8 => ("INX", None),
+        0xE9 => ("SBC", Some(Addressing::Immediate)),
+        0xEC => ("CPX", Some(Addressing::Absolute)),
+        0xED => ("SBC", Some(Addressing::Absolute)),
+        0xEE => ("INC", Some(Addressing::Absolute)),
+
+        0xF0 => ("BEQ", Some(Addressing::Relative)),
+        0xF1 => ("SBC", Some(Addressing::IndirectY)),
+        0xF5 => ("SBC", Some(Addressing::ZeroPageX)),
+        0xF6 => ("INC", Some(Addressing::ZeroPageX)),
+        0xF8 => ("SED", None),
+        0xF9 => ("SBC", Some(Addressing::AbsoluteY)),
+        0xFD => ("SBC", Some(Addressing::AbsoluteX)),
+        0xFE => ("INC", Some(Addressing::AbsoluteX)),
+
+        _ => return None,
+    };
+    Some((mnemonic, addressing))
+}
+
+/// Disassemble a single instruction
+///
+/// Returns the instruction text and its length in bytes, so the next instruction can be found.
+///
+/// # Arguments
+///
+/// * `cpu` - The CPU to read the instruction and symbols from
+/// * `address` - The address of the instruction
+pub fn disassemble(cpu: &CPU, address: u16) -> (String, u16) {
+    let opcode = cpu.memory.peek(address);
+    let (mnemonic, addressing) = match decode(opcode) {
+        Some(decoded) => decoded,
+        None => return (format!(".db ${:02X}", opcode), 1),
+    };
+
+    let byte = cpu.memory.peek(address.wrapping_add(1));
+    let double = (u16::from(cpu.memory.peek(address.wrapping_add(2))) << 8) | u16::from(byte);
+    let describe = |target: u16| cpu.symbols.describe(target, cpu.prg_bank(target));
+
+    let (operand, length) = match addressing {
+        None => (String::new(), 1),
+        Some(Addressing::Accumulator) => (String::from("A"), 1),
+        Some(Addressing::Immediate) => (format!("#${:02X}", byte), 2),
+        Some(Addressing::ZeroPage) => (describe_zero_page(cpu, byte), 2),
+        Some(Addressing::ZeroPageX) => (format!("{},X", describe_zero_page(cpu, byte)), 2),
+        Some(Addressing::ZeroPageY) => (format!("{},Y", describe_zero_page(cpu, byte)), 2),
+        Some(Addressing::IndirectX) => (format!("({},X)", describe_zero_page(cpu, byte)), 2),
+        Some(Addressing::IndirectY) => (format!("({}),Y", describe_zero_page(cpu, byte)), 2),
+        Some(Addressing::Relative) => {
+            let target = if byte & 0x80 == 0 {
+                address.wrapping_add(2).wrapping_add(u16::from(byte))
+            } else {
+                address.wrapping_add(2).wrapping_sub(u16::from(!byte) + 1)
+            };
+            (describe(target), 2)
+        }
+        Some(Addressing::Absolute) => (describe(double), 3),
+        Some(Addressing::AbsoluteX) => (format!("{},X", describe(double)), 3),
+        Some(Addressing::AbsoluteY) => (format!("{},Y", describe(double)), 3),
+        Some(Addressing::Indirect) => (format!("({})", describe(double)), 3),
+    };
+
+    if operand.is_empty() {
+        (String::from(mnemonic), length)
+    } else {
+        (format!("{} {}", mnemonic, operand), length)
+    }
+}
+
+/// Disassemble a run of instructions into a listing
+///
+/// Labels get their own line, followed by the instructions indented under them.
+///
+/// # Arguments
+///
+/// * `cpu` - The CPU to read the instructions and symbols from
+/// * `address` - The address to start from
+/// * `count` - The number of instructions to disassemble
+pub fn listing(cpu: &CPU, address: u16, count: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut address = address;
+
+    for _ in 0..count {
+        if let Some(label) = cpu.symbols.label(address, cpu.prg_bank(address)) {
+            lines.push(format!("{}:", label));
+        }
+        let (instruction, length) = disassemble(cpu, address);
+        lines.push(format!("{:04X}    {}", address, instruction));
+        address = address.wrapping_add(length);
+    }
+    lines
+}
+
+fn describe_zero_page(cpu: &CPU, address: u8) -> String {
+    match cpu.symbols.label(u16::from(address), None) {
+        Some(label) => label.to_string(),
+        None => format!("${:02X}", address),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cpu_with_program(program: Vec<u8>) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.memory.load_ram(program).expect("Failed to load ram");
+        cpu
+    }
+
+    #[test]
+    fn disassemble_without_symbols() {
+        let cpu = cpu_with_program(vec![0xAD, 0x00, 0x20, 0xA9, 0x10, 0xE8]);
+
+        assert_eq!(disassemble(&cpu, 0x0000), (String::from("LDA $2000"), 3));
+        assert_eq!(disassemble(&cpu, 0x0003), (String::from("LDA #$10"), 2));
+        assert_eq!(disassemble(&cpu, 0x0005), (String::from("INX"), 1));
+    }
+
+    #[test]
+    fn disassemble_with_symbols() {
+        let mut cpu = cpu_with_program(vec![0x8D, 0x00, 0x20, 0xB5, 0x40, 0x6C, 0x10, 0x00]);
+        cpu.symbols.insert("PPUCTRL", 0x2000, None);
+        cpu.symbols.insert("player_x", 0x0040, None);
+        cpu.symbols.insert("vector", 0x0010, None);
+
+        assert_eq!(disassemble(&cpu, 0x0000).0, "STA PPUCTRL");
+        assert_eq!(disassemble(&cpu, 0x0003).0, "LDA player_x,X");
+        assert_eq!(disassemble(&cpu, 0x0005).0, "JMP (vector)");
+    }
+
+    #[test]
+    fn disassemble_branch_targets() {
+        let mut cpu = cpu_with_program(vec![0xEA, 0xD0, 0xFD, 0xF0, 0x02]);
+        cpu.symbols.insert("loop", 0x0000, None);
+
+        assert_eq!(disassemble(&cpu, 0x0001).0, "BNE loop");
+        assert_eq!(disassemble(&cpu, 0x0003).0, "BEQ $0007");
+    }
+
+    #[test]
+    fn disassemble_unknown_opcode() {
+        let cpu = cpu_with_program(vec![0x02]);
+
+        assert_eq!(disassemble(&cpu, 0x0000), (String::from(".db $02"), 1));
+    }
+
+    #[test]
+    fn listing_puts_labels_on_their_own_line() {
+        let mut cpu = cpu_with_program(vec![0xE8, 0x4C, 0x00, 0x00]);
+        cpu.symbols.insert("loop", 0x0000, None);
+
+        assert_eq!(
+            listing(&cpu, 0x0000, 2),
+            vec!["loop:", "0000    INX", "0001    JMP loop"]
+        );
+    }
+}
@@ -0,0 +1,3 @@
+pub mod breakpoint;
+pub mod disassembler;
+pub mod symbols;
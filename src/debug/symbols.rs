@@ -0,0 +1,457 @@
+//! Symbol tables for debugging output
+//!
+//! Loads labels from ca65/ld65 debug files (`.dbg`), FCEUX name lists (`.nl`) and plain
+//! `label = $addr` lists, so traces, disassembly and breakpoints can refer to `NMI_Handler`
+//! instead of `$C0A3`.
+//!
+//! Addresses in the PRG ROM range ($8000-$FFFF) can be bank qualified, as the same CPU address
+//! can hold different code depending on what the mapper has switched in. Banks are counted in
+//! 16KB units from the start of PRG ROM, the same way FCEUX numbers its `.nl` files.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// The size of a PRG ROM bank, as far as symbol banks are concerned
+pub const PRG_BANK_SIZE: usize = 0x4000;
+
+/// Size of the iNES header, which ld65 includes in its output offsets
+const INES_HEADER_SIZE: usize = 16;
+
+#[derive(Default)]
+pub struct SymbolTable {
+    labels: HashMap<(Option<u16>, u16), String>,
+    addresses: HashMap<String, (Option<u16>, u16)>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    /// Add a single label
+    ///
+    /// If the address already has a label, the first one is kept for lookups, but both names
+    /// will resolve to the address.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the label
+    /// * `address` - The CPU address the label points to
+    /// * `bank` - The PRG bank the label lives in, `None` if it's not banked
+    pub fn insert(&mut self, name: &str, address: u16, bank: Option<u16>) {
+        self.labels
+            .entry((bank, address))
+            .or_insert_with(|| name.to_string());
+        self.addresses
+            .entry(name.to_string())
+            .or_insert((bank, address));
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Look up the label for an address
+    ///
+    /// A label in the given bank is preferred, falling back to a label that isn't bank specific.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut symbols = corrosiones::debug::symbols::SymbolTable::new();
+    ///
+    /// symbols.insert("player_x", 0x0040, None);
+    /// symbols.insert("NMI_Handler", 0xC000, Some(1));
+    ///
+    /// assert_eq!(symbols.label(0x0040, None), Some("player_x"));
+    /// assert_eq!(symbols.label(0xC000, Some(1)), Some("NMI_Handler"));
+    /// assert_eq!(symbols.label(0xC000, Some(0)), None);
+    /// ```
+    pub fn label(&self, address: u16, bank: Option<u16>) -> Option<&str> {
+        if bank.is_some() {
+            if let Some(name) = self.labels.get(&(bank, address)) {
+                return Some(name);
+            }
+        }
+        self.labels.get(&(None, address)).map(|name| name.as_str())
+    }
+
+    /// Look up the address, and bank, of a label
+    pub fn address(&self, name: &str) -> Option<(u16, Option<u16>)> {
+        self.addresses
+            .get(name)
+            .map(|&(bank, address)| (address, bank))
+    }
+
+    /// Describe an address, using the label if there is one or falling back to `$XXXX`
+    pub fn describe(&self, address: u16, bank: Option<u16>) -> String {
+        match self.label(address, bank) {
+            Some(name) => name.to_string(),
+            None => format!("${:04X}", address),
+        }
+    }
+
+    /// Load symbols from a file, picking the format from the file name
+    ///
+    /// * `*.dbg` files are read as ca65/ld65 debug info
+    /// * `*.nl` files are read as FCEUX name lists, where `game.nes.3.nl` holds bank 3 and
+    ///   `game.nes.ram.nl` holds the unbanked RAM labels
+    /// * Anything else is read as a `label = $addr` list
+    pub fn load_file(&mut self, filename: &str) -> Result<(), Box<dyn Error>> {
+        let mut f = File::open(filename)?;
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+
+        let path = Path::new(filename);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("dbg") => self.load_dbg(&contents)?,
+            Some("nl") => {
+                let bank = path
+                    .file_stem()
+                    .and_then(|stem| Path::new(stem).extension())
+                    .and_then(|ext| ext.to_str())
+                    .and_then(|ext| u16::from_str_radix(ext, 16).ok());
+                self.load_nl(&contents, bank)?
+            }
+            _ => self.load_labels(&contents)?,
+        }
+
+        Ok(())
+    }
+
+    /// Load symbols from a ca65/ld65 debug file
+    ///
+    /// Only labels are loaded, constants and imports are skipped. Labels in segments that ld65
+    /// wrote into the ROM get the PRG bank calculated from their output offset.
+    pub fn load_dbg(&mut self, contents: &str) -> Result<(), &'static str> {
+        // Segment id => (start address, offset in the output file)
+        let mut segments: HashMap<u32, (u32, Option<usize>)> = HashMap::new();
+        let mut symbols = Vec::new();
+
+        for line in contents.lines() {
+            let mut parts = line.splitn(2, |c: char| c.is_whitespace());
+            let kind = parts.next().unwrap_or("");
+            let fields = match kind {
+                "seg" | "sym" => dbg_fields(parts.next().unwrap_or("")),
+                _ => continue,
+            };
+
+            let id = fields
+                .get("id")
+                .and_then(|id| id.parse::<u32>().ok())
+                .ok_or("Debug file entry without an id")?;
+            if kind == "seg" {
+                let start = fields
+                    .get("start")
+                    .and_then(|start| parse_number(start))
+                    .ok_or("Debug file segment without a start")?;
+                let offset = fields
+                    .get("ooffs")
+                    .and_then(|offset| parse_number(offset))
+                    .map(|offset| offset as usize);
+                segments.insert(id, (start, offset));
+            } else {
+                symbols.push(fields);
+            }
+        }
+
+        for fields in symbols {
+            if fields.get("type").map(String::as_str) != Some("lab") {
+                continue;
+            }
+            let name = fields
+                .get("name")
+                .ok_or("Debug file symbol without a name")?;
+            let value = fields
+                .get("val")
+                .and_then(|value| parse_number(value))
+                .ok_or("Debug file symbol without a value")?;
+            if value > 0xFFFF {
+                continue;
+            }
+
+            let segment = fields
+                .get("seg")
+                .and_then(|seg| seg.parse::<u32>().ok())
+                .and_then(|seg| segments.get(&seg));
+            let bank = match segment {
+                Some(&(start, Some(offset))) if value >= 0x8000 && value >= start => {
+                    let file_offset = offset + (value - start) as usize;
+                    if file_offset < INES_HEADER_SIZE {
+                        None
+                    } else {
+                        Some(((file_offset - INES_HEADER_SIZE) / PRG_BANK_SIZE) as u16)
+                    }
+                }
+                _ => None,
+            };
+
+            self.insert(name, value as u16, bank);
+        }
+
+        Ok(())
+    }
+
+    /// Load symbols from an FCEUX name list
+    ///
+    /// Each line is in the form `$C000#NMI_Handler#Optional comment`, where the address can
+    /// have a `/size` suffix for arrays, which is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `contents` - The contents of the `.nl` file
+    /// * `bank` - The PRG bank the file describes, `None` for the RAM file
+    pub fn load_nl(&mut self, contents: &str, bank: Option<u16>) -> Result<(), &'static str> {
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.split('#');
+            let address = parts
+                .next()
+                .and_then(|address| address.split('/').next())
+                .and_then(parse_number)
+                .ok_or("Invalid address in name list")?;
+            let name = parts.next().map(str::trim).unwrap_or("");
+            if address > 0xFFFF {
+                return Err("Invalid address in name list");
+            }
+            if name.is_empty() {
+                continue;
+            }
+
+            let bank = if address >= 0x8000 { bank } else { None };
+            self.insert(name, address as u16, bank);
+        }
+
+        Ok(())
+    }
+
+    /// Load symbols from a simple label list
+    ///
+    /// Each line is in the form `label = $addr`, where the address can be bank qualified as
+    /// `$bank:addr` and anything after a `;` is a comment.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut symbols = corrosiones::debug::symbols::SymbolTable::new();
+    ///
+    /// symbols
+    ///     .load_labels("player_x = $0040 ; Player position\nReset = $02:8000")
+    ///     .expect("Failed to load labels");
+    ///
+    /// assert_eq!(symbols.address("player_x"), Some((0x0040, None)));
+    /// assert_eq!(symbols.address("Reset"), Some((0x8000, Some(2))));
+    /// ```
+    pub fn load_labels(&mut self, contents: &str) -> Result<(), &'static str> {
+        for line in contents.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().map(str::trim).unwrap_or("");
+            let value = parts.next().ok_or("Expected `label = $addr`")?;
+            if name.is_empty() {
+                return Err("Expected `label = $addr`");
+            }
+            let (address, bank) = parse_address(value).ok_or("Invalid address in label list")?;
+            self.insert(name, address, bank);
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a number in `$hex`, `0xhex` or decimal notation
+pub fn parse_number(input: &str) -> Option<u32> {
+    let input = input.trim();
+    if let Some(hex) = input.strip_prefix('$') {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = input
+        .strip_prefix("0x")
+        .or_else(|| input.strip_prefix("0X"))
+    {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        input.parse::<u32>().ok()
+    }
+}
+
+/// Parse an address, optionally bank qualified as `$bank:addr`
+pub fn parse_address(input: &str) -> Option<(u16, Option<u16>)> {
+    let input = input.trim();
+    let (bank, address) = match input.find(':') {
+        Some(index) => {
+            let bank = parse_number(&input[..index])?;
+            // The address inherits the notation of the bank
+            let address = if input.starts_with('$') {
+                parse_number(&format!("${}", &input[index + 1..]))?
+            } else {
+                parse_number(&input[index + 1..])?
+            };
+            (Some(bank), address)
+        }
+        None => (None, parse_number(input)?),
+    };
+    if address > 0xFFFF || bank.is_some_and(|bank| bank > 0xFFFF) {
+        return None;
+    }
+    Some((address as u16, bank.map(|bank| bank as u16)))
+}
+
+/// Split the `key=value,key="value"` fields of a debug file line
+fn dbg_fields(input: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut key = String::new();
+    let mut value = String::new();
+    let mut in_value = false;
+    let mut in_quotes = false;
+
+    for c in input.trim().chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '=' if !in_value && !in_quotes => in_value = true,
+            ',' if !in_quotes => {
+                fields.insert(key.clone(), value.clone());
+                key.clear();
+                value.clear();
+                in_value = false;
+            }
+            _ if in_value => value.push(c),
+            _ => key.push(c),
+        }
+    }
+    if !key.is_empty() {
+        fields.insert(key, value);
+    }
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DBG: &str = "version\tmajor=2,minor=0
+info\tcsym=0,file=1,lib=0,line=0,mod=1,scope=1,seg=4,span=0,sym=5,type=0
+file\tid=0,name=\"game, final.s\",size=100,mtime=0x5B8C0000,mod=0
+seg\tid=0,name=\"HEADER\",start=0x000000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=0
+seg\tid=1,name=\"ZEROPAGE\",start=0x000000,size=0x0010,addrsize=zeropage,type=rw
+seg\tid=2,name=\"CODE\",start=0x008000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+seg\tid=3,name=\"FIXED\",start=0x00C000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=49168
+sym\tid=0,name=\"player_x\",addrsize=zeropage,scope=0,def=1,val=0x40,seg=1,type=lab
+sym\tid=1,name=\"Reset\",addrsize=absolute,scope=0,def=2,val=0x8000,seg=2,type=lab
+sym\tid=2,name=\"NMI_Handler\",addrsize=absolute,scope=0,def=3,val=0xC010,seg=3,type=lab
+sym\tid=3,name=\"SPEED\",addrsize=zeropage,scope=0,def=4,val=0x3,type=equ
+sym\tid=4,name=\"extern\",addrsize=absolute,scope=0,ref=5,type=imp
+";
+
+    #[test]
+    fn load_dbg_labels() {
+        let mut symbols = SymbolTable::new();
+        symbols.load_dbg(DBG).expect("Failed to load dbg");
+
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.address("player_x"), Some((0x0040, None)));
+        assert_eq!(symbols.address("Reset"), Some((0x8000, Some(0))));
+        assert_eq!(symbols.address("SPEED"), None);
+        assert_eq!(symbols.address("extern"), None);
+    }
+
+    #[test]
+    fn load_dbg_calculates_bank_from_output_offset() {
+        let mut symbols = SymbolTable::new();
+        symbols.load_dbg(DBG).expect("Failed to load dbg");
+
+        // FIXED starts 0xC000 bytes into PRG ROM, so it's the fourth 16KB bank
+        assert_eq!(symbols.address("NMI_Handler"), Some((0xC010, Some(3))));
+        assert_eq!(symbols.label(0xC010, Some(3)), Some("NMI_Handler"));
+        assert_eq!(symbols.label(0xC010, Some(1)), None);
+    }
+
+    #[test]
+    fn load_nl_banked() {
+        let mut symbols = SymbolTable::new();
+        symbols
+            .load_nl("$C000#NMI_Handler#Runs every frame\n$C100##\n", Some(1))
+            .expect("Failed to load nl");
+
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols.label(0xC000, Some(1)), Some("NMI_Handler"));
+        assert_eq!(symbols.label(0xC000, None), None);
+    }
+
+    #[test]
+    fn load_nl_ram_is_never_banked() {
+        let mut symbols = SymbolTable::new();
+        symbols
+            .load_nl("$0300/40#oam_buffer#\n$0040#player_x#\n", Some(2))
+            .expect("Failed to load nl");
+
+        assert_eq!(symbols.label(0x0300, None), Some("oam_buffer"));
+        assert_eq!(symbols.label(0x0040, Some(5)), Some("player_x"));
+    }
+
+    #[test]
+    fn load_nl_invalid_address() {
+        let mut symbols = SymbolTable::new();
+
+        assert!(symbols.load_nl("$XYZ#broken#\n", None).is_err());
+    }
+
+    #[test]
+    fn load_labels_list() {
+        let mut symbols = SymbolTable::new();
+        symbols
+            .load_labels("; Comment\nplayer_x = $40\nPPUCTRL = 0x2000\ncounter=16\n")
+            .expect("Failed to load labels");
+
+        assert_eq!(symbols.address("player_x"), Some((0x0040, None)));
+        assert_eq!(symbols.address("PPUCTRL"), Some((0x2000, None)));
+        assert_eq!(symbols.address("counter"), Some((0x0010, None)));
+    }
+
+    #[test]
+    fn load_labels_invalid_line() {
+        let mut symbols = SymbolTable::new();
+
+        assert!(symbols.load_labels("player_x $40").is_err());
+        assert!(symbols.load_labels("player_x = $10000").is_err());
+    }
+
+    #[test]
+    fn first_label_wins_lookups() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("first", 0x0010, None);
+        symbols.insert("second", 0x0010, None);
+
+        assert_eq!(symbols.label(0x0010, None), Some("first"));
+        assert_eq!(symbols.address("second"), Some((0x0010, None)));
+    }
+
+    #[test]
+    fn describe_falls_back_to_hex() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("player_x", 0x0040, None);
+
+        assert_eq!(symbols.describe(0x0040, None), "player_x");
+        assert_eq!(symbols.describe(0x0041, None), "$0041");
+    }
+
+    #[test]
+    fn parse_bank_qualified_address() {
+        assert_eq!(parse_address("$03:C000"), Some((0xC000, Some(3))));
+        assert_eq!(parse_address("$C000"), Some((0xC000, None)));
+        assert_eq!(parse_address("49152"), Some((0xC000, None)));
+        assert_eq!(parse_address("$C000:"), None);
+    }
+}
@@ -1,2 +1,10 @@
+pub mod cartridge;
+pub mod controller;
 pub mod cpu;
+pub mod debug;
+pub mod mapper;
+pub mod movie;
+pub mod ppu;
+pub mod rewind;
+pub mod state;
 pub mod utils;
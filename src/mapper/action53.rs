@@ -0,0 +1,274 @@
+//! Action 53, mapper 28
+//!
+//! A multicart board: an outer bank picks the game and an inner bank works like the board the
+//! game was written for. Writing to $5000-$5FFF selects a register, which $8000-$FFFF writes to:
+//!
+//! ```text
+//! $00  CHR RAM bank (bits 0-1), single screen page (bit 4)
+//! $01  Inner PRG bank (bits 0-3), single screen page (bit 4)
+//! $80  Mode: mirroring (bits 0-1), PRG mode (bits 2-3), game size (bits 4-5)
+//! $81  Outer PRG bank, in 32KB units
+//! ```
+//!
+//! Mirroring is single screen lower or upper, vertical or horizontal. In single screen mirroring
+//! bit 4 of the CHR and inner bank registers also picks the page.
+//!
+//! The PRG modes are 32KB banks, 16KB banks with the first bank of the outer bank fixed at $8000,
+//! and 16KB banks with the last bank of the outer bank fixed at $C000. The game size of 32KB to
+//! 256KB is how many bits of the bank come from the inner bank. The outer bank starts at $FF so
+//! the menu at the end of the ROM boots.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x2000;
+const CHR_RAM_SIZE: usize = 0x8000;
+
+pub struct Action53 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    select: u8,
+    chr_bank: u8,
+    inner_bank: u8,
+    mode: u8,
+    outer_bank: u8,
+}
+
+impl Action53 {
+    pub fn new(cartridge: Cartridge) -> Action53 {
+        Action53 {
+            chr: Chr::with_ram(&cartridge, CHR_RAM_SIZE),
+            prg_ram: mapper::prg_ram(&cartridge),
+            prg_rom: cartridge.prg_rom,
+            select: 0,
+            chr_bank: 0,
+            inner_bank: 0,
+            mode: 0,
+            outer_bank: 0xFF,
+        }
+    }
+
+    /// Set the single screen page from bit 4 of a write, when the mirroring is single screen
+    fn write_page(&mut self, byte: u8) {
+        if self.mode & 0x02 == 0 {
+            self.mode = (self.mode & !0x01) | ((byte >> 4) & 0x01);
+        }
+    }
+
+    /// The 16KB bank at $8000 or $C000
+    fn prg_bank(&self, high: bool) -> usize {
+        let outer = usize::from(self.outer_bank) << 1;
+        let inner = match (self.mode >> 2) & 0x03 {
+            0 | 1 => (usize::from(self.inner_bank) << 1) | high as usize,
+            2 if !high => outer,
+            3 if high => outer | 0x01,
+            _ => usize::from(self.inner_bank),
+        };
+        let mask = (2 << ((self.mode >> 4) & 0x03)) - 1;
+        (outer & !mask) | (inner & mask)
+    }
+}
+
+impl Device for Action53 {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0),
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x5000..=0x5FFF => self.select = byte & 0x81,
+            0x6000..=0x7FFF => self.prg_ram.write(usize::from(addr - 0x6000), byte),
+            0x8000..=0xFFFF => match self.select {
+                0x00 => {
+                    self.chr_bank = byte & 0x03;
+                    self.write_page(byte);
+                }
+                0x01 => {
+                    self.inner_bank = byte & 0x0F;
+                    self.write_page(byte);
+                }
+                0x80 => self.mode = byte & 0x3F,
+                _ => self.outer_bank = byte,
+            },
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Action53 {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr
+            .read_bank(CHR_BANK_SIZE, usize::from(self.chr_bank), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        self.chr
+            .write_bank(CHR_BANK_SIZE, usize::from(self.chr_bank), addr, byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mode & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let bank = self.prg_bank(addr >= 0xC000);
+        Some(
+            mapper::bank_offset(self.prg_rom.len(), PRG_BANK_SIZE, bank)
+                + usize::from(addr) % PRG_BANK_SIZE,
+        )
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.select);
+        chunk.write_u8(self.chr_bank);
+        chunk.write_u8(self.inner_bank);
+        chunk.write_u8(self.mode);
+        chunk.write_u8(self.outer_bank);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.select = chunk.read_u8()? & 0x81;
+        self.chr_bank = chunk.read_u8()? & 0x03;
+        self.inner_bank = chunk.read_u8()? & 0x0F;
+        self.mode = chunk.read_u8()? & 0x3F;
+        self.outer_bank = chunk.read_u8()?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn action53() -> Action53 {
+        Action53::new(cartridge(28, 0x80000, 0))
+    }
+
+    fn write_register(action53: &mut Action53, register: u8, byte: u8) {
+        action53.write(0x5000, register);
+        action53.write(0x8000, byte);
+    }
+
+    /// The 16KB bank at an address, from the 8KB bank number in the test ROM
+    fn bank_at(action53: &mut Action53, addr: u16) -> u8 {
+        action53.read(addr) / 2
+    }
+
+    #[test]
+    fn boots_into_the_last_32kb() {
+        let mut action53 = action53();
+
+        assert_eq!(bank_at(&mut action53, 0x8000), 0x1E);
+        assert_eq!(bank_at(&mut action53, 0xC000), 0x1F);
+    }
+
+    #[test]
+    fn nrom_games_use_the_outer_bank() {
+        let mut action53 = action53();
+
+        write_register(&mut action53, 0x81, 0x03);
+
+        assert_eq!(bank_at(&mut action53, 0x8000), 0x06);
+        assert_eq!(bank_at(&mut action53, 0xC000), 0x07);
+    }
+
+    #[test]
+    fn unrom_games_fix_the_last_bank() {
+        let mut action53 = action53();
+        // 128KB UNROM game in the second 128KB of the ROM
+        write_register(&mut action53, 0x80, 0x2E);
+        write_register(&mut action53, 0x81, 0x07);
+        write_register(&mut action53, 0x01, 0x02);
+
+        assert_eq!(bank_at(&mut action53, 0x8000), 0x0A);
+        assert_eq!(bank_at(&mut action53, 0xC000), 0x0F);
+    }
+
+    #[test]
+    fn fixed_first_bank_mode() {
+        let mut action53 = action53();
+        write_register(&mut action53, 0x80, 0x1A);
+        write_register(&mut action53, 0x81, 0x02);
+        write_register(&mut action53, 0x01, 0x03);
+
+        assert_eq!(bank_at(&mut action53, 0x8000), 0x04);
+        assert_eq!(bank_at(&mut action53, 0xC000), 0x07);
+    }
+
+    #[test]
+    fn bnrom_games_switch_32kb_inner_banks() {
+        let mut action53 = action53();
+        write_register(&mut action53, 0x80, 0x12);
+        write_register(&mut action53, 0x81, 0x02);
+        write_register(&mut action53, 0x01, 0x01);
+
+        assert_eq!(bank_at(&mut action53, 0x8000), 0x06);
+        assert_eq!(bank_at(&mut action53, 0xC000), 0x07);
+    }
+
+    #[test]
+    fn single_screen_page_from_bank_writes() {
+        let mut action53 = action53();
+        assert_eq!(action53.mirroring(), Mirroring::SingleScreenLower);
+
+        write_register(&mut action53, 0x00, 0x10);
+        assert_eq!(action53.mirroring(), Mirroring::SingleScreenUpper);
+
+        write_register(&mut action53, 0x80, 0x02);
+        write_register(&mut action53, 0x00, 0x10);
+        assert_eq!(action53.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn chr_ram_banks() {
+        let mut action53 = action53();
+        write_register(&mut action53, 0x00, 0x03);
+        action53.ppu_write(0x0000, 0xAB);
+
+        write_register(&mut action53, 0x00, 0x00);
+        assert_eq!(action53.ppu_read(0x0000), 0x00);
+        write_register(&mut action53, 0x00, 0x03);
+        assert_eq!(action53.ppu_read(0x0000), 0xAB);
+    }
+}
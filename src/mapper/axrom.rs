@@ -0,0 +1,160 @@
+//! AxROM, mapper 7
+//!
+//! Writing to $8000-$FFFF selects the 32KB PRG bank (bits 0-2) and which of the two nametables
+//! is shown on every screen (bit 4). CHR is 8KB of RAM.
+//!
+//! ANROM, the most common board, doesn't have bus conflicts, but AMROM and AOROM do. Submapper 2
+//! marks boards with bus conflicts.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    bus_conflicts: bool,
+    register: u8,
+}
+
+impl Axrom {
+    pub fn new(cartridge: Cartridge) -> Axrom {
+        Axrom {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            bus_conflicts: cartridge.submapper == 2,
+            prg_rom: cartridge.prg_rom,
+            register: 0,
+        }
+    }
+}
+
+impl Device for Axrom {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0),
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.write(usize::from(addr - 0x6000), byte),
+            0x8000..=0xFFFF if self.bus_conflicts => self.register = byte & self.peek(addr),
+            0x8000..=0xFFFF => self.register = byte,
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr.read(usize::from(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        self.chr.write(usize::from(addr), byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.register & 0x10 == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let bank = usize::from(self.register & 0x07);
+        Some(
+            mapper::bank_offset(self.prg_rom.len(), PRG_BANK_SIZE, bank)
+                + usize::from(addr) % PRG_BANK_SIZE,
+        )
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.register);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.register = chunk.read_u8()?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn axrom() -> Axrom {
+        Axrom::new(cartridge(7, 0x40000, 0))
+    }
+
+    #[test]
+    fn switches_32k_prg_banks() {
+        let mut axrom = axrom();
+
+        axrom.write(0x8000, 0x03);
+
+        assert_eq!(axrom.read(0x8000), 0x0C);
+        assert_eq!(axrom.read(0xE000), 0x0F);
+        assert_eq!(axrom.prg_rom_offset(0xC000), Some(0x1C000));
+    }
+
+    #[test]
+    fn selects_the_nametable() {
+        let mut axrom = axrom();
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+
+        axrom.write(0x8000, 0x13);
+
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenUpper);
+        assert_eq!(axrom.read(0x8000), 0x0C);
+    }
+
+    #[test]
+    fn bus_conflicts_on_amrom() {
+        let mut cartridge = cartridge(7, 0x40000, 0);
+        cartridge.submapper = 2;
+        let mut axrom = Axrom::new(cartridge);
+
+        // The ROM at $E000 holds $03
+        axrom.write(0xE000, 0x16);
+
+        assert_eq!(axrom.register, 0x02);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+    }
+}
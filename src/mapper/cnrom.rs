@@ -0,0 +1,162 @@
+//! CNROM, mapper 3
+//!
+//! PRG ROM is mapped like NROM, and writing to $8000-$FFFF selects the 8KB CHR bank.
+//!
+//! Like UxROM, the ROM drives the data bus while the bank register is written, so the value
+//! written is ANDed with the byte in ROM. Submapper 1 marks boards without bus conflicts.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    chr_bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(cartridge: Cartridge) -> Cnrom {
+        Cnrom {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            mirroring: cartridge.mirroring,
+            bus_conflicts: cartridge.submapper != 1,
+            prg_rom: cartridge.prg_rom,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Device for Cnrom {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0),
+            0x8000..=0xFFFF => self.prg_rom[usize::from(addr - 0x8000) % self.prg_rom.len()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.write(usize::from(addr - 0x6000), byte),
+            0x8000..=0xFFFF if self.bus_conflicts => self.chr_bank = byte & self.peek(addr),
+            0x8000..=0xFFFF => self.chr_bank = byte,
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr
+            .read_bank(CHR_BANK_SIZE, usize::from(self.chr_bank), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        self.chr
+            .write_bank(CHR_BANK_SIZE, usize::from(self.chr_bank), addr, byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        Some(usize::from(addr - 0x8000) % self.prg_rom.len())
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.chr_bank);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.chr_bank = chunk.read_u8()?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn cnrom() -> Cnrom {
+        let mut cartridge = cartridge(3, 0x8000, 0x8000);
+        cartridge.submapper = 1;
+        Cnrom::new(cartridge)
+    }
+
+    #[test]
+    fn switches_the_chr_bank() {
+        let mut cnrom = cnrom();
+
+        cnrom.write(0x8000, 0x02);
+
+        assert_eq!(cnrom.ppu_read(0x0000), 0x10);
+        assert_eq!(cnrom.ppu_read(0x1C00), 0x17);
+    }
+
+    #[test]
+    fn chr_banks_wrap_around() {
+        let mut cnrom = cnrom();
+
+        cnrom.write(0x8000, 0x05);
+
+        assert_eq!(cnrom.ppu_read(0x0000), 0x08);
+    }
+
+    #[test]
+    fn prg_rom_is_not_banked() {
+        let mut cnrom = cnrom();
+
+        cnrom.write(0x8000, 0x03);
+
+        assert_eq!(cnrom.read(0x8000), 0x00);
+        assert_eq!(cnrom.read(0xE000), 0x03);
+    }
+
+    #[test]
+    fn bus_conflicts_and_the_written_value() {
+        let mut cnrom = Cnrom::new(cartridge(3, 0x8000, 0x8000));
+
+        // The ROM at $A000 holds $01
+        cnrom.write(0xA000, 0x03);
+
+        assert_eq!(cnrom.chr_bank, 0x01);
+        assert_eq!(cnrom.ppu_read(0x0000), 0x08);
+    }
+}
@@ -0,0 +1,234 @@
+//! SST39SF040 flash memory, for boards that save by rewriting their own PRG ROM
+//!
+//! Commands are sequences of writes to $5555 and $2AAA in the flash, ignoring the address bits
+//! above A14:
+//!
+//! ```text
+//! $5555=$AA, $2AAA=$55, $5555=$A0, addr=data                           Program a byte
+//! $5555=$AA, $2AAA=$55, $5555=$80, $5555=$AA, $2AAA=$55, sector=$30    Erase a 4KB sector
+//! $5555=$AA, $2AAA=$55, $5555=$80, $5555=$AA, $2AAA=$55, $5555=$10     Erase the chip
+//! $5555=$AA, $2AAA=$55, $5555=$90                                      Enter software ID mode
+//! $F0 anywhere, other than the byte to program                        Exit software ID mode
+//! ```
+//!
+//! Programming can only clear bits, erasing sets them all. The contents are kept in `PrgRam`, so
+//! they're saved like battery-backed RAM.
+
+use mapper::prg_ram::PrgRam;
+use state::{Chunk, ChunkReader};
+
+const SECTOR_SIZE: usize = 0x1000;
+const MANUFACTURER_ID: u8 = 0xBF;
+const DEVICE_ID: u8 = 0xB7;
+
+pub struct Flash {
+    data: PrgRam,
+    /// How many writes of the current command have been seen
+    step: u8,
+    /// Whether the command is an erase, after its first three writes
+    erase: bool,
+    id_mode: bool,
+}
+
+impl Flash {
+    pub fn new(rom: &[u8]) -> Flash {
+        let mut data = PrgRam::new(rom.len());
+        data.load(rom).expect("Flash is the size of the ROM");
+        Flash {
+            data,
+            step: 0,
+            erase: false,
+            id_mode: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn read(&self, offset: usize) -> u8 {
+        if self.id_mode {
+            if offset & 0x01 == 0 {
+                MANUFACTURER_ID
+            } else {
+                DEVICE_ID
+            }
+        } else {
+            self.data.read(offset).unwrap_or(0)
+        }
+    }
+
+    /// Write a byte of a command
+    pub fn write(&mut self, offset: usize, byte: u8) {
+        // The byte of a program command is data, even if it's $F0
+        let programming = self.step == 3 && !self.erase;
+        if byte == 0xF0 && !programming {
+            self.step = 0;
+            self.id_mode = false;
+            return;
+        }
+
+        let command = offset & 0x7FFF;
+        self.step = match (self.step, command, byte) {
+            (0, 0x5555, 0xAA) => 1,
+            (1, 0x2AAA, 0x55) => 2,
+            (2, 0x5555, 0xA0) => {
+                self.erase = false;
+                3
+            }
+            (2, 0x5555, 0x80) => {
+                self.erase = true;
+                3
+            }
+            (2, 0x5555, 0x90) => {
+                self.id_mode = true;
+                0
+            }
+            (3, 0x5555, 0xAA) if self.erase => 4,
+            (4, 0x2AAA, 0x55) => 5,
+            (3, _, _) if !self.erase => {
+                let old = self.data.read(offset).unwrap_or(0);
+                self.data.write(offset, old & byte);
+                0
+            }
+            (5, _, 0x30) => {
+                let start = offset / SECTOR_SIZE * SECTOR_SIZE;
+                for offset in start..start + SECTOR_SIZE {
+                    self.data.write(offset, 0xFF);
+                }
+                0
+            }
+            (5, 0x5555, 0x10) => {
+                for offset in 0..self.data.len() {
+                    self.data.write(offset, 0xFF);
+                }
+                0
+            }
+            _ => 0,
+        };
+    }
+
+    /// The contents of the flash, to keep in a save file
+    pub fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.data
+    }
+
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.step);
+        chunk.write_bool(self.erase);
+        chunk.write_bool(self.id_mode);
+        self.data.save_state(chunk);
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.step = chunk.read_u8()? % 6;
+        self.erase = chunk.read_bool()?;
+        self.id_mode = chunk.read_bool()?;
+        self.data.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn flash() -> Flash {
+        Flash::new(&[0x5A; 0x10000])
+    }
+
+    fn unlock(flash: &mut Flash) {
+        flash.write(0x5555, 0xAA);
+        flash.write(0x2AAA, 0x55);
+    }
+
+    #[test]
+    fn program_clears_bits() {
+        let mut flash = flash();
+
+        unlock(&mut flash);
+        flash.write(0x5555, 0xA0);
+        flash.write(0x1234, 0x0F);
+
+        assert_eq!(flash.read(0x1234), 0x0A);
+        assert_eq!(flash.read(0x1235), 0x5A);
+    }
+
+    #[test]
+    fn program_f0() {
+        let mut flash = Flash::new(&[0xFF; 0x10000]);
+
+        unlock(&mut flash);
+        flash.write(0x5555, 0xA0);
+        flash.write(0x1234, 0xF0);
+
+        assert_eq!(flash.read(0x1234), 0xF0);
+    }
+
+    #[test]
+    fn writes_without_a_command_are_ignored() {
+        let mut flash = flash();
+
+        flash.write(0x1234, 0x00);
+        flash.write(0x5555, 0xA0);
+        flash.write(0x1234, 0x00);
+
+        assert_eq!(flash.read(0x1234), 0x5A);
+    }
+
+    #[test]
+    fn sector_erase() {
+        let mut flash = flash();
+
+        unlock(&mut flash);
+        flash.write(0x5555, 0x80);
+        unlock(&mut flash);
+        flash.write(0x9123, 0x30);
+
+        assert_eq!(flash.read(0x8FFF), 0x5A);
+        assert_eq!(flash.read(0x9000), 0xFF);
+        assert_eq!(flash.read(0x9FFF), 0xFF);
+        assert_eq!(flash.read(0xA000), 0x5A);
+    }
+
+    #[test]
+    fn chip_erase() {
+        let mut flash = flash();
+
+        unlock(&mut flash);
+        flash.write(0x5555, 0x80);
+        unlock(&mut flash);
+        flash.write(0x5555, 0x10);
+
+        assert_eq!(flash.read(0x0000), 0xFF);
+        assert_eq!(flash.read(0xFFFF), 0xFF);
+    }
+
+    #[test]
+    fn commands_ignore_high_address_bits() {
+        let mut flash = flash();
+
+        flash.write(0xD555, 0xAA);
+        flash.write(0xAAAA, 0x55);
+        flash.write(0xD555, 0xA0);
+        flash.write(0xC000, 0x00);
+
+        assert_eq!(flash.read(0xC000), 0x00);
+    }
+
+    #[test]
+    fn software_id() {
+        let mut flash = flash();
+
+        unlock(&mut flash);
+        flash.write(0x5555, 0x90);
+        assert_eq!(flash.read(0x0000), MANUFACTURER_ID);
+        assert_eq!(flash.read(0x0001), DEVICE_ID);
+
+        flash.write(0x0000, 0xF0);
+        assert_eq!(flash.read(0x0000), 0x5A);
+    }
+}
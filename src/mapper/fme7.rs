@@ -0,0 +1,367 @@
+//! Sunsoft FME-7 and 5B, mapper 69
+//!
+//! ```text
+//! $8000-$9FFF  Command
+//! $A000-$BFFF  Parameter for the command
+//! $C000-$DFFF  Audio register select (5B only)
+//! $E000-$FFFF  Audio register data (5B only)
+//! ```
+//!
+//! The commands are:
+//!
+//! ```text
+//! $0-$7  1KB CHR banks
+//! $8     8KB bank at $6000 (bits 0-5), RAM instead of ROM (bit 6), RAM enable (bit 7)
+//! $9-$B  8KB PRG banks at $8000, $A000 and $C000
+//! $C     Mirroring: vertical, horizontal, single screen lower, single screen upper
+//! $D     IRQ enable (bit 0), counter enable (bit 7), and acknowledge
+//! $E-$F  IRQ counter, low and high byte
+//! ```
+//!
+//! The last 8KB of PRG ROM is fixed at $E000. The IRQ counter counts down every CPU cycle and
+//! raises an IRQ when it wraps from $0000 to $FFFF. The audio is a PSG, see `psg`.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::psg::Psg;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// CPU cycles for every clock of the PSG
+const CYCLES_PER_CLOCK: u8 = 16;
+
+/// The level of the PSG output, where a channel at full volume is about as loud as a 2A03 pulse
+/// channel at full volume
+const MIX_LEVEL: f32 = 0.1494;
+
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    command: u8,
+    chr_banks: [u8; 8],
+    /// The banks at $6000, $8000, $A000 and $C000
+    prg_banks: [u8; 4],
+    mirroring: u8,
+    irq_control: u8,
+    irq_counter: u16,
+    irq_pending: bool,
+    psg: Psg,
+    audio_register: u8,
+    /// CPU cycles since the last clock of the PSG
+    divider: u8,
+}
+
+impl Fme7 {
+    pub fn new(cartridge: Cartridge) -> Fme7 {
+        Fme7 {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            prg_rom: cartridge.prg_rom,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            mirroring: 0,
+            irq_control: 0,
+            irq_counter: 0,
+            irq_pending: false,
+            psg: Psg::new(),
+            audio_register: 0,
+            divider: 0,
+        }
+    }
+
+    /// Whether RAM rather than ROM is mapped in at $6000
+    fn prg_ram_selected(&self) -> bool {
+        self.prg_banks[0] & 0x40 != 0
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_selected() && self.prg_banks[0] & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn run_command(&mut self, byte: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[usize::from(self.command)] = byte,
+            0x8 => self.prg_banks[0] = byte,
+            0x9..=0xB => self.prg_banks[usize::from(self.command - 0x8)] = byte & 0x3F,
+            0xC => self.mirroring = byte & 0x03,
+            0xD => {
+                self.irq_control = byte;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | u16::from(byte),
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (u16::from(byte) << 8),
+        }
+    }
+}
+
+impl Device for Fme7 {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0)
+            }
+            0x6000..=0x7FFF if self.prg_ram_selected() => 0x00,
+            0x6000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.write(usize::from(addr - 0x6000), byte)
+            }
+            0x8000..=0x9FFF => self.command = byte & 0x0F,
+            0xA000..=0xBFFF => self.run_command(byte),
+            0xC000..=0xDFFF => self.audio_register = byte,
+            0xE000..=0xFFFF if self.audio_register & 0xF0 == 0 => {
+                self.psg.write(self.audio_register, byte)
+            }
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => 0x00,
+            0x6000..=0x7FFF if self.prg_ram_selected() => 0xFF,
+            0x6000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        let bank = usize::from(self.chr_banks[usize::from(addr / 0x0400) & 0x07]);
+        self.chr.read_bank(CHR_BANK_SIZE, bank, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = usize::from(self.chr_banks[usize::from(addr / 0x0400) & 0x07]);
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_control & 0x80 != 0 {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_control & 0x01 != 0 {
+                self.irq_pending = true;
+            }
+        }
+
+        self.divider += 1;
+        if self.divider == CYCLES_PER_CLOCK {
+            self.divider = 0;
+            self.psg.clock();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x6000..=0x7FFF if !self.prg_ram_selected() => usize::from(self.prg_banks[0] & 0x3F),
+            0x8000..=0x9FFF => usize::from(self.prg_banks[1]),
+            0xA000..=0xBFFF => usize::from(self.prg_banks[2]),
+            0xC000..=0xDFFF => usize::from(self.prg_banks[3]),
+            0xE000..=0xFFFF => (len / PRG_BANK_SIZE).max(1) - 1,
+            _ => return None,
+        };
+        Some(mapper::bank_offset(len, PRG_BANK_SIZE, bank) + usize::from(addr) % PRG_BANK_SIZE)
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio(&self) -> f32 {
+        self.psg.output() * MIX_LEVEL
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.command);
+        chunk.write_bytes(&self.chr_banks);
+        chunk.write_bytes(&self.prg_banks);
+        chunk.write_u8(self.mirroring);
+        chunk.write_u8(self.irq_control);
+        chunk.write_u16(self.irq_counter);
+        chunk.write_bool(self.irq_pending);
+        chunk.write_u8(self.audio_register);
+        chunk.write_u8(self.divider);
+        self.psg.save_state(chunk);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.command = chunk.read_u8()? & 0x0F;
+        let chr_banks = chunk.read_bytes()?;
+        let prg_banks = chunk.read_bytes()?;
+        if chr_banks.len() != self.chr_banks.len() || prg_banks.len() != self.prg_banks.len() {
+            return Err("FME-7 banks in save state have the wrong size");
+        }
+        self.chr_banks.copy_from_slice(chr_banks);
+        self.prg_banks.copy_from_slice(prg_banks);
+        self.mirroring = chunk.read_u8()? & 0x03;
+        self.irq_control = chunk.read_u8()?;
+        self.irq_counter = chunk.read_u16()?;
+        self.irq_pending = chunk.read_bool()?;
+        self.audio_register = chunk.read_u8()?;
+        self.divider = chunk.read_u8()? % CYCLES_PER_CLOCK;
+        self.psg.load_state(chunk)?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn fme7() -> Fme7 {
+        Fme7::new(cartridge(69, 0x40000, 0x40000))
+    }
+
+    fn command(fme7: &mut Fme7, command: u8, byte: u8) {
+        fme7.write(0x8000, command);
+        fme7.write(0xA000, byte);
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut fme7 = fme7();
+
+        command(&mut fme7, 0x8, 0x02);
+        command(&mut fme7, 0x9, 0x03);
+        command(&mut fme7, 0xA, 0x04);
+        command(&mut fme7, 0xB, 0x05);
+
+        assert_eq!(fme7.read(0x6000), 0x02);
+        assert_eq!(fme7.read(0x8000), 0x03);
+        assert_eq!(fme7.read(0xA000), 0x04);
+        assert_eq!(fme7.read(0xC000), 0x05);
+        assert_eq!(fme7.read(0xE000), 0x1F);
+    }
+
+    #[test]
+    fn chr_banks() {
+        let mut fme7 = fme7();
+
+        command(&mut fme7, 0x1, 0x21);
+        command(&mut fme7, 0x7, 0x42);
+
+        assert_eq!(fme7.ppu_read(0x0400), 0x21);
+        assert_eq!(fme7.ppu_read(0x1C00), 0x42);
+    }
+
+    #[test]
+    fn prg_ram_at_6000() {
+        let mut fme7 = fme7();
+        command(&mut fme7, 0x8, 0x40);
+        fme7.write(0x6000, 0x42);
+        assert_eq!(fme7.open_bus_mask(0x6000), 0xFF);
+
+        command(&mut fme7, 0x8, 0xC0);
+        fme7.write(0x6000, 0x42);
+
+        assert_eq!(fme7.read(0x6000), 0x42);
+        assert_eq!(fme7.prg_rom_offset(0x6000), None);
+    }
+
+    #[test]
+    fn mirroring() {
+        let mut fme7 = fme7();
+
+        command(&mut fme7, 0xC, 0x03);
+
+        assert_eq!(fme7.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn irq_when_the_counter_wraps() {
+        let mut fme7 = fme7();
+        command(&mut fme7, 0xE, 0x01);
+        command(&mut fme7, 0xF, 0x00);
+        command(&mut fme7, 0xD, 0x81);
+
+        fme7.cpu_clock();
+        assert!(!fme7.irq());
+        fme7.cpu_clock();
+        assert!(fme7.irq());
+
+        command(&mut fme7, 0xD, 0x81);
+        assert!(!fme7.irq());
+    }
+
+    #[test]
+    fn irq_counter_needs_enabling() {
+        let mut fme7 = fme7();
+        command(&mut fme7, 0xD, 0x01);
+
+        fme7.cpu_clock();
+
+        assert!(!fme7.irq());
+        assert_eq!(fme7.irq_counter, 0x0000);
+    }
+
+    #[test]
+    fn audio() {
+        let mut fme7 = fme7();
+        fme7.write(0xC000, 0x07);
+        fme7.write(0xE000, 0x3F);
+        fme7.write(0xC000, 0x08);
+        fme7.write(0xE000, 0x0F);
+
+        assert_eq!(fme7.audio(), MIX_LEVEL);
+
+        fme7.write(0xC000, 0x18);
+        fme7.write(0xE000, 0x00);
+        assert_eq!(fme7.audio(), MIX_LEVEL);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut fme7 = fme7();
+        command(&mut fme7, 0x9, 0x07);
+        command(&mut fme7, 0x8, 0xC0);
+        fme7.write(0x6000, 0x42);
+        let mut chunk = Chunk::new(b"MAPR");
+        fme7.save_state(&mut chunk);
+
+        let mut restored = self::fme7();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.read(0x8000), 0x07);
+        assert_eq!(restored.read(0x6000), 0x42);
+    }
+}
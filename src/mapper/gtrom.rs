@@ -0,0 +1,212 @@
+//! GTROM, mapper 111
+//!
+//! The register is at $5000-$5FFF and $7000-$7FFF:
+//!
+//! ```text
+//! 7  bit  0
+//! ---- ----
+//! RGNC PPPP
+//! |||| ++++- 32KB PRG bank at $8000
+//! |||+------ 8KB CHR RAM bank
+//! ||+------- 4KB nametable RAM page
+//! ++-------- Green and red LEDs
+//! ```
+//!
+//! The board has 16KB of CHR RAM and 8KB of nametable RAM of its own, used for four-screen
+//! mirroring. The PRG ROM is an SST39SF040 that the game rewrites to save, and writes to
+//! $8000-$FFFF go to the flash in the bank there, see `flash`.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::flash::Flash;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+const CHR_RAM_SIZE: usize = 0x4000;
+const NAMETABLE_PAGE_SIZE: usize = 0x1000;
+
+pub struct Gtrom {
+    flash: Flash,
+    chr: Chr,
+    nametables: Vec<u8>,
+    register: u8,
+}
+
+impl Gtrom {
+    pub fn new(cartridge: Cartridge) -> Gtrom {
+        Gtrom {
+            chr: Chr::with_ram(&cartridge, CHR_RAM_SIZE),
+            flash: Flash::new(&cartridge.prg_rom),
+            nametables: vec![0x00; NAMETABLE_PAGE_SIZE * 2],
+            register: 0,
+        }
+    }
+
+    fn chr_bank(&self) -> usize {
+        usize::from((self.register >> 4) & 0x01)
+    }
+
+    /// The offset into nametable RAM of a nametable address
+    fn nametable_offset(&self, addr: u16) -> usize {
+        let page = usize::from((self.register >> 5) & 0x01);
+        page * NAMETABLE_PAGE_SIZE + usize::from(addr) % NAMETABLE_PAGE_SIZE
+    }
+}
+
+impl Device for Gtrom {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => self.flash.read(offset),
+            None => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x5000..=0x5FFF | 0x7000..=0x7FFF => self.register = byte,
+            0x8000..=0xFFFF => {
+                let offset = self.prg_rom_offset(addr).unwrap();
+                self.flash.write(offset, byte);
+            }
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Gtrom {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr.read_bank(CHR_BANK_SIZE, self.chr_bank(), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = self.chr_bank();
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::FourScreen
+    }
+
+    fn nametable_peek(&self, addr: u16) -> Option<u8> {
+        Some(self.nametables[self.nametable_offset(addr)])
+    }
+
+    fn nametable_write(&mut self, addr: u16, byte: u8) -> bool {
+        let offset = self.nametable_offset(addr);
+        self.nametables[offset] = byte;
+        true
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let bank = usize::from(self.register & 0x0F);
+        Some(
+            mapper::bank_offset(self.flash.len(), PRG_BANK_SIZE, bank)
+                + usize::from(addr) % PRG_BANK_SIZE,
+        )
+    }
+
+    /// The flash, which is saved like battery-backed RAM
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        self.flash.prg_ram()
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.register);
+        chunk.write_bytes(&self.nametables);
+        self.flash.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.register = chunk.read_u8()?;
+        let nametables = chunk.read_bytes()?;
+        if nametables.len() != self.nametables.len() {
+            return Err("GTROM nametable RAM in save state has the wrong size");
+        }
+        self.nametables.copy_from_slice(nametables);
+        self.flash.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn gtrom() -> Gtrom {
+        Gtrom::new(cartridge(111, 0x80000, 0))
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut gtrom = gtrom();
+
+        gtrom.write(0x5000, 0x03);
+        assert_eq!(gtrom.read(0x8000), 0x0C);
+        assert_eq!(gtrom.read(0xFFFF), 0x0F);
+
+        gtrom.write(0x7000, 0x0F);
+        assert_eq!(gtrom.read(0x8000), 0x3C);
+    }
+
+    #[test]
+    fn chr_ram_banks() {
+        let mut gtrom = gtrom();
+        gtrom.write(0x5000, 0x10);
+        gtrom.ppu_write(0x0010, 0xAB);
+
+        gtrom.write(0x5000, 0x00);
+        assert_eq!(gtrom.ppu_read(0x0010), 0x00);
+        gtrom.write(0x5000, 0x10);
+        assert_eq!(gtrom.ppu_read(0x0010), 0xAB);
+    }
+
+    #[test]
+    fn four_screen_nametable_ram() {
+        let mut gtrom = gtrom();
+        assert_eq!(gtrom.mirroring(), Mirroring::FourScreen);
+
+        assert!(gtrom.nametable_write(0x2C00, 0x42));
+        assert_eq!(gtrom.nametable_peek(0x2C00), Some(0x42));
+        assert_eq!(gtrom.nametable_peek(0x2000), Some(0x00));
+
+        gtrom.write(0x5000, 0x20);
+        assert_eq!(gtrom.nametable_peek(0x2C00), Some(0x00));
+    }
+
+    #[test]
+    fn self_flashing() {
+        let mut gtrom = gtrom();
+
+        gtrom.write(0xD555, 0xAA);
+        gtrom.write(0xAAAA, 0x55);
+        gtrom.write(0xD555, 0xA0);
+        gtrom.write(0x5000, 0x02);
+        gtrom.write(0x8000, 0x00);
+
+        assert_eq!(gtrom.read(0x8000), 0x00);
+        assert_eq!(gtrom.prg_ram().read(0x10000), Some(0x00));
+    }
+}
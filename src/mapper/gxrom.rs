@@ -0,0 +1,254 @@
+//! GxROM and Color Dreams, mappers 66 and 11
+//!
+//! Writing to $8000-$FFFF selects the 32KB PRG bank and the 8KB CHR bank. The boards only differ
+//! in where the two bank numbers are in the byte written:
+//!
+//! ```text
+//!               PRG        CHR
+//! GxROM         Bits 4-5   Bits 0-1
+//! Color Dreams  Bits 0-1   Bits 4-7
+//! ```
+//!
+//! Both have bus conflicts, so the value written is ANDed with the byte in ROM.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/// Where a bank number is in the byte written to the board
+#[derive(Clone, Copy)]
+pub struct BankBits {
+    pub shift: u8,
+    pub mask: u8,
+}
+
+impl BankBits {
+    fn bank(self, register: u8) -> usize {
+        usize::from((register >> self.shift) & self.mask)
+    }
+}
+
+pub struct Gxrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+    prg_bits: BankBits,
+    chr_bits: BankBits,
+    register: u8,
+}
+
+impl Gxrom {
+    /// Create a GxROM board
+    pub fn new(cartridge: Cartridge) -> Gxrom {
+        Gxrom::with_bank_bits(
+            cartridge,
+            BankBits {
+                shift: 4,
+                mask: 0x03,
+            },
+            BankBits {
+                shift: 0,
+                mask: 0x03,
+            },
+        )
+    }
+
+    /// Create a Color Dreams board
+    pub fn color_dreams(cartridge: Cartridge) -> Gxrom {
+        Gxrom::with_bank_bits(
+            cartridge,
+            BankBits {
+                shift: 0,
+                mask: 0x03,
+            },
+            BankBits {
+                shift: 4,
+                mask: 0x0F,
+            },
+        )
+    }
+
+    /// Create a board with the PRG and CHR bank numbers at other bits
+    pub fn with_bank_bits(cartridge: Cartridge, prg_bits: BankBits, chr_bits: BankBits) -> Gxrom {
+        Gxrom {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            mirroring: cartridge.mirroring,
+            prg_rom: cartridge.prg_rom,
+            prg_bits,
+            chr_bits,
+            register: 0,
+        }
+    }
+
+    fn chr_bank(&self) -> usize {
+        self.chr_bits.bank(self.register)
+    }
+}
+
+impl Device for Gxrom {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0),
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.write(usize::from(addr - 0x6000), byte),
+            0x8000..=0xFFFF => self.register = byte & self.peek(addr),
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Gxrom {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr.read_bank(CHR_BANK_SIZE, self.chr_bank(), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = self.chr_bank();
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let bank = self.prg_bits.bank(self.register);
+        Some(
+            mapper::bank_offset(self.prg_rom.len(), PRG_BANK_SIZE, bank)
+                + usize::from(addr) % PRG_BANK_SIZE,
+        )
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.register);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.register = chunk.read_u8()?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    /// A cart with $FF at $FFFF in every bank, so writes there don't conflict
+    fn board(mapper: u16, chr_size: usize) -> Gxrom {
+        let mut cartridge = cartridge(mapper, 0x20000, chr_size);
+        for bank in cartridge.prg_rom.chunks_mut(PRG_BANK_SIZE) {
+            bank[PRG_BANK_SIZE - 1] = 0xFF;
+        }
+        match mapper {
+            11 => Gxrom::color_dreams(cartridge),
+            _ => Gxrom::new(cartridge),
+        }
+    }
+
+    fn gxrom() -> Gxrom {
+        board(66, 0x8000)
+    }
+
+    fn color_dreams() -> Gxrom {
+        board(11, 0x20000)
+    }
+
+    #[test]
+    fn gxrom_switches_prg_and_chr_banks() {
+        let mut gxrom = gxrom();
+
+        gxrom.write(0xFFFF, 0x21);
+
+        assert_eq!(gxrom.read(0x8000), 0x08);
+        assert_eq!(gxrom.read(0xC000), 0x0A);
+        assert_eq!(gxrom.ppu_read(0x0000), 0x08);
+        assert_eq!(gxrom.ppu_read(0x1C00), 0x0F);
+    }
+
+    #[test]
+    fn gxrom_ignores_unused_bits() {
+        let mut gxrom = gxrom();
+
+        gxrom.write(0xFFFF, 0xCE);
+
+        assert_eq!(gxrom.read(0x8000), 0x00);
+        assert_eq!(gxrom.ppu_read(0x0000), 0x10);
+    }
+
+    #[test]
+    fn color_dreams_switches_prg_and_chr_banks() {
+        let mut color_dreams = color_dreams();
+
+        color_dreams.write(0xFFFF, 0x32);
+
+        assert_eq!(color_dreams.read(0x8000), 0x08);
+        assert_eq!(color_dreams.read(0xC000), 0x0A);
+        assert_eq!(color_dreams.ppu_read(0x0000), 0x18);
+        assert_eq!(color_dreams.ppu_read(0x1C00), 0x1F);
+    }
+
+    #[test]
+    fn color_dreams_uses_all_four_chr_bits() {
+        let mut color_dreams = color_dreams();
+
+        color_dreams.write(0xFFFF, 0xF0);
+
+        assert_eq!(color_dreams.read(0x8000), 0x00);
+        assert_eq!(color_dreams.ppu_read(0x0000), 0x78);
+    }
+
+    #[test]
+    fn bus_conflicts_and_the_written_value() {
+        let mut gxrom = gxrom();
+        let mut color_dreams = color_dreams();
+
+        // The ROM at $E000 holds $03
+        gxrom.write(0xE000, 0x32);
+        color_dreams.write(0xE000, 0x12);
+
+        assert_eq!(gxrom.register, 0x02);
+        assert_eq!(gxrom.read(0x8000), 0x00);
+        assert_eq!(color_dreams.register, 0x02);
+        assert_eq!(color_dreams.ppu_read(0x0000), 0x00);
+    }
+}
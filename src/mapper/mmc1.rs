@@ -0,0 +1,400 @@
+//! MMC1, mapper 1
+//!
+//! The registers are written one bit at a time through a serial port at $8000-$FFFF. Five
+//! writes of bit 0 fill the shift register, and the fifth write copies it into the register
+//! selected by bits 13-14 of its address:
+//!
+//! ```text
+//! $8000-$9FFF  Control: mirroring (bits 0-1), PRG bank mode (bits 2-3), CHR bank mode (bit 4)
+//! $A000-$BFFF  CHR bank 0
+//! $C000-$DFFF  CHR bank 1
+//! $E000-$FFFF  PRG bank (bits 0-3), PRG RAM disable (bit 4)
+//! ```
+//!
+//! Writing a byte with bit 7 set resets the shift register and locks the last PRG bank at $C000.
+//! On boards with 512KB of PRG ROM (SUROM), bit 4 of the CHR bank selects the 256KB half.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    shift: u8,
+    writes: u8,
+    control: u8,
+    chr_banks: [u8; 2],
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(cartridge: Cartridge) -> Mmc1 {
+        Mmc1 {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            prg_rom: cartridge.prg_rom,
+            shift: 0,
+            writes: 0,
+            control: 0x0C,
+            chr_banks: [0; 2],
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0 && !self.prg_ram.is_empty()
+    }
+
+    /// The 16KB PRG bank mapped in at an address in $8000-$FFFF
+    fn prg_bank_at(&self, addr: u16) -> usize {
+        let bank = usize::from(self.prg_bank & 0x0F);
+        let upper = addr >= 0xC000;
+        let bank = match (self.control >> 2) & 0x03 {
+            0 | 1 => (bank & !0x01) | upper as usize,
+            2 if upper => bank,
+            2 => 0,
+            _ if upper => 0x0F,
+            _ => bank,
+        };
+        // SUROM uses the CHR bank line to select the 256KB half of PRG ROM
+        let outer = if self.prg_rom.len() > 0x40000 {
+            usize::from(self.chr_banks[0] & 0x10)
+        } else {
+            0
+        };
+        outer | bank
+    }
+
+    /// The 4KB CHR bank mapped in at an address in $0000-$1FFF
+    fn chr_bank_at(&self, addr: u16) -> usize {
+        let high = addr >= 0x1000;
+        if self.control & 0x10 == 0 {
+            usize::from(self.chr_banks[0] & !0x01) | high as usize
+        } else {
+            usize::from(self.chr_banks[high as usize])
+        }
+    }
+
+    /// Write to the serial port
+    fn write_serial(&mut self, addr: u16, byte: u8) {
+        if byte & 0x80 != 0 {
+            self.shift = 0;
+            self.writes = 0;
+            self.control |= 0x0C;
+            return;
+        }
+
+        self.shift |= (byte & 0x01) << self.writes;
+        self.writes += 1;
+        if self.writes < 5 {
+            return;
+        }
+
+        let value = self.shift;
+        match addr {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_banks[0] = value,
+            0xC000..=0xDFFF => self.chr_banks[1] = value,
+            _ => self.prg_bank = value,
+        }
+        self.shift = 0;
+        self.writes = 0;
+    }
+}
+
+impl Device for Mmc1 {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0)
+            }
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.write(usize::from(addr - 0x6000), byte)
+            }
+            0x8000..=0xFFFF => self.write_serial(addr, byte),
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr
+            .read_bank(CHR_BANK_SIZE, self.chr_bank_at(addr), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = self.chr_bank_at(addr);
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let bank = self.prg_bank_at(addr);
+        Some(
+            mapper::bank_offset(self.prg_rom.len(), PRG_BANK_SIZE, bank)
+                + usize::from(addr) % PRG_BANK_SIZE,
+        )
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.shift);
+        chunk.write_u8(self.writes);
+        chunk.write_u8(self.control);
+        chunk.write_u8(self.chr_banks[0]);
+        chunk.write_u8(self.chr_banks[1]);
+        chunk.write_u8(self.prg_bank);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.shift = chunk.read_u8()?;
+        self.writes = chunk.read_u8()?;
+        if self.writes >= 5 {
+            return Err("MMC1 shift register in save state has too many writes");
+        }
+        self.control = chunk.read_u8()?;
+        self.chr_banks[0] = chunk.read_u8()?;
+        self.chr_banks[1] = chunk.read_u8()?;
+        self.prg_bank = chunk.read_u8()?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    /// Write a register through the serial port
+    fn write_register(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1.write(addr, value >> bit & 0x01);
+        }
+    }
+
+    fn mmc1() -> Mmc1 {
+        Mmc1::new(cartridge(1, 0x20000, 0x20000))
+    }
+
+    #[test]
+    fn powers_on_with_the_last_bank_fixed() {
+        let mut mmc1 = mmc1();
+
+        assert_eq!(mmc1.read(0x8000), 0x00);
+        assert_eq!(mmc1.read(0xC000), 0x0E);
+        assert_eq!(mmc1.read(0xE000), 0x0F);
+    }
+
+    #[test]
+    fn registers_take_five_writes() {
+        let mut mmc1 = mmc1();
+
+        for _ in 0..4 {
+            mmc1.write(0xE000, 0x01);
+        }
+        assert_eq!(mmc1.prg_bank, 0x00);
+        mmc1.write(0xE000, 0x00);
+
+        assert_eq!(mmc1.prg_bank, 0x0F);
+        assert_eq!(mmc1.writes, 0);
+    }
+
+    #[test]
+    fn reset_bit_clears_the_shift_register() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0x8000, 0x00);
+
+        mmc1.write(0xE000, 0x01);
+        mmc1.write(0xE000, 0x01);
+        mmc1.write(0x8000, 0x80);
+        write_register(&mut mmc1, 0xE000, 0x02);
+
+        assert_eq!(mmc1.prg_bank, 0x02);
+        assert_eq!(mmc1.control & 0x0C, 0x0C);
+    }
+
+    #[test]
+    fn prg_mode_switch_8000() {
+        let mut mmc1 = mmc1();
+
+        write_register(&mut mmc1, 0xE000, 0x03);
+
+        assert_eq!(mmc1.read(0x8000), 0x06);
+        assert_eq!(mmc1.read(0xA000), 0x07);
+        assert_eq!(mmc1.read(0xC000), 0x0E);
+        assert_eq!(mmc1.prg_rom_offset(0x8000), Some(0xC000));
+    }
+
+    #[test]
+    fn prg_mode_switch_c000() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0x8000, 0x08);
+
+        write_register(&mut mmc1, 0xE000, 0x03);
+
+        assert_eq!(mmc1.read(0x8000), 0x00);
+        assert_eq!(mmc1.read(0xC000), 0x06);
+    }
+
+    #[test]
+    fn prg_mode_32k_ignores_the_low_bit() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0x8000, 0x00);
+
+        write_register(&mut mmc1, 0xE000, 0x03);
+
+        assert_eq!(mmc1.read(0x8000), 0x04);
+        assert_eq!(mmc1.read(0xC000), 0x06);
+    }
+
+    #[test]
+    fn surom_selects_the_256k_half_with_chr_bank_0() {
+        let mut mmc1 = Mmc1::new(cartridge(1, 0x80000, 0));
+
+        write_register(&mut mmc1, 0xA000, 0x10);
+
+        assert_eq!(mmc1.read(0x8000), 0x20);
+        assert_eq!(mmc1.read(0xC000), 0x3E);
+    }
+
+    #[test]
+    fn chr_mode_8k() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0x8000, 0x0C);
+
+        write_register(&mut mmc1, 0xA000, 0x03);
+
+        assert_eq!(mmc1.ppu_read(0x0000), 0x08);
+        assert_eq!(mmc1.ppu_read(0x1000), 0x0C);
+    }
+
+    #[test]
+    fn chr_mode_4k() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0x8000, 0x1C);
+
+        write_register(&mut mmc1, 0xA000, 0x03);
+        write_register(&mut mmc1, 0xC000, 0x05);
+
+        assert_eq!(mmc1.ppu_read(0x0000), 0x0C);
+        assert_eq!(mmc1.ppu_read(0x1000), 0x14);
+    }
+
+    #[test]
+    fn chr_ram_is_banked() {
+        let mut mmc1 = Mmc1::new(cartridge(1, 0x20000, 0));
+        write_register(&mut mmc1, 0x8000, 0x1C);
+        write_register(&mut mmc1, 0xC000, 0x00);
+
+        mmc1.ppu_write(0x1000, 0xAB);
+
+        assert_eq!(mmc1.ppu_read(0x0000), 0xAB);
+    }
+
+    #[test]
+    fn mirroring_control() {
+        let mut mmc1 = mmc1();
+        let mut mirroring = |control| {
+            write_register(&mut mmc1, 0x8000, control);
+            mmc1.mirroring()
+        };
+
+        assert_eq!(mirroring(0x00), Mirroring::SingleScreenLower);
+        assert_eq!(mirroring(0x01), Mirroring::SingleScreenUpper);
+        assert_eq!(mirroring(0x02), Mirroring::Vertical);
+        assert_eq!(mirroring(0x03), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn prg_ram_can_be_disabled() {
+        let mut mmc1 = mmc1();
+        mmc1.write(0x6000, 0xAB);
+        assert_eq!(mmc1.read(0x6000), 0xAB);
+
+        write_register(&mut mmc1, 0xE000, 0x10);
+        mmc1.write(0x6000, 0xCD);
+
+        assert_eq!(mmc1.open_bus_mask(0x6000), 0xFF);
+        write_register(&mut mmc1, 0xE000, 0x00);
+        assert_eq!(mmc1.read(0x6000), 0xAB);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut mmc1 = mmc1();
+        write_register(&mut mmc1, 0xE000, 0x05);
+        mmc1.write(0x8000, 0x01);
+        mmc1.write(0x6000, 0x42);
+        let mut chunk = Chunk::new(b"MAPR");
+        mmc1.save_state(&mut chunk);
+
+        let mut restored = self::mmc1();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.read(0x8000), 0x0A);
+        assert_eq!(restored.read(0x6000), 0x42);
+        assert_eq!((restored.shift, restored.writes), (0x01, 1));
+    }
+
+    #[test]
+    fn states_with_a_full_shift_register_are_rejected() {
+        let mut corrupt = Chunk::new(b"MAPR");
+        corrupt.write_u8(0x00);
+        corrupt.write_u8(5);
+
+        assert_eq!(
+            mmc1().load_state(&mut corrupt.reader()),
+            Err("MMC1 shift register in save state has too many writes")
+        );
+    }
+}
@@ -0,0 +1,317 @@
+//! MMC2 and MMC4, mappers 9 and 10
+//!
+//! Each half of the pattern tables has two 4KB CHR banks, and a latch that picks between them.
+//! The latches flip when the PPU fetches tile $FD or $FE, so a game can switch banks midway
+//! through a frame by placing those tiles on screen:
+//!
+//! ```text
+//! $0FD8        Latch 0 selects the $FD bank (MMC4: $0FD8-$0FDF)
+//! $0FE8        Latch 0 selects the $FE bank (MMC4: $0FE8-$0FEF)
+//! $1FD8-$1FDF  Latch 1 selects the $FD bank
+//! $1FE8-$1FEF  Latch 1 selects the $FE bank
+//! ```
+//!
+//! The fetch that flips a latch still comes from the old bank. The registers are:
+//!
+//! ```text
+//! $A000-$AFFF  PRG bank at $8000, 8KB on MMC2 and 16KB on MMC4
+//! $B000-$BFFF  CHR $FD bank at $0000
+//! $C000-$CFFF  CHR $FE bank at $0000
+//! $D000-$DFFF  CHR $FD bank at $1000
+//! $E000-$EFFF  CHR $FE bank at $1000
+//! $F000-$FFFF  Mirroring: vertical (0) or horizontal (1)
+//! ```
+//!
+//! The rest of PRG ROM is fixed to the last banks.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const CHR_BANK_SIZE: usize = 0x1000;
+
+pub struct Mmc2 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    /// MMC4 has 16KB PRG banks and wider latch triggers for the lower pattern table
+    mmc4: bool,
+    prg_bank: u8,
+    /// The $FD and $FE banks for each half of the pattern tables
+    chr_banks: [[u8; 2]; 2],
+    /// Whether each half is showing its $FE bank
+    latches: [bool; 2],
+    mirroring: u8,
+}
+
+impl Mmc2 {
+    /// Create an MMC2 board
+    pub fn new(cartridge: Cartridge) -> Mmc2 {
+        Mmc2 {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            mmc4: false,
+            mirroring: (cartridge.mirroring == Mirroring::Horizontal) as u8,
+            prg_rom: cartridge.prg_rom,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [true; 2],
+        }
+    }
+
+    /// Create an MMC4 board
+    pub fn mmc4(cartridge: Cartridge) -> Mmc2 {
+        Mmc2 {
+            mmc4: true,
+            ..Mmc2::new(cartridge)
+        }
+    }
+
+    fn prg_bank_size(&self) -> usize {
+        if self.mmc4 {
+            0x4000
+        } else {
+            0x2000
+        }
+    }
+
+    fn chr_bank_at(&self, addr: u16) -> usize {
+        let half = usize::from(addr >> 12 & 0x01);
+        usize::from(self.chr_banks[half][self.latches[half] as usize])
+    }
+
+    /// Flip a latch if the PPU fetched from tile $FD or $FE
+    fn update_latches(&mut self, addr: u16) {
+        let half = usize::from(addr >> 12 & 0x01);
+        // MMC2 only watches the first byte of the tiles in the lower pattern table
+        let exact = half == 0 && !self.mmc4;
+        let tile = addr & 0x0FF8;
+        if exact && addr & 0x0007 != 0 {
+            return;
+        }
+        match tile {
+            0x0FD8 => self.latches[half] = false,
+            0x0FE8 => self.latches[half] = true,
+            _ => {}
+        }
+    }
+}
+
+impl Device for Mmc2 {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0),
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.write(usize::from(addr - 0x6000), byte),
+            0xA000..=0xAFFF => self.prg_bank = byte & 0x0F,
+            0xB000..=0xBFFF => self.chr_banks[0][0] = byte & 0x1F,
+            0xC000..=0xCFFF => self.chr_banks[0][1] = byte & 0x1F,
+            0xD000..=0xDFFF => self.chr_banks[1][0] = byte & 0x1F,
+            0xE000..=0xEFFF => self.chr_banks[1][1] = byte & 0x1F,
+            0xF000..=0xFFFF => self.mirroring = byte & 0x01,
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let byte = self.ppu_peek(addr);
+        self.update_latches(addr);
+        byte
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr
+            .read_bank(CHR_BANK_SIZE, self.chr_bank_at(addr), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = self.chr_bank_at(addr);
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.mirroring == 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let size = self.prg_bank_size();
+        let len = self.prg_rom.len();
+        if usize::from(addr - 0x8000) < size {
+            let bank = mapper::bank_offset(len, size, usize::from(self.prg_bank));
+            return Some(bank + usize::from(addr) % size);
+        }
+        // The rest of the window is fixed to the end of PRG ROM
+        Some((len.saturating_sub(0x8000) + usize::from(addr - 0x8000)) % len)
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.prg_bank);
+        for banks in &self.chr_banks {
+            chunk.write_u8(banks[0]);
+            chunk.write_u8(banks[1]);
+        }
+        chunk.write_bool(self.latches[0]);
+        chunk.write_bool(self.latches[1]);
+        chunk.write_u8(self.mirroring);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.prg_bank = chunk.read_u8()?;
+        for banks in &mut self.chr_banks {
+            banks[0] = chunk.read_u8()?;
+            banks[1] = chunk.read_u8()?;
+        }
+        self.latches[0] = chunk.read_bool()?;
+        self.latches[1] = chunk.read_bool()?;
+        self.mirroring = chunk.read_u8()?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn mmc2() -> Mmc2 {
+        let mut mmc2 = Mmc2::new(cartridge(9, 0x20000, 0x20000));
+        mmc2.write(0xB000, 0x01);
+        mmc2.write(0xC000, 0x02);
+        mmc2.write(0xD000, 0x03);
+        mmc2.write(0xE000, 0x04);
+        mmc2
+    }
+
+    #[test]
+    fn mmc2_prg_banks() {
+        let mut mmc2 = mmc2();
+
+        mmc2.write(0xA000, 0x05);
+
+        assert_eq!(mmc2.read(0x8000), 0x05);
+        assert_eq!(mmc2.read(0xA000), 0x0D);
+        assert_eq!(mmc2.read(0xC000), 0x0E);
+        assert_eq!(mmc2.read(0xE000), 0x0F);
+    }
+
+    #[test]
+    fn mmc4_prg_banks() {
+        let mut mmc4 = Mmc2::mmc4(cartridge(10, 0x20000, 0x20000));
+
+        mmc4.write(0xA000, 0x02);
+
+        assert_eq!(mmc4.read(0x8000), 0x04);
+        assert_eq!(mmc4.read(0xA000), 0x05);
+        assert_eq!(mmc4.read(0xC000), 0x0E);
+        assert_eq!(mmc4.prg_rom_offset(0xFFFF), Some(0x1FFFF));
+    }
+
+    #[test]
+    fn latches_start_on_the_fe_banks() {
+        let mut mmc2 = mmc2();
+
+        assert_eq!(mmc2.ppu_read(0x0000), 0x08);
+        assert_eq!(mmc2.ppu_read(0x1000), 0x10);
+    }
+
+    #[test]
+    fn fetching_tile_fd_switches_after_the_fetch() {
+        let mut mmc2 = mmc2();
+
+        assert_eq!(mmc2.ppu_read(0x0FD8), 0x0B);
+        assert_eq!(mmc2.ppu_read(0x0000), 0x04);
+        assert_eq!(mmc2.ppu_read(0x1000), 0x10);
+
+        mmc2.ppu_read(0x1FDD);
+        assert_eq!(mmc2.ppu_read(0x1000), 0x0C);
+
+        mmc2.ppu_read(0x0FE8);
+        mmc2.ppu_read(0x1FEF);
+        assert_eq!(mmc2.ppu_read(0x0000), 0x08);
+        assert_eq!(mmc2.ppu_read(0x1000), 0x10);
+    }
+
+    #[test]
+    fn mmc2_only_latches_on_the_first_byte_of_the_lower_tile() {
+        let mut mmc2 = mmc2();
+        mmc2.ppu_read(0x0FD9);
+        assert_eq!(mmc2.ppu_read(0x0000), 0x08);
+
+        let mut mmc4 = Mmc2::mmc4(cartridge(10, 0x20000, 0x20000));
+        mmc4.write(0xB000, 0x01);
+        mmc4.ppu_read(0x0FD9);
+        assert_eq!(mmc4.ppu_read(0x0000), 0x04);
+    }
+
+    #[test]
+    fn peeking_leaves_the_latches_alone() {
+        let mmc2 = mmc2();
+
+        mmc2.ppu_peek(0x0FD8);
+
+        assert!(mmc2.latches[0]);
+    }
+
+    #[test]
+    fn mirroring() {
+        let mut mmc2 = mmc2();
+
+        mmc2.write(0xF000, 0x01);
+        assert_eq!(mmc2.mirroring(), Mirroring::Horizontal);
+        mmc2.write(0xF000, 0x00);
+        assert_eq!(mmc2.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut mmc2 = mmc2();
+        mmc2.write(0xA000, 0x03);
+        mmc2.ppu_read(0x1FD8);
+        let mut chunk = Chunk::new(b"MAPR");
+        mmc2.save_state(&mut chunk);
+
+        let mut restored = Mmc2::new(cartridge(9, 0x20000, 0x20000));
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.read(0x8000), 0x03);
+        assert_eq!(restored.ppu_read(0x1000), 0x0C);
+    }
+}
@@ -0,0 +1,454 @@
+//! MMC3, mapper 4
+//!
+//! The registers are paired by address, with even and odd addresses selecting the register:
+//!
+//! ```text
+//! $8000  Bank select: register (bits 0-2), PRG mode (bit 6), CHR inversion (bit 7)
+//! $8001  Bank data for the selected register
+//! $A000  Mirroring: vertical (0) or horizontal (1)
+//! $A001  PRG RAM protect: writes denied (bit 6), enabled (bit 7)
+//! $C000  IRQ latch
+//! $C001  IRQ reload
+//! $E000  IRQ disable, which also acknowledges a pending IRQ
+//! $E001  IRQ enable
+//! ```
+//!
+//! Registers 0-1 select 2KB CHR banks and 2-5 select 1KB CHR banks, which swap halves of the
+//! pattern tables when CHR inversion is set. Registers 6-7 select 8KB PRG banks at $8000 and
+//! $A000, with the second to last bank at $C000. The PRG mode swaps $8000 and $C000.
+//!
+//! The IRQ counter is clocked by rising edges of PPU A12. The MMC3 filters out edges after A12
+//! was low for only a few cycles, so the sprite fetches that go back and forth between nametables
+//! and the pattern table at $1000 clock it once per scanline.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// PPU dots A12 has to be low for a rising edge to clock the IRQ counter, about 3 CPU cycles
+const A12_FILTER: u8 = 9;
+
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    four_screen: bool,
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: u8,
+    prg_ram_protect: u8,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    /// PPU dots since A12 went low
+    a12_low: u8,
+}
+
+impl Mmc3 {
+    pub fn new(cartridge: Cartridge) -> Mmc3 {
+        Mmc3 {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            four_screen: cartridge.mirroring == Mirroring::FourScreen,
+            mirroring: (cartridge.mirroring == Mirroring::Horizontal) as u8,
+            prg_rom: cartridge.prg_rom,
+            bank_select: 0,
+            registers: [0; 8],
+            prg_ram_protect: 0x80,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12_low: 0,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_protect & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_enabled() && self.prg_ram_protect & 0x40 == 0
+    }
+
+    /// The 8KB PRG bank mapped in at an address in $8000-$FFFF
+    fn prg_bank_at(&self, addr: u16) -> usize {
+        let banks = (self.prg_rom.len() / PRG_BANK_SIZE).max(1);
+        let swapped = self.bank_select & 0x40 != 0;
+        match (addr - 0x8000) / 0x2000 {
+            0 if swapped => banks.saturating_sub(2),
+            0 => usize::from(self.registers[6] & 0x3F),
+            1 => usize::from(self.registers[7] & 0x3F),
+            2 if swapped => usize::from(self.registers[6] & 0x3F),
+            2 => banks.saturating_sub(2),
+            _ => banks - 1,
+        }
+    }
+
+    /// The 1KB CHR bank mapped in at an address in $0000-$1FFF
+    fn chr_bank_at(&self, addr: u16) -> usize {
+        let inverted = self.bank_select & 0x80 != 0;
+        let slot = usize::from(addr / 0x0400) ^ if inverted { 0x04 } else { 0x00 };
+        match slot {
+            0 | 1 => usize::from(self.registers[0] & 0xFE) | slot,
+            2 | 3 => usize::from(self.registers[1] & 0xFE) | (slot & 0x01),
+            _ => usize::from(self.registers[slot - 2]),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, byte: u8) {
+        match (addr & 0xE000, addr & 0x0001) {
+            (0x8000, 0) => self.bank_select = byte,
+            (0x8000, _) => self.registers[usize::from(self.bank_select & 0x07)] = byte,
+            (0xA000, 0) => self.mirroring = byte & 0x01,
+            (0xA000, _) => self.prg_ram_protect = byte,
+            (0xC000, 0) => self.irq_latch = byte,
+            (0xC000, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, 0) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (_, _) => self.irq_enabled = true,
+        }
+    }
+
+    /// Clock the IRQ counter on a rising edge of A12
+    fn clock_irq(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Device for Mmc3 {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0)
+            }
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_writable() => {
+                self.prg_ram.write(usize::from(addr - 0x6000), byte)
+            }
+            0x8000..=0xFFFF => self.write_register(addr, byte),
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr
+            .read_bank(CHR_BANK_SIZE, self.chr_bank_at(addr), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = self.chr_bank_at(addr);
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn ppu_clock(&mut self, addr: u16) {
+        if addr & 0x1000 == 0 {
+            self.a12_low = self.a12_low.saturating_add(1);
+            return;
+        }
+        if self.a12_low >= A12_FILTER {
+            self.clock_irq();
+        }
+        self.a12_low = 0;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.four_screen {
+            Mirroring::FourScreen
+        } else if self.mirroring == 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let bank = self.prg_bank_at(addr);
+        Some(
+            mapper::bank_offset(self.prg_rom.len(), PRG_BANK_SIZE, bank)
+                + usize::from(addr) % PRG_BANK_SIZE,
+        )
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.bank_select);
+        chunk.write_bytes(&self.registers);
+        chunk.write_u8(self.mirroring);
+        chunk.write_u8(self.prg_ram_protect);
+        chunk.write_u8(self.irq_latch);
+        chunk.write_u8(self.irq_counter);
+        chunk.write_bool(self.irq_reload);
+        chunk.write_bool(self.irq_enabled);
+        chunk.write_bool(self.irq_pending);
+        chunk.write_u8(self.a12_low);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.bank_select = chunk.read_u8()?;
+        let registers = chunk.read_bytes()?;
+        if registers.len() != self.registers.len() {
+            return Err("MMC3 registers in save state have the wrong size");
+        }
+        self.registers.copy_from_slice(registers);
+        self.mirroring = chunk.read_u8()?;
+        self.prg_ram_protect = chunk.read_u8()?;
+        self.irq_latch = chunk.read_u8()?;
+        self.irq_counter = chunk.read_u8()?;
+        self.irq_reload = chunk.read_bool()?;
+        self.irq_enabled = chunk.read_bool()?;
+        self.irq_pending = chunk.read_bool()?;
+        self.a12_low = chunk.read_u8()?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn mmc3() -> Mmc3 {
+        Mmc3::new(cartridge(4, 0x20000, 0x20000))
+    }
+
+    fn set_register(mmc3: &mut Mmc3, register: u8, value: u8) {
+        let select = mmc3.bank_select & 0xC0;
+        mmc3.write(0x8000, select | register);
+        mmc3.write(0x8001, value);
+    }
+
+    /// Run a scanline of PPU fetches, with the background at $0000 and sprites at $1000
+    fn scanline(mmc3: &mut Mmc3) {
+        for dot in 0..341u16 {
+            let addr = match dot {
+                257..=320 if dot % 8 >= 4 => 0x1000,
+                257..=320 => 0x2000,
+                _ => 0x0000,
+            };
+            mmc3.ppu_clock(addr);
+        }
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut mmc3 = mmc3();
+
+        set_register(&mut mmc3, 6, 0x03);
+        set_register(&mut mmc3, 7, 0x05);
+
+        assert_eq!(mmc3.read(0x8000), 0x03);
+        assert_eq!(mmc3.read(0xA000), 0x05);
+        assert_eq!(mmc3.read(0xC000), 0x0E);
+        assert_eq!(mmc3.read(0xE000), 0x0F);
+    }
+
+    #[test]
+    fn prg_mode_swaps_8000_and_c000() {
+        let mut mmc3 = mmc3();
+        set_register(&mut mmc3, 6, 0x03);
+
+        mmc3.write(0x8000, 0x40);
+
+        assert_eq!(mmc3.read(0x8000), 0x0E);
+        assert_eq!(mmc3.read(0xC000), 0x03);
+        assert_eq!(mmc3.prg_rom_offset(0xC000), Some(0x6000));
+    }
+
+    #[test]
+    fn a_single_prg_bank_is_everywhere() {
+        // Only NES 2.0 headers can give a size of 8KB
+        let mut cartridge = cartridge(4, 0x4000, 0x2000);
+        cartridge.prg_rom.truncate(0x2000);
+        let mut mmc3 = Mmc3::new(cartridge);
+
+        for &mode in &[0x00, 0x40] {
+            mmc3.write(0x8000, mode);
+            for &addr in &[0x8000, 0xA000, 0xC000, 0xE000] {
+                assert_eq!(mmc3.prg_rom_offset(addr), Some(0x0000));
+            }
+        }
+    }
+
+    #[test]
+    fn chr_banks() {
+        let mut mmc3 = mmc3();
+
+        set_register(&mut mmc3, 0, 0x11);
+        set_register(&mut mmc3, 1, 0x20);
+        set_register(&mut mmc3, 2, 0x30);
+        set_register(&mut mmc3, 5, 0x33);
+
+        assert_eq!(mmc3.ppu_read(0x0000), 0x10);
+        assert_eq!(mmc3.ppu_read(0x0400), 0x11);
+        assert_eq!(mmc3.ppu_read(0x0C00), 0x21);
+        assert_eq!(mmc3.ppu_read(0x1000), 0x30);
+        assert_eq!(mmc3.ppu_read(0x1C00), 0x33);
+    }
+
+    #[test]
+    fn chr_inversion() {
+        let mut mmc3 = mmc3();
+        set_register(&mut mmc3, 0, 0x10);
+        set_register(&mut mmc3, 2, 0x30);
+
+        mmc3.write(0x8000, 0x80);
+
+        assert_eq!(mmc3.ppu_read(0x0000), 0x30);
+        assert_eq!(mmc3.ppu_read(0x1000), 0x10);
+        assert_eq!(mmc3.ppu_read(0x1400), 0x11);
+    }
+
+    #[test]
+    fn mirroring() {
+        let mut mmc3 = mmc3();
+
+        mmc3.write(0xA000, 0x01);
+        assert_eq!(mmc3.mirroring(), Mirroring::Horizontal);
+        mmc3.write(0xA000, 0x00);
+        assert_eq!(mmc3.mirroring(), Mirroring::Vertical);
+
+        mmc3.four_screen = true;
+        assert_eq!(mmc3.mirroring(), Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn prg_ram_protect() {
+        let mut mmc3 = mmc3();
+        mmc3.write(0x6000, 0x42);
+
+        mmc3.write(0xA001, 0xC0);
+        mmc3.write(0x6000, 0xFF);
+        assert_eq!(mmc3.read(0x6000), 0x42);
+
+        mmc3.write(0xA001, 0x00);
+        assert_eq!(mmc3.open_bus_mask(0x6000), 0xFF);
+    }
+
+    #[test]
+    fn irq_fires_after_latch_scanlines() {
+        let mut mmc3 = mmc3();
+        mmc3.write(0xC000, 0x02);
+        mmc3.write(0xC001, 0x00);
+        mmc3.write(0xE001, 0x00);
+
+        scanline(&mut mmc3);
+        assert_eq!(mmc3.irq_counter, 0x02);
+        scanline(&mut mmc3);
+        assert!(!mmc3.irq());
+        scanline(&mut mmc3);
+
+        assert!(mmc3.irq());
+    }
+
+    #[test]
+    fn irq_disable_acknowledges() {
+        let mut mmc3 = mmc3();
+        mmc3.write(0xC000, 0x00);
+        mmc3.write(0xE001, 0x00);
+        scanline(&mut mmc3);
+        assert!(mmc3.irq());
+
+        mmc3.write(0xE000, 0x00);
+
+        assert!(!mmc3.irq());
+        scanline(&mut mmc3);
+        assert!(!mmc3.irq());
+    }
+
+    #[test]
+    fn a12_rises_after_a_short_low_are_filtered() {
+        let mut mmc3 = mmc3();
+        mmc3.write(0xC000, 0x05);
+
+        for _ in 0..A12_FILTER {
+            mmc3.ppu_clock(0x0000);
+        }
+        mmc3.ppu_clock(0x1000);
+        assert_eq!(mmc3.irq_counter, 0x05);
+
+        mmc3.ppu_clock(0x0000);
+        mmc3.ppu_clock(0x1000);
+        assert_eq!(mmc3.irq_counter, 0x05);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut mmc3 = mmc3();
+        set_register(&mut mmc3, 6, 0x07);
+        mmc3.write(0xC000, 0x10);
+        mmc3.write(0xE001, 0x00);
+        scanline(&mut mmc3);
+        mmc3.write(0x6000, 0x42);
+        let mut chunk = Chunk::new(b"MAPR");
+        mmc3.save_state(&mut chunk);
+
+        let mut restored = self::mmc3();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.read(0x8000), 0x07);
+        assert_eq!(restored.read(0x6000), 0x42);
+        assert_eq!(restored.irq_counter, 0x10);
+        assert!(restored.irq_enabled);
+    }
+}
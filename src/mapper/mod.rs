@@ -0,0 +1,279 @@
+//! Cartridge boards
+//!
+//! A mapper owns everything the cartridge connects to: $4020-$FFFF on the CPU bus, through the
+//! `Device` trait, and the pattern tables at $0000-$1FFF on the PPU bus. It also decides how the
+//! nametables at $2000-$2FFF are mapped onto the 2KB of VRAM in the console, through its
+//! mirroring, unless the board has nametable RAM of its own.
+//!
+//! Bank switching boards map windows of their PRG and CHR into those ranges, which are worked out
+//! on every access from the bank registers.
+
+pub mod action53;
+pub mod axrom;
+pub mod cnrom;
+pub mod flash;
+pub mod fme7;
+pub mod gtrom;
+pub mod gxrom;
+pub mod mmc1;
+pub mod mmc2;
+pub mod mmc3;
+pub mod n163;
+pub mod nrom;
+pub mod opll;
+pub mod prg_ram;
+pub mod psg;
+pub mod unrom512;
+pub mod uxrom;
+pub mod vrc6;
+pub mod vrc7;
+pub mod vrc_irq;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use cartridge::{Cartridge, CartridgeError, Mirroring};
+use cpu::device::Device;
+use state::{Chunk, ChunkReader};
+
+use self::action53::Action53;
+use self::axrom::Axrom;
+use self::cnrom::Cnrom;
+use self::fme7::Fme7;
+use self::gtrom::Gtrom;
+use self::gxrom::Gxrom;
+use self::mmc1::Mmc1;
+use self::mmc2::Mmc2;
+use self::mmc3::Mmc3;
+use self::n163::N163;
+use self::nrom::Nrom;
+use self::prg_ram::PrgRam;
+use self::unrom512::Unrom512;
+use self::uxrom::Uxrom;
+use self::vrc6::Vrc6;
+use self::vrc7::Vrc7;
+
+pub trait Mapper: Device {
+    /// Read from the pattern tables, with any side effects the read has on the board
+    fn ppu_read(&mut self, addr: u16) -> u8;
+
+    /// Read from the pattern tables without any side effects
+    fn ppu_peek(&self, addr: u16) -> u8;
+
+    fn ppu_write(&mut self, addr: u16, byte: u8);
+
+    /// Called by the PPU on every dot with the address on its bus, for boards that watch it
+    fn ppu_clock(&mut self, _addr: u16) {}
+
+    /// Called on every CPU cycle, for boards with timers or audio
+    fn cpu_clock(&mut self) {}
+
+    /// The level of the expansion audio, on the scale of the 2A03 output
+    fn audio(&self) -> f32 {
+        0.0
+    }
+
+    /// How the nametables are mapped onto the VRAM in the console
+    fn mirroring(&self) -> Mirroring;
+
+    /// Read a nametable byte from RAM on the board, for four-screen boards that have their own
+    ///
+    /// Returns `None` if the nametables are in the VRAM in the console.
+    fn nametable_peek(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    /// Write a nametable byte to RAM on the board, returning whether the board has any
+    fn nametable_write(&mut self, _addr: u16, _byte: u8) -> bool {
+        false
+    }
+
+    /// The offset into PRG ROM mapped in at a CPU address, if any
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize>;
+
+    fn prg_ram(&mut self) -> &mut PrgRam;
+
+    /// Whether the board is holding the IRQ line of the CPU low
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Save the bank registers and RAM of the board
+    fn save_state(&self, chunk: &mut Chunk);
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str>;
+}
+
+/// Create the board for a cartridge
+pub fn create(cartridge: Cartridge) -> Result<Rc<RefCell<dyn Mapper>>, CartridgeError> {
+    let mapper: Rc<RefCell<dyn Mapper>> = match cartridge.mapper {
+        0 => Rc::new(RefCell::new(Nrom::new(cartridge))),
+        1 => Rc::new(RefCell::new(Mmc1::new(cartridge))),
+        2 => Rc::new(RefCell::new(Uxrom::new(cartridge))),
+        3 => Rc::new(RefCell::new(Cnrom::new(cartridge))),
+        4 => Rc::new(RefCell::new(Mmc3::new(cartridge))),
+        7 => Rc::new(RefCell::new(Axrom::new(cartridge))),
+        9 => Rc::new(RefCell::new(Mmc2::new(cartridge))),
+        10 => Rc::new(RefCell::new(Mmc2::mmc4(cartridge))),
+        11 => Rc::new(RefCell::new(Gxrom::color_dreams(cartridge))),
+        19 => Rc::new(RefCell::new(N163::new(cartridge))),
+        24 | 26 => Rc::new(RefCell::new(Vrc6::new(cartridge))),
+        28 => Rc::new(RefCell::new(Action53::new(cartridge))),
+        30 => Rc::new(RefCell::new(Unrom512::new(cartridge))),
+        66 => Rc::new(RefCell::new(Gxrom::new(cartridge))),
+        69 => Rc::new(RefCell::new(Fme7::new(cartridge))),
+        85 => Rc::new(RefCell::new(Vrc7::new(cartridge))),
+        111 => Rc::new(RefCell::new(Gtrom::new(cartridge))),
+        mapper => return Err(CartridgeError::UnsupportedMapper(mapper)),
+    };
+    Ok(mapper)
+}
+
+/// The PRG RAM a cartridge asks for
+fn prg_ram(cartridge: &Cartridge) -> PrgRam {
+    PrgRam::new(cartridge.prg_ram_size + cartridge.prg_nvram_size)
+}
+
+/// The offset of a bank, wrapping around banks past the end of the data
+///
+/// # Arguments
+///
+/// * `len` - The size of the data that is banked
+/// * `size` - The size of a bank
+/// * `bank` - The bank number
+fn bank_offset(len: usize, size: usize, bank: usize) -> usize {
+    let banks = (len / size).max(1);
+    (bank % banks) * size
+}
+
+/// CHR ROM, or CHR RAM for boards without it
+pub struct Chr {
+    data: Vec<u8>,
+    ram: bool,
+}
+
+impl Chr {
+    pub fn new(cartridge: &Cartridge) -> Chr {
+        Chr::with_ram(cartridge, 0x2000)
+    }
+
+    /// CHR for boards that have at least a given size of CHR RAM when there's no CHR ROM
+    pub fn with_ram(cartridge: &Cartridge, min_ram_size: usize) -> Chr {
+        if cartridge.chr_rom.is_empty() {
+            let size = cartridge.chr_ram_size + cartridge.chr_nvram_size;
+            Chr {
+                data: vec![0x00; size.max(min_ram_size)],
+                ram: true,
+            }
+        } else {
+            Chr {
+                data: cartridge.chr_rom.clone(),
+                ram: false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Read from an offset into the CHR, wrapping around its size
+    pub fn read(&self, offset: usize) -> u8 {
+        self.data[offset % self.data.len()]
+    }
+
+    /// Write to an offset into the CHR, which only does something for CHR RAM
+    pub fn write(&mut self, offset: usize, byte: u8) {
+        if self.ram {
+            let len = self.data.len();
+            self.data[offset % len] = byte;
+        }
+    }
+
+    /// Read from a bank of the given size
+    pub fn read_bank(&self, size: usize, bank: usize, addr: u16) -> u8 {
+        self.read(bank_offset(self.data.len(), size, bank) + usize::from(addr) % size)
+    }
+
+    pub fn write_bank(&mut self, size: usize, bank: usize, addr: u16, byte: u8) {
+        let offset = bank_offset(self.data.len(), size, bank) + usize::from(addr) % size;
+        self.write(offset, byte);
+    }
+
+    /// Save CHR RAM, CHR ROM is loaded from the cartridge
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        if self.ram {
+            chunk.write_bytes(&self.data);
+        } else {
+            chunk.write_bytes(&[]);
+        }
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        let data = chunk.read_bytes()?;
+        if self.ram {
+            if data.len() != self.data.len() {
+                return Err("CHR RAM in save state has the wrong size");
+            }
+            self.data.copy_from_slice(data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// A cartridge with numbered 1KB CHR banks and numbered 8KB PRG banks
+    pub fn cartridge(mapper: u16, prg_size: usize, chr_size: usize) -> Cartridge {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A];
+        rom.push((prg_size / 0x4000) as u8);
+        rom.push((chr_size / 0x2000) as u8);
+        rom.push((mapper as u8) << 4);
+        rom.push(mapper as u8 & 0xF0);
+        rom.resize(16, 0x00);
+        rom.extend((0..prg_size).map(|i| (i / 0x2000) as u8));
+        rom.extend((0..chr_size).map(|i| (i / 0x0400) as u8));
+        Cartridge::from_bytes(&rom).expect("Invalid cartridge")
+    }
+
+    #[test]
+    fn bank_offsets_wrap_around() {
+        assert_eq!(bank_offset(0x8000, 0x4000, 1), 0x4000);
+        assert_eq!(bank_offset(0x8000, 0x4000, 3), 0x4000);
+        assert_eq!(bank_offset(0x2000, 0x4000, 1), 0x0000);
+    }
+
+    #[test]
+    fn chr_rom_is_read_only() {
+        let mut chr = Chr::new(&cartridge(0, 0x4000, 0x2000));
+
+        chr.write(0x0400, 0xFF);
+
+        assert_eq!(chr.read(0x0400), 0x01);
+        assert_eq!(chr.read_bank(0x1000, 1, 0x0000), 0x04);
+    }
+
+    #[test]
+    fn chr_ram_is_writable() {
+        let mut chr = Chr::new(&cartridge(0, 0x4000, 0));
+
+        chr.write_bank(0x1000, 1, 0x0010, 0xAB);
+
+        assert_eq!(chr.len(), 0x2000);
+        assert_eq!(chr.read(0x1010), 0xAB);
+    }
+
+    #[test]
+    fn create_rejects_unknown_mappers() {
+        assert_eq!(
+            create(cartridge(0xFF, 0x4000, 0x2000)).err(),
+            Some(CartridgeError::UnsupportedMapper(0xFF))
+        );
+    }
+}
@@ -0,0 +1,498 @@
+//! Namco 163, mapper 19
+//!
+//! ```text
+//! $4800-$4FFF  Internal RAM data port
+//! $5000-$57FF  IRQ counter, low 8 bits
+//! $5800-$5FFF  IRQ counter, high 7 bits and IRQ enable (bit 7)
+//! $8000-$BFFF  CHR banks 0-7, every $800
+//! $C000-$DFFF  Nametables 0-3, every $800
+//! $E000-$E7FF  8KB PRG bank at $8000 (bits 0-5), sound disable (bit 6)
+//! $E800-$EFFF  8KB PRG bank at $A000 (bits 0-5)
+//! $F000-$F7FF  8KB PRG bank at $C000 (bits 0-5)
+//! $F800-$FFFF  PRG RAM write protect, and the internal RAM address (bits 0-6) with auto
+//!              increment (bit 7)
+//! ```
+//!
+//! The last 8KB of PRG ROM is fixed at $E000. The IRQ counter counts up every CPU cycle and
+//! raises an IRQ when it reaches $7FFF.
+//!
+//! Nametable banks $E0 and up select the VRAM in the console, and the mirroring is worked out
+//! from which half each nametable selects. Nametables in CHR ROM and CHR banks in VRAM aren't
+//! supported.
+//!
+//! # Audio
+//!
+//! Up to 8 wavetable channels play 4 bit samples from the 128 bytes of internal RAM, two samples
+//! to a byte. The registers of channel n are at $40 + 8n:
+//!
+//! ```text
+//! +0, +2, +4  Frequency, 18 bits over the low, middle and bits 0-1 of the high byte
+//! +1, +3, +5  Phase, 24 bits
+//! +4          Wave length, 256 - (bits 2-7) samples
+//! +6          Wave address, in samples
+//! +7          Volume (bits 0-3), and in channel 7 the number of channels - 1 (bits 4-6)
+//! ```
+//!
+//! Enabled channels count down from channel 7. One channel is updated every 15 CPU cycles, and
+//! the chip switches its output between channels as it does, which is averaged here.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// CPU cycles between channel updates
+const CYCLES_PER_UPDATE: u8 = 15;
+
+/// The level of one step of a channel, where a channel at full volume is about as loud as a 2A03
+/// pulse channel at full volume
+const MIX_LEVEL: f32 = 0.1494 / 105.0;
+
+pub struct N163 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    ram: [u8; 0x80],
+    ram_address: u8,
+    chr_banks: [u8; 8],
+    nametables: [u8; 4],
+    prg_banks: [u8; 3],
+    write_protect: u8,
+    irq_counter: u16,
+    irq_pending: bool,
+    sound_disabled: bool,
+    /// CPU cycles since the last channel update
+    divider: u8,
+    /// The channel to update next, counting down from 7
+    channel: u8,
+    /// The last output of every channel
+    outputs: [i8; 8],
+}
+
+impl N163 {
+    pub fn new(cartridge: Cartridge) -> N163 {
+        let vertical = cartridge.mirroring == Mirroring::Vertical;
+        N163 {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            prg_rom: cartridge.prg_rom,
+            ram: [0; 0x80],
+            ram_address: 0,
+            chr_banks: [0; 8],
+            nametables: if vertical {
+                [0xE0, 0xE1, 0xE0, 0xE1]
+            } else {
+                [0xE0, 0xE0, 0xE1, 0xE1]
+            },
+            prg_banks: [0; 3],
+            write_protect: 0,
+            irq_counter: 0,
+            irq_pending: false,
+            sound_disabled: false,
+            divider: 0,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    /// How many channels are enabled, counting down from channel 7
+    fn channels(&self) -> u8 {
+        ((self.ram[0x7F] >> 4) & 0x07) + 1
+    }
+
+    /// Whether PRG RAM at an address can be written, in 2KB windows
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let window = (addr - 0x6000) / 0x0800;
+        self.write_protect & 0xF0 == 0x40 && self.write_protect & (1 << window) == 0
+    }
+
+    fn data_port(&mut self) -> usize {
+        let address = usize::from(self.ram_address & 0x7F);
+        if self.ram_address & 0x80 != 0 {
+            self.ram_address = 0x80 | (self.ram_address.wrapping_add(1) & 0x7F);
+        }
+        address
+    }
+
+    /// Run a channel for its update and keep its output
+    fn update_channel(&mut self, channel: usize) {
+        let base = 0x40 + channel * 8;
+        let registers = &self.ram[base..base + 8];
+        let frequency = u32::from(registers[0])
+            | (u32::from(registers[2]) << 8)
+            | (u32::from(registers[4] & 0x03) << 16);
+        let mut phase = u32::from(registers[1])
+            | (u32::from(registers[3]) << 8)
+            | (u32::from(registers[5]) << 16);
+        let length = 256 - u32::from(registers[4] & 0xFC);
+        let address = u32::from(registers[6]);
+        let volume = (registers[7] & 0x0F) as i8;
+
+        phase = (phase + frequency) % (length << 16);
+        let sample_address = (((phase >> 16) + address) & 0xFF) as usize;
+        let byte = self.ram[sample_address / 2];
+        let sample = if sample_address & 0x01 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        };
+        self.outputs[channel] = (sample as i8 - 8) * volume;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+}
+
+impl Device for N163 {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4FFF => {
+                let address = self.data_port();
+                self.ram[address]
+            }
+            _ => self.peek(addr),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4FFF => self.ram[usize::from(self.ram_address & 0x7F)],
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => (self.irq_counter >> 8) as u8,
+            0x6000..=0x7FFF => self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0),
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x4800..=0x4FFF => {
+                let address = self.data_port();
+                self.ram[address] = byte;
+            }
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0xFF00) | u16::from(byte);
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (u16::from(byte) << 8);
+                self.irq_pending = false;
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable(addr) => {
+                self.prg_ram.write(usize::from(addr - 0x6000), byte)
+            }
+            0x8000..=0xBFFF => self.chr_banks[usize::from((addr - 0x8000) / 0x0800)] = byte,
+            0xC000..=0xDFFF => self.nametables[usize::from((addr - 0xC000) / 0x0800)] = byte,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = byte & 0x3F;
+                self.sound_disabled = byte & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => self.prg_banks[1] = byte & 0x3F,
+            0xF000..=0xF7FF => self.prg_banks[2] = byte & 0x3F,
+            0xF800..=0xFFFF => {
+                self.write_protect = byte;
+                self.ram_address = byte;
+            }
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x5FFF => 0x00,
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for N163 {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        let bank = usize::from(self.chr_banks[usize::from(addr / 0x0400) & 0x07]);
+        self.chr.read_bank(CHR_BANK_SIZE, bank, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = usize::from(self.chr_banks[usize::from(addr / 0x0400) & 0x07]);
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn cpu_clock(&mut self) {
+        if self.irq_counter & 0x8000 != 0 && self.irq_counter & 0x7FFF != 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter & 0x7FFF == 0x7FFF {
+                self.irq_pending = true;
+            }
+        }
+
+        if self.sound_disabled {
+            return;
+        }
+        self.divider += 1;
+        if self.divider < CYCLES_PER_UPDATE {
+            return;
+        }
+        self.divider = 0;
+        let channel = self.channel;
+        self.update_channel(usize::from(channel));
+        self.channel = if channel <= 8 - self.channels() {
+            7
+        } else {
+            channel - 1
+        };
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        let pages: Vec<u8> = self.nametables.iter().map(|bank| bank & 0x01).collect();
+        match pages[..] {
+            [0, 1, 0, 1] => Mirroring::Vertical,
+            [0, 0, 1, 1] => Mirroring::Horizontal,
+            [1, 1, 1, 1] => Mirroring::SingleScreenUpper,
+            _ => Mirroring::SingleScreenLower,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x8000..=0x9FFF => usize::from(self.prg_banks[0]),
+            0xA000..=0xBFFF => usize::from(self.prg_banks[1]),
+            0xC000..=0xDFFF => usize::from(self.prg_banks[2]),
+            _ => (len / PRG_BANK_SIZE).max(1) - 1,
+        };
+        Some(mapper::bank_offset(len, PRG_BANK_SIZE, bank) + usize::from(addr) % PRG_BANK_SIZE)
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio(&self) -> f32 {
+        if self.sound_disabled {
+            return 0.0;
+        }
+        let channels = self.channels();
+        let sum: i32 = self.outputs[usize::from(8 - channels)..]
+            .iter()
+            .map(|&output| i32::from(output))
+            .sum();
+        sum as f32 / f32::from(channels) * MIX_LEVEL
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_bytes(&self.ram);
+        chunk.write_u8(self.ram_address);
+        chunk.write_bytes(&self.chr_banks);
+        chunk.write_bytes(&self.nametables);
+        chunk.write_bytes(&self.prg_banks);
+        chunk.write_u8(self.write_protect);
+        chunk.write_u16(self.irq_counter);
+        chunk.write_bool(self.irq_pending);
+        chunk.write_bool(self.sound_disabled);
+        chunk.write_u8(self.divider);
+        chunk.write_u8(self.channel);
+        let outputs: Vec<u8> = self.outputs.iter().map(|&output| output as u8).collect();
+        chunk.write_bytes(&outputs);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        fn copy(target: &mut [u8], source: &[u8]) -> Result<(), &'static str> {
+            if target.len() != source.len() {
+                return Err("Namco 163 registers in save state have the wrong size");
+            }
+            target.copy_from_slice(source);
+            Ok(())
+        }
+
+        copy(&mut self.ram, chunk.read_bytes()?)?;
+        self.ram_address = chunk.read_u8()?;
+        copy(&mut self.chr_banks, chunk.read_bytes()?)?;
+        copy(&mut self.nametables, chunk.read_bytes()?)?;
+        copy(&mut self.prg_banks, chunk.read_bytes()?)?;
+        self.write_protect = chunk.read_u8()?;
+        self.irq_counter = chunk.read_u16()?;
+        self.irq_pending = chunk.read_bool()?;
+        self.sound_disabled = chunk.read_bool()?;
+        self.divider = chunk.read_u8()? % CYCLES_PER_UPDATE;
+        self.channel = chunk.read_u8()? & 0x07;
+        let mut outputs = [0; 8];
+        copy(&mut outputs, chunk.read_bytes()?)?;
+        for (output, &byte) in self.outputs.iter_mut().zip(&outputs) {
+            *output = byte as i8;
+        }
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn n163() -> N163 {
+        N163::new(cartridge(19, 0x40000, 0x40000))
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut n163 = n163();
+
+        n163.write(0xE000, 0x03);
+        n163.write(0xE800, 0x04);
+        n163.write(0xF000, 0x05);
+
+        assert_eq!(n163.read(0x8000), 0x03);
+        assert_eq!(n163.read(0xA000), 0x04);
+        assert_eq!(n163.read(0xC000), 0x05);
+        assert_eq!(n163.read(0xE000), 0x1F);
+    }
+
+    #[test]
+    fn chr_banks() {
+        let mut n163 = n163();
+
+        n163.write(0x8800, 0x21);
+        n163.write(0xB800, 0x42);
+
+        assert_eq!(n163.ppu_read(0x0400), 0x21);
+        assert_eq!(n163.ppu_read(0x1C00), 0x42);
+    }
+
+    #[test]
+    fn mirroring_from_nametable_banks() {
+        let mut n163 = n163();
+
+        for (index, &bank) in [0xE0, 0xE1, 0xE0, 0xE1].iter().enumerate() {
+            n163.write(0xC000 + index as u16 * 0x0800, bank);
+        }
+        assert_eq!(n163.mirroring(), Mirroring::Vertical);
+
+        for (index, &bank) in [0xE0, 0xE0, 0xE1, 0xE1].iter().enumerate() {
+            n163.write(0xC000 + index as u16 * 0x0800, bank);
+        }
+        assert_eq!(n163.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn prg_ram_write_protect() {
+        let mut n163 = n163();
+        n163.write(0x6000, 0x42);
+        assert_eq!(n163.read(0x6000), 0x00);
+
+        n163.write(0xF800, 0x42);
+        n163.write(0x6000, 0x42);
+        n163.write(0x6800, 0x42);
+
+        assert_eq!(n163.read(0x6000), 0x42);
+        assert_eq!(n163.read(0x6800), 0x00);
+    }
+
+    #[test]
+    fn internal_ram_auto_increments() {
+        let mut n163 = n163();
+
+        n163.write(0xF800, 0x80 | 0x10);
+        n163.write(0x4800, 0xAB);
+        n163.write(0x4800, 0xCD);
+        n163.write(0xF800, 0x10);
+
+        assert_eq!(n163.read(0x4800), 0xAB);
+        assert_eq!(n163.read(0x4800), 0xAB);
+        assert_eq!(n163.ram[0x11], 0xCD);
+    }
+
+    #[test]
+    fn irq_counts_up_to_7fff() {
+        let mut n163 = n163();
+        n163.write(0x5000, 0xFD);
+        n163.write(0x5800, 0xFF);
+
+        n163.cpu_clock();
+        assert!(!n163.irq());
+        n163.cpu_clock();
+        assert!(n163.irq());
+        n163.cpu_clock();
+        assert_eq!(n163.read(0x5000), 0xFF);
+
+        n163.write(0x5800, 0x00);
+        assert!(!n163.irq());
+    }
+
+    #[test]
+    fn wavetable_channel() {
+        let mut n163 = n163();
+        // A 2 sample wave of 15 then 0, at address 0
+        n163.ram[0x00] = 0x0F;
+        n163.ram[0x7C] = 0xFE;
+        n163.ram[0x7F] = 0x0F;
+        // Advance a sample every update
+        n163.ram[0x7A] = 0x01;
+
+        let mut samples = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..CYCLES_PER_UPDATE {
+                n163.cpu_clock();
+            }
+            samples.push(n163.outputs[7]);
+        }
+
+        assert_eq!(samples, [-8 * 15, 7 * 15, -8 * 15, 7 * 15]);
+        assert_eq!(n163.audio(), f32::from(7i8 * 15) * MIX_LEVEL);
+    }
+
+    #[test]
+    fn channels_take_turns() {
+        let mut n163 = n163();
+        n163.ram[0x7F] = 0x10;
+        n163.ram[0x77] = 0x0F;
+
+        for _ in 0..CYCLES_PER_UPDATE * 2 {
+            n163.cpu_clock();
+        }
+
+        assert_eq!(n163.channel, 7);
+        assert_eq!(n163.outputs[6], -8 * 15);
+        assert_eq!(n163.audio(), f32::from(-8i8 * 15) / 2.0 * MIX_LEVEL);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut n163 = n163();
+        n163.write(0xE000, 0x07);
+        n163.write(0xF800, 0x40);
+        n163.write(0x6000, 0x42);
+        n163.ram[0x7F] = 0x0F;
+        let mut chunk = Chunk::new(b"MAPR");
+        n163.save_state(&mut chunk);
+
+        let mut restored = self::n163();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.read(0x8000), 0x07);
+        assert_eq!(restored.read(0x6000), 0x42);
+        assert_eq!(restored.ram[0x7F], 0x0F);
+    }
+}
@@ -0,0 +1,143 @@
+//! NROM, mapper 0
+//!
+//! No bank switching: 16KB or 32KB of PRG ROM at $8000, a 16KB ROM being mirrored at $C000, and
+//! 8KB of CHR. Family Basic carts have PRG RAM at $6000.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(cartridge: Cartridge) -> Nrom {
+        Nrom {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            mirroring: cartridge.mirroring,
+            prg_rom: cartridge.prg_rom,
+        }
+    }
+}
+
+impl Device for Nrom {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0),
+            0x8000..=0xFFFF => self.prg_rom[usize::from(addr - 0x8000) % self.prg_rom.len()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.prg_ram.write(usize::from(addr - 0x6000), byte);
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr.read(usize::from(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        self.chr.write(usize::from(addr), byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        Some(usize::from(addr - 0x8000) % self.prg_rom.len())
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    #[test]
+    fn small_prg_rom_is_mirrored() {
+        let mut nrom = Nrom::new(cartridge(0, 0x4000, 0x2000));
+
+        assert_eq!(nrom.read(0x8000), 0x00);
+        assert_eq!(nrom.read(0xA000), 0x01);
+        assert_eq!(nrom.read(0xC000), 0x00);
+        assert_eq!(nrom.prg_rom_offset(0xE000), Some(0x2000));
+    }
+
+    #[test]
+    fn large_prg_rom() {
+        let mut nrom = Nrom::new(cartridge(0, 0x8000, 0x2000));
+
+        assert_eq!(nrom.read(0xC000), 0x02);
+        assert_eq!(nrom.read(0xFFFF), 0x03);
+        assert_eq!(nrom.prg_rom_offset(0xC000), Some(0x4000));
+        assert_eq!(nrom.prg_rom_offset(0x7FFF), None);
+    }
+
+    #[test]
+    fn prg_ram() {
+        let mut nrom = Nrom::new(cartridge(0, 0x4000, 0x2000));
+
+        nrom.write(0x6001, 0xAB);
+        nrom.write(0x8000, 0xFF);
+
+        assert_eq!(nrom.read(0x6001), 0xAB);
+        assert_eq!(nrom.read(0x8000), 0x00);
+        assert_eq!(nrom.open_bus_mask(0x6001), 0x00);
+        assert_eq!(nrom.open_bus_mask(0x5000), 0xFF);
+    }
+
+    #[test]
+    fn chr() {
+        let mut nrom = Nrom::new(cartridge(0, 0x4000, 0x2000));
+
+        assert_eq!(nrom.ppu_read(0x1C00), 0x07);
+        nrom.ppu_write(0x1C00, 0xFF);
+        assert_eq!(nrom.ppu_read(0x1C00), 0x07);
+    }
+}
@@ -0,0 +1,575 @@
+//! The FM synthesizer in the VRC7, a cut down YM2413 (OPLL)
+//!
+//! It has six channels of two operators each. The modulator is a sine wave that bends the phase
+//! of the carrier, which is what's heard. Each channel plays one of 15 built in instruments, or
+//! the custom instrument in registers $00-$07:
+//!
+//! ```text
+//! $00-$01  Modulator, carrier: tremolo, vibrato, sustained, key scale rate, multiplier
+//! $02      Modulator key scale level (bits 6-7) and total level (bits 0-5)
+//! $03      Carrier key scale level (bits 6-7), rectified carrier (bit 4), rectified
+//!          modulator (bit 3) and feedback (bits 0-2)
+//! $04-$05  Modulator, carrier: attack rate (bits 4-7) and decay rate (bits 0-3)
+//! $06-$07  Modulator, carrier: sustain level (bits 4-7) and release rate (bits 0-3)
+//! $10-$15  Frequency, low 8 bits
+//! $20-$25  Sustain (bit 5), key on (bit 4), octave (bits 1-3), frequency bit 8 (bit 0)
+//! $30-$35  Instrument (bits 4-7) and volume (bits 0-3)
+//! ```
+//!
+//! The chip makes a sample every 36 CPU cycles, about 49.7kHz. Levels are worked out as
+//! attenuation in steps of 0.375dB and turned into amplitudes through a table, like the chip does
+//! with its log-sin tables.
+
+use std::f64::consts::PI;
+
+use state::{Chunk, ChunkReader};
+
+const CHANNELS: usize = 6;
+
+/// The built in instruments of the VRC7, in the same layout as the custom instrument
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+/// Twice the frequency multipliers, as a multiplier of 0 halves the frequency
+const MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+/// Key scale attenuation for the top 4 frequency bits in octave 7, in steps of 0.75dB
+const KEY_SCALE: [i32; 16] = [
+    0, 24, 32, 37, 40, 43, 45, 47, 48, 50, 51, 52, 53, 54, 55, 56,
+];
+
+/// Vibrato offsets over the 8 steps of the vibrato cycle
+const VIBRATO: [i32; 8] = [0, 1, 2, 1, 0, -1, -2, -1];
+
+/// Entries in the sine table, one full period
+const SINE_SIZE: usize = 1024;
+
+/// The amplitude of an operator at full volume
+const AMPLITUDE: i32 = 4096;
+
+/// Attenuation steps of 0.375dB down to silence
+const ATTENUATION_STEPS: usize = 512;
+
+/// The envelope level of a silent operator, 127 steps of 0.375dB
+const SILENT: u8 = 127;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Envelope {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+impl Envelope {
+    fn to_byte(self) -> u8 {
+        match self {
+            Envelope::Attack => 0,
+            Envelope::Decay => 1,
+            Envelope::Sustain => 2,
+            Envelope::Release => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Envelope, &'static str> {
+        match byte {
+            0 => Ok(Envelope::Attack),
+            1 => Ok(Envelope::Decay),
+            2 => Ok(Envelope::Sustain),
+            3 => Ok(Envelope::Release),
+            _ => Err("Invalid OPLL envelope state"),
+        }
+    }
+}
+
+/// The settings of one operator, decoded from an instrument
+struct Patch {
+    tremolo: bool,
+    vibrato: bool,
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: u32,
+    key_scale_level: u8,
+    rectified: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl Patch {
+    /// Decode the modulator (0) or the carrier (1) of an instrument
+    fn new(instrument: &[u8; 8], operator: usize) -> Patch {
+        Patch {
+            tremolo: instrument[operator] & 0x80 != 0,
+            vibrato: instrument[operator] & 0x40 != 0,
+            sustained: instrument[operator] & 0x20 != 0,
+            key_scale_rate: instrument[operator] & 0x10 != 0,
+            multiplier: MULTIPLIERS[usize::from(instrument[operator] & 0x0F)],
+            key_scale_level: instrument[2 + operator] >> 6,
+            rectified: instrument[3] & (0x08 << operator) != 0,
+            attack: instrument[4 + operator] >> 4,
+            decay: instrument[4 + operator] & 0x0F,
+            sustain_level: instrument[6 + operator] >> 4,
+            release: instrument[6 + operator] & 0x0F,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Operator {
+    phase: u32,
+    envelope: Envelope,
+    level: u8,
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0,
+            envelope: Envelope::Release,
+            level: SILENT,
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0;
+        self.envelope = Envelope::Attack;
+    }
+
+    /// Run the envelope for a sample
+    ///
+    /// # Arguments
+    ///
+    /// * `patch` - The settings of the operator
+    /// * `key_scale` - The octave and top frequency bit, which speed up the envelope
+    /// * `sustain` - The sustain bit of the channel, which slows down the release
+    /// * `counter` - The sample counter of the chip
+    fn envelope(&mut self, patch: &Patch, key_scale: u8, sustain: bool, counter: u32) {
+        let rate = match self.envelope {
+            Envelope::Attack => patch.attack,
+            Envelope::Decay => patch.decay,
+            Envelope::Sustain if patch.sustained => 0,
+            Envelope::Sustain => patch.release,
+            Envelope::Release if sustain => 5,
+            Envelope::Release if patch.sustained => patch.release,
+            Envelope::Release => 7,
+        };
+        let rate = effective_rate(rate, key_scale, patch.key_scale_rate);
+        let steps = envelope_steps(rate, counter);
+
+        match self.envelope {
+            Envelope::Attack if rate >= 60 => self.level = 0,
+            Envelope::Attack => {
+                for _ in 0..steps {
+                    if self.level == 0 {
+                        break;
+                    }
+                    self.level -= (self.level >> 3) + 1;
+                }
+            }
+            _ => self.level = (self.level + steps).min(SILENT),
+        }
+
+        match self.envelope {
+            Envelope::Attack if self.level == 0 => self.envelope = Envelope::Decay,
+            Envelope::Decay if self.level >= patch.sustain_level * 8 => {
+                self.envelope = Envelope::Sustain
+            }
+            _ => {}
+        }
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u32(self.phase);
+        chunk.write_u8(self.envelope.to_byte());
+        chunk.write_u8(self.level);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.phase = chunk.read_u32()?;
+        self.envelope = Envelope::from_byte(chunk.read_u8()?)?;
+        self.level = chunk.read_u8()?.min(SILENT);
+        Ok(())
+    }
+}
+
+/// The envelope rate with key scaling, from 0 to 63
+fn effective_rate(rate: u8, key_scale: u8, key_scale_rate: bool) -> u8 {
+    if rate == 0 {
+        return 0;
+    }
+    let offset = if key_scale_rate {
+        key_scale
+    } else {
+        key_scale >> 2
+    };
+    (rate * 4 + offset).min(63)
+}
+
+/// How many steps the envelope moves on a sample
+///
+/// Every 4 rates double the speed, from a step every 4096 samples up to 8 steps per sample.
+fn envelope_steps(rate: u8, counter: u32) -> u8 {
+    if rate < 4 {
+        return 0;
+    }
+    let speed = rate >> 2;
+    if speed >= 13 {
+        return 1 << (speed - 13);
+    }
+    let mask = (1 << (13 - speed)) - 1;
+    (counter & mask == 0) as u8
+}
+
+pub struct Opll {
+    registers: [u8; 0x40],
+    /// Modulator and carrier of every channel
+    operators: [[Operator; 2]; CHANNELS],
+    /// The last two modulator outputs of every channel, for feedback
+    feedback: [[i32; 2]; CHANNELS],
+    counter: u32,
+    output: i32,
+    sine: Vec<i32>,
+    attenuation: Vec<i32>,
+}
+
+impl Opll {
+    pub fn new() -> Opll {
+        let sine = (0..SINE_SIZE)
+            .map(|i| {
+                let angle = 2.0 * PI * i as f64 / SINE_SIZE as f64;
+                (angle.sin() * f64::from(AMPLITUDE)).round() as i32
+            })
+            .collect();
+        let attenuation = (0..ATTENUATION_STEPS)
+            .map(|step| {
+                let db = step as f64 * 0.375;
+                (10f64.powf(-db / 20.0) * f64::from(AMPLITUDE)).round() as i32
+            })
+            .collect();
+
+        Opll {
+            registers: [0; 0x40],
+            operators: [[Operator::new(); 2]; CHANNELS],
+            feedback: [[0; 2]; CHANNELS],
+            counter: 0,
+            output: 0,
+            sine,
+            attenuation,
+        }
+    }
+
+    /// Write to one of the registers of the chip
+    pub fn write(&mut self, register: u8, byte: u8) {
+        let register = usize::from(register & 0x3F);
+        let old = self.registers[register];
+        self.registers[register] = byte;
+
+        let channel = register & 0x0F;
+        if register & 0xF0 == 0x20 && channel < CHANNELS {
+            let was_on = old & 0x10 != 0;
+            let is_on = byte & 0x10 != 0;
+            for operator in &mut self.operators[channel] {
+                match (was_on, is_on) {
+                    (false, true) => operator.key_on(),
+                    (true, false) => operator.envelope = Envelope::Release,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// The mix of all channels from the last sample
+    pub fn output(&self) -> i32 {
+        self.output
+    }
+
+    /// Make the next sample
+    pub fn clock(&mut self) {
+        self.counter = self.counter.wrapping_add(1);
+        let output = (0..CHANNELS).map(|channel| self.channel(channel)).sum();
+        self.output = output;
+    }
+
+    fn instrument(&self, channel: usize) -> [u8; 8] {
+        match self.registers[0x30 + channel] >> 4 {
+            0 => {
+                let mut instrument = [0; 8];
+                instrument.copy_from_slice(&self.registers[0x00..0x08]);
+                instrument
+            }
+            number => PATCHES[usize::from(number) - 1],
+        }
+    }
+
+    /// Attenuation from the tremolo, a triangle of up to 4.875dB at about 3.7Hz
+    fn tremolo(&self) -> i32 {
+        let position = ((self.counter >> 6) % 210) as i32;
+        let level = if position < 105 {
+            position
+        } else {
+            209 - position
+        };
+        level >> 3
+    }
+
+    /// Run a channel for a sample and get its output
+    fn channel(&mut self, channel: usize) -> i32 {
+        let instrument = self.instrument(channel);
+        let patches = [Patch::new(&instrument, 0), Patch::new(&instrument, 1)];
+        let frequency = u32::from(self.registers[0x10 + channel])
+            | (u32::from(self.registers[0x20 + channel] & 0x01) << 8);
+        let octave = (self.registers[0x20 + channel] >> 1) & 0x07;
+        let sustain = self.registers[0x20 + channel] & 0x20 != 0;
+        let key_scale = (octave << 1) | (frequency >> 8) as u8;
+        let volume = i32::from(self.registers[0x30 + channel] & 0x0F);
+        let levels = [i32::from(instrument[2] & 0x3F) * 2, volume * 8];
+
+        // The vibrato steps every 1024 samples, about 6.1Hz for the whole cycle
+        let vibrato = VIBRATO[((self.counter >> 10) & 0x07) as usize];
+        let tremolo = self.tremolo();
+        let counter = self.counter;
+
+        let mut outputs = [0; 2];
+        for (index, patch) in patches.iter().enumerate() {
+            let mut frequency = frequency as i32;
+            if patch.vibrato {
+                frequency += ((frequency >> 6) * vibrato) >> 2;
+            }
+            let step = (((frequency as u32) << octave) * patch.multiplier) >> 1;
+
+            let (phase, level) = {
+                let operator = &mut self.operators[channel][index];
+                operator.envelope(patch, key_scale, sustain, counter);
+                let phase = operator.phase;
+                operator.phase = (phase + step) & 0x7FFFF;
+                (phase, operator.level)
+            };
+            if level == SILENT {
+                continue;
+            }
+
+            let offset = if index == 0 {
+                let feedback = i32::from(instrument[3] & 0x07);
+                let history = self.feedback[channel];
+                if feedback == 0 {
+                    0
+                } else {
+                    (history[0] + history[1]) >> (9 - feedback)
+                }
+            } else {
+                outputs[0] >> 1
+            };
+
+            let key_scale_level = match patch.key_scale_level {
+                0 => 0,
+                shift => {
+                    let base =
+                        KEY_SCALE[(frequency as usize >> 5) & 0x0F] - 8 * (7 - i32::from(octave));
+                    (base.max(0) * 2) >> (3 - shift)
+                }
+            };
+            let attenuation = i32::from(level)
+                + levels[index]
+                + key_scale_level
+                + if patch.tremolo { tremolo } else { 0 };
+
+            outputs[index] = self.operator_output(phase, offset, attenuation, patch.rectified);
+        }
+
+        self.feedback[channel] = [self.feedback[channel][1], outputs[0]];
+        outputs[1]
+    }
+
+    fn operator_output(&self, phase: u32, offset: i32, attenuation: i32, rectified: bool) -> i32 {
+        let index = ((phase >> 9) as i32 + offset) as usize & (SINE_SIZE - 1);
+        let sine = self.sine[index];
+        if rectified && sine < 0 {
+            return 0;
+        }
+        let attenuation = attenuation.min(ATTENUATION_STEPS as i32 - 1) as usize;
+        sine * self.attenuation[attenuation] / AMPLITUDE
+    }
+
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_bytes(&self.registers);
+        for (operators, feedback) in self.operators.iter().zip(&self.feedback) {
+            operators[0].save_state(chunk);
+            operators[1].save_state(chunk);
+            chunk.write_u32(feedback[0] as u32);
+            chunk.write_u32(feedback[1] as u32);
+        }
+        chunk.write_u32(self.counter);
+        chunk.write_u32(self.output as u32);
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        let registers = chunk.read_bytes()?;
+        if registers.len() != self.registers.len() {
+            return Err("OPLL registers in save state have the wrong size");
+        }
+        self.registers.copy_from_slice(registers);
+        for (operators, feedback) in self.operators.iter_mut().zip(&mut self.feedback) {
+            operators[0].load_state(chunk)?;
+            operators[1].load_state(chunk)?;
+            feedback[0] = chunk.read_u32()? as i32;
+            feedback[1] = chunk.read_u32()? as i32;
+        }
+        self.counter = chunk.read_u32()?;
+        self.output = chunk.read_u32()? as i32;
+        Ok(())
+    }
+}
+
+impl Default for Opll {
+    fn default() -> Opll {
+        Opll::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A sine wave instrument: silent modulator, instant attack and no decay
+    const SINE: [u8; 8] = [0x00, 0x21, 0x3F, 0x00, 0x00, 0xF0, 0x00, 0x0F];
+
+    fn render(writes: &[(u8, u8)], samples: usize) -> Vec<i32> {
+        let mut opll = Opll::new();
+        for &(register, byte) in writes {
+            opll.write(register, byte);
+        }
+        (0..samples)
+            .map(|_| {
+                opll.clock();
+                opll.output()
+            })
+            .collect()
+    }
+
+    /// Set up the custom instrument and play it on channel 0
+    fn sine_writes(frequency: u16, octave: u8) -> Vec<(u8, u8)> {
+        let mut writes: Vec<(u8, u8)> = SINE
+            .iter()
+            .enumerate()
+            .map(|(register, &byte)| (register as u8, byte))
+            .collect();
+        writes.push((0x30, 0x00));
+        writes.push((0x10, frequency as u8));
+        writes.push((0x20, 0x10 | octave << 1 | (frequency >> 8) as u8));
+        writes
+    }
+
+    fn zero_crossings(samples: &[i32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0) != (pair[1] < 0))
+            .count()
+    }
+
+    #[test]
+    fn rendering_is_deterministic() {
+        let writes = [
+            (0x30, 0x35),
+            (0x10, 0xAC),
+            (0x20, 0x1A),
+            (0x31, 0x70),
+            (0x21, 0x3C),
+        ];
+
+        let first = render(&writes, 4096);
+        let second = render(&writes, 4096);
+
+        assert_eq!(first, second);
+        assert!(first.iter().any(|&sample| sample != 0));
+    }
+
+    #[test]
+    fn plays_the_right_pitch() {
+        // 49716Hz * 290 * 2^3 / 2^18 is 440Hz
+        let samples = render(&sine_writes(290, 4), 49_716);
+
+        let crossings = zero_crossings(&samples);
+
+        assert!((875..=885).contains(&crossings), "{}", crossings);
+    }
+
+    #[test]
+    fn each_octave_doubles_the_pitch() {
+        let low = zero_crossings(&render(&sine_writes(290, 3), 49_716));
+        let high = zero_crossings(&render(&sine_writes(290, 4), 49_716));
+
+        assert!((low * 2).max(high) - (low * 2).min(high) <= 4);
+    }
+
+    #[test]
+    fn volume_attenuates_the_carrier() {
+        let loud = render(&sine_writes(290, 4), 512);
+        let mut writes = sine_writes(290, 4);
+        // 15 steps of 3dB
+        writes.push((0x30, 0x0F));
+        let quiet = render(&writes, 512);
+
+        let peak = |samples: &[i32]| samples.iter().map(|sample| sample.abs()).max().unwrap();
+
+        assert!(peak(&loud) > 4000);
+        assert!(peak(&quiet) < 30);
+    }
+
+    #[test]
+    fn key_off_releases_to_silence() {
+        let mut opll = Opll::new();
+        for (register, byte) in sine_writes(290, 4) {
+            opll.write(register, byte);
+        }
+        for _ in 0..1000 {
+            opll.clock();
+        }
+
+        opll.write(0x20, 0x08);
+        for _ in 0..49_716 {
+            opll.clock();
+        }
+
+        assert_eq!(opll.operators[0][1].level, SILENT);
+        assert_eq!(opll.output(), 0);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut opll = Opll::new();
+        for (register, byte) in sine_writes(290, 4) {
+            opll.write(register, byte);
+        }
+        for _ in 0..100 {
+            opll.clock();
+        }
+        let mut chunk = Chunk::new(b"OPLL");
+        opll.save_state(&mut chunk);
+
+        let mut restored = Opll::new();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+        opll.clock();
+        restored.clock();
+
+        assert_eq!(restored.output(), opll.output());
+    }
+}
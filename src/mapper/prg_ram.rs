@@ -0,0 +1,204 @@
+//! PRG RAM at $6000-$7FFF
+//!
+//! Battery-backed PRG RAM is kept in a save file, which is written whenever the RAM is flushed
+//! after it changed, and when the RAM is dropped.
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+use state::{Chunk, ChunkReader};
+
+const TRAINER_SIZE: usize = 0x0200;
+const TRAINER_OFFSET: usize = 0x1000;
+
+pub struct PrgRam {
+    data: Vec<u8>,
+    /// Where the RAM is saved, for cartridges with a battery
+    save_file: Option<PathBuf>,
+    /// Whether the RAM changed since it was last saved
+    dirty: bool,
+}
+
+impl PrgRam {
+    pub fn new(size: usize) -> PrgRam {
+        PrgRam {
+            data: vec![0x00; size],
+            save_file: None,
+            dirty: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Read a byte, mirrored over the size of the RAM
+    ///
+    /// Returns `None` if there's no RAM at all, which leaves the bus open.
+    pub fn read(&self, offset: usize) -> Option<u8> {
+        if self.data.is_empty() {
+            return None;
+        }
+        Some(self.data[offset % self.data.len()])
+    }
+
+    pub fn write(&mut self, offset: usize, byte: u8) {
+        if self.data.is_empty() {
+            return;
+        }
+        let len = self.data.len();
+        self.data[offset % len] = byte;
+        self.dirty = true;
+    }
+
+    /// Replace the start of the RAM, leaving the rest zeroed
+    pub fn load(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        if data.len() > self.data.len() {
+            return Err("Save file is bigger than the SRAM");
+        }
+        for byte in &mut self.data {
+            *byte = 0x00;
+        }
+        self.data[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Load a trainer at $7000-$71FF
+    pub fn load_trainer(&mut self, trainer: &[u8]) -> Result<(), &'static str> {
+        if trainer.len() != TRAINER_SIZE {
+            return Err("Invalid trainer size");
+        }
+        if self.data.len() < TRAINER_OFFSET + TRAINER_SIZE {
+            return Err("Cartridge has no PRG RAM for the trainer");
+        }
+        self.data[TRAINER_OFFSET..TRAINER_OFFSET + TRAINER_SIZE].copy_from_slice(trainer);
+        Ok(())
+    }
+
+    /// Keep the RAM in a save file, loading it if the file exists
+    pub fn attach(&mut self, path: PathBuf) -> Result<(), Box<dyn Error>> {
+        match fs::read(&path) {
+            Ok(data) => self.load(&data)?,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+        self.save_file = Some(path);
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Write the RAM to the save file
+    ///
+    /// Does nothing if there's no save file, or the RAM hasn't changed since it was last saved.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(ref path) = self.save_file {
+            if self.dirty {
+                fs::write(path, &self.data)?;
+            }
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_bytes(&self.data);
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        let data = chunk.read_bytes()?;
+        if data.len() != self.data.len() {
+            return Err("PRG RAM in save state has the wrong size");
+        }
+        self.data.copy_from_slice(data);
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+impl Drop for PrgRam {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            eprintln!("Failed to save SRAM: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_are_mirrored() {
+        let mut ram = PrgRam::new(0x0800);
+
+        ram.write(0x0001, 0xAB);
+
+        assert_eq!(ram.read(0x0801), Some(0xAB));
+        assert_eq!(ram.read(0x1FFF), Some(0x00));
+    }
+
+    #[test]
+    fn no_ram_is_open_bus() {
+        let mut ram = PrgRam::new(0);
+
+        ram.write(0x0000, 0xAB);
+
+        assert_eq!(ram.read(0x0000), None);
+    }
+
+    #[test]
+    fn load_pads_short_data() {
+        let mut ram = PrgRam::new(0x2000);
+        ram.write(0x0002, 0xFF);
+
+        ram.load(&[0x11, 0x22]).expect("Failed to load");
+
+        assert_eq!(ram.read(0x0001), Some(0x22));
+        assert_eq!(ram.read(0x0002), Some(0x00));
+        assert_eq!(
+            ram.load(&[0x00; 0x2001]),
+            Err("Save file is bigger than the SRAM")
+        );
+    }
+
+    #[test]
+    fn load_trainer() {
+        let mut ram = PrgRam::new(0x2000);
+        let trainer: Vec<u8> = (0..TRAINER_SIZE).map(|i| i as u8).collect();
+
+        ram.load_trainer(&trainer).expect("Failed to load trainer");
+
+        assert_eq!(ram.read(0x0FFF), Some(0x00));
+        assert_eq!(ram.read(0x1000), Some(0x00));
+        assert_eq!(ram.read(0x1001), Some(0x01));
+        assert_eq!(ram.read(0x11FF), Some(0xFF));
+        assert_eq!(ram.read(0x1200), Some(0x00));
+        assert_eq!(ram.load_trainer(&[0x00; 16]), Err("Invalid trainer size"));
+        assert_eq!(
+            PrgRam::new(0x0800).load_trainer(&trainer),
+            Err("Cartridge has no PRG RAM for the trainer")
+        );
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut ram = PrgRam::new(0x2000);
+        ram.write(0x1234, 0x42);
+        let mut chunk = Chunk::new(b"TEST");
+        ram.save_state(&mut chunk);
+
+        let mut restored = PrgRam::new(0x2000);
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.read(0x1234), Some(0x42));
+        assert!(PrgRam::new(0x0800).load_state(&mut chunk.reader()).is_err());
+    }
+}
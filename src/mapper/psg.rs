@@ -0,0 +1,322 @@
+//! The programmable sound generator in the Sunsoft 5B, a YM2149F that's compatible with the
+//! AY-3-8910
+//!
+//! ```text
+//! $00-$05  Tone periods of channels A-C, 12 bits over a low and a high register
+//! $06      Noise period (bits 0-4)
+//! $07      Tone disable for channels A-C (bits 0-2), noise disable (bits 3-5)
+//! $08-$0A  Volumes of channels A-C (bits 0-3), envelope instead of volume (bit 4)
+//! $0B-$0C  Envelope period, 16 bits
+//! $0D      Envelope shape: hold (bit 0), alternate (bit 1), attack (bit 2), continue (bit 3)
+//! ```
+//!
+//! The generator runs at the CPU clock divided by 16. A tone toggles after every period, the
+//! noise is a 17 bit LFSR clocked at half that rate, and the envelope takes 32 steps of 1.5dB,
+//! one for every envelope period. Fixed volumes are every other step of the envelope.
+
+use state::{Chunk, ChunkReader};
+
+/// The amplitude of every envelope level, 1.5dB apart, with level 0 silent
+fn amplitude(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        10f32.powf(f32::from(31 - level) * -1.5 / 20.0)
+    }
+}
+
+pub struct Psg {
+    registers: [u8; 16],
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u8,
+    /// The noise is clocked every other tick
+    noise_half: bool,
+    lfsr: u32,
+    envelope_counter: u16,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+}
+
+impl Psg {
+    pub fn new() -> Psg {
+        Psg {
+            registers: [0; 16],
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise_half: false,
+            lfsr: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+        }
+    }
+
+    pub fn write(&mut self, register: u8, byte: u8) {
+        let register = usize::from(register & 0x0F);
+        self.registers[register] = byte;
+        if register == 0x0D {
+            self.envelope_counter = 0;
+            self.envelope_step = 0;
+            self.envelope_attack = byte & 0x04 != 0;
+            self.envelope_holding = false;
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let period = u16::from(self.registers[channel * 2])
+            | (u16::from(self.registers[channel * 2 + 1] & 0x0F) << 8);
+        period.max(1)
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    /// Run the generator for 16 CPU cycles
+    pub fn clock(&mut self) {
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        self.noise_half = !self.noise_half;
+        if self.noise_half {
+            self.noise_counter += 1;
+            if self.noise_counter >= (self.registers[0x06] & 0x1F).max(1) {
+                self.noise_counter = 0;
+                let bit = (self.lfsr ^ (self.lfsr >> 3)) & 0x01;
+                self.lfsr = (self.lfsr >> 1) | (bit << 16);
+            }
+        }
+
+        self.clock_envelope();
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        let period =
+            (u16::from(self.registers[0x0B]) | (u16::from(self.registers[0x0C]) << 8)).max(1);
+        self.envelope_counter += 1;
+        if self.envelope_counter < period {
+            return;
+        }
+        self.envelope_counter = 0;
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        let shape = self.registers[0x0D];
+        let alternate = shape & 0x02 != 0;
+        if shape & 0x08 == 0 {
+            // Without continue the envelope ends silent
+            self.envelope_holding = true;
+            self.envelope_attack = false;
+        } else if shape & 0x01 != 0 {
+            self.envelope_holding = true;
+            self.envelope_attack ^= alternate;
+        } else {
+            self.envelope_attack ^= alternate;
+            self.envelope_step = 0;
+        }
+    }
+
+    /// The output of the three channels, where each is up to 1.0
+    pub fn output(&self) -> f32 {
+        let mixer = self.registers[0x07];
+        let noise = self.lfsr & 0x01 != 0;
+        (0..3)
+            .filter(|&channel| {
+                let tone = self.tone_outputs[channel] || mixer & (0x01 << channel) != 0;
+                let noise = noise || mixer & (0x08 << channel) != 0;
+                tone && noise
+            })
+            .map(|channel| {
+                let volume = self.registers[0x08 + channel];
+                if volume & 0x10 != 0 {
+                    amplitude(self.envelope_level())
+                } else if volume & 0x0F == 0 {
+                    0.0
+                } else {
+                    amplitude((volume & 0x0F) * 2 + 1)
+                }
+            })
+            .sum()
+    }
+
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_bytes(&self.registers);
+        for (&counter, &output) in self.tone_counters.iter().zip(&self.tone_outputs) {
+            chunk.write_u16(counter);
+            chunk.write_bool(output);
+        }
+        chunk.write_u8(self.noise_counter);
+        chunk.write_bool(self.noise_half);
+        chunk.write_u32(self.lfsr);
+        chunk.write_u16(self.envelope_counter);
+        chunk.write_u8(self.envelope_step);
+        chunk.write_bool(self.envelope_attack);
+        chunk.write_bool(self.envelope_holding);
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        let registers = chunk.read_bytes()?;
+        if registers.len() != self.registers.len() {
+            return Err("5B registers in save state have the wrong size");
+        }
+        self.registers.copy_from_slice(registers);
+        for channel in 0..3 {
+            self.tone_counters[channel] = chunk.read_u16()?;
+            self.tone_outputs[channel] = chunk.read_bool()?;
+        }
+        self.noise_counter = chunk.read_u8()?;
+        self.noise_half = chunk.read_bool()?;
+        self.lfsr = chunk.read_u32()? & 0x1FFFF;
+        self.envelope_counter = chunk.read_u16()?;
+        self.envelope_step = chunk.read_u8()? & 0x1F;
+        self.envelope_attack = chunk.read_bool()?;
+        self.envelope_holding = chunk.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Psg {
+    fn default() -> Psg {
+        Psg::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A generator with only channel A playing, at full volume
+    fn psg() -> Psg {
+        let mut psg = Psg::new();
+        psg.write(0x07, 0x3E);
+        psg.write(0x08, 0x0F);
+        psg
+    }
+
+    #[test]
+    fn tone_toggles_every_period() {
+        let mut psg = psg();
+        psg.write(0x00, 0x02);
+
+        let outputs: Vec<f32> = (0..4)
+            .map(|_| {
+                psg.clock();
+                psg.output()
+            })
+            .collect();
+
+        assert_eq!(outputs, [0.0, 1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn volume_steps_are_3db() {
+        let mut psg = psg();
+        psg.write(0x07, 0x3F);
+
+        psg.write(0x08, 0x0D);
+        let level = psg.output();
+
+        assert!((level - 10f32.powf(-6.0 / 20.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn disabled_channels_are_silent() {
+        let mut psg = psg();
+        psg.write(0x07, 0x3F);
+        psg.write(0x08, 0x00);
+
+        assert_eq!(psg.output(), 0.0);
+    }
+
+    #[test]
+    fn noise_repeats_after_131071_steps() {
+        let mut psg = psg();
+        psg.write(0x06, 0x01);
+        let start = psg.lfsr;
+
+        for _ in 0..2 {
+            psg.clock();
+        }
+        assert_ne!(psg.lfsr, start);
+        for _ in 2..((1 << 17) - 1) * 2 {
+            psg.clock();
+        }
+
+        assert_eq!(psg.lfsr, start);
+    }
+
+    #[test]
+    fn envelope_decays_then_holds_silent() {
+        let mut psg = psg();
+        psg.write(0x07, 0x3F);
+        psg.write(0x08, 0x10);
+        psg.write(0x0B, 0x01);
+        psg.write(0x0D, 0x00);
+        assert_eq!(psg.output(), 1.0);
+
+        for _ in 0..40 {
+            psg.clock();
+        }
+
+        assert_eq!(psg.output(), 0.0);
+        assert!(psg.envelope_holding);
+    }
+
+    #[test]
+    fn envelope_alternates() {
+        let mut psg = psg();
+        psg.write(0x0B, 0x01);
+        psg.write(0x0D, 0x0E);
+
+        let levels: Vec<u8> = (0..64)
+            .map(|_| {
+                psg.clock();
+                psg.envelope_level()
+            })
+            .collect();
+
+        assert_eq!(levels[30], 31);
+        assert_eq!(levels[31], 31);
+        assert_eq!(levels[62], 0);
+        assert_eq!(levels[63], 0);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut psg = psg();
+        psg.write(0x00, 0x03);
+        for _ in 0..5 {
+            psg.clock();
+        }
+        let mut chunk = Chunk::new(b"MAPR");
+        psg.save_state(&mut chunk);
+
+        let mut restored = Psg::new();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        restored.clock();
+        psg.clock();
+        assert_eq!(restored.output(), psg.output());
+        assert_eq!(restored.tone_counters, psg.tone_counters);
+    }
+}
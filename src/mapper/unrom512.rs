@@ -0,0 +1,230 @@
+//! UNROM 512, mapper 30
+//!
+//! ```text
+//! 7  bit  0
+//! ---- ----
+//! MCCP PPPP
+//! |||+-++++- 16KB PRG bank at $8000
+//! |++------- 8KB CHR RAM bank
+//! +--------- One screen page, on boards with switchable mirroring
+//! ```
+//!
+//! The last 16KB bank is fixed at $C000, and CHR is 32KB of RAM. A header asking for four-screen
+//! mirroring marks boards with switchable single screen mirroring.
+//!
+//! Boards with a battery are flashable: the PRG ROM is an SST39SF040 that the game rewrites to
+//! save, see `flash`. On those the register is at $C000-$FFFF, and writes to $8000-$BFFF go to
+//! the flash in the bank there. Boards without a battery have bus conflicts.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::flash::Flash;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x2000;
+const CHR_RAM_SIZE: usize = 0x8000;
+
+pub struct Unrom512 {
+    flash: Flash,
+    chr: Chr,
+    mirroring: Mirroring,
+    flashable: bool,
+    register: u8,
+}
+
+impl Unrom512 {
+    pub fn new(cartridge: Cartridge) -> Unrom512 {
+        Unrom512 {
+            chr: Chr::with_ram(&cartridge, CHR_RAM_SIZE),
+            flash: Flash::new(&cartridge.prg_rom),
+            mirroring: cartridge.mirroring,
+            flashable: cartridge.battery,
+            register: 0,
+        }
+    }
+
+    fn chr_bank(&self) -> usize {
+        usize::from((self.register >> 5) & 0x03)
+    }
+}
+
+impl Device for Unrom512 {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match self.prg_rom_offset(addr) {
+            Some(offset) => self.flash.read(offset),
+            None => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x8000..=0xBFFF if self.flashable => {
+                let offset = self.prg_rom_offset(addr).unwrap();
+                self.flash.write(offset, byte);
+            }
+            0x8000..=0xFFFF if self.flashable => self.register = byte,
+            0x8000..=0xFFFF => self.register = byte & self.peek(addr),
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Unrom512 {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr.read_bank(CHR_BANK_SIZE, self.chr_bank(), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = self.chr_bank();
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            Mirroring::FourScreen if self.register & 0x80 != 0 => Mirroring::SingleScreenUpper,
+            Mirroring::FourScreen => Mirroring::SingleScreenLower,
+            mirroring => mirroring,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let len = self.flash.len();
+        let bank = match addr {
+            0x8000..=0xBFFF => {
+                mapper::bank_offset(len, PRG_BANK_SIZE, usize::from(self.register & 0x1F))
+            }
+            0xC000..=0xFFFF => len.saturating_sub(PRG_BANK_SIZE),
+            _ => return None,
+        };
+        Some(bank + usize::from(addr) % PRG_BANK_SIZE)
+    }
+
+    /// The flash, which is saved on boards with a battery
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        self.flash.prg_ram()
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.register);
+        self.flash.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.register = chunk.read_u8()?;
+        self.flash.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn unrom512(battery: bool) -> Unrom512 {
+        let mut cartridge = cartridge(30, 0x80000, 0);
+        cartridge.battery = battery;
+        Unrom512::new(cartridge)
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut unrom512 = unrom512(true);
+
+        unrom512.write(0xC000, 0x1E);
+
+        assert_eq!(unrom512.read(0x8000), 0x3C);
+        assert_eq!(unrom512.read(0xC000), 0x3E);
+        assert_eq!(unrom512.read(0xFFFF), 0x3F);
+    }
+
+    #[test]
+    fn chr_ram_banks() {
+        let mut unrom512 = unrom512(true);
+        unrom512.write(0xC000, 0x60);
+        unrom512.ppu_write(0x0010, 0xAB);
+
+        unrom512.write(0xC000, 0x00);
+        assert_eq!(unrom512.ppu_read(0x0010), 0x00);
+        unrom512.write(0xC000, 0x60);
+        assert_eq!(unrom512.ppu_read(0x0010), 0xAB);
+    }
+
+    #[test]
+    fn switchable_single_screen() {
+        let mut cartridge = cartridge(30, 0x80000, 0);
+        cartridge.mirroring = Mirroring::FourScreen;
+        cartridge.battery = true;
+        let mut unrom512 = Unrom512::new(cartridge);
+        assert_eq!(unrom512.mirroring(), Mirroring::SingleScreenLower);
+
+        unrom512.write(0xC000, 0x80);
+
+        assert_eq!(unrom512.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn self_flashing() {
+        let mut unrom512 = unrom512(true);
+
+        // $5555 is in bank 1 at $9555, $2AAA in bank 0 at $AAAA
+        unrom512.write(0xC000, 0x01);
+        unrom512.write(0x9555, 0xAA);
+        unrom512.write(0xC000, 0x00);
+        unrom512.write(0xAAAA, 0x55);
+        unrom512.write(0xC000, 0x01);
+        unrom512.write(0x9555, 0xA0);
+        unrom512.write(0xC000, 0x05);
+        unrom512.write(0x8123, 0x00);
+
+        assert_eq!(unrom512.read(0x8123), 0x00);
+        assert_eq!(unrom512.read(0x8124), 0x0A);
+        assert_eq!(unrom512.prg_ram().read(0x14123), Some(0x00));
+    }
+
+    #[test]
+    fn bus_conflicts_without_a_battery() {
+        let mut unrom512 = unrom512(false);
+
+        // The ROM at $C000 holds $3E
+        unrom512.write(0xC000, 0x03);
+        assert_eq!(unrom512.register, 0x02);
+
+        unrom512.write(0x8000, 0x00);
+        assert_eq!(unrom512.register, 0x00);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut unrom512 = unrom512(true);
+        unrom512.write(0xC000, 0x07);
+        let mut chunk = Chunk::new(b"MAPR");
+        unrom512.save_state(&mut chunk);
+
+        let mut restored = self::unrom512(true);
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.read(0x8000), 0x0E);
+    }
+}
@@ -0,0 +1,166 @@
+//! UxROM, mapper 2
+//!
+//! Writing to $8000-$FFFF selects the 16KB PRG bank at $8000, the last bank is fixed at $C000.
+//! CHR is 8KB of RAM.
+//!
+//! The bank register is wired straight to the data bus, so on UNROM and UOROM the ROM drives the
+//! bus at the same time as the CPU and the value written is ANDed with the byte in ROM. Submapper
+//! 1 marks boards without bus conflicts.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    prg_bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(cartridge: Cartridge) -> Uxrom {
+        Uxrom {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            mirroring: cartridge.mirroring,
+            bus_conflicts: cartridge.submapper != 1,
+            prg_rom: cartridge.prg_rom,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Device for Uxrom {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0),
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram.write(usize::from(addr - 0x6000), byte),
+            0x8000..=0xFFFF if self.bus_conflicts => self.prg_bank = byte & self.peek(addr),
+            0x8000..=0xFFFF => self.prg_bank = byte,
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr.read(usize::from(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        self.chr.write(usize::from(addr), byte);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x8000..=0xBFFF => mapper::bank_offset(len, PRG_BANK_SIZE, usize::from(self.prg_bank)),
+            0xC000..=0xFFFF => len.saturating_sub(PRG_BANK_SIZE),
+            _ => return None,
+        };
+        Some(bank + usize::from(addr) % PRG_BANK_SIZE)
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.prg_bank);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.prg_bank = chunk.read_u8()?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn uxrom() -> Uxrom {
+        let mut cartridge = cartridge(2, 0x20000, 0);
+        cartridge.submapper = 1;
+        Uxrom::new(cartridge)
+    }
+
+    #[test]
+    fn switches_the_bank_at_8000() {
+        let mut uxrom = uxrom();
+
+        uxrom.write(0x8000, 0x03);
+
+        assert_eq!(uxrom.read(0x8000), 0x06);
+        assert_eq!(uxrom.read(0xA000), 0x07);
+        assert_eq!(uxrom.prg_rom_offset(0x8000), Some(0xC000));
+    }
+
+    #[test]
+    fn last_bank_is_fixed_at_c000() {
+        let mut uxrom = uxrom();
+
+        uxrom.write(0xC000, 0x03);
+
+        assert_eq!(uxrom.read(0xC000), 0x0E);
+        assert_eq!(uxrom.read(0xFFFF), 0x0F);
+    }
+
+    #[test]
+    fn bus_conflicts_and_the_written_value() {
+        let mut uxrom = Uxrom::new(cartridge(2, 0x20000, 0));
+
+        // The ROM at $C000 holds $0E
+        uxrom.write(0xC000, 0x03);
+
+        assert_eq!(uxrom.prg_bank, 0x02);
+        assert_eq!(uxrom.read(0x8000), 0x04);
+    }
+
+    #[test]
+    fn chr_ram() {
+        let mut uxrom = uxrom();
+
+        uxrom.ppu_write(0x1234, 0xAB);
+
+        assert_eq!(uxrom.ppu_read(0x1234), 0xAB);
+    }
+}
@@ -0,0 +1,580 @@
+//! Konami VRC6, mappers 24 and 26
+//!
+//! VRC6b (mapper 26) has address lines A0 and A1 swapped, otherwise the boards are the same:
+//!
+//! ```text
+//! $8000-$8003  16KB PRG bank at $8000
+//! $9000-$9002  Pulse 1: mode, duty and volume, period low, enable and period high
+//! $9003        Audio control: halt (bit 0), periods divided by 16 (bit 1) or 256 (bit 2)
+//! $A000-$A002  Pulse 2
+//! $B000-$B002  Sawtooth: accumulator rate, period low, enable and period high
+//! $B003        CHR mode (bits 0-1), mirroring (bits 2-3), PRG RAM enable (bit 7)
+//! $C000-$C003  8KB PRG bank at $C000
+//! $D000-$E003  CHR banks 0-7
+//! $F000-$F002  IRQ latch, control and acknowledge
+//! ```
+//!
+//! The last 8KB of PRG ROM is fixed at $E000. In CHR mode 0 the banks are 1KB each. Mode 1 uses
+//! banks 0-3 as 2KB banks, and modes 2 and 3 use 1KB banks for $0000-$0FFF and banks 4-5 as 2KB
+//! banks for $1000-$1FFF.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::prg_ram::PrgRam;
+use mapper::vrc_irq::VrcIrq;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// The level of one step of a channel
+///
+/// A 2A03 pulse channel at full volume mixes to about 0.15, and a VRC6 pulse channel at full
+/// volume is about as loud.
+const MIX_LEVEL: f32 = 0.1494 / 15.0;
+
+struct Pulse {
+    volume: u8,
+    duty: u8,
+    /// Ignore the duty cycle and output the volume all the time
+    constant: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+}
+
+impl Pulse {
+    fn new() -> Pulse {
+        Pulse {
+            volume: 0,
+            duty: 0,
+            constant: false,
+            period: 0,
+            enabled: false,
+            timer: 0,
+            step: 15,
+        }
+    }
+
+    fn write(&mut self, register: u16, byte: u8) {
+        match register {
+            0 => {
+                self.constant = byte & 0x80 != 0;
+                self.duty = (byte >> 4) & 0x07;
+                self.volume = byte & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | u16::from(byte),
+            _ => {
+                self.period = (self.period & 0x00FF) | (u16::from(byte & 0x0F) << 8);
+                self.enabled = byte & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.volume);
+        chunk.write_u8(self.duty);
+        chunk.write_bool(self.constant);
+        chunk.write_u16(self.period);
+        chunk.write_bool(self.enabled);
+        chunk.write_u16(self.timer);
+        chunk.write_u8(self.step);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.volume = chunk.read_u8()?;
+        self.duty = chunk.read_u8()?;
+        self.constant = chunk.read_bool()?;
+        self.period = chunk.read_u16()?;
+        self.enabled = chunk.read_bool()?;
+        self.timer = chunk.read_u16()?;
+        self.step = chunk.read_u8()?;
+        Ok(())
+    }
+}
+
+struct Sawtooth {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn new() -> Sawtooth {
+        Sawtooth {
+            rate: 0,
+            period: 0,
+            enabled: false,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn write(&mut self, register: u16, byte: u8) {
+        match register {
+            0 => self.rate = byte & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | u16::from(byte),
+            _ => {
+                self.period = (self.period & 0x00FF) | (u16::from(byte & 0x0F) << 8);
+                self.enabled = byte & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    /// The accumulator grows by the rate on every second step, and is cleared on the 14th
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 0x01 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.rate);
+        chunk.write_u16(self.period);
+        chunk.write_bool(self.enabled);
+        chunk.write_u16(self.timer);
+        chunk.write_u8(self.step);
+        chunk.write_u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.rate = chunk.read_u8()?;
+        self.period = chunk.read_u16()?;
+        self.enabled = chunk.read_bool()?;
+        self.timer = chunk.read_u16()?;
+        self.step = chunk.read_u8()?;
+        self.accumulator = chunk.read_u8()?;
+        Ok(())
+    }
+}
+
+pub struct Vrc6 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    /// VRC6b, with A0 and A1 swapped
+    swapped: bool,
+    prg_banks: [u8; 2],
+    chr_banks: [u8; 8],
+    control: u8,
+    irq: VrcIrq,
+    audio_control: u8,
+    pulses: [Pulse; 2],
+    sawtooth: Sawtooth,
+}
+
+impl Vrc6 {
+    pub fn new(cartridge: Cartridge) -> Vrc6 {
+        Vrc6 {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            swapped: cartridge.mapper == 26,
+            prg_rom: cartridge.prg_rom,
+            prg_banks: [0; 2],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            audio_control: 0,
+            pulses: [Pulse::new(), Pulse::new()],
+            sawtooth: Sawtooth::new(),
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    /// The 1KB CHR bank mapped in at an address in $0000-$1FFF
+    fn chr_bank_at(&self, addr: u16) -> usize {
+        let slot = usize::from(addr / 0x0400);
+        let two_kb = match self.control & 0x03 {
+            0 => false,
+            1 => true,
+            _ => slot >= 4,
+        };
+        if !two_kb {
+            return usize::from(self.chr_banks[slot]);
+        }
+        let register = match self.control & 0x03 {
+            1 => slot / 2,
+            _ => 4 + (slot - 4) / 2,
+        };
+        (usize::from(self.chr_banks[register]) << 1) | (slot & 0x01)
+    }
+
+    /// How far the audio periods are shifted right, from the audio control register
+    fn period_shift(&self) -> u8 {
+        if self.audio_control & 0x04 != 0 {
+            8
+        } else if self.audio_control & 0x02 != 0 {
+            4
+        } else {
+            0
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, byte: u8) {
+        let register = if self.swapped {
+            (addr & 0x0001) << 1 | (addr & 0x0002) >> 1
+        } else {
+            addr & 0x0003
+        };
+        match (addr & 0xF000, register) {
+            (0x8000, _) => self.prg_banks[0] = byte & 0x0F,
+            (0x9000, 3) => self.audio_control = byte,
+            (0x9000, register) => self.pulses[0].write(register, byte),
+            (0xA000, 3) => {}
+            (0xA000, register) => self.pulses[1].write(register, byte),
+            (0xB000, 3) => self.control = byte,
+            (0xB000, register) => self.sawtooth.write(register, byte),
+            (0xC000, _) => self.prg_banks[1] = byte & 0x1F,
+            (0xD000, register) => self.chr_banks[usize::from(register)] = byte,
+            (0xE000, register) => self.chr_banks[4 + usize::from(register)] = byte,
+            (_, 0) => self.irq.write_latch(byte),
+            (_, 1) => self.irq.write_control(byte),
+            (_, 2) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl Device for Vrc6 {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0)
+            }
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.write(usize::from(addr - 0x6000), byte)
+            }
+            0x8000..=0xFFFF => self.write_register(addr, byte),
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr
+            .read_bank(CHR_BANK_SIZE, self.chr_bank_at(addr), addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = self.chr_bank_at(addr);
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+        if self.audio_control & 0x01 != 0 {
+            return;
+        }
+        let shift = self.period_shift();
+        self.pulses[0].clock(shift);
+        self.pulses[1].clock(shift);
+        self.sawtooth.clock(shift);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match (self.control >> 2) & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let len = self.prg_rom.len();
+        let offset = match addr {
+            0x8000..=0xBFFF => {
+                mapper::bank_offset(len, 0x4000, usize::from(self.prg_banks[0]))
+                    + usize::from(addr) % 0x4000
+            }
+            0xC000..=0xDFFF => {
+                mapper::bank_offset(len, 0x2000, usize::from(self.prg_banks[1]))
+                    + usize::from(addr) % 0x2000
+            }
+            0xE000..=0xFFFF => len.saturating_sub(0x2000) + usize::from(addr) % 0x2000,
+            _ => return None,
+        };
+        Some(offset)
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio(&self) -> f32 {
+        let level = self.pulses[0].output() + self.pulses[1].output() + self.sawtooth.output();
+        f32::from(level) * MIX_LEVEL
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.prg_banks[0]);
+        chunk.write_u8(self.prg_banks[1]);
+        chunk.write_bytes(&self.chr_banks);
+        chunk.write_u8(self.control);
+        self.irq.save_state(chunk);
+        chunk.write_u8(self.audio_control);
+        self.pulses[0].save_state(chunk);
+        self.pulses[1].save_state(chunk);
+        self.sawtooth.save_state(chunk);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.prg_banks[0] = chunk.read_u8()?;
+        self.prg_banks[1] = chunk.read_u8()?;
+        let chr_banks = chunk.read_bytes()?;
+        if chr_banks.len() != self.chr_banks.len() {
+            return Err("VRC6 CHR banks in save state have the wrong size");
+        }
+        self.chr_banks.copy_from_slice(chr_banks);
+        self.control = chunk.read_u8()?;
+        self.irq.load_state(chunk)?;
+        self.audio_control = chunk.read_u8()?;
+        self.pulses[0].load_state(chunk)?;
+        self.pulses[1].load_state(chunk)?;
+        self.sawtooth.load_state(chunk)?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn vrc6() -> Vrc6 {
+        Vrc6::new(cartridge(24, 0x40000, 0x40000))
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut vrc6 = vrc6();
+
+        vrc6.write(0x8000, 0x03);
+        vrc6.write(0xC000, 0x09);
+
+        assert_eq!(vrc6.read(0x8000), 0x06);
+        assert_eq!(vrc6.read(0xA000), 0x07);
+        assert_eq!(vrc6.read(0xC000), 0x09);
+        assert_eq!(vrc6.read(0xE000), 0x1F);
+    }
+
+    #[test]
+    fn chr_mode_0_has_1k_banks() {
+        let mut vrc6 = vrc6();
+
+        vrc6.write(0xD001, 0x21);
+        vrc6.write(0xE003, 0x42);
+
+        assert_eq!(vrc6.ppu_read(0x0400), 0x21);
+        assert_eq!(vrc6.ppu_read(0x1C00), 0x42);
+    }
+
+    #[test]
+    fn chr_mode_1_has_2k_banks() {
+        let mut vrc6 = vrc6();
+        vrc6.write(0xB003, 0x01);
+
+        vrc6.write(0xD001, 0x05);
+
+        assert_eq!(vrc6.ppu_read(0x0800), 0x0A);
+        assert_eq!(vrc6.ppu_read(0x0C00), 0x0B);
+    }
+
+    #[test]
+    fn vrc6b_swaps_a0_and_a1() {
+        let mut vrc6b = Vrc6::new(cartridge(26, 0x40000, 0x40000));
+
+        vrc6b.write(0xD001, 0x21);
+        vrc6b.write(0xD002, 0x31);
+
+        assert_eq!(vrc6b.ppu_read(0x0800), 0x21);
+        assert_eq!(vrc6b.ppu_read(0x0400), 0x31);
+    }
+
+    #[test]
+    fn mirroring_and_prg_ram_enable() {
+        let mut vrc6 = vrc6();
+        assert_eq!(vrc6.open_bus_mask(0x6000), 0xFF);
+
+        vrc6.write(0xB003, 0x8C);
+        vrc6.write(0x6000, 0x42);
+
+        assert_eq!(vrc6.mirroring(), Mirroring::SingleScreenUpper);
+        assert_eq!(vrc6.read(0x6000), 0x42);
+    }
+
+    #[test]
+    fn irq_in_cycle_mode() {
+        let mut vrc6 = vrc6();
+        vrc6.write(0xF000, 0xFE);
+        vrc6.write(0xF001, 0x06);
+
+        vrc6.cpu_clock();
+        assert!(!vrc6.irq());
+        vrc6.cpu_clock();
+        assert!(vrc6.irq());
+
+        vrc6.write(0xF002, 0x00);
+        assert!(!vrc6.irq());
+    }
+
+    #[test]
+    fn pulse_duty_cycle() {
+        let mut vrc6 = vrc6();
+        // Duty 7 of 16 at volume 10, with a period of 1 CPU cycle
+        vrc6.write(0x9000, 0x7A);
+        vrc6.write(0x9001, 0x00);
+        vrc6.write(0x9002, 0x80);
+
+        let mut high = 0;
+        for _ in 0..16 {
+            vrc6.cpu_clock();
+            if vrc6.pulses[0].output() == 10 {
+                high += 1;
+            }
+        }
+
+        assert_eq!(high, 8);
+    }
+
+    #[test]
+    fn pulse_constant_mode_ignores_the_duty() {
+        let mut vrc6 = vrc6();
+
+        vrc6.write(0xA000, 0x8F);
+        vrc6.write(0xA002, 0x80);
+
+        assert_eq!(vrc6.pulses[1].output(), 15);
+        assert_eq!(vrc6.audio(), 15.0 * MIX_LEVEL);
+    }
+
+    #[test]
+    fn sawtooth_ramps_and_resets() {
+        let mut vrc6 = vrc6();
+        vrc6.write(0xB000, 0x20);
+        vrc6.write(0xB002, 0x80);
+
+        let mut outputs = Vec::new();
+        for _ in 0..14 {
+            vrc6.cpu_clock();
+            outputs.push(vrc6.sawtooth.output());
+        }
+
+        assert_eq!(outputs, [0, 4, 4, 8, 8, 12, 12, 16, 16, 20, 20, 24, 24, 0]);
+    }
+
+    #[test]
+    fn halt_stops_the_channels() {
+        let mut vrc6 = vrc6();
+        vrc6.write(0xB000, 0x20);
+        vrc6.write(0xB002, 0x80);
+        vrc6.write(0x9003, 0x01);
+
+        vrc6.cpu_clock();
+        vrc6.cpu_clock();
+
+        assert_eq!(vrc6.sawtooth.output(), 0);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut vrc6 = vrc6();
+        vrc6.write(0x8000, 0x02);
+        vrc6.write(0xB000, 0x20);
+        vrc6.write(0xB002, 0x80);
+        vrc6.cpu_clock();
+        vrc6.cpu_clock();
+        let mut chunk = Chunk::new(b"MAPR");
+        vrc6.save_state(&mut chunk);
+
+        let mut restored = self::vrc6();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.read(0x8000), 0x04);
+        assert_eq!(restored.sawtooth.output(), 4);
+    }
+}
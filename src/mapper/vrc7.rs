@@ -0,0 +1,359 @@
+//! Konami VRC7, mapper 85
+//!
+//! The second register of each pair is at $xx10 on VRC7a and at $xx08 on VRC7b:
+//!
+//! ```text
+//! $8000, $8010  8KB PRG banks at $8000 and $A000
+//! $9000         8KB PRG bank at $C000
+//! $9010, $9030  Audio register select and data
+//! $A000-$D010   CHR banks 0-7
+//! $E000         Mirroring (bits 0-1), audio reset (bit 6), PRG RAM enable (bit 7)
+//! $E010         IRQ latch
+//! $F000, $F010  IRQ control and acknowledge
+//! ```
+//!
+//! The last 8KB of PRG ROM is fixed at $E000. The audio is an FM synthesizer, see `opll`.
+
+use cartridge::{Cartridge, Mirroring};
+use cpu::device::Device;
+use mapper::opll::Opll;
+use mapper::prg_ram::PrgRam;
+use mapper::vrc_irq::VrcIrq;
+use mapper::{self, Chr, Mapper};
+use state::{Chunk, ChunkReader};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// CPU cycles for every sample of the synthesizer
+const CYCLES_PER_SAMPLE: u8 = 36;
+
+/// The level of the synthesizer output, where a channel at full volume is about as loud as a
+/// 2A03 pulse channel at full volume
+const MIX_LEVEL: f32 = 0.1494 / 4096.0;
+
+pub struct Vrc7 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: PrgRam,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    control: u8,
+    irq: VrcIrq,
+    opll: Opll,
+    audio_register: u8,
+    /// CPU cycles since the last sample
+    divider: u8,
+}
+
+impl Vrc7 {
+    pub fn new(cartridge: Cartridge) -> Vrc7 {
+        Vrc7 {
+            chr: Chr::new(&cartridge),
+            prg_ram: mapper::prg_ram(&cartridge),
+            prg_rom: cartridge.prg_rom,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            opll: Opll::new(),
+            audio_register: 0,
+            divider: 0,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    /// Whether the synthesizer is held in reset
+    fn audio_reset(&self) -> bool {
+        self.control & 0x40 != 0
+    }
+
+    fn write_register(&mut self, addr: u16, byte: u8) {
+        let second = addr & 0x0018 != 0;
+        match (addr & 0xF000, second) {
+            (0x8000, false) => self.prg_banks[0] = byte & 0x3F,
+            (0x8000, true) => self.prg_banks[1] = byte & 0x3F,
+            (0x9000, false) => self.prg_banks[2] = byte & 0x3F,
+            (0x9000, true) if addr & 0x0030 == 0x0030 => {
+                if !self.audio_reset() {
+                    self.opll.write(self.audio_register, byte);
+                }
+            }
+            (0x9000, true) => self.audio_register = byte,
+            (0xE000, false) => {
+                self.control = byte;
+                if self.audio_reset() {
+                    self.opll = Opll::new();
+                }
+            }
+            (0xE000, true) => self.irq.write_latch(byte),
+            (0xF000, false) => self.irq.write_control(byte),
+            (0xF000, true) => self.irq.acknowledge(),
+            (bank, second) => {
+                let index = usize::from((bank - 0xA000) >> 12) * 2 + second as usize;
+                self.chr_banks[index] = byte;
+            }
+        }
+    }
+}
+
+impl Device for Vrc7 {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.read(usize::from(addr - 0x6000)).unwrap_or(0)
+            }
+            0x8000..=0xFFFF => self.prg_rom[self.prg_rom_offset(addr).unwrap()],
+            _ => 0x00,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram.write(usize::from(addr - 0x6000), byte)
+            }
+            0x8000..=0xFFFF => self.write_register(addr, byte),
+            _ => {}
+        }
+    }
+
+    fn open_bus_mask(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF,
+        }
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        let bank = usize::from(self.chr_banks[usize::from(addr / 0x0400) & 0x07]);
+        self.chr.read_bank(CHR_BANK_SIZE, bank, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, byte: u8) {
+        let bank = usize::from(self.chr_banks[usize::from(addr / 0x0400) & 0x07]);
+        self.chr.write_bank(CHR_BANK_SIZE, bank, addr, byte);
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+        if self.audio_reset() {
+            return;
+        }
+        self.divider += 1;
+        if self.divider == CYCLES_PER_SAMPLE {
+            self.divider = 0;
+            self.opll.clock();
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            0x8000..=0x9FFF => usize::from(self.prg_banks[0]),
+            0xA000..=0xBFFF => usize::from(self.prg_banks[1]),
+            0xC000..=0xDFFF => usize::from(self.prg_banks[2]),
+            _ => (len / PRG_BANK_SIZE).max(1) - 1,
+        };
+        Some(mapper::bank_offset(len, PRG_BANK_SIZE, bank) + usize::from(addr) % PRG_BANK_SIZE)
+    }
+
+    fn prg_ram(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio(&self) -> f32 {
+        if self.audio_reset() {
+            return 0.0;
+        }
+        self.opll.output() as f32 * MIX_LEVEL
+    }
+
+    fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_bytes(&self.prg_banks);
+        chunk.write_bytes(&self.chr_banks);
+        chunk.write_u8(self.control);
+        self.irq.save_state(chunk);
+        chunk.write_u8(self.audio_register);
+        chunk.write_u8(self.divider);
+        self.opll.save_state(chunk);
+        self.prg_ram.save_state(chunk);
+        self.chr.save_state(chunk);
+    }
+
+    fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        let prg_banks = chunk.read_bytes()?;
+        let chr_banks = chunk.read_bytes()?;
+        if prg_banks.len() != self.prg_banks.len() || chr_banks.len() != self.chr_banks.len() {
+            return Err("VRC7 banks in save state have the wrong size");
+        }
+        self.prg_banks.copy_from_slice(prg_banks);
+        self.chr_banks.copy_from_slice(chr_banks);
+        self.control = chunk.read_u8()?;
+        self.irq.load_state(chunk)?;
+        self.audio_register = chunk.read_u8()?;
+        self.divider = chunk.read_u8()? % CYCLES_PER_SAMPLE;
+        self.opll.load_state(chunk)?;
+        self.prg_ram.load_state(chunk)?;
+        self.chr.load_state(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper::test::cartridge;
+
+    fn vrc7() -> Vrc7 {
+        Vrc7::new(cartridge(85, 0x40000, 0x40000))
+    }
+
+    fn write_audio(vrc7: &mut Vrc7, register: u8, byte: u8) {
+        vrc7.write(0x9010, register);
+        vrc7.write(0x9030, byte);
+    }
+
+    /// Play the first built in instrument and record a sample after every 36 CPU cycles
+    fn render(vrc7: &mut Vrc7, samples: usize) -> Vec<f32> {
+        write_audio(vrc7, 0x30, 0x10);
+        write_audio(vrc7, 0x10, 0x22);
+        write_audio(vrc7, 0x20, 0x19);
+        (0..samples)
+            .map(|_| {
+                for _ in 0..CYCLES_PER_SAMPLE {
+                    vrc7.cpu_clock();
+                }
+                vrc7.audio()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut vrc7 = vrc7();
+
+        vrc7.write(0x8000, 0x03);
+        vrc7.write(0x8010, 0x04);
+        vrc7.write(0x9000, 0x05);
+
+        assert_eq!(vrc7.read(0x8000), 0x03);
+        assert_eq!(vrc7.read(0xA000), 0x04);
+        assert_eq!(vrc7.read(0xC000), 0x05);
+        assert_eq!(vrc7.read(0xE000), 0x1F);
+    }
+
+    #[test]
+    fn vrc7b_uses_a3_for_the_second_register() {
+        let mut vrc7 = vrc7();
+
+        vrc7.write(0x8008, 0x06);
+
+        assert_eq!(vrc7.read(0xA000), 0x06);
+    }
+
+    #[test]
+    fn chr_banks() {
+        let mut vrc7 = vrc7();
+
+        vrc7.write(0xA000, 0x11);
+        vrc7.write(0xA010, 0x12);
+        vrc7.write(0xD010, 0x42);
+
+        assert_eq!(vrc7.ppu_read(0x0000), 0x11);
+        assert_eq!(vrc7.ppu_read(0x0400), 0x12);
+        assert_eq!(vrc7.ppu_read(0x1C00), 0x42);
+    }
+
+    #[test]
+    fn control_register() {
+        let mut vrc7 = vrc7();
+        vrc7.write(0x6000, 0x42);
+        assert_eq!(vrc7.open_bus_mask(0x6000), 0xFF);
+
+        vrc7.write(0xE000, 0x81);
+        vrc7.write(0x6000, 0x42);
+
+        assert_eq!(vrc7.read(0x6000), 0x42);
+        assert_eq!(vrc7.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn irq() {
+        let mut vrc7 = vrc7();
+        vrc7.write(0xE010, 0xFF);
+        vrc7.write(0xF000, 0x06);
+
+        vrc7.cpu_clock();
+        assert!(vrc7.irq());
+
+        vrc7.write(0xF010, 0x00);
+        assert!(!vrc7.irq());
+    }
+
+    #[test]
+    fn renders_audio_deterministically() {
+        let first = render(&mut vrc7(), 2048);
+        let second = render(&mut vrc7(), 2048);
+
+        assert_eq!(first, second);
+        assert!(first.iter().any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn audio_reset_silences_the_synthesizer() {
+        let mut vrc7 = vrc7();
+        render(&mut vrc7, 256);
+
+        vrc7.write(0xE000, 0x40);
+
+        assert_eq!(vrc7.audio(), 0.0);
+        assert!(render(&mut vrc7, 256).iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut vrc7 = vrc7();
+        vrc7.write(0x8000, 0x07);
+        render(&mut vrc7, 100);
+        let mut chunk = Chunk::new(b"MAPR");
+        vrc7.save_state(&mut chunk);
+
+        let mut restored = self::vrc7();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.read(0x8000), 0x07);
+        assert_eq!(render(&mut restored, 10), render(&mut vrc7, 10));
+    }
+}
@@ -0,0 +1,166 @@
+//! The IRQ counter shared by the Konami VRC4, VRC6 and VRC7
+//!
+//! An 8 bit counter counts up towards $FF, where it raises an IRQ and reloads from the latch. In
+//! cycle mode it's clocked on every CPU cycle. In scanline mode a prescaler divides the CPU clock
+//! by 113⅔, clocking the counter once per scanline without looking at the PPU.
+
+use state::{Chunk, ChunkReader};
+
+/// The prescaler counts down by 3 every CPU cycle, so it wraps every 341 PPU dots
+const PRESCALER_PERIOD: i16 = 341;
+
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    /// Whether the IRQ is enabled again after it's acknowledged
+    enabled_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> VrcIrq {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: PRESCALER_PERIOD,
+            enabled: false,
+            enabled_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn write_latch(&mut self, byte: u8) {
+        self.latch = byte;
+    }
+
+    /// Write the control register, which reloads the counter when the IRQ is enabled
+    pub fn write_control(&mut self, byte: u8) {
+        self.enabled_after_ack = byte & 0x01 != 0;
+        self.enabled = byte & 0x02 != 0;
+        self.cycle_mode = byte & 0x04 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enabled_after_ack;
+    }
+
+    /// Run the counter for a CPU cycle
+    pub fn cpu_clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if !self.cycle_mode {
+            self.prescaler -= 3;
+            if self.prescaler > 0 {
+                return;
+            }
+            self.prescaler += PRESCALER_PERIOD;
+        }
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.latch);
+        chunk.write_u8(self.counter);
+        chunk.write_u16(self.prescaler as u16);
+        chunk.write_bool(self.enabled);
+        chunk.write_bool(self.enabled_after_ack);
+        chunk.write_bool(self.cycle_mode);
+        chunk.write_bool(self.pending);
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.latch = chunk.read_u8()?;
+        self.counter = chunk.read_u8()?;
+        self.prescaler = chunk.read_u16()? as i16;
+        self.enabled = chunk.read_bool()?;
+        self.enabled_after_ack = chunk.read_bool()?;
+        self.cycle_mode = chunk.read_bool()?;
+        self.pending = chunk.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for VrcIrq {
+    fn default() -> VrcIrq {
+        VrcIrq::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cycle_mode_counts_up_to_ff() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFD);
+        irq.write_control(0x06);
+
+        irq.cpu_clock();
+        irq.cpu_clock();
+        assert!(!irq.pending());
+        irq.cpu_clock();
+
+        assert!(irq.pending());
+        assert_eq!(irq.counter, 0xFD);
+    }
+
+    #[test]
+    fn scanline_mode_clocks_every_341_dots() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFF);
+        irq.write_control(0x02);
+
+        for _ in 0..113 {
+            irq.cpu_clock();
+        }
+        assert!(!irq.pending());
+        irq.cpu_clock();
+
+        assert!(irq.pending());
+    }
+
+    #[test]
+    fn acknowledge_restores_the_enable_after_ack_bit() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFF);
+        irq.write_control(0x06);
+        irq.cpu_clock();
+
+        irq.acknowledge();
+
+        assert!(!irq.pending());
+        assert!(!irq.enabled);
+    }
+
+    #[test]
+    fn disabled_counter_stands_still() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0x10);
+        irq.write_control(0x04);
+
+        irq.cpu_clock();
+
+        assert_eq!(irq.counter, 0x00);
+    }
+}
@@ -0,0 +1,447 @@
+//! Input movies
+//!
+//! A movie is the controller input for every frame, together with the ROM it was recorded on
+//! and the settings the machine was powered on with. Playing it back from power on gives the
+//! exact same run, which makes movies useful both for reproducing bugs and as regression tests.
+//!
+//! Movies can be imported from and exported to the FCEUX `.fm2` text format, so existing TAS
+//! movies can be used as test fixtures. FM2 has no place for the power on settings, so they're
+//! kept in extra header keys that FCEUX ignores, `ramFill` for the RAM fill.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+
+use cpu::memory::RAM_SIZE;
+use cpu::CPU;
+
+/// The order of the buttons in an FM2 input log, from bit 7 down to bit 0
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+/// The state of the machine at power on, which isn't part of the ROM
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PowerOn {
+    /// The value RAM is filled with
+    pub ram_fill: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Movie {
+    /// The MD5 of the PRG and CHR data of the ROM the movie was recorded with
+    pub rom_md5: [u8; 16],
+    pub power_on: PowerOn,
+    /// The buttons held down on both controllers, for every frame
+    pub frames: Vec<[u8; 2]>,
+}
+
+impl Movie {
+    /// Start a new, empty movie for the ROM loaded in the CPU
+    pub fn new(cpu: &CPU, power_on: PowerOn) -> Movie {
+        Movie {
+            rom_md5: cpu.rom_md5(),
+            power_on,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Get a freshly loaded CPU ready to record or play back the movie
+    ///
+    /// Checks that the CPU has the right ROM and hasn't run yet, and applies the power on
+    /// settings.
+    pub fn power_on(&self, cpu: &mut CPU) -> Result<(), &'static str> {
+        if cpu.rom_md5() != self.rom_md5 {
+            return Err("Movie was recorded with a different ROM");
+        }
+        if cpu.cycles() != 0 {
+            return Err("Movies have to start from power on");
+        }
+        cpu.memory
+            .load_ram(vec![self.power_on.ram_fill; RAM_SIZE])?;
+        Ok(())
+    }
+
+    /// Run a frame with the given input, adding it to the movie
+    pub fn record_frame(&mut self, cpu: &mut CPU, buttons: [u8; 2]) {
+        self.frames.push(buttons);
+        run_frame(cpu, buttons);
+    }
+
+    /// Play the whole movie back from power on
+    pub fn play(&self, cpu: &mut CPU) -> Result<(), &'static str> {
+        self.power_on(cpu)?;
+        for &buttons in &self.frames {
+            run_frame(cpu, buttons);
+        }
+        Ok(())
+    }
+
+    /// Load an FCEUX movie from a file
+    pub fn load_fm2(filename: &str) -> Result<Movie, Box<dyn Error>> {
+        let mut f = File::open(filename)?;
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+
+        Ok(Movie::from_fm2(&contents)?)
+    }
+
+    /// Parse an FCEUX movie
+    ///
+    /// Only text movies that start from power on, with standard controllers, are supported.
+    pub fn from_fm2(contents: &str) -> Result<Movie, &'static str> {
+        let mut rom_md5 = None;
+        let mut power_on = PowerOn::default();
+        let mut ports = [1, 1];
+        let mut frames = Vec::new();
+
+        for line in contents.lines() {
+            let line = line.trim_end();
+            if line.starts_with('|') {
+                frames.push(parse_fm2_frame(line, frames.is_empty(), ports)?);
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "romChecksum" => {
+                    let encoded = value.trim_start_matches("base64:");
+                    let digest = base64_decode(encoded).ok_or("Invalid FM2 ROM checksum")?;
+                    if digest.len() != 16 {
+                        return Err("Invalid FM2 ROM checksum");
+                    }
+                    let mut md5 = [0; 16];
+                    md5.copy_from_slice(&digest);
+                    rom_md5 = Some(md5);
+                }
+                "port0" | "port1" => {
+                    let port = if key == "port0" { 0 } else { 1 };
+                    ports[port] = match value {
+                        "0" => 0,
+                        "1" => 1,
+                        _ => return Err("Only standard controllers are supported in FM2 movies"),
+                    };
+                }
+                "ramFill" => {
+                    power_on.ram_fill = value.parse().map_err(|_| "Invalid FM2 RAM fill")?;
+                }
+                "binary" if value != "0" => return Err("Binary FM2 movies aren't supported"),
+                "savestate" => {
+                    return Err("FM2 movies starting from a save state aren't supported")
+                }
+                "palFlag" if value != "0" => return Err("PAL FM2 movies aren't supported"),
+                "fourscore" if value != "0" => {
+                    return Err("Four Score FM2 movies aren't supported")
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Movie {
+            rom_md5: rom_md5.ok_or("FM2 movie without a ROM checksum")?,
+            power_on,
+            frames,
+        })
+    }
+
+    /// Write the movie as an FCEUX movie
+    ///
+    /// # Arguments
+    ///
+    /// * `rom_filename` - The name of the ROM, which FCEUX shows when the movie is loaded
+    pub fn to_fm2(&self, rom_filename: &str) -> String {
+        let mut fm2 = String::new();
+        fm2.push_str("version 3\n");
+        fm2.push_str("emuVersion 22020\n");
+        fm2.push_str("rerecordCount 0\n");
+        fm2.push_str("palFlag 0\n");
+        fm2.push_str(&format!("romFilename {}\n", rom_filename));
+        fm2.push_str(&format!(
+            "romChecksum base64:{}\n",
+            base64_encode(&self.rom_md5)
+        ));
+        fm2.push_str("guid 00000000-0000-0000-0000-000000000000\n");
+        fm2.push_str("fourscore 0\n");
+        fm2.push_str("microphone 0\n");
+        fm2.push_str("port0 1\n");
+        fm2.push_str("port1 1\n");
+        fm2.push_str("port2 0\n");
+        fm2.push_str("FDS 0\n");
+        fm2.push_str("NewPPU 0\n");
+        fm2.push_str(&format!("ramFill {}\n", self.power_on.ram_fill));
+        for buttons in &self.frames {
+            fm2.push_str(&format!(
+                "|0|{}|{}||\n",
+                fm2_buttons(buttons[0]),
+                fm2_buttons(buttons[1])
+            ));
+        }
+        fm2
+    }
+
+    /// Save the movie as an FCEUX movie
+    pub fn save_fm2(&self, filename: &str, rom_filename: &str) -> Result<(), Box<dyn Error>> {
+        let mut f = File::create(filename)?;
+        f.write_all(self.to_fm2(rom_filename).as_bytes())?;
+        Ok(())
+    }
+}
+
+fn run_frame(cpu: &mut CPU, buttons: [u8; 2]) {
+    cpu.set_buttons(0, buttons[0]);
+    cpu.set_buttons(1, buttons[1]);
+    cpu.step_frame();
+}
+
+/// Parse a `|commands|port0|port1|port2|` input line
+fn parse_fm2_frame(line: &str, first: bool, ports: [u8; 2]) -> Result<[u8; 2], &'static str> {
+    let fields: Vec<&str> = line.split('|').collect();
+    if fields.len() < 4 {
+        return Err("Invalid FM2 input line");
+    }
+
+    let commands = fields[1]
+        .trim()
+        .parse::<u8>()
+        .map_err(|_| "Invalid FM2 input line")?;
+    // A hard reset on the first frame is the same as starting from power on
+    let allowed = if first { 0x02 } else { 0x00 };
+    if commands & !allowed != 0 {
+        return Err("FM2 commands other than power on aren't supported");
+    }
+
+    let mut buttons = [0; 2];
+    for port in 0..2 {
+        if ports[port] == 0 {
+            continue;
+        }
+        let field = fields[port + 2].as_bytes();
+        if field.len() != FM2_BUTTONS.len() {
+            return Err("Invalid FM2 input line");
+        }
+        for (index, &c) in field.iter().enumerate() {
+            if c != b'.' && c != b' ' {
+                buttons[port] |= 0x80 >> index;
+            }
+        }
+    }
+    Ok(buttons)
+}
+
+fn fm2_buttons(buttons: u8) -> String {
+    FM2_BUTTONS
+        .iter()
+        .enumerate()
+        .map(|(index, &c)| {
+            if buttons & (0x80 >> index) != 0 {
+                c as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for group in bytes.chunks(3) {
+        let value = group
+            .iter()
+            .enumerate()
+            .fold(0u32, |value, (index, &byte)| {
+                value | u32::from(byte) << (16 - 8 * index)
+            });
+        for index in 0..4 {
+            if index <= group.len() {
+                encoded.push(BASE64[(value >> (18 - 6 * index) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut value = 0u32;
+    let mut bits = 0;
+    for c in encoded.bytes().filter(|&c| c != b'=') {
+        let digit = BASE64.iter().position(|&d| d == c)? as u32;
+        value = value << 6 | digit;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((value >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use controller::{A, B, RIGHT, START, UP};
+
+    /// A CPU running a program that adds up the buttons on the first controller every loop
+    fn reading_cpu() -> CPU {
+        let program = vec![
+            0xA9, 0x01, // LDA #$01
+            0x8D, 0x16, 0x40, // STA $4016
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x16, 0x40, // STA $4016
+            0xA2, 0x08, // LDX #$08
+            0xAD, 0x16, 0x40, // LDA $4016
+            0x4A, // LSR A
+            0x26, 0x10, // ROL $10
+            0xCA, // DEX
+            0xD0, 0xF7, // BNE $800C
+            0xA5, 0x10, // LDA $10
+            0x65, 0x11, // ADC $11
+            0x85, 0x11, // STA $11
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let mut rom = program;
+        rom.resize(0x8000, 0xEA);
+        rom[0x7FFC] = 0x00;
+        rom[0x7FFD] = 0x80;
+
+        let mut cpu = CPU::new();
+        cpu.memory.load_rom(rom).expect("Failed to load rom");
+        cpu.memory.load_ram(Vec::new()).expect("Failed to load ram");
+        cpu.memory
+            .load_sram(Vec::new())
+            .expect("Failed to load sram");
+        cpu.set_pc(0x8000);
+        cpu
+    }
+
+    fn recorded_movie() -> (Movie, Vec<u8>) {
+        let mut cpu = reading_cpu();
+        let mut movie = Movie::new(&cpu, PowerOn { ram_fill: 0xFF });
+        movie.power_on(&mut cpu).expect("Failed to power on");
+        for frame in 0..5 {
+            movie.record_frame(&mut cpu, [frame as u8 * 3, 0x00]);
+        }
+        (movie, cpu.save_state())
+    }
+
+    #[test]
+    fn playback_is_identical() {
+        let (movie, expected) = recorded_movie();
+
+        let mut cpu = reading_cpu();
+        movie.play(&mut cpu).expect("Failed to play movie");
+
+        assert_eq!(cpu.save_state(), expected);
+    }
+
+    #[test]
+    fn input_changes_the_run() {
+        let (mut movie, expected) = recorded_movie();
+        movie.frames[2] = [A | B, 0x00];
+
+        let mut cpu = reading_cpu();
+        movie.play(&mut cpu).expect("Failed to play movie");
+
+        assert_ne!(cpu.save_state(), expected);
+    }
+
+    #[test]
+    fn playback_needs_a_fresh_cpu() {
+        let (movie, _) = recorded_movie();
+        let mut cpu = reading_cpu();
+        cpu.step(false);
+
+        assert_eq!(
+            movie.play(&mut cpu),
+            Err("Movies have to start from power on")
+        );
+    }
+
+    #[test]
+    fn playback_needs_the_same_rom() {
+        let (mut movie, _) = recorded_movie();
+        movie.rom_md5[0] ^= 0xFF;
+        let mut cpu = reading_cpu();
+
+        assert_eq!(
+            movie.play(&mut cpu),
+            Err("Movie was recorded with a different ROM")
+        );
+    }
+
+    #[test]
+    fn fm2_round_trip() {
+        let movie = Movie {
+            rom_md5: [0xAB; 16],
+            power_on: PowerOn { ram_fill: 0xFF },
+            frames: vec![[0x00, 0x00], [A | START, RIGHT], [UP, 0xFF]],
+        };
+
+        let fm2 = movie.to_fm2("game");
+
+        assert!(fm2.contains("ramFill 255\n"));
+        assert!(fm2.contains("|0|....T..A|R.......||\n"));
+        assert_eq!(Movie::from_fm2(&fm2), Ok(movie));
+    }
+
+    #[test]
+    fn fm2_import() {
+        let fm2 = "version 3\n\
+                   romFilename game\n\
+                   romChecksum base64:1B2M2Y8AsgTpgAmY7PhCfg==\n\
+                   port0 1\n\
+                   port1 0\n\
+                   port2 0\n\
+                   |2|........|||\n\
+                   |0|R......A|||\n";
+
+        let movie = Movie::from_fm2(fm2).expect("Failed to parse movie");
+
+        assert_eq!(movie.rom_md5[..4], [0xD4, 0x1D, 0x8C, 0xD9]);
+        assert_eq!(movie.frames, vec![[0x00, 0x00], [RIGHT | A, 0x00]]);
+    }
+
+    #[test]
+    fn fm2_unsupported_features() {
+        let header = "romChecksum base64:1B2M2Y8AsgTpgAmY7PhCfg==\n";
+
+        assert_eq!(
+            Movie::from_fm2(&format!("{}binary 1\n", header)),
+            Err("Binary FM2 movies aren't supported")
+        );
+        assert_eq!(
+            Movie::from_fm2(&format!("{}port0 2\n", header)),
+            Err("Only standard controllers are supported in FM2 movies")
+        );
+        assert_eq!(
+            Movie::from_fm2(&format!(
+                "{}|0|........|........||\n|1|........|........||\n",
+                header
+            )),
+            Err("FM2 commands other than power on aren't supported")
+        );
+        assert_eq!(
+            Movie::from_fm2("|0|........|........||\n"),
+            Err("FM2 movie without a ROM checksum")
+        );
+        assert_eq!(
+            Movie::from_fm2(&format!("{}ramFill 256\n", header)),
+            Err("Invalid FM2 RAM fill")
+        );
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        for length in 0..8 {
+            let bytes: Vec<u8> = (0..length).map(|i| i * 37).collect();
+
+            assert_eq!(base64_decode(&base64_encode(&bytes)), Some(bytes));
+        }
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(b"Ma"), "TWE=");
+    }
+}
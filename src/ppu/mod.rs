@@ -0,0 +1,1076 @@
+//! The picture processing unit
+//!
+//! The CPU talks to the PPU through eight registers, mirrored every 8 bytes over $2000-$3FFF:
+//!
+//! ```text
+//! $2000  PPUCTRL    write  Base nametable (bits 0-1), VRAM increment of 1 or 32 (bit 2),
+//!                          sprite and background pattern tables (bits 3-4), sprite size
+//!                          (bit 5), NMI at vblank (bit 7)
+//! $2001  PPUMASK    write  Grayscale (bit 0), left 8 pixels of background and sprites
+//!                          (bits 1-2), background and sprites (bits 3-4), emphasis (bits 5-7)
+//! $2002  PPUSTATUS  read   Sprite overflow (bit 5), sprite 0 hit (bit 6), vblank (bit 7)
+//! $2003  OAMADDR    write  Address in OAM
+//! $2004  OAMDATA    r/w    Byte of OAM at OAMADDR, writes increment OAMADDR
+//! $2005  PPUSCROLL  write  X then Y scroll
+//! $2006  PPUADDR    write  High then low byte of the VRAM address
+//! $2007  PPUDATA    r/w    Byte of VRAM at the VRAM address, which is then incremented
+//! ```
+//!
+//! Scrolling and addressing share loopy's internal registers: `v` is the current VRAM address,
+//! `t` the temporary address the scroll and address writes build up, `x` the fine X scroll and
+//! `w` the toggle between the first and second write of $2005 and $2006. Reading $2002 clears
+//! the toggle and the vblank flag.
+//!
+//! Reads from VRAM through $2007 come from a buffer holding the byte of the previous read, except
+//! for the palette, which is read right away while the buffer gets the nametable byte under it.
+//!
+//! The registers drive a data bus of their own, which write-only registers read back, along with
+//! the low 5 bits of $2002 and the top 2 bits of palette reads.
+//!
+//! A frame is 262 scanlines of 341 dots, three dots for every CPU cycle. Scanlines 0-239 are
+//! drawn into the frame buffer a dot at a time, see `render`. Vblank starts at dot 1 of scanline
+//! 241 and ends at dot 1 of the pre-render scanline 261. With rendering enabled, every other
+//! frame skips the last dot of the pre-render scanline.
+//!
+//! The PPU runs ahead to the dot of every register access, so writes in the middle of a scanline
+//! take effect on the right pixel. Reading $2002 right as vblank starts suppresses the flag or
+//! the NMI for that frame.
+//!
+//! The four 1KB nametables at $2000-$2FFF, mirrored at $3000-$3EFF, are mapped onto the 2KB of
+//! VRAM in the console by the mirroring of the cartridge, which bank switching boards can change
+//! at any time:
+//!
+//! ```text
+//!              $2000  $2400  $2800  $2C00
+//! Horizontal     A      A      B      B
+//! Vertical       A      B      A      B
+//! Single A       A      A      A      A
+//! Single B       B      B      B      B
+//! Four-screen    A      B      C      D
+//! ```
+//!
+//! Four-screen boards carry another 2KB of VRAM for C and D. Boards that do more with it keep
+//! the nametables themselves, see `Mapper::nametable_peek`.
+
+pub mod palette;
+mod render;
+mod sprites;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use cartridge::Mirroring;
+use cpu::device::Device;
+use mapper::Mapper;
+use ppu::render::Background;
+use ppu::sprites::Sprite;
+use state::{Chunk, ChunkReader};
+
+/// The VRAM in the console, and the VRAM on four-screen boards that don't keep it themselves
+const NAMETABLES_SIZE: usize = 0x1000;
+const NAMETABLE_SIZE: usize = 0x0400;
+const PALETTE_SIZE: usize = 0x20;
+const OAM_SIZE: usize = 0x100;
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+const DOTS_PER_CPU_CYCLE: u64 = 3;
+/// How many dots into a CPU cycle its register access happens
+const ACCESS_DOT: u64 = 2;
+const DOTS_PER_SCANLINE: u16 = 341;
+const SCANLINES_PER_FRAME: u16 = 262;
+const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+const CTRL_INCREMENT_32: u8 = 0b0000_0100;
+const CTRL_SPRITE_TABLE: u8 = 0b0000_1000;
+const CTRL_BACKGROUND_TABLE: u8 = 0b0001_0000;
+const CTRL_SPRITE_SIZE: u8 = 0b0010_0000;
+const CTRL_NMI: u8 = 0b1000_0000;
+const MASK_GRAYSCALE: u8 = 0b0000_0001;
+const MASK_BACKGROUND_LEFT: u8 = 0b0000_0010;
+const MASK_SPRITES_LEFT: u8 = 0b0000_0100;
+const MASK_BACKGROUND: u8 = 0b0000_1000;
+const MASK_SPRITES: u8 = 0b0001_0000;
+const MASK_EMPHASIS: u8 = 0b1110_0000;
+pub const STATUS_SPRITE_OVERFLOW: u8 = 0b0010_0000;
+pub const STATUS_SPRITE_0_HIT: u8 = 0b0100_0000;
+pub const STATUS_VBLANK: u8 = 0b1000_0000;
+
+pub struct Ppu {
+    mapper: Option<Rc<RefCell<dyn Mapper>>>,
+    nametables: Vec<u8>,
+    palette: [u8; PALETTE_SIZE],
+    oam: Vec<u8>,
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+    /// The current VRAM address
+    v: u16,
+    /// The temporary VRAM address, the address of the top left of the screen
+    t: u16,
+    /// Fine X scroll
+    x: u8,
+    /// Whether the next write to $2005 or $2006 is the second one
+    w: bool,
+    /// The byte $2007 returns on the next read
+    read_buffer: u8,
+    /// The last value on the data bus between the CPU and the PPU
+    latch: u8,
+    scanline: u16,
+    /// The next dot of the scanline to run
+    dot: u16,
+    /// The number of dots run since power on
+    clock: u64,
+    /// The number of frames started since power on
+    frame: u64,
+    odd_frame: bool,
+    /// Whether the next vblank doesn't set the flag, after a read of $2002 just before it
+    suppress_vblank: bool,
+    nmi_line: bool,
+    /// Whether the NMI line went low since the CPU last looked
+    nmi_pending: bool,
+    /// The address the PPU last put on its bus, which boards watch through `ppu_clock`
+    bus_addr: u16,
+    background: Background,
+    /// The sprites on the scanline being drawn
+    sprites: Vec<Sprite>,
+    /// The color index and emphasis bits of every pixel of the last frame
+    frame_buffer: Vec<u16>,
+}
+
+impl Default for Ppu {
+    fn default() -> Ppu {
+        Ppu {
+            mapper: None,
+            nametables: vec![0x00; NAMETABLES_SIZE],
+            palette: [0x00; PALETTE_SIZE],
+            oam: vec![0x00; OAM_SIZE],
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            latch: 0,
+            scanline: 0,
+            dot: 0,
+            clock: 0,
+            frame: 0,
+            odd_frame: false,
+            suppress_vblank: false,
+            nmi_line: false,
+            nmi_pending: false,
+            bus_addr: 0,
+            background: Background::default(),
+            sprites: Vec::new(),
+            frame_buffer: vec![0x00; WIDTH * HEIGHT],
+        }
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu::default()
+    }
+
+    /// Connect the cartridge, which holds the pattern tables
+    pub fn set_mapper(&mut self, mapper: Rc<RefCell<dyn Mapper>>) {
+        self.mapper = Some(mapper);
+    }
+
+    /// Set the vblank flag at the start of vertical blanking
+    pub fn start_vblank(&mut self) {
+        self.status |= STATUS_VBLANK;
+        self.update_nmi();
+    }
+
+    /// Clear the vblank, sprite 0 hit and overflow flags at the end of vertical blanking
+    pub fn end_vblank(&mut self) {
+        self.status &= !(STATUS_VBLANK | STATUS_SPRITE_0_HIT | STATUS_SPRITE_OVERFLOW);
+        self.update_nmi();
+    }
+
+    /// Every pixel of the last frame, a row at a time, as the color index, 0-63, with the
+    /// emphasis bits of PPUMASK above it. `palette::Palette` turns them into RGB.
+    pub fn frame_buffer(&self) -> &[u16] {
+        &self.frame_buffer
+    }
+
+    /// The number of frames the PPU has started since power on, which ticks over at the start of
+    /// scanline 0
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Run the PPU for a number of dots
+    pub fn run(&mut self, dots: u64) {
+        for _ in 0..dots {
+            self.tick();
+        }
+    }
+
+    /// Run the PPU until it has run a number of dots since power on
+    pub fn run_to(&mut self, clock: u64) {
+        if clock > self.clock {
+            self.run(clock - self.clock);
+        }
+    }
+
+    /// Run the PPU up to the start of a CPU cycle
+    pub fn run_to_cycle(&mut self, cycle: u64) {
+        self.run_to(cycle * DOTS_PER_CPU_CYCLE);
+    }
+
+    fn tick(&mut self) {
+        match (self.scanline, self.dot) {
+            (0..=239, _) => self.render_dot(),
+            (VBLANK_SCANLINE, 1) => {
+                if !self.suppress_vblank {
+                    self.start_vblank();
+                }
+                self.suppress_vblank = false;
+            }
+            (PRE_RENDER_SCANLINE, dot) => {
+                if dot == 1 {
+                    self.end_vblank();
+                }
+                self.render_dot();
+            }
+            _ => {}
+        }
+        if !self.rendering_enabled() {
+            self.bus_addr = self.v & 0x3FFF;
+        }
+        if let Some(ref mapper) = self.mapper {
+            mapper.borrow_mut().ppu_clock(self.bus_addr);
+        }
+
+        self.clock += 1;
+        self.dot += 1;
+        if self.scanline == PRE_RENDER_SCANLINE
+            && self.dot == DOTS_PER_SCANLINE - 1
+            && self.odd_frame
+            && self.rendering_enabled()
+        {
+            self.dot = DOTS_PER_SCANLINE;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline = (self.scanline + 1) % SCANLINES_PER_FRAME;
+            if self.scanline == 0 {
+                self.frame += 1;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask & (MASK_BACKGROUND | MASK_SPRITES) != 0
+    }
+
+    /// Whether the PPU is pulling the NMI line of the CPU low
+    pub fn nmi(&self) -> bool {
+        self.status & STATUS_VBLANK != 0 && self.ctrl & CTRL_NMI != 0
+    }
+
+    /// Whether the NMI line went low since the last call, which the CPU handles as an NMI
+    pub fn take_nmi(&mut self) -> bool {
+        let pending = self.nmi_pending;
+        self.nmi_pending = false;
+        pending
+    }
+
+    fn update_nmi(&mut self) {
+        let line = self.nmi();
+        if line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = line;
+    }
+
+    /// Read $2002, racing the vblank flag being set
+    fn read_status(&mut self) {
+        if self.scanline == VBLANK_SCANLINE {
+            match self.dot {
+                1 => self.suppress_vblank = true,
+                2 | 3 => self.nmi_pending = false,
+                _ => {}
+            }
+        }
+        self.status &= !STATUS_VBLANK;
+        self.update_nmi();
+        self.w = false;
+    }
+
+    /// The index into the palette RAM of a palette address
+    ///
+    /// The backdrop entries of the sprite palettes at $3F10, $3F14, $3F18 and $3F1C are the same
+    /// bytes as the ones of the background palettes.
+    fn palette_index(addr: u16) -> usize {
+        let index = usize::from(addr) % PALETTE_SIZE;
+        if index & 0x13 == 0x10 {
+            index & 0x0F
+        } else {
+            index
+        }
+    }
+
+    /// Read a byte of the PPU address space, with any side effects the read has on the cartridge
+    fn read_vram(&mut self, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => match self.mapper {
+                Some(ref mapper) => mapper.borrow_mut().ppu_read(addr),
+                None => 0x00,
+            },
+            _ => self.peek_vram(addr),
+        }
+    }
+
+    fn peek_vram(&self, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => match self.mapper {
+                Some(ref mapper) => mapper.borrow().ppu_peek(addr),
+                None => 0x00,
+            },
+            0x2000..=0x3EFF => self.peek_nametable(addr),
+            _ => self.palette[Ppu::palette_index(addr)],
+        }
+    }
+
+    fn write_vram(&mut self, addr: u16, byte: u8) {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000..=0x1FFF => {
+                if let Some(ref mapper) = self.mapper {
+                    mapper.borrow_mut().ppu_write(addr, byte);
+                }
+            }
+            0x2000..=0x3EFF => self.write_nametable(addr, byte),
+            _ => self.palette[Ppu::palette_index(addr)] = byte & 0x3F,
+        }
+    }
+
+    /// The index into the VRAM of a nametable address
+    fn nametable_index(addr: u16, mirroring: Mirroring) -> usize {
+        let table = usize::from(addr >> 10) & 0x03;
+        let page = match mirroring {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 0x01,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+        page * NAMETABLE_SIZE + usize::from(addr) % NAMETABLE_SIZE
+    }
+
+    /// Read a nametable byte, from the board if it keeps the nametables
+    ///
+    /// Without a cartridge, the nametables are four screens of VRAM.
+    fn peek_nametable(&self, addr: u16) -> u8 {
+        let mirroring = match self.mapper {
+            Some(ref mapper) => {
+                let mapper = mapper.borrow();
+                if let Some(byte) = mapper.nametable_peek(addr) {
+                    return byte;
+                }
+                mapper.mirroring()
+            }
+            None => Mirroring::FourScreen,
+        };
+        self.nametables[Ppu::nametable_index(addr, mirroring)]
+    }
+
+    fn write_nametable(&mut self, addr: u16, byte: u8) {
+        let mirroring = match self.mapper {
+            Some(ref mapper) => {
+                let mut mapper = mapper.borrow_mut();
+                if mapper.nametable_write(addr, byte) {
+                    return;
+                }
+                mapper.mirroring()
+            }
+            None => Mirroring::FourScreen,
+        };
+        self.nametables[Ppu::nametable_index(addr, mirroring)] = byte;
+    }
+
+    /// A palette entry as read through $2007, with the top 2 bits from the data bus
+    fn read_palette(&self, addr: u16) -> u8 {
+        let mut color = self.palette[Ppu::palette_index(addr)];
+        if self.mask & MASK_GRAYSCALE != 0 {
+            color &= 0x30;
+        }
+        color | self.latch & 0xC0
+    }
+
+    fn increment_v(&mut self) {
+        let increment = if self.ctrl & CTRL_INCREMENT_32 != 0 {
+            32
+        } else {
+            1
+        };
+        self.v = (self.v + increment) & 0x7FFF;
+    }
+
+    /// OAM as read through $2004, where bits 2-4 of the sprite attributes don't exist
+    fn read_oam(&self) -> u8 {
+        let byte = self.oam[usize::from(self.oam_addr)];
+        if self.oam_addr & 0x03 == 0x02 {
+            byte & 0xE3
+        } else {
+            byte
+        }
+    }
+
+    pub fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_bytes(&self.nametables);
+        chunk.write_bytes(&self.palette);
+        chunk.write_bytes(&self.oam);
+        chunk.write_u8(self.ctrl);
+        chunk.write_u8(self.mask);
+        chunk.write_u8(self.status);
+        chunk.write_u8(self.oam_addr);
+        chunk.write_u16(self.v);
+        chunk.write_u16(self.t);
+        chunk.write_u8(self.x);
+        chunk.write_bool(self.w);
+        chunk.write_u8(self.read_buffer);
+        chunk.write_u8(self.latch);
+        chunk.write_u16(self.scanline);
+        chunk.write_u16(self.dot);
+        self.save_sprites(chunk);
+        chunk.write_u64(self.clock);
+        chunk.write_bool(self.odd_frame);
+        chunk.write_bool(self.suppress_vblank);
+        chunk.write_bool(self.nmi_line);
+        chunk.write_bool(self.nmi_pending);
+        chunk.write_u16(self.bus_addr);
+        self.background.save_state(chunk);
+        chunk.write_u64(self.frame);
+        self.save_sprite_tiles(chunk);
+    }
+
+    pub fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        let nametables = chunk.read_bytes()?;
+        let palette = chunk.read_bytes()?;
+        let oam = chunk.read_bytes()?;
+        if nametables.len() != NAMETABLES_SIZE
+            || palette.len() != PALETTE_SIZE
+            || oam.len() != OAM_SIZE
+        {
+            return Err("PPU memory in save state has the wrong size");
+        }
+        self.nametables.copy_from_slice(nametables);
+        self.palette.copy_from_slice(palette);
+        self.oam.copy_from_slice(oam);
+        self.ctrl = chunk.read_u8()?;
+        self.mask = chunk.read_u8()?;
+        self.status = chunk.read_u8()?;
+        self.oam_addr = chunk.read_u8()?;
+        self.v = chunk.read_u16()? & 0x7FFF;
+        self.t = chunk.read_u16()? & 0x7FFF;
+        self.x = chunk.read_u8()? & 0x07;
+        self.w = chunk.read_bool()?;
+        self.read_buffer = chunk.read_u8()?;
+        self.latch = chunk.read_u8()?;
+        if chunk.has_more() {
+            self.scanline = chunk.read_u16()? % SCANLINES_PER_FRAME;
+            self.dot = chunk.read_u16()? % DOTS_PER_SCANLINE;
+            self.load_sprites(chunk)?;
+        }
+        if chunk.has_more() {
+            self.clock = chunk.read_u64()?;
+            self.odd_frame = chunk.read_bool()?;
+            self.suppress_vblank = chunk.read_bool()?;
+            self.nmi_line = chunk.read_bool()?;
+            self.nmi_pending = chunk.read_bool()?;
+            self.bus_addr = chunk.read_u16()?;
+            self.background.load_state(chunk)?;
+        }
+        self.frame = if chunk.has_more() {
+            chunk.read_u64()?
+        } else {
+            self.clock / (u64::from(DOTS_PER_SCANLINE) * u64::from(SCANLINES_PER_FRAME))
+        };
+        if chunk.has_more() {
+            self.load_sprite_tiles(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for Ppu {
+    fn sync(&mut self, cycle: u64) {
+        self.run_to(cycle * DOTS_PER_CPU_CYCLE + ACCESS_DOT);
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        let byte = self.peek(addr);
+        match addr & 0x0007 {
+            2 => self.read_status(),
+            7 => {
+                let v = self.v & 0x3FFF;
+                // The buffer gets the nametable byte under the palette
+                self.read_buffer = if v >= 0x3F00 {
+                    self.peek_vram(v - 0x1000)
+                } else {
+                    self.read_vram(v)
+                };
+                self.increment_v();
+            }
+            _ => {}
+        }
+        self.latch = byte;
+        byte
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr & 0x0007 {
+            2 => self.status & 0xE0 | self.latch & 0x1F,
+            4 => self.read_oam(),
+            7 if self.v & 0x3FFF >= 0x3F00 => self.read_palette(self.v),
+            7 => self.read_buffer,
+            _ => self.latch,
+        }
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        self.latch = byte;
+        match addr & 0x0007 {
+            0 => {
+                self.ctrl = byte;
+                self.t = (self.t & !0x0C00) | (u16::from(byte & 0x03) << 10);
+                self.update_nmi();
+            }
+            1 => self.mask = byte,
+            3 => self.oam_addr = byte,
+            4 => {
+                self.oam[usize::from(self.oam_addr)] = byte;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            5 => {
+                if self.w {
+                    self.t = (self.t & !0x73E0)
+                        | (u16::from(byte & 0x07) << 12)
+                        | (u16::from(byte & 0xF8) << 2);
+                } else {
+                    self.t = (self.t & !0x001F) | u16::from(byte >> 3);
+                    self.x = byte & 0x07;
+                }
+                self.w = !self.w;
+            }
+            6 => {
+                if self.w {
+                    self.t = (self.t & 0xFF00) | u16::from(byte);
+                    self.v = self.t;
+                } else {
+                    self.t = (self.t & 0x00FF) | (u16::from(byte & 0x3F) << 8);
+                }
+                self.w = !self.w;
+            }
+            7 => {
+                self.write_vram(self.v, byte);
+                self.increment_v();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper;
+    use mapper::test::cartridge;
+
+    fn set_address(ppu: &mut Ppu, addr: u16) {
+        ppu.write(0x2006, (addr >> 8) as u8);
+        ppu.write(0x2006, addr as u8);
+    }
+
+    #[test]
+    fn ppuctrl_sets_the_base_nametable() {
+        let mut ppu = Ppu::new();
+        ppu.t = 0x7FFF;
+
+        ppu.write(0x2000, 0x01);
+
+        assert_eq!(ppu.t, 0x77FF);
+        assert_eq!(ppu.ctrl, 0x01);
+    }
+
+    #[test]
+    fn ppuctrl_enables_nmi_in_vblank() {
+        let mut ppu = Ppu::new();
+        ppu.start_vblank();
+        assert!(!ppu.nmi());
+
+        ppu.write(0x2000, CTRL_NMI);
+
+        assert!(ppu.nmi());
+    }
+
+    #[test]
+    fn ppumask_grayscale_applies_to_palette_reads() {
+        let mut ppu = Ppu::new();
+        set_address(&mut ppu, 0x3F01);
+        ppu.write(0x2007, 0x2D);
+
+        ppu.write(0x2001, MASK_GRAYSCALE);
+        set_address(&mut ppu, 0x3F01);
+
+        assert_eq!(ppu.read(0x2007), 0x20);
+    }
+
+    #[test]
+    fn ppustatus_read_clears_vblank_and_the_toggle() {
+        let mut ppu = Ppu::new();
+        ppu.start_vblank();
+        ppu.write(0x2005, 0x00);
+
+        assert_eq!(ppu.read(0x2002) & STATUS_VBLANK, STATUS_VBLANK);
+
+        assert_eq!(ppu.read(0x2002) & STATUS_VBLANK, 0x00);
+        assert!(!ppu.w);
+    }
+
+    #[test]
+    fn ppustatus_low_bits_are_the_data_bus() {
+        let mut ppu = Ppu::new();
+        ppu.status = STATUS_SPRITE_0_HIT;
+
+        ppu.write(0x2000, 0x1F);
+
+        assert_eq!(ppu.read(0x2002), 0x5F);
+        assert_eq!(ppu.peek(0x2005), 0x5F);
+    }
+
+    #[test]
+    fn end_of_vblank_clears_the_flags() {
+        let mut ppu = Ppu::new();
+        ppu.status = 0xE0;
+
+        ppu.end_vblank();
+
+        assert_eq!(ppu.read(0x2002) & 0xE0, 0x00);
+    }
+
+    #[test]
+    fn oamaddr_and_oamdata() {
+        let mut ppu = Ppu::new();
+
+        ppu.write(0x2003, 0xFF);
+        ppu.write(0x2004, 0x11);
+        ppu.write(0x2004, 0x22);
+
+        assert_eq!(ppu.oam[0xFF], 0x11);
+        assert_eq!(ppu.oam[0x00], 0x22);
+        ppu.write(0x2003, 0x00);
+        assert_eq!(ppu.read(0x2004), 0x22);
+        assert_eq!(ppu.read(0x2004), 0x22);
+    }
+
+    #[test]
+    fn oamdata_sprite_attributes_lack_bits_2_to_4() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2003, 0x02);
+        ppu.write(0x2004, 0xFF);
+
+        ppu.write(0x2003, 0x02);
+
+        assert_eq!(ppu.read(0x2004), 0xE3);
+    }
+
+    #[test]
+    fn ppuscroll_writes_x_then_y() {
+        let mut ppu = Ppu::new();
+
+        ppu.write(0x2005, 0x7D);
+        assert_eq!(ppu.t, 0x000F);
+        assert_eq!(ppu.x, 0x05);
+
+        ppu.write(0x2005, 0x5E);
+        assert_eq!(ppu.t, 0x616F);
+        assert!(!ppu.w);
+    }
+
+    #[test]
+    fn ppuaddr_writes_high_then_low() {
+        let mut ppu = Ppu::new();
+
+        ppu.write(0x2006, 0xFF);
+        assert_eq!(ppu.t, 0x3F00);
+        assert_eq!(ppu.v, 0x0000);
+
+        ppu.write(0x2006, 0x12);
+        assert_eq!(ppu.v, 0x3F12);
+    }
+
+    #[test]
+    fn ppudata_reads_are_buffered() {
+        let mut ppu = Ppu::new();
+        set_address(&mut ppu, 0x2400);
+        ppu.write(0x2007, 0xAB);
+        ppu.write(0x2007, 0xCD);
+
+        set_address(&mut ppu, 0x2400);
+
+        assert_eq!(ppu.read(0x2007), 0x00);
+        assert_eq!(ppu.read(0x2007), 0xAB);
+        assert_eq!(ppu.read(0x2007), 0xCD);
+    }
+
+    #[test]
+    fn ppudata_increments_by_32() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2000, CTRL_INCREMENT_32);
+        set_address(&mut ppu, 0x2000);
+
+        ppu.write(0x2007, 0x01);
+        ppu.write(0x2007, 0x02);
+
+        assert_eq!(ppu.v, 0x2040);
+        assert_eq!(ppu.nametables[0x020], 0x02);
+    }
+
+    #[test]
+    fn palette_reads_skip_the_buffer() {
+        let mut ppu = Ppu::new();
+        ppu.nametables[0xF00] = 0x42;
+        set_address(&mut ppu, 0x3F00);
+        ppu.write(0x2007, 0x0F);
+
+        set_address(&mut ppu, 0x3F00);
+
+        assert_eq!(ppu.read(0x2007), 0x0F);
+        assert_eq!(ppu.read_buffer, 0x42);
+    }
+
+    #[test]
+    fn palette_is_mirrored() {
+        let mut ppu = Ppu::new();
+
+        set_address(&mut ppu, 0x3F10);
+        ppu.write(0x2007, 0x21);
+        set_address(&mut ppu, 0x3FE4);
+        ppu.write(0x2007, 0x22);
+
+        assert_eq!(ppu.palette[0x00], 0x21);
+        assert_eq!(ppu.palette[0x04], 0x22);
+        set_address(&mut ppu, 0x3F20);
+        assert_eq!(ppu.read(0x2007), 0x21);
+    }
+
+    #[test]
+    fn pattern_tables_are_on_the_cartridge() {
+        let mut ppu = Ppu::new();
+        ppu.set_mapper(mapper::create(cartridge(0, 0x4000, 0)).unwrap());
+        set_address(&mut ppu, 0x1234);
+
+        ppu.write(0x2007, 0xAB);
+        set_address(&mut ppu, 0x1234);
+        ppu.read(0x2007);
+
+        assert_eq!(ppu.read(0x2007), 0xAB);
+    }
+
+    #[test]
+    fn registers_are_mirrored() {
+        let mut ppu = Ppu::new();
+
+        ppu.write(0x3FFE, 0x21);
+        ppu.write(0x3456, 0x00);
+
+        assert_eq!(ppu.v, 0x2100);
+    }
+
+    #[test]
+    fn write_only_registers_read_the_data_bus() {
+        let mut ppu = Ppu::new();
+
+        ppu.write(0x2001, 0xA5);
+
+        assert_eq!(ppu.read(0x2000), 0xA5);
+        assert_eq!(ppu.read(0x2006), 0xA5);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2000, 0x80);
+        ppu.write(0x2005, 0x7D);
+        ppu.write(0x2005, 0x00);
+        set_address(&mut ppu, 0x3F01);
+        ppu.write(0x2007, 0x30);
+        ppu.run(1000);
+        let mut chunk = Chunk::new(b"PPU ");
+        ppu.save_state(&mut chunk);
+
+        let mut restored = Ppu::new();
+        restored
+            .load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!(restored.ctrl, 0x80);
+        assert_eq!(restored.x, 0x05);
+        assert_eq!(restored.palette[0x01], 0x30);
+        assert_eq!(restored.v, ppu.v);
+        assert_eq!((restored.scanline, restored.dot), (2, 318));
+        assert_eq!(restored.clock, 1000);
+    }
+
+    #[test]
+    fn states_without_the_later_fields_load() {
+        // The layout before the fetch pipeline, ending with a sprite on the scanline
+        let mut chunk = Chunk::new(b"PPU ");
+        chunk.write_bytes(&[0x00; NAMETABLES_SIZE]);
+        chunk.write_bytes(&[0x00; PALETTE_SIZE]);
+        chunk.write_bytes(&[0x00; OAM_SIZE]);
+        chunk.write_u8(0x00);
+        chunk.write_u8(MASK_SPRITES);
+        chunk.write_u8(0x00);
+        chunk.write_u8(0x00);
+        chunk.write_u16(0x0000);
+        chunk.write_u16(0x0000);
+        chunk.write_u8(0x00);
+        chunk.write_bool(false);
+        chunk.write_u8(0x00);
+        chunk.write_u8(0x00);
+        chunk.write_u16(10);
+        chunk.write_u16(20);
+        chunk.write_u8(1);
+        chunk.write_u8(0x30);
+        chunk.write_u8(0x03);
+        chunk.write_u8(0xF0);
+        chunk.write_u8(0x0F);
+        chunk.write_bool(true);
+
+        let mut ppu = Ppu::new();
+        ppu.load_state(&mut chunk.reader())
+            .expect("Failed to load state");
+
+        assert_eq!((ppu.scanline, ppu.dot), (10, 20));
+        assert_eq!(ppu.sprites.len(), 1);
+        let pixel = ppu.sprite_pixel(0x30).expect("No sprite pixel");
+        assert_eq!((pixel.index, pixel.zero), (0x1D, true));
+        let pixel = ppu.sprite_pixel(0x34).expect("No sprite pixel");
+        assert_eq!(pixel.index, 0x1E);
+    }
+
+    fn ppu_with_mirroring(mapper: u16, mirroring: Mirroring) -> Ppu {
+        let mut cartridge = cartridge(mapper, 0x8000, 0);
+        cartridge.mirroring = mirroring;
+        let mut ppu = Ppu::new();
+        ppu.set_mapper(mapper::create(cartridge).unwrap());
+        ppu
+    }
+
+    /// The nametable addresses that read back a byte written to another
+    fn mirrors_of(ppu: &mut Ppu, addr: u16) -> Vec<u16> {
+        for table in 0..4 {
+            set_address(ppu, 0x2000 + table * 0x0400);
+            ppu.write(0x2007, 0x00);
+        }
+        set_address(ppu, addr);
+        ppu.write(0x2007, 0x5A);
+        (0..4)
+            .map(|table| 0x2000 + table * 0x0400)
+            .filter(|&table| ppu.peek_vram(table + addr % 0x0400) == 0x5A)
+            .collect()
+    }
+
+    #[test]
+    fn nametable_address_folding() {
+        let index = Ppu::nametable_index;
+        let all = [0x2000, 0x2400, 0x2800, 0x2C00];
+        let pages = |mirroring| {
+            all.iter()
+                .map(|&addr| index(addr, mirroring) / NAMETABLE_SIZE)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(pages(Mirroring::Horizontal), [0, 0, 1, 1]);
+        assert_eq!(pages(Mirroring::Vertical), [0, 1, 0, 1]);
+        assert_eq!(pages(Mirroring::SingleScreenLower), [0, 0, 0, 0]);
+        assert_eq!(pages(Mirroring::SingleScreenUpper), [1, 1, 1, 1]);
+        assert_eq!(pages(Mirroring::FourScreen), [0, 1, 2, 3]);
+        assert_eq!(index(0x2BCD, Mirroring::Vertical), 0x03CD);
+        assert_eq!(index(0x3BCD, Mirroring::Vertical), 0x03CD);
+    }
+
+    #[test]
+    fn horizontal_mirroring() {
+        let mut ppu = ppu_with_mirroring(0, Mirroring::Horizontal);
+
+        assert_eq!(mirrors_of(&mut ppu, 0x2012), [0x2000, 0x2400]);
+        assert_eq!(mirrors_of(&mut ppu, 0x2C34), [0x2800, 0x2C00]);
+    }
+
+    #[test]
+    fn vertical_mirroring() {
+        let mut ppu = ppu_with_mirroring(0, Mirroring::Vertical);
+
+        assert_eq!(mirrors_of(&mut ppu, 0x2012), [0x2000, 0x2800]);
+        assert_eq!(mirrors_of(&mut ppu, 0x2C34), [0x2400, 0x2C00]);
+    }
+
+    #[test]
+    fn single_screen_mirroring_set_by_the_board() {
+        // AxROM picks the page with bit 4 of its register
+        let mut ppu = ppu_with_mirroring(7, Mirroring::Horizontal);
+        let board = ppu.mapper.clone().unwrap();
+        assert_eq!(
+            mirrors_of(&mut ppu, 0x2456),
+            [0x2000, 0x2400, 0x2800, 0x2C00]
+        );
+
+        board.borrow_mut().write(0x8000, 0x10);
+        assert_eq!(ppu.peek_vram(0x2056), 0x00);
+        set_address(&mut ppu, 0x2C56);
+        ppu.write(0x2007, 0x22);
+        assert_eq!(ppu.peek_vram(0x2056), 0x22);
+
+        board.borrow_mut().write(0x8000, 0x00);
+        assert_eq!(ppu.peek_vram(0x2856), 0x5A);
+    }
+
+    #[test]
+    fn four_screen_mirroring() {
+        let mut ppu = ppu_with_mirroring(0, Mirroring::FourScreen);
+
+        assert_eq!(mirrors_of(&mut ppu, 0x2012), [0x2000]);
+        assert_eq!(mirrors_of(&mut ppu, 0x2C34), [0x2C00]);
+    }
+
+    #[test]
+    fn boards_can_keep_the_nametables() {
+        let mut ppu = ppu_with_mirroring(111, Mirroring::Vertical);
+
+        assert_eq!(mirrors_of(&mut ppu, 0x2812), [0x2800]);
+        assert!(ppu.nametables.iter().all(|&byte| byte == 0x00));
+    }
+
+    #[test]
+    fn nametables_are_mirrored_at_3000() {
+        let mut ppu = ppu_with_mirroring(0, Mirroring::Vertical);
+
+        set_address(&mut ppu, 0x3456);
+        ppu.write(0x2007, 0xAB);
+
+        assert_eq!(ppu.peek_vram(0x2456), 0xAB);
+        assert_eq!(ppu.peek_vram(0x2C56), 0xAB);
+        assert_eq!(ppu.peek_vram(0x3C56), 0xAB);
+    }
+
+    /// Run to a dot of the first frame
+    fn run_to_dot(ppu: &mut Ppu, scanline: u16, dot: u16) {
+        ppu.run_to(u64::from(scanline) * u64::from(DOTS_PER_SCANLINE) + u64::from(dot));
+    }
+
+    #[test]
+    fn vblank_starts_at_dot_1_of_scanline_241() {
+        let mut ppu = Ppu::new();
+
+        run_to_dot(&mut ppu, 241, 1);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        ppu.run(1);
+        assert_ne!(ppu.status & STATUS_VBLANK, 0);
+
+        run_to_dot(&mut ppu, 261, 1);
+        assert_ne!(ppu.status & STATUS_VBLANK, 0);
+        ppu.run(1);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+    }
+
+    #[test]
+    fn odd_frames_skip_a_dot_with_rendering_enabled() {
+        let mut ppu = Ppu::new();
+        let frame = u64::from(DOTS_PER_SCANLINE) * u64::from(SCANLINES_PER_FRAME);
+        ppu.mask = MASK_BACKGROUND;
+
+        ppu.run(frame);
+        assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+        assert_eq!(ppu.frame(), 1);
+        ppu.run(frame - 1);
+        assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+        assert_eq!(ppu.frame(), 2);
+        ppu.run(frame);
+        assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+        assert_eq!(ppu.frame(), 3);
+    }
+
+    #[test]
+    fn odd_frames_are_full_length_with_rendering_disabled() {
+        let mut ppu = Ppu::new();
+        let frame = u64::from(DOTS_PER_SCANLINE) * u64::from(SCANLINES_PER_FRAME);
+
+        ppu.run(frame * 2);
+
+        assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+    }
+
+    #[test]
+    fn nmi_at_the_start_of_vblank() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2000, CTRL_NMI);
+
+        run_to_dot(&mut ppu, 241, 1);
+        assert!(!ppu.take_nmi());
+        ppu.run(1);
+        assert!(ppu.take_nmi());
+        assert!(!ppu.take_nmi());
+    }
+
+    #[test]
+    fn enabling_nmi_in_vblank_raises_another() {
+        let mut ppu = Ppu::new();
+        run_to_dot(&mut ppu, 245, 0);
+
+        ppu.write(0x2000, CTRL_NMI);
+        assert!(ppu.take_nmi());
+
+        ppu.write(0x2000, 0x00);
+        ppu.write(0x2000, CTRL_NMI);
+        assert!(ppu.take_nmi());
+    }
+
+    #[test]
+    fn reading_status_just_before_vblank_suppresses_it() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2000, CTRL_NMI);
+        run_to_dot(&mut ppu, 241, 1);
+
+        assert_eq!(ppu.read(0x2002) & STATUS_VBLANK, 0);
+        ppu.run(10);
+
+        assert_eq!(ppu.read(0x2002) & STATUS_VBLANK, 0);
+        assert!(!ppu.take_nmi());
+    }
+
+    #[test]
+    fn reading_status_as_vblank_starts_suppresses_the_nmi() {
+        let mut ppu = Ppu::new();
+        ppu.write(0x2000, CTRL_NMI);
+        run_to_dot(&mut ppu, 241, 2);
+
+        assert_ne!(ppu.read(0x2002) & STATUS_VBLANK, 0);
+
+        assert!(!ppu.take_nmi());
+    }
+
+    #[test]
+    fn status_reads_catch_up_to_the_cpu_cycle() {
+        let mut ppu = Ppu::new();
+
+        ppu.sync(241 * 341 / 3 + 1);
+
+        assert_ne!(ppu.read(0x2002) & STATUS_VBLANK, 0);
+    }
+}
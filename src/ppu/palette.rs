@@ -0,0 +1,299 @@
+//! Turning the colors the PPU draws into RGB
+//!
+//! The frame buffer holds a color index, 0-63, in bits 0-5 of every pixel and the emphasis bits
+//! of PPUMASK in bits 6-8, so a palette has 512 colors:
+//!
+//! ```text
+//! 8 7 6 5 4 3 2 1 0
+//! | | | | | +-+-+-+- Hue, where 0 and $D are grays and $E-$F are black
+//! | | | +-+--------- Brightness
+//! | | +------------- Emphasize red, darkening green and blue
+//! | +--------------- Emphasize green, darkening red and blue
+//! +----------------- Emphasize blue, darkening red and green
+//! ```
+//!
+//! `.pal` files hold 3 bytes of RGB for either the 64 colors, in which case emphasis is worked
+//! out from them, or all 512.
+//!
+//! `Palette::ntsc` generates the palette the way a TV would decode it from the composite video
+//! signal of the PPU. Each hue is a square wave between two voltages, its phase the hue, sampled
+//! over the 12 phases of the color subcarrier and turned from YIQ into RGB.
+
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::Read;
+
+pub const COLORS: usize = 64;
+pub const COLORS_WITH_EMPHASIS: usize = 512;
+
+/// How much emphasis darkens the other colors
+const ATTENUATION: f32 = 0.816_328;
+
+/// The 64 colors the PPU makes, in RGB
+#[rustfmt::skip]
+const DEFAULT_COLORS: [u8; COLORS * 3] = [
+    0x54, 0x54, 0x54, 0x00, 0x1E, 0x74, 0x08, 0x10, 0x90, 0x30, 0x00, 0x88,
+    0x44, 0x00, 0x64, 0x5C, 0x00, 0x30, 0x54, 0x04, 0x00, 0x3C, 0x18, 0x00,
+    0x20, 0x2A, 0x00, 0x08, 0x3A, 0x00, 0x00, 0x40, 0x00, 0x00, 0x3C, 0x00,
+    0x00, 0x32, 0x3C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x98, 0x96, 0x98, 0x08, 0x4C, 0xC4, 0x30, 0x32, 0xEC, 0x5C, 0x1E, 0xE4,
+    0x88, 0x14, 0xB0, 0xA0, 0x14, 0x64, 0x98, 0x22, 0x20, 0x78, 0x3C, 0x00,
+    0x54, 0x5A, 0x00, 0x28, 0x72, 0x00, 0x08, 0x7C, 0x00, 0x00, 0x76, 0x28,
+    0x00, 0x66, 0x78, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xEC, 0xEE, 0xEC, 0x4C, 0x9A, 0xEC, 0x78, 0x7C, 0xEC, 0xB0, 0x62, 0xEC,
+    0xE4, 0x54, 0xEC, 0xEC, 0x58, 0xB4, 0xEC, 0x6A, 0x64, 0xD4, 0x88, 0x20,
+    0xA0, 0xAA, 0x00, 0x74, 0xC4, 0x00, 0x4C, 0xD0, 0x20, 0x38, 0xCC, 0x6C,
+    0x38, 0xB4, 0xCC, 0x3C, 0x3C, 0x3C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xEC, 0xEE, 0xEC, 0xA8, 0xCC, 0xEC, 0xBC, 0xBC, 0xEC, 0xD4, 0xB2, 0xEC,
+    0xEC, 0xAE, 0xEC, 0xEC, 0xAE, 0xD4, 0xEC, 0xB4, 0xB0, 0xE4, 0xC4, 0x90,
+    0xCC, 0xD2, 0x78, 0xB4, 0xDE, 0x78, 0xA8, 0xE2, 0x90, 0x98, 0xE2, 0xB4,
+    0xA0, 0xD6, 0xE4, 0xA0, 0xA2, 0xA0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// The voltages of the low and high halves of the wave for each brightness, as a fraction of
+/// white above black
+const LOW_LEVELS: [f32; 4] = [-0.117, 0.0, 0.308, 0.715];
+const HIGH_LEVELS: [f32; 4] = [0.399, 0.686, 1.0, 1.0];
+
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::from_pal(&DEFAULT_COLORS).unwrap()
+    }
+}
+
+impl Palette {
+    pub fn new() -> Palette {
+        Palette::default()
+    }
+
+    /// Read a palette from the contents of a `.pal` file, of 64 or 512 colors
+    pub fn from_pal(bytes: &[u8]) -> Result<Palette, &'static str> {
+        if bytes.len() != COLORS * 3 && bytes.len() != COLORS_WITH_EMPHASIS * 3 {
+            return Err("A palette has 64 or 512 colors of 3 bytes");
+        }
+        let colors: Vec<[u8; 3]> = bytes
+            .chunks(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+            .collect();
+        if colors.len() == COLORS_WITH_EMPHASIS {
+            return Ok(Palette { colors });
+        }
+
+        let colors = (0..COLORS_WITH_EMPHASIS)
+            .map(|index| emphasize(colors[index % COLORS], index))
+            .collect();
+        Ok(Palette { colors })
+    }
+
+    /// Load a `.pal` file
+    pub fn load_file(filename: &str) -> Result<Palette, Box<dyn Error>> {
+        let mut f = File::open(filename)?;
+        let mut bytes = Vec::new();
+        f.read_to_end(&mut bytes)?;
+
+        Ok(Palette::from_pal(&bytes)?)
+    }
+
+    /// Generate the palette an NTSC TV shows
+    ///
+    /// # Arguments
+    ///
+    /// * `hue` - Degrees to turn the hues by, 0 for the usual look
+    /// * `saturation` - How colorful the colors are, 1 for the usual look and 0 for grays
+    pub fn ntsc(hue: f32, saturation: f32) -> Palette {
+        let colors = (0..COLORS_WITH_EMPHASIS)
+            .map(|index| ntsc_color(index, hue, saturation))
+            .collect();
+        Palette { colors }
+    }
+
+    /// The RGB of a pixel of the frame buffer
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        self.colors[usize::from(pixel) % COLORS_WITH_EMPHASIS]
+    }
+
+    /// Turn a frame buffer into 4 bytes of RGBA for every pixel
+    pub fn to_rgba(&self, frame_buffer: &[u16]) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(frame_buffer.len() * 4);
+        for &pixel in frame_buffer {
+            rgba.extend_from_slice(&self.rgb(pixel));
+            rgba.push(0xFF);
+        }
+        rgba
+    }
+
+    /// The contents of a `.pal` file of all 512 colors
+    pub fn to_pal(&self) -> Vec<u8> {
+        self.colors
+            .iter()
+            .flat_map(|rgb| rgb.iter().cloned())
+            .collect()
+    }
+}
+
+/// Darken the channels the emphasis bits of a color index don't emphasize
+///
+/// The blacks in columns $E and $F stay black.
+fn emphasize(rgb: [u8; 3], index: usize) -> [u8; 3] {
+    let emphasis = index >> 6;
+    if index & 0x0E == 0x0E {
+        return rgb;
+    }
+    let mut result = rgb;
+    for (channel, value) in result.iter_mut().enumerate() {
+        if emphasis & !(1 << channel) != 0 {
+            *value = (f32::from(*value) * ATTENUATION).round() as u8;
+        }
+    }
+    result
+}
+
+fn ntsc_color(index: usize, hue: f32, saturation: f32) -> [u8; 3] {
+    let color = index & 0x0F;
+    let level = if color > 0x0D { 1 } else { (index >> 4) & 0x03 };
+    let emphasis = index >> 6;
+    let mut low = LOW_LEVELS[level];
+    let mut high = HIGH_LEVELS[level];
+    if color == 0x00 {
+        low = high;
+    }
+    if color > 0x0C {
+        high = low;
+    }
+
+    // The wave of a hue is high for 6 of the 12 phases, starting at a phase that depends on it
+    let in_phase = |hue: usize, phase: usize| (hue + phase) % 12 < 6;
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let mut signal = if in_phase(color, phase) { high } else { low };
+        let emphasized = (0..3).any(|bit| emphasis & (1 << bit) != 0 && in_phase(bit * 4, phase));
+        if emphasized && color < 0x0E {
+            signal = (signal + 0.117) * 0.746 - 0.117;
+        }
+        // Where the hues fall against the color burst, picked to match the default colors
+        let angle = PI * (phase as f32 + 3.9) / 6.0 + hue.to_radians();
+        y += signal / 12.0;
+        i += signal * angle.cos() / 12.0 * saturation;
+        q += signal * angle.sin() / 12.0 * saturation;
+    }
+
+    let channel = |value: f32| (value.max(0.0).powf(2.2 / 1.8) * 255.0).round().min(255.0) as u8;
+    [
+        channel(y + 0.946_882 * i + 0.623_557 * q),
+        channel(y - 0.274_788 * i - 0.635_691 * q),
+        channel(y - 1.108_545 * i + 1.709_007 * q),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_palette() {
+        let palette = Palette::new();
+
+        assert_eq!(palette.rgb(0x0F), [0x00, 0x00, 0x00]);
+        assert_eq!(palette.rgb(0x16), [0x98, 0x22, 0x20]);
+        assert_eq!(palette.rgb(0x30), [0xEC, 0xEE, 0xEC]);
+    }
+
+    #[test]
+    fn emphasis_darkens_the_other_channels() {
+        let palette = Palette::new();
+
+        // Red emphasis
+        assert_eq!(palette.rgb(0x30 | 0x40), [0xEC, 0xC2, 0xC1]);
+        // Green and blue emphasis
+        assert_eq!(palette.rgb(0x30 | 0x180), [0xC1, 0xC2, 0xC1]);
+        assert_eq!(palette.rgb(0x0F | 0x1C0), [0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn pal_files_of_64_colors() {
+        let bytes: Vec<u8> = (0..COLORS * 3).map(|i| i as u8).collect();
+
+        let palette = Palette::from_pal(&bytes).expect("Invalid palette");
+
+        assert_eq!(palette.rgb(0x01), [0x03, 0x04, 0x05]);
+        assert_eq!(palette.rgb(0x3F), [0xBD, 0xBE, 0xBF]);
+        assert_eq!(palette.rgb(0x41), [0x03, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn pal_files_of_512_colors() {
+        let bytes: Vec<u8> = (0..COLORS_WITH_EMPHASIS * 3)
+            .map(|i| (i / 3) as u8)
+            .collect();
+
+        let palette = Palette::from_pal(&bytes).expect("Invalid palette");
+
+        assert_eq!(palette.rgb(0x141), [0x41, 0x41, 0x41]);
+        assert_eq!(palette.to_pal(), bytes);
+    }
+
+    #[test]
+    fn pal_files_of_other_sizes_are_rejected() {
+        assert!(Palette::from_pal(&[0x00; 190]).is_err());
+    }
+
+    #[test]
+    fn ntsc_palette() {
+        let palette = Palette::ntsc(0.0, 1.0);
+
+        assert_eq!(palette.rgb(0x0F), [0x00, 0x00, 0x00]);
+        assert_eq!(palette.rgb(0x1D), [0x00, 0x00, 0x00]);
+        let [r, g, b] = palette.rgb(0x30);
+        assert!(r > 0xF0 && g > 0xF0 && b > 0xF0);
+        // Red, green and blue
+        let [r, g, b] = palette.rgb(0x16);
+        assert!(r > g && r > b);
+        let [r, g, b] = palette.rgb(0x1A);
+        assert!(g > r && g > b);
+        let [r, g, b] = palette.rgb(0x12);
+        assert!(b > r && b > g);
+    }
+
+    #[test]
+    fn ntsc_palette_is_close_to_the_default() {
+        let ntsc = Palette::ntsc(0.0, 1.0);
+        let default = Palette::new();
+
+        for index in 0..COLORS as u16 {
+            for (&a, &b) in ntsc.rgb(index).iter().zip(default.rgb(index).iter()) {
+                assert!(
+                    (i32::from(a) - i32::from(b)).abs() < 0x40,
+                    "Color {:02X}: {:?} {:?}",
+                    index,
+                    ntsc.rgb(index),
+                    default.rgb(index)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ntsc_saturation_and_hue() {
+        let gray = Palette::ntsc(0.0, 0.0).rgb(0x16);
+        assert_eq!(gray[0], gray[1]);
+        assert_eq!(gray[1], gray[2]);
+
+        // A third of the way around the hues, red turns green
+        let [r, g, b] = Palette::ntsc(-120.0, 1.0).rgb(0x16);
+        assert!(g > r && g > b);
+    }
+
+    #[test]
+    fn rgba_export() {
+        let palette = Palette::new();
+
+        let rgba = palette.to_rgba(&[0x16, 0x30]);
+
+        assert_eq!(rgba, [0x98, 0x22, 0x20, 0xFF, 0xEC, 0xEE, 0xEC, 0xFF]);
+    }
+}
@@ -0,0 +1,504 @@
+//! Drawing scanlines
+//!
+//! Each pixel is the frontmost opaque sprite pixel, see `sprites`, unless it is behind an opaque
+//! background pixel or there is none. Sprite 0 hits when an opaque pixel of it lands on an opaque
+//! background pixel, anywhere but the last column.
+//!
+//! Every 8 dots the background takes four fetches of 2 dots each: the tile from the nametable,
+//! its palette from the attribute table, and the two planes of its row from the pattern table.
+//! `v` walks the nametables as the scanline is drawn:
+//!
+//! ```text
+//! yyy NN YYYYY XXXXX
+//! ||| || ||||| +++++- Coarse X scroll, the tile column
+//! ||| || +++++------- Coarse Y scroll, the tile row
+//! ||| ++------------- Nametable
+//! +++---------------- Fine Y scroll, the row in the tile
+//! ```
+//!
+//! ```text
+//! Dots      Visible and pre-render scanlines
+//! 1-256     Fetch tiles 3-34, drawing a pixel on every dot. Coarse X goes up after every
+//!           tile, and fine Y after dot 256
+//! 257-320   Fetch the patterns of the sprites on the next scanline. Coarse X is copied from `t`
+//!           at dot 257, and the pre-render scanline copies the rest of `t` over dots 280-304
+//! 321-336   Fetch the first two tiles of the next scanline
+//! 337-340   Fetch two nametable bytes that aren't used
+//! ```
+//!
+//! The fetched tiles go through 16-bit shift registers, which fine X scroll picks a bit from.
+
+use super::*;
+
+/// The background fetch pipeline
+#[derive(Default)]
+pub(super) struct Background {
+    /// The tile being fetched
+    tile: u8,
+    palette: u8,
+    low: u8,
+    high: u8,
+    /// Shift registers, the high byte is the tile being drawn and the low byte the next one
+    pattern_low: u16,
+    pattern_high: u16,
+    palette_low: u16,
+    palette_high: u16,
+}
+
+impl Background {
+    fn shift(&mut self) {
+        self.pattern_low <<= 1;
+        self.pattern_high <<= 1;
+        self.palette_low <<= 1;
+        self.palette_high <<= 1;
+    }
+
+    /// Move the fetched tile into the low byte of the shift registers
+    fn reload(&mut self) {
+        let spread = |bit: u8| if bit != 0 { 0xFF } else { 0x00 };
+        self.pattern_low = (self.pattern_low & 0xFF00) | u16::from(self.low);
+        self.pattern_high = (self.pattern_high & 0xFF00) | u16::from(self.high);
+        self.palette_low = (self.palette_low & 0xFF00) | spread(self.palette & 0x01);
+        self.palette_high = (self.palette_high & 0xFF00) | spread(self.palette & 0x02);
+    }
+
+    /// The pixel at a fine X scroll, as an index into the palette RAM, 0 if transparent
+    fn pixel(&self, x: u8) -> u8 {
+        let bit = 15 - x;
+        let pixel =
+            (self.pattern_low >> bit) as u8 & 0x01 | ((self.pattern_high >> bit) as u8 & 0x01) << 1;
+        if pixel == 0 {
+            return 0x00;
+        }
+        let palette =
+            (self.palette_low >> bit) as u8 & 0x01 | ((self.palette_high >> bit) as u8 & 0x01) << 1;
+        palette << 2 | pixel
+    }
+
+    pub(super) fn save_state(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.tile);
+        chunk.write_u8(self.palette);
+        chunk.write_u8(self.low);
+        chunk.write_u8(self.high);
+        chunk.write_u16(self.pattern_low);
+        chunk.write_u16(self.pattern_high);
+        chunk.write_u16(self.palette_low);
+        chunk.write_u16(self.palette_high);
+    }
+
+    pub(super) fn load_state(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        self.tile = chunk.read_u8()?;
+        self.palette = chunk.read_u8()?;
+        self.low = chunk.read_u8()?;
+        self.high = chunk.read_u8()?;
+        self.pattern_low = chunk.read_u16()?;
+        self.pattern_high = chunk.read_u16()?;
+        self.palette_low = chunk.read_u16()?;
+        self.palette_high = chunk.read_u16()?;
+        Ok(())
+    }
+}
+
+impl Ppu {
+    /// Run a dot of a visible or the pre-render scanline
+    pub(super) fn render_dot(&mut self) {
+        let dot = self.dot;
+        let visible = usize::from(self.scanline) < HEIGHT;
+        if !self.rendering_enabled() {
+            if visible && (1..=256).contains(&dot) {
+                let backdrop = self.backdrop();
+                self.set_pixel(usize::from(dot - 1), backdrop);
+            }
+            return;
+        }
+
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.background.shift();
+        }
+        if dot % 8 == 1 && (9..=257).contains(&dot) || dot == 329 || dot == 337 {
+            self.background.reload();
+        }
+        if visible && (1..=256).contains(&dot) {
+            self.draw_pixel(usize::from(dot - 1));
+        }
+        if dot == 257 {
+            self.copy_horizontal();
+            if visible {
+                self.evaluate_sprites();
+            } else {
+                self.sprites.clear();
+            }
+        }
+
+        match dot {
+            1..=256 | 321..=336 => self.fetch_background((dot - 1) % 8),
+            257..=320 => {
+                self.oam_addr = 0;
+                self.fetch_sprites((dot - 257) / 8, (dot - 257) % 8);
+            }
+            337..=340 if dot % 2 == 1 => self.bus_addr = 0x2000 | (self.v & 0x0FFF),
+            337..=340 => {
+                self.read_vram(self.bus_addr);
+            }
+            _ => {}
+        }
+
+        if dot == 256 {
+            self.increment_y();
+        }
+        if !visible && (280..=304).contains(&dot) {
+            self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+        }
+    }
+
+    /// The color drawn with rendering disabled, the palette entry `v` points at if it points at
+    /// the palette
+    fn backdrop(&self) -> u8 {
+        if self.v & 0x3FFF >= 0x3F00 {
+            self.palette[Ppu::palette_index(self.v)]
+        } else {
+            self.palette[0]
+        }
+    }
+
+    /// Put a color in the frame buffer, grayed out and with the emphasis bits of PPUMASK
+    fn set_pixel(&mut self, column: usize, color: u8) {
+        let color = if self.mask & MASK_GRAYSCALE != 0 {
+            color & 0x30
+        } else {
+            color & 0x3F
+        };
+        self.frame_buffer[usize::from(self.scanline) * WIDTH + column] =
+            u16::from(color) | u16::from(self.mask & MASK_EMPHASIS) << 1;
+    }
+
+    fn draw_pixel(&mut self, column: usize) {
+        let background = if self.mask & MASK_BACKGROUND != 0
+            && (column >= 8 || self.mask & MASK_BACKGROUND_LEFT != 0)
+        {
+            self.background.pixel(self.x)
+        } else {
+            0x00
+        };
+        let pixel = match self.sprite_pixel(column) {
+            Some(sprite) => {
+                if sprite.zero && background != 0 && column != WIDTH - 1 {
+                    self.status |= STATUS_SPRITE_0_HIT;
+                }
+                if background != 0 && sprite.behind_background {
+                    background
+                } else {
+                    sprite.index
+                }
+            }
+            None => background,
+        };
+        let color = self.palette[usize::from(pixel)];
+        self.set_pixel(column, color);
+    }
+
+    /// Run a step of the fetches of a background tile, reads take the second dot of a fetch
+    fn fetch_background(&mut self, step: u16) {
+        let v = self.v;
+        match step {
+            0 => self.bus_addr = 0x2000 | (v & 0x0FFF),
+            2 => self.bus_addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07),
+            4 => {
+                let table = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 {
+                    0x1000
+                } else {
+                    0x0000
+                };
+                self.bus_addr = table | u16::from(self.background.tile) << 4 | (v >> 12);
+            }
+            6 => self.bus_addr += 8,
+            1 => self.background.tile = self.read_vram(self.bus_addr),
+            3 => {
+                let attribute = self.read_vram(self.bus_addr);
+                self.background.palette = (attribute >> (((v >> 4) & 0x04) | (v & 0x02))) & 0x03;
+            }
+            5 => self.background.low = self.read_vram(self.bus_addr),
+            _ => {
+                self.background.high = self.read_vram(self.bus_addr);
+                self.v = increment_x(self.v);
+            }
+        }
+    }
+
+    /// Move `v` down a row, wrapping from the bottom of a nametable to the top of the one below
+    ///
+    /// Rows 30 and 31 hold the attribute table, coarse Y only gets there when written directly
+    /// and then wraps without switching nametables.
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let coarse_y = match (self.v >> 5) & 0x1F {
+            29 => {
+                self.v ^= 0x0800;
+                0
+            }
+            31 => 0,
+            coarse_y => coarse_y + 1,
+        };
+        self.v = (self.v & !0x03E0) | coarse_y << 5;
+    }
+
+    /// Copy coarse X and the horizontal nametable from `t`
+    fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+}
+
+/// Move `v` right a tile, wrapping to the nametable next to it
+fn increment_x(v: u16) -> u16 {
+    if v & 0x001F == 0x001F {
+        (v & !0x001F) ^ 0x0400
+    } else {
+        v + 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper;
+    use mapper::test::cartridge;
+
+    /// A PPU with CHR RAM where tile 1 is solid color 1 and tile 2 solid color 3
+    fn ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.set_mapper(mapper::create(cartridge(0, 0x4000, 0)).unwrap());
+        for row in 0..8 {
+            ppu.write_vram(0x0010 + row, 0xFF);
+            ppu.write_vram(0x0020 + row, 0xFF);
+            ppu.write_vram(0x0028 + row, 0xFF);
+        }
+        for (i, &color) in [0x0F, 0x16, 0x27, 0x18, 0x0F, 0x1A, 0x2A, 0x3A]
+            .iter()
+            .enumerate()
+        {
+            ppu.write_vram(0x3F00 + i as u16, color);
+        }
+        ppu.mask = MASK_BACKGROUND | MASK_BACKGROUND_LEFT;
+        ppu
+    }
+
+    /// Run from the start of the pre-render scanline to the end of a number of scanlines
+    fn draw(ppu: &mut Ppu, scanlines: u64) {
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.dot = 0;
+        ppu.run(u64::from(DOTS_PER_SCANLINE) * (scanlines + 1));
+    }
+
+    fn line(ppu: &Ppu, scanline: usize) -> &[u16] {
+        &ppu.frame_buffer()[scanline * WIDTH..(scanline + 1) * WIDTH]
+    }
+
+    #[test]
+    fn solid_tiles() {
+        let mut ppu = ppu();
+        for addr in 0x2000..0x23C0 {
+            ppu.write_vram(addr, 0x01);
+        }
+
+        draw(&mut ppu, 1);
+
+        assert!(line(&ppu, 0).iter().all(|&pixel| pixel == 0x16));
+    }
+
+    #[test]
+    fn grayscale_and_emphasis() {
+        let mut ppu = ppu();
+        for addr in 0x2000..0x23C0 {
+            ppu.write_vram(addr, 0x01);
+        }
+        ppu.mask |= MASK_GRAYSCALE | 0b1010_0000;
+
+        draw(&mut ppu, 1);
+
+        assert!(line(&ppu, 0).iter().all(|&pixel| pixel == 0x10 | 0x140));
+    }
+
+    #[test]
+    fn attributes_pick_the_palette() {
+        let mut ppu = ppu();
+        for addr in 0x2000..0x23C0 {
+            ppu.write_vram(addr, 0x02);
+        }
+        // Palette 1 for the top right 16x16 pixels of the first 32x32
+        ppu.write_vram(0x23C0, 0b0000_0100);
+
+        draw(&mut ppu, 1);
+
+        assert_eq!(line(&ppu, 0)[15], 0x18);
+        assert_eq!(line(&ppu, 0)[16], 0x3A);
+        assert_eq!(line(&ppu, 0)[31], 0x3A);
+        assert_eq!(line(&ppu, 0)[32], 0x18);
+    }
+
+    #[test]
+    fn transparent_pixels_are_the_backdrop() {
+        let mut ppu = ppu();
+        ppu.write_vram(0x2001, 0x01);
+
+        draw(&mut ppu, 1);
+
+        assert_eq!(line(&ppu, 0)[7], 0x0F);
+        assert_eq!(line(&ppu, 0)[8], 0x16);
+        assert_eq!(line(&ppu, 0)[16], 0x0F);
+    }
+
+    #[test]
+    fn fine_x_scroll() {
+        let mut ppu = ppu();
+        ppu.write_vram(0x2001, 0x01);
+        ppu.write(0x2005, 0x03);
+        ppu.write(0x2005, 0x00);
+
+        draw(&mut ppu, 1);
+
+        assert_eq!(line(&ppu, 0)[4], 0x0F);
+        assert_eq!(line(&ppu, 0)[5], 0x16);
+        assert_eq!(line(&ppu, 0)[12], 0x16);
+        assert_eq!(line(&ppu, 0)[13], 0x0F);
+    }
+
+    #[test]
+    fn coarse_x_scroll_wraps_into_the_next_nametable() {
+        let mut ppu = ppu();
+        ppu.write_vram(0x2400, 0x01);
+        ppu.write(0x2005, 0xF8);
+        ppu.write(0x2005, 0x00);
+
+        draw(&mut ppu, 1);
+
+        assert_eq!(line(&ppu, 0)[7], 0x0F);
+        assert_eq!(line(&ppu, 0)[8], 0x16);
+        assert_eq!(line(&ppu, 0)[16], 0x0F);
+    }
+
+    #[test]
+    fn fine_y_scroll() {
+        let mut ppu = ppu();
+        ppu.write_vram(0x0010, 0x00);
+        ppu.write_vram(0x2000, 0x01);
+        ppu.write(0x2005, 0x00);
+        ppu.write(0x2005, 0x07);
+
+        draw(&mut ppu, 2);
+
+        assert_eq!(line(&ppu, 0)[0], 0x16);
+        // The next scanline is the first row of the tile below
+        assert_eq!(line(&ppu, 1)[0], 0x0F);
+    }
+
+    #[test]
+    fn left_8_pixels_are_masked() {
+        let mut ppu = ppu();
+        for addr in 0x2000..0x23C0 {
+            ppu.write_vram(addr, 0x01);
+        }
+        ppu.mask = MASK_BACKGROUND;
+
+        draw(&mut ppu, 1);
+
+        assert!(line(&ppu, 0)[..8].iter().all(|&pixel| pixel == 0x0F));
+        assert_eq!(line(&ppu, 0)[8], 0x16);
+    }
+
+    #[test]
+    fn rendering_disabled_draws_the_backdrop() {
+        let mut ppu = ppu();
+        ppu.write_vram(0x2000, 0x01);
+        ppu.mask = 0x00;
+        ppu.v = 0x0123;
+
+        draw(&mut ppu, 1);
+
+        assert!(line(&ppu, 0).iter().all(|&pixel| pixel == 0x0F));
+        assert_eq!(ppu.v, 0x0123);
+    }
+
+    #[test]
+    fn rendering_disabled_draws_the_palette_entry_at_v() {
+        let mut ppu = ppu();
+        ppu.mask = 0x00;
+        ppu.v = 0x3F03;
+
+        draw(&mut ppu, 1);
+
+        assert!(line(&ppu, 0).iter().all(|&pixel| pixel == 0x18));
+    }
+
+    #[test]
+    fn writes_take_effect_in_the_middle_of_a_scanline() {
+        let mut ppu = ppu();
+        for addr in 0x2000..0x23C0 {
+            ppu.write_vram(addr, 0x01);
+        }
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.dot = 0;
+
+        // Up to the dot drawing column 128
+        ppu.run(u64::from(DOTS_PER_SCANLINE) + 129);
+        ppu.write(0x2001, 0x00);
+        ppu.run(u64::from(DOTS_PER_SCANLINE));
+
+        assert_eq!(line(&ppu, 0)[127], 0x16);
+        assert_eq!(line(&ppu, 0)[128], 0x0F);
+    }
+
+    #[test]
+    fn increment_y_wraps_at_the_bottom_of_a_nametable() {
+        let mut ppu = Ppu::new();
+
+        ppu.v = 0x7000 | 29 << 5;
+        ppu.increment_y();
+        assert_eq!(ppu.v, 0x0800);
+
+        ppu.v = 0x7000 | 31 << 5;
+        ppu.increment_y();
+        assert_eq!(ppu.v, 0x0000);
+
+        ppu.v = 0x6000 | 31 << 5;
+        ppu.increment_y();
+        assert_eq!(ppu.v, 0x7000 | 31 << 5);
+    }
+
+    #[test]
+    fn pre_render_scanline_reloads_the_scroll() {
+        let mut ppu = ppu();
+        ppu.write(0x2000, 0x03);
+        ppu.write(0x2005, 0x08);
+        ppu.write(0x2005, 0x10);
+
+        draw(&mut ppu, 0);
+
+        // Two tiles into the next scanline
+        assert_eq!(ppu.v & 0x7BE0, ppu.t & 0x7BE0);
+        assert_eq!(ppu.v & 0x041F, (ppu.t & 0x041F) + 2);
+    }
+
+    #[test]
+    fn fetches_clock_the_mmc3_irq_counter_once_per_scanline() {
+        let mut ppu = ppu();
+        let mapper = mapper::create(cartridge(4, 0x8000, 0x2000)).unwrap();
+        ppu.set_mapper(mapper.clone());
+        // Sprites from $1000 raise A12 once every scanline
+        ppu.ctrl = CTRL_SPRITE_TABLE;
+        ppu.mask = MASK_BACKGROUND | MASK_SPRITES;
+        mapper.borrow_mut().write(0xC000, 4);
+        mapper.borrow_mut().write(0xC001, 0);
+        mapper.borrow_mut().write(0xE001, 0);
+
+        // The pre-render scanline reloads the counter, and scanlines 0-2 count it down to 1
+        draw(&mut ppu, 3);
+        assert!(!mapper.borrow().irq());
+
+        ppu.run(u64::from(DOTS_PER_SCANLINE));
+        assert!(mapper.borrow().irq());
+    }
+}
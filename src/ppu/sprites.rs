@@ -0,0 +1,435 @@
+//! Sprite evaluation
+//!
+//! OAM holds 64 sprites of 4 bytes:
+//!
+//! ```text
+//! 0  Y of the top of the sprite, minus 1
+//! 1  Tile, for 8x16 sprites bit 0 is the pattern table and bits 1-7 the top tile
+//! 2  Palette (bits 0-1), behind the background (bit 5), horizontal and vertical flip (bits 6-7)
+//! 3  X of the left of the sprite
+//! ```
+//!
+//! At the end of a scanline the PPU looks for the first 8 sprites on the next one, then fetches
+//! their patterns over dots 257-320, 8 dots each. Empty slots fetch tile $FF instead. Sprites
+//! earlier in OAM are in front of later ones, even behind the background.
+//!
+//! Once 8 sprites are found the PPU keeps looking for more to set the overflow flag, but it
+//! moves on to the next byte of every sprite along with the next sprite, so it checks tiles,
+//! attributes and X as if they were Y. It finds spurious overflows and misses real ones.
+
+use super::*;
+
+const SPRITES_PER_SCANLINE: usize = 8;
+const ATTRIBUTE_BEHIND_BACKGROUND: u8 = 0b0010_0000;
+const ATTRIBUTE_FLIP_HORIZONTAL: u8 = 0b0100_0000;
+const ATTRIBUTE_FLIP_VERTICAL: u8 = 0b1000_0000;
+
+/// A sprite found on the next scanline, with the row of its pattern to draw
+#[derive(Clone, Copy, Default)]
+pub(super) struct Sprite {
+    y: u8,
+    tile: u8,
+    x: u8,
+    attributes: u8,
+    /// The low and high planes of the row, flipped already
+    low: u8,
+    high: u8,
+    /// Whether this is sprite 0, for sprite 0 hit
+    zero: bool,
+}
+
+/// The color of a sprite at a pixel
+pub(super) struct SpritePixel {
+    /// The index into the palette RAM
+    pub index: u8,
+    pub behind_background: bool,
+    pub zero: bool,
+}
+
+impl Ppu {
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_SPRITE_SIZE != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Find the sprites on the scanline after the current one
+    pub(super) fn evaluate_sprites(&mut self) {
+        let height = self.sprite_height();
+        let in_range = |y: u8| self.scanline.wrapping_sub(u16::from(y)) < height;
+
+        let mut found = Vec::with_capacity(SPRITES_PER_SCANLINE);
+        let mut n = 0;
+        while n < OAM_SIZE / 4 && found.len() < SPRITES_PER_SCANLINE {
+            if in_range(self.oam[n * 4]) {
+                found.push(Sprite {
+                    y: self.oam[n * 4],
+                    tile: self.oam[n * 4 + 1],
+                    attributes: self.oam[n * 4 + 2],
+                    x: self.oam[n * 4 + 3],
+                    zero: n == 0,
+                    ..Sprite::default()
+                });
+            }
+            n += 1;
+        }
+
+        let mut m = 0;
+        while n < OAM_SIZE / 4 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.status |= STATUS_SPRITE_OVERFLOW;
+                break;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+
+        self.sprites = found;
+    }
+
+    /// Run a step of the fetches for a sprite slot, reads take the second dot of a fetch
+    ///
+    /// The first two fetches are from the nametable, and aren't used.
+    pub(super) fn fetch_sprites(&mut self, slot: u16, step: u16) {
+        let slot = usize::from(slot);
+        match step {
+            0 => self.bus_addr = 0x2000 | (self.v & 0x0FFF),
+            4 => self.bus_addr = self.sprite_pattern_addr(slot),
+            6 => self.bus_addr += 8,
+            5 | 7 => {
+                let mut byte = self.read_vram(self.bus_addr);
+                if let Some(sprite) = self.sprites.get_mut(slot) {
+                    if sprite.attributes & ATTRIBUTE_FLIP_HORIZONTAL != 0 {
+                        byte = byte.reverse_bits();
+                    }
+                    if step == 5 {
+                        sprite.low = byte;
+                    } else {
+                        sprite.high = byte;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The address of the low plane of the row of a slot's sprite on the next scanline
+    fn sprite_pattern_addr(&self, slot: usize) -> u16 {
+        let height = self.sprite_height();
+        let (tile, row) = match self.sprites.get(slot) {
+            Some(sprite) => {
+                let row = self.scanline.wrapping_sub(u16::from(sprite.y));
+                if sprite.attributes & ATTRIBUTE_FLIP_VERTICAL != 0 {
+                    (sprite.tile, height - 1 - row)
+                } else {
+                    (sprite.tile, row)
+                }
+            }
+            None => (0xFF, 0),
+        };
+        if height == 16 {
+            let table = u16::from(tile & 0x01) << 12;
+            let tile = u16::from(tile & 0xFE) + row / 8;
+            table | tile << 4 | (row % 8)
+        } else {
+            let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 {
+                0x1000
+            } else {
+                0x0000
+            };
+            table | u16::from(tile) << 4 | row
+        }
+    }
+
+    /// The frontmost opaque sprite pixel at a column of the scanline being drawn
+    pub(super) fn sprite_pixel(&self, column: usize) -> Option<SpritePixel> {
+        if self.mask & MASK_SPRITES == 0 || (column < 8 && self.mask & MASK_SPRITES_LEFT == 0) {
+            return None;
+        }
+        self.sprites.iter().find_map(|sprite| {
+            let offset = column.wrapping_sub(usize::from(sprite.x));
+            if offset >= 8 {
+                return None;
+            }
+            let pixel =
+                (sprite.low >> (7 - offset)) & 0x01 | ((sprite.high >> (7 - offset)) & 0x01) << 1;
+            if pixel == 0 {
+                return None;
+            }
+            Some(SpritePixel {
+                index: 0x10 | (sprite.attributes & 0x03) << 2 | pixel,
+                behind_background: sprite.attributes & ATTRIBUTE_BEHIND_BACKGROUND != 0,
+                zero: sprite.zero,
+            })
+        })
+    }
+
+    pub(super) fn save_sprites(&self, chunk: &mut Chunk) {
+        chunk.write_u8(self.sprites.len() as u8);
+        for sprite in &self.sprites {
+            chunk.write_u8(sprite.x);
+            chunk.write_u8(sprite.attributes);
+            chunk.write_u8(sprite.low);
+            chunk.write_u8(sprite.high);
+            chunk.write_bool(sprite.zero);
+        }
+    }
+
+    pub(super) fn load_sprites(&mut self, chunk: &mut ChunkReader) -> Result<(), &'static str> {
+        let count = usize::from(chunk.read_u8()?);
+        if count > SPRITES_PER_SCANLINE {
+            return Err("Too many sprites on a scanline in save state");
+        }
+        self.sprites.clear();
+        for _ in 0..count {
+            self.sprites.push(Sprite {
+                y: 0xFF,
+                tile: 0xFF,
+                x: chunk.read_u8()?,
+                attributes: chunk.read_u8()?,
+                low: chunk.read_u8()?,
+                high: chunk.read_u8()?,
+                zero: chunk.read_bool()?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Save the Y and tile of the sprites, which were added to the state after the rest
+    pub(super) fn save_sprite_tiles(&self, chunk: &mut Chunk) {
+        for sprite in &self.sprites {
+            chunk.write_u8(sprite.y);
+            chunk.write_u8(sprite.tile);
+        }
+    }
+
+    pub(super) fn load_sprite_tiles(
+        &mut self,
+        chunk: &mut ChunkReader,
+    ) -> Result<(), &'static str> {
+        for sprite in &mut self.sprites {
+            sprite.y = chunk.read_u8()?;
+            sprite.tile = chunk.read_u8()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mapper;
+    use mapper::test::cartridge;
+
+    /// A PPU with CHR RAM where tile 1 is solid color 1, tile 2 solid color 3, and tile 3 has
+    /// color 2 in its left column and top row only
+    fn ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.set_mapper(mapper::create(cartridge(0, 0x4000, 0)).unwrap());
+        for row in 0..8 {
+            ppu.write_vram(0x0010 + row, 0xFF);
+            ppu.write_vram(0x0020 + row, 0xFF);
+            ppu.write_vram(0x0028 + row, 0xFF);
+            ppu.write_vram(0x0038 + row, 0x80);
+        }
+        ppu.write_vram(0x0038, 0xFF);
+        for i in (0..0x20).filter(|i| i & 0x13 != 0x10) {
+            ppu.write_vram(0x3F00 + i, i as u8);
+        }
+        // Hide every sprite below the screen
+        for n in 0..64 {
+            ppu.oam[n * 4] = 0xF0;
+        }
+        ppu.mask = MASK_SPRITES | MASK_SPRITES_LEFT | MASK_BACKGROUND | MASK_BACKGROUND_LEFT;
+        ppu
+    }
+
+    fn set_sprite(ppu: &mut Ppu, n: usize, y: u8, tile: u8, attributes: u8, x: u8) {
+        ppu.oam[n * 4..n * 4 + 4].copy_from_slice(&[y, tile, attributes, x]);
+    }
+
+    /// Run from the start of the pre-render scanline to the end of a scanline
+    fn render_to(ppu: &mut Ppu, last: u16) {
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.dot = 0;
+        ppu.run(u64::from(DOTS_PER_SCANLINE) * (u64::from(last) + 2));
+    }
+
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> u16 {
+        ppu.frame_buffer()[y * WIDTH + x]
+    }
+
+    #[test]
+    fn sprites_are_drawn_a_scanline_below_their_y() {
+        let mut ppu = ppu();
+        set_sprite(&mut ppu, 0, 9, 0x01, 0x01, 20);
+
+        render_to(&mut ppu, 18);
+
+        assert_eq!(pixel(&ppu, 20, 9), 0x00);
+        assert_eq!(pixel(&ppu, 20, 10), 0x15);
+        assert_eq!(pixel(&ppu, 27, 17), 0x15);
+        assert_eq!(pixel(&ppu, 28, 17), 0x00);
+        assert_eq!(pixel(&ppu, 20, 18), 0x00);
+    }
+
+    #[test]
+    fn sprites_flip() {
+        let mut ppu = ppu();
+        set_sprite(&mut ppu, 0, 0, 0x03, 0x00, 16);
+        set_sprite(&mut ppu, 1, 0, 0x03, ATTRIBUTE_FLIP_HORIZONTAL, 32);
+        set_sprite(&mut ppu, 2, 0, 0x03, ATTRIBUTE_FLIP_VERTICAL, 48);
+
+        render_to(&mut ppu, 8);
+
+        assert_eq!(pixel(&ppu, 16, 1), 0x12);
+        assert_eq!(pixel(&ppu, 23, 1), 0x12);
+        assert_eq!(pixel(&ppu, 23, 2), 0x00);
+        assert_eq!(pixel(&ppu, 32, 2), 0x00);
+        assert_eq!(pixel(&ppu, 39, 2), 0x12);
+        assert_eq!(pixel(&ppu, 48, 8), 0x12);
+        assert_eq!(pixel(&ppu, 55, 8), 0x12);
+        assert_eq!(pixel(&ppu, 55, 7), 0x00);
+    }
+
+    #[test]
+    fn tall_sprites_use_two_tiles() {
+        let mut ppu = ppu();
+        ppu.ctrl |= CTRL_SPRITE_SIZE;
+        // Tiles 2 and 3 from the pattern table at $0000
+        set_sprite(&mut ppu, 0, 0, 0x02, 0x00, 0);
+
+        render_to(&mut ppu, 17);
+
+        assert_eq!(pixel(&ppu, 7, 1), 0x13);
+        assert_eq!(pixel(&ppu, 7, 9), 0x12);
+        assert_eq!(pixel(&ppu, 7, 10), 0x00);
+        assert_eq!(pixel(&ppu, 0, 16), 0x12);
+        assert_eq!(pixel(&ppu, 0, 17), 0x00);
+    }
+
+    #[test]
+    fn earlier_sprites_are_in_front() {
+        let mut ppu = ppu();
+        set_sprite(&mut ppu, 0, 0, 0x01, 0x00, 10);
+        set_sprite(&mut ppu, 1, 0, 0x02, 0x01, 6);
+
+        render_to(&mut ppu, 1);
+
+        assert_eq!(pixel(&ppu, 9, 1), 0x17);
+        assert_eq!(pixel(&ppu, 10, 1), 0x11);
+    }
+
+    #[test]
+    fn sprites_behind_the_background() {
+        let mut ppu = ppu();
+        ppu.write_vram(0x2001, 0x01);
+        set_sprite(&mut ppu, 0, 0, 0x02, ATTRIBUTE_BEHIND_BACKGROUND, 4);
+
+        render_to(&mut ppu, 1);
+
+        assert_eq!(pixel(&ppu, 7, 1), 0x13);
+        assert_eq!(pixel(&ppu, 8, 1), 0x01);
+    }
+
+    #[test]
+    fn left_8_pixels_of_sprites_are_masked() {
+        let mut ppu = ppu();
+        ppu.mask &= !MASK_SPRITES_LEFT;
+        set_sprite(&mut ppu, 0, 0, 0x01, 0x00, 4);
+
+        render_to(&mut ppu, 1);
+
+        assert_eq!(pixel(&ppu, 7, 1), 0x00);
+        assert_eq!(pixel(&ppu, 8, 1), 0x11);
+    }
+
+    #[test]
+    fn only_8_sprites_per_scanline() {
+        let mut ppu = ppu();
+        for n in 0..9 {
+            set_sprite(&mut ppu, n, 0, 0x01, 0x00, n as u8 * 8);
+        }
+
+        render_to(&mut ppu, 1);
+
+        assert_eq!(pixel(&ppu, 63, 1), 0x11);
+        assert_eq!(pixel(&ppu, 64, 1), 0x00);
+        assert_ne!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn overflow_checks_the_wrong_bytes() {
+        let mut ppu = ppu();
+        for n in 0..64 {
+            set_sprite(&mut ppu, n, 0xF0, 0xF0, 0xF0, 0xF0);
+        }
+        for n in 0..8 {
+            set_sprite(&mut ppu, n, 0, 0x01, 0x00, 0);
+        }
+        // The tenth sprite is on the scanline, but its tile is checked as if it were Y
+        set_sprite(&mut ppu, 9, 0, 0xF0, 0xF0, 0xF0);
+
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+
+        set_sprite(&mut ppu, 9, 0xF0, 0x00, 0xF0, 0xF0);
+        ppu.evaluate_sprites();
+        assert_ne!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn sprite_0_hit() {
+        let mut ppu = ppu();
+        ppu.write_vram(0x2002, 0x01);
+        set_sprite(&mut ppu, 0, 0, 0x01, ATTRIBUTE_BEHIND_BACKGROUND, 20);
+
+        render_to(&mut ppu, 0);
+        assert_eq!(ppu.status & STATUS_SPRITE_0_HIT, 0);
+
+        render_to(&mut ppu, 1);
+        assert_ne!(ppu.status & STATUS_SPRITE_0_HIT, 0);
+    }
+
+    #[test]
+    fn no_sprite_0_hit_on_a_transparent_background() {
+        let mut ppu = ppu();
+        set_sprite(&mut ppu, 0, 0, 0x01, 0x00, 20);
+
+        render_to(&mut ppu, 8);
+
+        assert_eq!(ppu.status & STATUS_SPRITE_0_HIT, 0);
+    }
+
+    #[test]
+    fn no_sprite_0_hit_at_the_last_column() {
+        let mut ppu = ppu();
+        for addr in 0x2000..0x23C0 {
+            ppu.write_vram(addr, 0x01);
+        }
+        set_sprite(&mut ppu, 0, 0, 0x01, 0x00, 255);
+
+        render_to(&mut ppu, 8);
+        assert_eq!(ppu.status & STATUS_SPRITE_0_HIT, 0);
+
+        set_sprite(&mut ppu, 0, 0, 0x01, 0x00, 254);
+        render_to(&mut ppu, 8);
+        assert_ne!(ppu.status & STATUS_SPRITE_0_HIT, 0);
+    }
+
+    #[test]
+    fn no_sprite_0_hit_in_the_masked_left_8_pixels() {
+        let mut ppu = ppu();
+        for addr in 0x2000..0x23C0 {
+            ppu.write_vram(addr, 0x01);
+        }
+        ppu.mask &= !MASK_BACKGROUND_LEFT;
+        set_sprite(&mut ppu, 0, 0, 0x01, 0x00, 0);
+
+        render_to(&mut ppu, 8);
+
+        assert_eq!(ppu.status & STATUS_SPRITE_0_HIT, 0);
+    }
+}
@@ -0,0 +1,392 @@
+//! Rewinding the machine
+//!
+//! Keeps a ring buffer of save states taken at a fixed interval. Only the oldest state is kept
+//! whole, every other state is stored as a delta against the one before it, which keeps memory
+//! use down as most of the machine doesn't change between two snapshots.
+//!
+//! Rewinding loads the nearest snapshot and runs the CPU forward again to the exact point that
+//! was asked for, which also makes it possible to step backwards one instruction at a time.
+
+use std::collections::VecDeque;
+
+use cpu::{CPU, CYCLES_PER_FRAME};
+
+/// How often snapshots are taken
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interval {
+    Frames(u64),
+    Cycles(u64),
+}
+
+impl Interval {
+    fn cycles(self) -> u64 {
+        match self {
+            Interval::Frames(frames) => frames * CYCLES_PER_FRAME,
+            Interval::Cycles(cycles) => cycles,
+        }
+        .max(1)
+    }
+}
+
+struct Snapshot {
+    cycles: u64,
+    /// The whole state for the oldest snapshot, a delta against the previous one for the rest
+    data: Vec<u8>,
+}
+
+pub struct Rewind {
+    interval: u64,
+    capacity: usize,
+    snapshots: VecDeque<Snapshot>,
+    /// The newest state in full, to calculate the next delta against
+    latest: Vec<u8>,
+}
+
+impl Rewind {
+    /// Create a new rewind buffer
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - How often to take a snapshot
+    /// * `capacity` - The number of snapshots to keep, at least one
+    pub fn new(interval: Interval, capacity: usize) -> Rewind {
+        Rewind {
+            interval: interval.cycles(),
+            capacity: capacity.max(1),
+            snapshots: VecDeque::new(),
+            latest: Vec::new(),
+        }
+    }
+
+    /// The number of snapshots in the buffer
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// The number of bytes used by the stored snapshots
+    pub fn memory_usage(&self) -> usize {
+        self.snapshots
+            .iter()
+            .map(|snapshot| snapshot.data.len())
+            .sum::<usize>()
+            + self.latest.len()
+    }
+
+    /// Take a snapshot if the interval has passed since the last one
+    pub fn record(&mut self, cpu: &CPU) {
+        let due = match self.snapshots.back() {
+            Some(snapshot) => cpu.cycles() >= snapshot.cycles + self.interval,
+            None => true,
+        };
+        if due {
+            self.capture(cpu);
+        }
+    }
+
+    /// Run a single instruction, taking snapshots as needed
+    pub fn step(&mut self, cpu: &mut CPU) -> Option<u8> {
+        self.record(cpu);
+        let cycles = cpu.step(false);
+        self.record(cpu);
+        cycles
+    }
+
+    /// Take a snapshot right away
+    pub fn capture(&mut self, cpu: &CPU) {
+        let state = cpu.save_state();
+        let data = if self.snapshots.is_empty() {
+            state.clone()
+        } else {
+            encode_delta(&self.latest, &state)
+        };
+        self.snapshots.push_back(Snapshot {
+            cycles: cpu.cycles(),
+            data,
+        });
+        self.latest = state;
+
+        while self.snapshots.len() > self.capacity {
+            let oldest = self.snapshots.pop_front().unwrap();
+            if let Some(next) = self.snapshots.front_mut() {
+                next.data = decode_delta(&oldest.data, &next.data)
+                    .expect("Rewind buffer holds an invalid delta");
+            }
+        }
+    }
+
+    /// Step back a number of frames, to the start of the frame
+    pub fn rewind_frames(&mut self, cpu: &mut CPU, frames: u64) -> Result<(), &'static str> {
+        let frame = cpu.frame().saturating_sub(frames);
+        // Frames are a little shorter than `CYCLES_PER_FRAME` on average, but never by a whole
+        // cycle, so this is at or before the start of the frame
+        self.rewind_to(cpu, frame * (CYCLES_PER_FRAME - 1))?;
+        while cpu.frame() < frame {
+            cpu.step(false);
+        }
+        Ok(())
+    }
+
+    /// Step back a single instruction
+    ///
+    /// The instruction boundaries aren't stored anywhere, so the CPU is run forward from the
+    /// nearest snapshot to find where the previous instruction started, before doing it again
+    /// and stopping there.
+    pub fn step_back(&mut self, cpu: &mut CPU) -> Result<(), &'static str> {
+        let current = cpu.cycles();
+        if current == 0 {
+            return Err("Nothing to step back to");
+        }
+
+        let index = self.nearest(current - 1)?;
+        self.load(cpu, index)?;
+        let mut previous = cpu.cycles();
+        while cpu.cycles() < current {
+            previous = cpu.cycles();
+            cpu.step(false);
+        }
+        if cpu.cycles() != current {
+            return Err("Snapshots don't match the current run");
+        }
+
+        self.rewind_to(cpu, previous)
+    }
+
+    /// Go back to the first instruction boundary at or after the given cycle
+    fn rewind_to(&mut self, cpu: &mut CPU, cycles: u64) -> Result<(), &'static str> {
+        let index = self.nearest(cycles)?;
+        self.load(cpu, index)?;
+        while cpu.cycles() < cycles {
+            cpu.step(false);
+        }
+        Ok(())
+    }
+
+    /// Find the newest snapshot taken at or before the given cycle
+    fn nearest(&self, cycles: u64) -> Result<usize, &'static str> {
+        self.snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.cycles <= cycles)
+            .ok_or("No snapshot that far back")
+    }
+
+    /// Load a snapshot, dropping every snapshot taken after it
+    fn load(&mut self, cpu: &mut CPU, index: usize) -> Result<(), &'static str> {
+        let mut state = self.snapshots[0].data.clone();
+        for snapshot in self.snapshots.iter().skip(1).take(index) {
+            state = decode_delta(&state, &snapshot.data)?;
+        }
+        cpu.load_state(&state)?;
+
+        self.snapshots.truncate(index + 1);
+        self.latest = state;
+        Ok(())
+    }
+}
+
+/// Encode the difference between two states
+///
+/// The states are XORed together, and the result stored as alternating runs of unchanged bytes
+/// and changed bytes. Each run starts with its length as a variable length integer.
+fn encode_delta(previous: &[u8], next: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_length(&mut delta, next.len());
+
+    let xor = |index: usize| next[index] ^ previous.get(index).cloned().unwrap_or(0);
+    let mut index = 0;
+    while index < next.len() {
+        let start = index;
+        while index < next.len() && xor(index) == 0 {
+            index += 1;
+        }
+        write_length(&mut delta, index - start);
+
+        let start = index;
+        while index < next.len() && xor(index) != 0 {
+            index += 1;
+        }
+        write_length(&mut delta, index - start);
+        delta.extend((start..index).map(xor));
+    }
+    delta
+}
+
+/// Apply a delta from `encode_delta` to the previous state
+fn decode_delta(previous: &[u8], delta: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut position = 0;
+    let length = read_length(delta, &mut position)?;
+    let mut next: Vec<u8> = previous.iter().cloned().take(length).collect();
+    next.resize(length, 0x00);
+
+    let mut index = 0;
+    while index < length {
+        index += read_length(delta, &mut position)?;
+        let changed = read_length(delta, &mut position)?;
+        if index + changed > length || position + changed > delta.len() {
+            return Err("Invalid rewind delta");
+        }
+        for byte in &delta[position..position + changed] {
+            next[index] ^= byte;
+            index += 1;
+        }
+        position += changed;
+    }
+    Ok(next)
+}
+
+fn write_length(buffer: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        buffer.push((length as u8 & 0x7F) | 0x80);
+        length >>= 7;
+    }
+    buffer.push(length as u8);
+}
+
+fn read_length(buffer: &[u8], position: &mut usize) -> Result<usize, &'static str> {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *buffer.get(*position).ok_or("Invalid rewind delta")?;
+        *position += 1;
+        length |= usize::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(length);
+        }
+        shift += 7;
+        if shift > 28 {
+            return Err("Invalid rewind delta");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A CPU running `INX, STX $10, JMP $0000` forever
+    fn looping_cpu() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.memory
+            .load_ram(vec![0xE8, 0x86, 0x10, 0x4C, 0x00, 0x00])
+            .expect("Failed to load ram");
+        cpu.memory
+            .load_sram(Vec::new())
+            .expect("Failed to load sram");
+        cpu
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let previous = vec![0x00, 0x01, 0x02, 0x03, 0x04];
+        let next = vec![0x00, 0x11, 0x02, 0x03, 0x14, 0x05];
+
+        let delta = encode_delta(&previous, &next);
+
+        assert_eq!(decode_delta(&previous, &delta), Ok(next));
+    }
+
+    #[test]
+    fn delta_to_a_shorter_state() {
+        let previous = vec![0x01; 300];
+        let next = vec![0x01; 200];
+
+        let delta = encode_delta(&previous, &next);
+
+        assert_eq!(delta.len(), 5);
+        assert_eq!(decode_delta(&previous, &delta), Ok(next));
+    }
+
+    #[test]
+    fn delta_of_identical_states_is_small() {
+        let state = vec![0xAB; 0x2000];
+
+        assert_eq!(encode_delta(&state, &state).len(), 5);
+    }
+
+    #[test]
+    fn invalid_delta() {
+        assert_eq!(
+            decode_delta(&[], &[0x05, 0x00, 0x09]),
+            Err("Invalid rewind delta")
+        );
+    }
+
+    #[test]
+    fn snapshots_are_taken_at_the_interval() {
+        let mut cpu = looping_cpu();
+        let mut rewind = Rewind::new(Interval::Cycles(100), 10);
+
+        while cpu.cycles() < 450 {
+            rewind.step(&mut cpu);
+        }
+
+        assert_eq!(rewind.len(), 5);
+    }
+
+    #[test]
+    fn capacity_drops_the_oldest_snapshots() {
+        let mut cpu = looping_cpu();
+        let mut rewind = Rewind::new(Interval::Cycles(100), 3);
+
+        while cpu.cycles() < 1000 {
+            rewind.step(&mut cpu);
+        }
+
+        assert_eq!(rewind.len(), 3);
+        assert!(rewind.memory_usage() < 3 * cpu.save_state().len());
+        assert_eq!(
+            rewind.rewind_to(&mut cpu, 0),
+            Err("No snapshot that far back")
+        );
+    }
+
+    #[test]
+    fn step_back_a_single_instruction() {
+        let mut cpu = looping_cpu();
+        let mut rewind = Rewind::new(Interval::Cycles(50), 10);
+        for _ in 0..40 {
+            rewind.step(&mut cpu);
+        }
+        let before = cpu.save_state();
+        rewind.step(&mut cpu);
+
+        rewind.step_back(&mut cpu).expect("Failed to step back");
+
+        assert_eq!(cpu.save_state(), before);
+    }
+
+    #[test]
+    fn step_back_after_dropping_snapshots() {
+        let mut cpu = looping_cpu();
+        let mut rewind = Rewind::new(Interval::Cycles(20), 2);
+        for _ in 0..100 {
+            rewind.step(&mut cpu);
+        }
+        let before = cpu.save_state();
+        rewind.step(&mut cpu);
+
+        rewind.step_back(&mut cpu).expect("Failed to step back");
+
+        assert_eq!(cpu.save_state(), before);
+    }
+
+    #[test]
+    fn rewind_frames_returns_to_the_start_of_the_frame() {
+        let mut cpu = looping_cpu();
+        let mut rewind = Rewind::new(Interval::Frames(1), 10);
+        while cpu.frame() < 3 {
+            rewind.step(&mut cpu);
+        }
+        for _ in 0..100 {
+            rewind.step(&mut cpu);
+        }
+
+        rewind.rewind_frames(&mut cpu, 2).expect("Failed to rewind");
+
+        assert_eq!(cpu.frame(), 1);
+        assert!(cpu.cycles() - CYCLES_PER_FRAME < 5);
+    }
+}
@@ -0,0 +1,296 @@
+//! The save state format
+//!
+//! A save state is a small header followed by a list of tagged chunks:
+//!
+//! ```text
+//! "CNSS"          Magic
+//! u16             Format version
+//! u32             CRC32 of the ROM file the state was made from
+//! [u8; 4] u32 ..  Chunks: a tag, the length of the payload and the payload
+//! ```
+//!
+//! All numbers are little endian. Chunks a build doesn't know about are skipped, and new fields
+//! are only ever appended to the end of a chunk, with readers falling back to a default when a
+//! field is missing. That way older builds can load newer states and the other way around. The
+//! version is only bumped if the meaning of an existing field changes, which older builds then
+//! refuse to load.
+
+const MAGIC: [u8; 4] = *b"CNSS";
+pub const STATE_VERSION: u16 = 1;
+
+/// A single chunk of state, built up one field at a time
+pub struct Chunk {
+    tag: [u8; 4],
+    data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn new(tag: &[u8; 4]) -> Chunk {
+        Chunk {
+            tag: *tag,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Write a length prefixed run of bytes
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Read back the fields written so far
+    pub fn reader(&self) -> ChunkReader<'_> {
+        ChunkReader::new(&self.data)
+    }
+}
+
+/// Reads the fields of a chunk back in the order they were written
+pub struct ChunkReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ChunkReader<'a> {
+    pub fn new(data: &'a [u8]) -> ChunkReader<'a> {
+        ChunkReader { data, position: 0 }
+    }
+
+    /// Check if there are more fields to read, for fields that were added in later versions
+    pub fn has_more(&self) -> bool {
+        self.position < self.data.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], &'static str> {
+        if self.data.len() - self.position < length {
+            return Err("Save state chunk is truncated");
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, &'static str> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, &'static str> {
+        let bytes = self.take(2)?;
+        Ok(u16::from(bytes[0]) | u16::from(bytes[1]) << 8)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, &'static str> {
+        let bytes = self.take(4)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | u32::from(byte)))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, &'static str> {
+        let bytes = self.take(8)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | u64::from(byte)))
+    }
+
+    /// Read a length prefixed run of bytes
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], &'static str> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+}
+
+/// A whole machine save state
+pub struct SaveState {
+    pub version: u16,
+    pub rom_hash: u32,
+    chunks: Vec<Chunk>,
+}
+
+impl SaveState {
+    pub fn new(rom_hash: u32) -> SaveState {
+        SaveState {
+            version: STATE_VERSION,
+            rom_hash,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, chunk: Chunk) {
+        self.chunks.push(chunk);
+    }
+
+    /// Get a reader for the chunk with the given tag, if the state has one
+    pub fn chunk(&self, tag: &[u8; 4]) -> Option<ChunkReader<'_>> {
+        self.chunks
+            .iter()
+            .find(|chunk| &chunk.tag == tag)
+            .map(|chunk| ChunkReader::new(&chunk.data))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&self.rom_hash.to_le_bytes());
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(&chunk.tag);
+            bytes.extend_from_slice(&(chunk.data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&chunk.data);
+        }
+        bytes
+    }
+
+    /// Parse a save state
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use corrosiones::state::{Chunk, SaveState};
+    ///
+    /// let mut state = SaveState::new(0xDEADBEEF);
+    /// let mut chunk = Chunk::new(b"TEST");
+    /// chunk.write_u16(0xABCD);
+    /// state.add(chunk);
+    ///
+    /// let state = SaveState::from_bytes(&state.to_bytes()).unwrap();
+    ///
+    /// assert_eq!(state.rom_hash, 0xDEADBEEF);
+    /// assert_eq!(state.chunk(b"TEST").unwrap().read_u16(), Ok(0xABCD));
+    /// ```
+    pub fn from_bytes(bytes: &[u8]) -> Result<SaveState, &'static str> {
+        if bytes.len() < 10 || bytes[0..4] != MAGIC {
+            return Err("Not a save state");
+        }
+        let mut reader = ChunkReader::new(&bytes[4..]);
+        let version = reader.read_u16()?;
+        if version > STATE_VERSION {
+            return Err("Save state is from a newer, incompatible version");
+        }
+        let rom_hash = reader.read_u32()?;
+
+        let mut chunks = Vec::new();
+        while reader.has_more() {
+            let tag = reader.take(4)?;
+            let data = reader.read_bytes()?;
+            chunks.push(Chunk {
+                tag: [tag[0], tag[1], tag[2], tag[3]],
+                data: data.to_vec(),
+            });
+        }
+
+        Ok(SaveState {
+            version,
+            rom_hash,
+            chunks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunk_fields_round_trip() {
+        let mut chunk = Chunk::new(b"TEST");
+        chunk.write_u8(0xAB);
+        chunk.write_bool(true);
+        chunk.write_u16(0x1234);
+        chunk.write_u32(0xDEADBEEF);
+        chunk.write_u64(0x0123_4567_89AB_CDEF);
+        chunk.write_bytes(&[0x01, 0x02, 0x03]);
+
+        let mut reader = chunk.reader();
+
+        assert_eq!(reader.read_u8(), Ok(0xAB));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x1234));
+        assert_eq!(reader.read_u32(), Ok(0xDEADBEEF));
+        assert_eq!(reader.read_u64(), Ok(0x0123_4567_89AB_CDEF));
+        assert_eq!(reader.read_bytes(), Ok(&[0x01, 0x02, 0x03][..]));
+        assert!(!reader.has_more());
+    }
+
+    #[test]
+    fn reading_past_the_end_of_a_chunk() {
+        let mut reader = ChunkReader::new(&[0x01]);
+
+        assert_eq!(reader.read_u16(), Err("Save state chunk is truncated"));
+    }
+
+    #[test]
+    fn unknown_chunks_are_kept_but_ignored() {
+        let mut state = SaveState::new(0);
+        state.add(Chunk::new(b"NEW!"));
+        let mut chunk = Chunk::new(b"OLD ");
+        chunk.write_u8(0x42);
+        state.add(chunk);
+
+        let state = SaveState::from_bytes(&state.to_bytes()).unwrap();
+
+        assert_eq!(state.chunk(b"OLD ").unwrap().read_u8(), Ok(0x42));
+        assert!(state.chunk(b"GONE").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_magic() {
+        let mut bytes = SaveState::new(0).to_bytes();
+        bytes[0] = b'X';
+
+        assert_eq!(
+            SaveState::from_bytes(&bytes).err(),
+            Some("Not a save state")
+        );
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut state = SaveState::new(0);
+        state.version = STATE_VERSION + 1;
+
+        assert_eq!(
+            SaveState::from_bytes(&state.to_bytes()).err(),
+            Some("Save state is from a newer, incompatible version")
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_chunks() {
+        let mut state = SaveState::new(0);
+        let mut chunk = Chunk::new(b"TEST");
+        chunk.write_u32(0);
+        state.add(chunk);
+        let bytes = state.to_bytes();
+
+        assert_eq!(
+            SaveState::from_bytes(&bytes[..bytes.len() - 1]).err(),
+            Some("Save state chunk is truncated")
+        );
+    }
+}
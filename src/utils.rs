@@ -1,14 +1,16 @@
 use cpu::CPU;
 
+/// Print the ROM mapped in at $8000-$FFFF as rows of hex bytes, starting a new row at every label
 pub fn print_rom(cpu: &CPU, width: usize) {
-    let mut count = 0x8000;
-    let it = cpu.memory.rom.iter();
-    for elm in it {
-        if count % width == 0 {
+    for count in 0x8000..=0xFFFF {
+        let label = cpu.symbols.label(count, cpu.prg_bank(count));
+        if let Some(label) = label {
+            print!("\n{}:", label);
+        }
+        if usize::from(count) % width == 0 || label.is_some() {
             print!("\n0x{:04X?} ", count);
         }
-        count += 1;
-        print!("{:02X?} ", elm);
+        print!("{:02X?} ", cpu.memory.peek(count));
     }
     println!();
 }
@@ -28,3 +30,105 @@ pub fn read_blargg_message(cpu: &mut CPU) -> String {
     }
     String::from_utf8(bytes).unwrap()
 }
+
+/// Calculate the CRC32 of a run of bytes
+///
+/// Uses the same polynomial as zip and PNG, so the checksums match what ROM databases list.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Calculate the MD5 digest of a run of bytes
+///
+/// FCEUX identifies ROMs by the MD5 of their PRG and CHR data, so it's needed to match movies
+/// with their ROM.
+pub fn md5(bytes: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constants: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32)
+        .collect();
+
+    let mut message = bytes.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0x00);
+    }
+    message.extend_from_slice(&((bytes.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(constants[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[(i / 16) * 4 + i % 4]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+
+    let mut digest = [0; 16];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc32_of_empty_input() {
+        assert_eq!(crc32(&[]), 0x0000_0000);
+    }
+
+    #[test]
+    fn crc32_of_check_string() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    fn hex(digest: [u8; 16]) -> String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn md5_of_empty_input() {
+        assert_eq!(hex(md5(&[])), "d41d8cd98f00b204e9800998ecf8427e");
+    }
+
+    #[test]
+    fn md5_of_multiple_blocks() {
+        assert_eq!(
+            hex(md5(b"The quick brown fox jumps over the lazy dog")),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        assert_eq!(hex(md5(&[b'a'; 1000])), "cabe45dcc9ae5b66ba86600cca6b8ba8");
+    }
+}
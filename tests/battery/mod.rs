@@ -0,0 +1,190 @@
+//! Battery-backed SRAM should survive reloading the ROM
+extern crate corrosiones;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use battery::corrosiones::cpu::CPU;
+
+/// A fresh directory for a test to keep its ROM and save files in
+fn directory(name: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!("corrosiones-battery-{}", name));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// Write an NROM image looping on `JMP $8000`, with or without a battery
+fn write_rom(directory: &Path, battery: bool) -> String {
+    let mut rom = vec![b'N', b'E', b'S', 0x1A, 0x01, 0x00, 0x00, 0x00];
+    rom[6] = if battery { 0b0000_0010 } else { 0x00 };
+    rom.resize(16, 0x00);
+
+    let mut prg = vec![0x00; 0x4000];
+    prg[0..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    rom.extend(prg);
+
+    let path = directory.join("game.nes");
+    fs::write(&path, rom).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+fn load(rom: &str) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.load_file(String::from(rom)).unwrap();
+    cpu
+}
+
+#[test]
+fn sram_round_trips_through_the_save_file() {
+    let directory = directory("round-trip");
+    let rom = write_rom(&directory, true);
+
+    let mut cpu = load(&rom);
+    cpu.memory.write(0x6000, 0x42);
+    cpu.memory.write(0x7FFF, 0x24);
+    cpu.flush_sram().unwrap();
+
+    assert_eq!(fs::read(directory.join("game.sav")).unwrap().len(), 0x2000);
+    let mut cpu = load(&rom);
+    assert_eq!(cpu.memory.read(0x6000), 0x42);
+    assert_eq!(cpu.memory.read(0x7FFF), 0x24);
+}
+
+#[test]
+fn reloading_the_rom_keeps_unsaved_sram() {
+    let directory = directory("reload");
+    let rom = write_rom(&directory, true);
+
+    let mut cpu = load(&rom);
+    cpu.memory.write(0x6000, 0x42);
+    cpu.load_file(rom.clone()).unwrap();
+
+    assert_eq!(cpu.memory.read(0x6000), 0x42);
+    cpu.flush_sram().unwrap();
+    assert_eq!(fs::read(directory.join("game.sav")).unwrap()[0], 0x42);
+}
+
+#[test]
+fn sram_is_saved_when_the_cpu_is_dropped() {
+    let directory = directory("drop");
+    let rom = write_rom(&directory, true);
+
+    {
+        let mut cpu = load(&rom);
+        cpu.memory.write(0x6001, 0xAB);
+    }
+
+    assert_eq!(fs::read(directory.join("game.sav")).unwrap()[1], 0xAB);
+}
+
+#[test]
+fn sram_is_saved_at_intervals() {
+    let directory = directory("interval");
+    let rom = write_rom(&directory, true);
+    let mut cpu = load(&rom);
+    cpu.set_sram_flush_interval(2);
+    cpu.memory.write(0x6000, 0x01);
+
+    cpu.step_frame();
+    assert!(!directory.join("game.sav").exists());
+    cpu.step_frame();
+
+    assert_eq!(fs::read(directory.join("game.sav")).unwrap()[0], 0x01);
+}
+
+#[test]
+fn save_directory_is_configurable() {
+    let directory = directory("save-directory");
+    let saves = directory.join("saves");
+    fs::create_dir_all(&saves).unwrap();
+    let rom = write_rom(&directory, true);
+
+    let mut cpu = CPU::new();
+    cpu.set_save_directory(&saves);
+    cpu.load_file(rom).unwrap();
+    cpu.memory.write(0x6000, 0x42);
+    cpu.flush_sram().unwrap();
+
+    assert!(saves.join("game.sav").exists());
+    assert!(!directory.join("game.sav").exists());
+}
+
+#[test]
+fn short_save_files_are_padded() {
+    let directory = directory("short");
+    let rom = write_rom(&directory, true);
+    fs::write(directory.join("game.sav"), [0x11, 0x22]).unwrap();
+
+    let mut cpu = load(&rom);
+
+    assert_eq!(cpu.memory.read(0x6001), 0x22);
+    assert_eq!(cpu.memory.read(0x6002), 0x00);
+}
+
+#[test]
+fn oversized_save_files_are_rejected() {
+    let directory = directory("oversized");
+    let rom = write_rom(&directory, true);
+    fs::write(directory.join("game.sav"), vec![0xFF; 0x2001]).unwrap();
+
+    let error = CPU::new().load_file(rom).unwrap_err();
+
+    assert_eq!(error.to_string(), "Save file is bigger than the SRAM");
+    assert_eq!(fs::read(directory.join("game.sav")).unwrap().len(), 0x2001);
+}
+
+#[test]
+fn carts_without_a_battery_are_not_saved() {
+    let directory = directory("no-battery");
+    let rom = write_rom(&directory, false);
+
+    let mut cpu = load(&rom);
+    cpu.memory.write(0x6000, 0x42);
+    cpu.flush_sram().unwrap();
+
+    assert!(!directory.join("game.sav").exists());
+}
+
+/// Write a flashable UNROM 512 image looping on `JMP $C000`
+fn write_unrom512_rom(directory: &Path) -> String {
+    let mut rom = vec![b'N', b'E', b'S', 0x1A, 0x20, 0x00, 0xE2, 0x10];
+    rom.resize(16, 0x00);
+
+    let mut prg = vec![0xFF; 0x80000];
+    prg[0x7C000..0x7C003].copy_from_slice(&[0x4C, 0x00, 0xC0]);
+    prg[0x7FFFC] = 0x00;
+    prg[0x7FFFD] = 0xC0;
+    rom.extend(prg);
+
+    let path = directory.join("game.nes");
+    fs::write(&path, rom).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn self_flashed_prg_round_trips_through_the_save_file() {
+    let directory = directory("unrom512");
+    let rom = write_unrom512_rom(&directory);
+
+    let mut cpu = load(&rom);
+    for &(bank, addr, byte) in &[
+        (0x01, 0x9555, 0xAA),
+        (0x00, 0xAAAA, 0x55),
+        (0x01, 0x9555, 0xA0),
+        (0x02, 0x8010, 0x42),
+    ] {
+        cpu.memory.write(0xC000, bank);
+        cpu.memory.write(addr, byte);
+    }
+    cpu.flush_sram().unwrap();
+
+    assert_eq!(fs::read(directory.join("game.sav")).unwrap().len(), 0x80000);
+    let mut cpu = load(&rom);
+    cpu.memory.write(0xC000, 0x02);
+    assert_eq!(cpu.memory.read(0x8010), 0x42);
+    assert_eq!(cpu.memory.read(0x8011), 0xFF);
+}
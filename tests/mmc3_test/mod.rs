@@ -0,0 +1,71 @@
+//! The mmc3_test suite from blargg
+//!
+//! Downloaded from https://wiki.nesdev.com/w/index.php/Emulator_tests, the ROMs go next to this
+//! file. The IRQ counter is clocked by the PPU fetching from the pattern tables.
+//!
+//! `6-MMC3_alt.nes` is left out, it checks the IRQ of the older MMC3A, which doesn't fire when
+//! the counter is reloaded with 0. `Mmc3` behaves like the MMC3B and later, which do.
+extern crate corrosiones;
+
+use mmc3_test::corrosiones::cpu::CPU;
+use mmc3_test::corrosiones::utils::read_blargg_message;
+
+/// Give up on a test after this many frames, about a minute
+const FRAME_LIMIT: u64 = 3600;
+
+fn run(rom: &str) {
+    let mut cpu = CPU::new();
+    cpu.load_file(format!("tests/mmc3_test/{}", rom)).unwrap();
+
+    while cpu.frame() < FRAME_LIMIT {
+        if [0xDE, 0xB0, 0x61]
+            == [
+                cpu.memory.read(0x6001),
+                cpu.memory.read(0x6002),
+                cpu.memory.read(0x6003),
+            ]
+        {
+            match cpu.memory.read(0x6000) {
+                0x00 => return, // Passed
+                0x80 | 0x81 => {}
+                byte => panic!(
+                    "\nError code: 0x{:02X?}\n{}\n",
+                    byte,
+                    read_blargg_message(&mut cpu)
+                ),
+            }
+        }
+        cpu.step(false);
+    }
+    panic!("Timed out after {} frames", FRAME_LIMIT);
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn clocking() {
+    run("1-clocking.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn details() {
+    run("2-details.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn a12_clocking() {
+    run("3-A12_clocking.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn scanline_timing() {
+    run("4-scanline_timing.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn mmc3() {
+    run("5-MMC3.nes");
+}
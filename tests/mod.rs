@@ -1 +1,9 @@
 pub mod instr_test_v5;
+pub mod save_state;
+pub mod movie;
+pub mod battery;
+pub mod trainer;
+pub mod mmc3_test;
+pub mod sprite_hit_tests;
+pub mod sprite_overflow_tests;
+pub mod ppu_vbl_nmi;
@@ -0,0 +1,52 @@
+//! Movies played back on a freshly loaded ROM should reproduce the recorded run
+extern crate corrosiones;
+
+use movie::corrosiones::controller::{A, LEFT, START};
+use movie::corrosiones::cpu::CPU;
+use movie::corrosiones::movie::{Movie, PowerOn};
+
+const ROM: &str = "tests/instr_test_v5/01-basics.nes";
+
+fn load() -> CPU {
+    let mut cpu = CPU::new();
+    cpu.load_file(String::from(ROM)).unwrap();
+    cpu
+}
+
+fn record() -> (Movie, Vec<u8>) {
+    let mut cpu = load();
+    let mut movie = Movie::new(&cpu, PowerOn::default());
+    movie.power_on(&mut cpu).unwrap();
+    for frame in 0..10 {
+        let buttons = match frame % 3 {
+            0 => A,
+            1 => START | LEFT,
+            _ => 0,
+        };
+        movie.record_frame(&mut cpu, [buttons, 0]);
+    }
+    (movie, cpu.save_state())
+}
+
+#[test]
+fn playback_reproduces_the_recording() {
+    let (movie, expected) = record();
+
+    let mut cpu = load();
+    movie.play(&mut cpu).unwrap();
+
+    assert_eq!(cpu.save_state(), expected);
+}
+
+#[test]
+fn fm2_export_plays_back_the_same() {
+    let (movie, expected) = record();
+    let fm2 = movie.to_fm2("01-basics");
+
+    let imported = Movie::from_fm2(&fm2).unwrap();
+    let mut cpu = load();
+    imported.play(&mut cpu).unwrap();
+
+    assert_eq!(imported.rom_md5, load().rom_md5());
+    assert_eq!(cpu.save_state(), expected);
+}
@@ -0,0 +1,98 @@
+//! The ppu_vbl_nmi suite from blargg
+//!
+//! Downloaded from https://wiki.nesdev.com/w/index.php/Emulator_tests, the ROMs go next to this
+//! file. These time the vblank flag and the NMI down to the PPU dot.
+extern crate corrosiones;
+
+use ppu_vbl_nmi::corrosiones::cpu::CPU;
+use ppu_vbl_nmi::corrosiones::utils::read_blargg_message;
+
+/// Give up on a test after this many frames, about a minute
+const FRAME_LIMIT: u64 = 3600;
+
+fn run(rom: &str) {
+    let mut cpu = CPU::new();
+    cpu.load_file(format!("tests/ppu_vbl_nmi/{}", rom)).unwrap();
+
+    while cpu.frame() < FRAME_LIMIT {
+        if [0xDE, 0xB0, 0x61]
+            == [
+                cpu.memory.read(0x6001),
+                cpu.memory.read(0x6002),
+                cpu.memory.read(0x6003),
+            ]
+        {
+            match cpu.memory.read(0x6000) {
+                0x00 => return, // Passed
+                0x80 | 0x81 => {}
+                byte => panic!(
+                    "\nError code: 0x{:02X?}\n{}\n",
+                    byte,
+                    read_blargg_message(&mut cpu)
+                ),
+            }
+        }
+        cpu.step(false);
+    }
+    panic!("Timed out after {} frames", FRAME_LIMIT);
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn vbl_basics() {
+    run("01-vbl_basics.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn vbl_set_time() {
+    run("02-vbl_set_time.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn vbl_clear_time() {
+    run("03-vbl_clear_time.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn nmi_control() {
+    run("04-nmi_control.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn nmi_timing() {
+    run("05-nmi_timing.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn suppression() {
+    run("06-suppression.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn nmi_on_timing() {
+    run("07-nmi_on_timing.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn nmi_off_timing() {
+    run("08-nmi_off_timing.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn even_odd_frames() {
+    run("09-even_odd_frames.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn even_odd_timing() {
+    run("10-even_odd_timing.nes");
+}
@@ -0,0 +1,60 @@
+//! Save states taken mid-run should continue exactly like the original run
+extern crate corrosiones;
+
+use save_state::corrosiones::cpu::CPU;
+
+const ROM: &str = "tests/instr_test_v5/01-basics.nes";
+
+fn run(cpu: &mut CPU, steps: usize) {
+    for _ in 0..steps {
+        cpu.step(false);
+    }
+}
+
+#[test]
+fn continued_execution_is_identical() {
+    let mut cpu = CPU::new();
+    cpu.load_file(String::from(ROM)).unwrap();
+    run(&mut cpu, 20_000);
+
+    let checkpoint = cpu.save_state();
+    run(&mut cpu, 20_000);
+    let expected = cpu.save_state();
+
+    let mut restored = CPU::new();
+    restored.load_file(String::from(ROM)).unwrap();
+    restored.load_state(&checkpoint).unwrap();
+    run(&mut restored, 20_000);
+
+    assert_eq!(restored.save_state(), expected);
+}
+
+#[test]
+fn reloading_a_state_rewinds_the_machine() {
+    let mut cpu = CPU::new();
+    cpu.load_file(String::from(ROM)).unwrap();
+    run(&mut cpu, 5_000);
+
+    let checkpoint = cpu.save_state();
+    run(&mut cpu, 5_000);
+    cpu.load_state(&checkpoint).unwrap();
+
+    assert_eq!(cpu.save_state(), checkpoint);
+}
+
+#[test]
+fn states_from_other_roms_are_rejected() {
+    let mut cpu = CPU::new();
+    cpu.load_file(String::from(ROM)).unwrap();
+    let checkpoint = cpu.save_state();
+
+    let mut other = CPU::new();
+    other
+        .load_file(String::from("tests/instr_test_v5/02-implied.nes"))
+        .unwrap();
+
+    assert_eq!(
+        other.load_state(&checkpoint),
+        Err("Save state was made with a different ROM")
+    );
+}
@@ -0,0 +1,91 @@
+//! The sprite_hit_tests suite from blargg
+//!
+//! Downloaded from https://wiki.nesdev.com/w/index.php/Emulator_tests, the ROMs go next to this
+//! file. These come from before the tests reported through $6000, they leave a result code in
+//! $F8 where 1 is a pass.
+extern crate corrosiones;
+
+use sprite_hit_tests::corrosiones::cpu::CPU;
+
+/// The tests are done well within this many frames
+const FRAMES: u64 = 300;
+
+fn run(rom: &str) {
+    let mut cpu = CPU::new();
+    cpu.load_file(format!("tests/sprite_hit_tests/{}", rom))
+        .unwrap();
+
+    while cpu.frame() < FRAMES {
+        cpu.step(false);
+    }
+    match cpu.memory.read(0x00F8) {
+        0x01 => {} // Passed
+        byte => panic!("\nFailed test {}\n", byte),
+    }
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn basics() {
+    run("01.basics.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn alignment() {
+    run("02.alignment.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn corners() {
+    run("03.corners.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn flip() {
+    run("04.flip.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn left_clip() {
+    run("05.left_clip.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn right_edge() {
+    run("06.right_edge.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn screen_bottom() {
+    run("07.screen_bottom.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn double_height() {
+    run("08.double_height.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn timing_basics() {
+    run("09.timing_basics.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn timing_order() {
+    run("10.timing_order.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn edge_timing() {
+    run("11.edge_timing.nes");
+}
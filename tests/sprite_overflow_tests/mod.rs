@@ -0,0 +1,54 @@
+//! The sprite_overflow_tests suite from blargg
+//!
+//! Downloaded from https://wiki.nesdev.com/w/index.php/Emulator_tests, the ROMs go next to this
+//! file. Like sprite_hit_tests, these leave a result code in $F8 where 1 is a pass.
+extern crate corrosiones;
+
+use sprite_overflow_tests::corrosiones::cpu::CPU;
+
+/// The tests are done well within this many frames
+const FRAMES: u64 = 300;
+
+fn run(rom: &str) {
+    let mut cpu = CPU::new();
+    cpu.load_file(format!("tests/sprite_overflow_tests/{}", rom))
+        .unwrap();
+
+    while cpu.frame() < FRAMES {
+        cpu.step(false);
+    }
+    match cpu.memory.read(0x00F8) {
+        0x01 => {} // Passed
+        byte => panic!("\nFailed test {}\n", byte),
+    }
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn basics() {
+    run("1.Basics.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn details() {
+    run("2.Details.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn timing() {
+    run("3.Timing.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn obscure() {
+    run("4.Obscure.nes");
+}
+
+#[test]
+#[ignore = "needs the ROMs"]
+fn emulator() {
+    run("5.Emulator.nes");
+}
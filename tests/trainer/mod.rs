@@ -0,0 +1,59 @@
+//! Trainers should be loaded into $7000 and survive a reset
+extern crate corrosiones;
+
+use std::env;
+use std::fs;
+
+use trainer::corrosiones::cpu::CPU;
+
+/// Write an NROM image that jumps into a trainer storing $42 at $0000
+fn write_rom(name: &str) -> String {
+    let mut rom = vec![b'N', b'E', b'S', 0x1A, 0x01, 0x00, 0b0000_0100, 0x00];
+    rom.resize(16, 0x00);
+
+    // LDA #$42, STA $00, JMP $7004
+    let mut trainer = vec![0xA9, 0x42, 0x85, 0x00, 0x4C, 0x04, 0x70];
+    trainer.resize(0x0200, 0x00);
+    rom.extend(trainer);
+
+    // JMP $7000
+    let mut prg = vec![0x00; 0x4000];
+    prg[0..3].copy_from_slice(&[0x4C, 0x00, 0x70]);
+    prg[0x3FFC] = 0x00;
+    prg[0x3FFD] = 0x80;
+    rom.extend(prg);
+
+    let path = env::temp_dir().join(format!("corrosiones-trainer-{}.nes", name));
+    fs::write(&path, rom).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+fn run(cpu: &mut CPU, steps: usize) {
+    for _ in 0..steps {
+        cpu.step(false);
+    }
+}
+
+#[test]
+fn trainer_code_runs_from_sram() {
+    let mut cpu = CPU::new();
+    cpu.load_file(write_rom("runs")).unwrap();
+
+    run(&mut cpu, 4);
+
+    assert_eq!(cpu.memory.read(0x0000), 0x42);
+}
+
+#[test]
+fn trainer_is_kept_across_resets() {
+    let mut cpu = CPU::new();
+    cpu.load_file(write_rom("reset")).unwrap();
+    run(&mut cpu, 4);
+    cpu.memory.write(0x0000, 0x00);
+
+    cpu.reset();
+    run(&mut cpu, 4);
+
+    assert_eq!(cpu.memory.read(0x7000), 0xA9);
+    assert_eq!(cpu.memory.read(0x0000), 0x42);
+}